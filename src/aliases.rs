@@ -0,0 +1,232 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::builtins::split_words;
+
+/// How many times in a row aliases are expanded in one command, so aliases defined in
+/// terms of each other can't loop forever.
+const MAX_ALIAS_EXPANSIONS: usize = 10;
+
+/// The aliases of a shell-mode session, by name: those from `aliases` in the
+/// configuration, then those defined with `:alias`.
+pub(crate) type Aliases = BTreeMap<String, String>;
+
+/// Checks whether a word can be used as an alias name; like the shell, names can't hold
+/// quotes, `/`, `=`, `$`, or operators.
+fn is_alias_name(name: &str) -> bool {
+    !name.is_empty() && !name.chars().any(|c| c.is_whitespace() || "/=$`'\"\\|&;<>()".contains(c))
+}
+
+/// Loads the aliases from the configuration, warning about names that can't be aliases.
+///
+/// # Arguments
+///
+/// * `configured` - `aliases` from `.gptsh_config`, if set.
+///
+/// # Returns
+///
+/// * `Aliases` - The usable aliases.
+pub(crate) fn load_aliases(configured: Option<&HashMap<String, String>>) -> Aliases {
+    let mut aliases = Aliases::new();
+    for (name, value) in configured.into_iter().flatten() {
+        if is_alias_name(name) {
+            aliases.insert(name.clone(), value.clone());
+        } else {
+            eprintln!("Warning: '{}' in aliases in .gptsh_config can't be an alias name; it is ignored.", name);
+        }
+    }
+    aliases
+}
+
+/// Runs `:alias`: with no arguments it lists the aliases, `name` shows one, and
+/// `name=value` defines one for the rest of the session.
+///
+/// # Arguments
+///
+/// * `arguments` - What followed `:alias`, e.g. `gs='git status'`.
+/// * `aliases` - The session's aliases.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - An error message if an argument is malformed or names an
+///   unknown alias.
+pub(crate) fn run_alias_command(arguments: &str, aliases: &mut Aliases) -> Result<(), String> {
+    let words = split_words(arguments)?
+        .ok_or("Error: alias: Quote the value if it uses operators, e.g. :alias lc='ls | wc -l'.")?;
+    if words.is_empty() {
+        if aliases.is_empty() {
+            println!("No aliases. Define one with :alias name='command', or in aliases in .gptsh_config.");
+        }
+        for (name, value) in aliases.iter() {
+            println!("{}", format_alias(name, value));
+        }
+        return Ok(());
+    }
+    for word in words {
+        match word.split_once('=') {
+            Some((name, _)) if !is_alias_name(name) => {
+                return Err(format!("Error: alias: '{}' can't be an alias name.", name));
+            }
+            Some((name, value)) => {
+                aliases.insert(name.to_string(), value.to_string());
+            }
+            None => match aliases.get(&word) {
+                Some(value) => println!("{}", format_alias(&word, value)),
+                None => return Err(format!("Error: alias: {}: not found.", word)),
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Runs `:unalias`, removing aliases for the rest of the session.
+///
+/// # Arguments
+///
+/// * `arguments` - The names to remove.
+/// * `aliases` - The session's aliases.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - An error message if a name isn't an alias.
+pub(crate) fn run_unalias_command(arguments: &str, aliases: &mut Aliases) -> Result<(), String> {
+    let names: Vec<&str> = arguments.split_whitespace().collect();
+    if names.is_empty() {
+        return Err("Usage: :unalias <name>...".to_string());
+    }
+    for name in names {
+        if aliases.remove(name).is_none() {
+            return Err(format!("Error: unalias: {}: not found.", name));
+        }
+    }
+    Ok(())
+}
+
+/// Formats an alias as the shell lists it, e.g. `alias gs='git status'`.
+fn format_alias(name: &str, value: &str) -> String {
+    format!("alias {}='{}'", name, value.replace('\'', "'\\''"))
+}
+
+/// Expands an alias at the start of a command, and any alias its value starts with, as
+/// the shell does. An alias isn't expanded again inside its own value, so `ls='ls -F'`
+/// works.
+///
+/// # Arguments
+///
+/// * `command` - The command as typed.
+/// * `aliases` - The session's aliases.
+///
+/// # Returns
+///
+/// * `String` - The command to run.
+pub(crate) fn expand_aliases(command: &str, aliases: &Aliases) -> String {
+    let mut command = command.trim_start().to_string();
+    let mut expanded = Vec::new();
+    for _ in 0..MAX_ALIAS_EXPANSIONS {
+        let end = command.find(char::is_whitespace).unwrap_or(command.len());
+        let name = &command[..end];
+        let Some(value) = aliases.get(name).filter(|_| !expanded.iter().any(|done| done == name)) else {
+            break;
+        };
+        expanded.push(name.to_string());
+        command = format!("{}{}", value, &command[end..]);
+    }
+    command
+}
+
+/// Describes the aliases for the model, so prompts that mention them make sense.
+///
+/// # Returns
+///
+/// * `Option<String>` - The description, or `None` if there are no aliases.
+pub(crate) fn describe_aliases(aliases: &Aliases) -> Option<String> {
+    if aliases.is_empty() {
+        return None;
+    }
+    let lines: Vec<String> = aliases.iter().map(|(name, value)| format!("- `{}` runs `{}`", name, value)).collect();
+    Some(format!(
+        "The user has these aliases, which only work as the first word of commands they type themselves, so write suggested commands out in full:\n{}",
+        lines.join("\n")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aliases(pairs: &[(&str, &str)]) -> Aliases {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn aliases_expand_at_the_start_of_commands() {
+        let aliases = aliases(&[("gs", "git status"), ("ll", "ls -l")]);
+        assert_eq!(expand_aliases("gs --short", &aliases), "git status --short");
+        assert_eq!(expand_aliases("  ll", &aliases), "ls -l");
+        assert_eq!(expand_aliases("echo gs", &aliases), "echo gs");
+        assert_eq!(expand_aliases("gsx", &aliases), "gsx");
+    }
+
+    #[test]
+    fn aliases_are_not_expanded_inside_themselves() {
+        let aliases = aliases(&[("ls", "ls -F"), ("l", "ls -a"), ("ping", "pong"), ("pong", "ping -c 1")]);
+        assert_eq!(expand_aliases("ls src", &aliases), "ls -F src");
+        assert_eq!(expand_aliases("l", &aliases), "ls -F -a");
+        assert_eq!(expand_aliases("ping host", &aliases), "ping -c 1 host");
+    }
+
+    #[test]
+    fn alias_chains_stop_after_the_limit() {
+        let chain: Vec<(String, String)> =
+            (0..MAX_ALIAS_EXPANSIONS + 2).map(|i| (format!("a{}", i), format!("a{} x", i + 1))).collect();
+        let aliases: Aliases = chain.into_iter().collect();
+
+        let expanded = expand_aliases("a0", &aliases);
+        assert_eq!(expanded, format!("a{}{}", MAX_ALIAS_EXPANSIONS, " x".repeat(MAX_ALIAS_EXPANSIONS)));
+    }
+
+    #[test]
+    fn alias_values_are_unquoted() {
+        let mut aliases = Aliases::new();
+        run_alias_command("lc='ls | wc -l' gs=\"git status\" e=echo\\ hi", &mut aliases).unwrap();
+        assert_eq!(aliases["lc"], "ls | wc -l");
+        assert_eq!(aliases["gs"], "git status");
+        assert_eq!(aliases["e"], "echo hi");
+        assert_eq!(format_alias("q", "echo 'hi'"), "alias q='echo '\\''hi'\\'''");
+
+        assert!(run_alias_command("lc=ls | wc -l", &mut aliases).is_err());
+        assert!(run_alias_command("x='unclosed", &mut aliases).is_err());
+        assert_eq!(
+            run_alias_command("a/b=ls", &mut aliases),
+            Err("Error: alias: 'a/b' can't be an alias name.".to_string())
+        );
+        assert_eq!(run_alias_command("nope", &mut aliases), Err("Error: alias: nope: not found.".to_string()));
+        assert_eq!(aliases.len(), 3);
+    }
+
+    #[test]
+    fn unalias_removes_known_names_only() {
+        let mut aliases = aliases(&[("gs", "git status"), ("ll", "ls -l")]);
+        assert_eq!(run_unalias_command("gs", &mut aliases), Ok(()));
+        assert!(!aliases.contains_key("gs"));
+
+        assert_eq!(run_unalias_command("ll gs", &mut aliases), Err("Error: unalias: gs: not found.".to_string()));
+        assert!(aliases.is_empty());
+        assert_eq!(run_unalias_command("  ", &mut aliases), Err("Usage: :unalias <name>...".to_string()));
+    }
+}
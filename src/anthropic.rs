@@ -0,0 +1,394 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use reqwest::{Response, StatusCode};
+use serde_json::{json, Value};
+
+use crate::client::{ApiEndpoint, ChatClient, CompletionBackend, PendingResponse};
+use crate::images::split_data_url;
+
+/// The reply length requested when none is configured; the Messages API requires one.
+const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+/// Sends requests to Anthropic's Messages API. The modes build chat-completions requests as
+/// usual; they are translated on the way out, and replies are translated back into the
+/// chat-completions shape, so nothing else needs to know which provider is in use.
+pub(crate) struct AnthropicClient {
+    inner: ChatClient,
+}
+
+impl AnthropicClient {
+    /// Wraps a client whose endpoint points at the Messages API.
+    pub(crate) fn new(inner: ChatClient) -> Self {
+        Self { inner }
+    }
+}
+
+impl CompletionBackend for AnthropicClient {
+    fn endpoint(&self) -> &ApiEndpoint {
+        self.inner.endpoint()
+    }
+
+    fn complete<'a>(&'a self, body: &'a Value) -> PendingResponse<'a> {
+        Box::pin(async move {
+            let request = to_messages_request(body);
+            let response = self.inner.complete(&request).await?;
+            if !response.status().is_success() {
+                // Error bodies already have the `{"error": {"type", "message"}}` shape
+                return Ok(response);
+            }
+            let status = response.status();
+            let headers = response.headers().clone();
+            let reply: Value = response.json().await?;
+
+            let mut translated = http::Response::new(from_messages_response(&reply).to_string());
+            *translated.status_mut() = status;
+            *translated.headers_mut() = headers;
+            Ok(Response::from(translated))
+        })
+    }
+
+    fn key_label(&self) -> Option<String> {
+        self.inner.key_label()
+    }
+
+    fn report_request_error(&self, error: &reqwest::Error) {
+        self.inner.report_request_error(error);
+    }
+
+    fn report_api_error(&self, status: StatusCode, error_text: &str) {
+        self.inner.report_api_error(status, error_text);
+    }
+}
+
+/// Translates a chat-completions request into a Messages API request.
+/// `system` messages become the top-level `system` field, tool calls and their results
+/// become `tool_use` and `tool_result` blocks, attached images become `image` blocks, and
+/// fields the Messages API doesn't accept (seed, penalties, response format) are dropped.
+///
+/// # Arguments
+///
+/// * `body` - The chat-completions request body.
+///
+/// # Returns
+///
+/// * `Value` - The Messages API request body.
+fn to_messages_request(body: &Value) -> Value {
+    let mut system: Vec<&str> = Vec::new();
+    let mut messages: Vec<Value> = Vec::new();
+
+    for message in body["messages"].as_array().into_iter().flatten() {
+        let content = message["content"].as_str().unwrap_or_default();
+        let (role, blocks) = match message["role"].as_str() {
+            Some("system" | "developer") => {
+                system.push(content);
+                continue;
+            }
+            Some("assistant") => {
+                let mut blocks = Vec::new();
+                if !content.is_empty() {
+                    blocks.push(json!({ "type": "text", "text": content }));
+                }
+                for call in message["tool_calls"].as_array().into_iter().flatten() {
+                    let arguments = call["function"]["arguments"].as_str().unwrap_or("{}");
+                    blocks.push(json!({
+                        "type": "tool_use",
+                        "id": call["id"],
+                        "name": call["function"]["name"],
+                        "input": serde_json::from_str::<Value>(arguments).unwrap_or_else(|_| json!({}))
+                    }));
+                }
+                ("assistant", blocks)
+            }
+            Some("tool") => (
+                "user",
+                vec![json!({
+                    "type": "tool_result",
+                    "tool_use_id": message["tool_call_id"],
+                    "content": content
+                })],
+            ),
+            _ => match message["content"].as_array() {
+                Some(parts) => ("user", parts.iter().filter_map(to_content_block).collect()),
+                None => ("user", vec![json!({ "type": "text", "text": content })]),
+            },
+        };
+
+        // The Messages API rejects empty content, e.g. an assistant reply that was left blank
+        if blocks.is_empty() {
+            continue;
+        }
+        // Consecutive messages from the same side are merged, so every tool result
+        // directly follows the tool use it answers
+        match messages.last_mut() {
+            Some(last) if last["role"] == role => {
+                if let Some(existing) = last["content"].as_array_mut() {
+                    existing.extend(blocks);
+                }
+            }
+            _ => messages.push(json!({ "role": role, "content": blocks })),
+        }
+    }
+
+    let max_tokens = body["max_tokens"]
+        .as_u64()
+        .or_else(|| body["max_completion_tokens"].as_u64())
+        .unwrap_or(u64::from(DEFAULT_MAX_TOKENS));
+    let mut request = json!({
+        "model": body["model"],
+        "max_tokens": max_tokens,
+        "messages": messages
+    });
+    if !system.is_empty() {
+        request["system"] = Value::from(system.join("\n\n"));
+    }
+    for field in ["temperature", "top_p"] {
+        if !body[field].is_null() {
+            request[field] = body[field].clone();
+        }
+    }
+    if let Some(tools) = body["tools"].as_array() {
+        let tools: Vec<Value> = tools
+            .iter()
+            .map(|tool| {
+                json!({
+                    "name": tool["function"]["name"],
+                    "description": tool["function"]["description"],
+                    "input_schema": tool["function"]["parameters"]
+                })
+            })
+            .collect();
+        request["tools"] = Value::Array(tools);
+        request["tool_choice"] = json!({ "type": "auto" });
+    }
+    request
+}
+
+/// Translates a part of a multi-part user message into a Messages API content block.
+/// Images must be base64 `data:` URLs; anything else is left out.
+fn to_content_block(part: &Value) -> Option<Value> {
+    match part["type"].as_str() {
+        Some("text") => Some(json!({ "type": "text", "text": part["text"] })),
+        Some("image_url") => {
+            let (media_type, data) = split_data_url(part["image_url"]["url"].as_str()?)?;
+            Some(json!({
+                "type": "image",
+                "source": { "type": "base64", "media_type": media_type, "data": data }
+            }))
+        }
+        _ => None,
+    }
+}
+
+/// Translates a Messages API reply into a chat-completions response.
+///
+/// # Arguments
+///
+/// * `reply` - The Messages API response body.
+///
+/// # Returns
+///
+/// * `Value` - The equivalent chat-completions response body.
+fn from_messages_response(reply: &Value) -> Value {
+    let mut text = String::new();
+    let mut tool_calls = Vec::new();
+    for block in reply["content"].as_array().into_iter().flatten() {
+        match block["type"].as_str() {
+            Some("text") => text.push_str(block["text"].as_str().unwrap_or_default()),
+            Some("tool_use") => tool_calls.push(json!({
+                "id": block["id"],
+                "type": "function",
+                "function": {
+                    "name": block["name"],
+                    "arguments": block["input"].to_string()
+                }
+            })),
+            _ => {}
+        }
+    }
+
+    let mut message = json!({ "role": "assistant", "content": text });
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = Value::Array(tool_calls);
+    }
+    let finish_reason = match reply["stop_reason"].as_str() {
+        Some("tool_use") => "tool_calls",
+        Some("max_tokens") => "length",
+        _ => "stop",
+    };
+
+    json!({
+        "model": reply["model"],
+        "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason }],
+        "usage": {
+            "prompt_tokens": reply["usage"]["input_tokens"].as_u64().unwrap_or_default(),
+            "completion_tokens": reply["usage"]["output_tokens"].as_u64().unwrap_or_default()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_prompts_become_the_system_field() {
+        let body = json!({
+            "model": "claude-sonnet-4-5",
+            "messages": [
+                {"role": "system", "content": "Be brief."},
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "Hello."},
+                {"role": "user", "content": "Bye"}
+            ],
+            "seed": 42,
+            "temperature": 0.2
+        });
+
+        let request = to_messages_request(&body);
+
+        assert_eq!(request["system"], "Be brief.");
+        assert_eq!(request["max_tokens"], DEFAULT_MAX_TOKENS);
+        assert_eq!(request["temperature"], 0.2);
+        assert!(request.get("seed").is_none());
+        assert_eq!(
+            request["messages"],
+            json!([
+                {"role": "user", "content": [{"type": "text", "text": "Hi"}]},
+                {"role": "assistant", "content": [{"type": "text", "text": "Hello."}]},
+                {"role": "user", "content": [{"type": "text", "text": "Bye"}]}
+            ])
+        );
+    }
+
+    #[test]
+    fn empty_assistant_messages_are_left_out() {
+        let body = json!({
+            "messages": [
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": ""},
+                {"role": "user", "content": "Anyone there?"}
+            ]
+        });
+
+        let request = to_messages_request(&body);
+
+        assert_eq!(
+            request["messages"],
+            json!([{"role": "user", "content": [
+                {"type": "text", "text": "Hi"},
+                {"type": "text", "text": "Anyone there?"}
+            ]}])
+        );
+    }
+
+    #[test]
+    fn tool_calls_and_results_become_tool_use_blocks() {
+        let body = json!({
+            "messages": [
+                {"role": "user", "content": "What's here?"},
+                {"role": "assistant", "content": null, "tool_calls": [{
+                    "id": "call_1",
+                    "type": "function",
+                    "function": {"name": "list_directory", "arguments": "{\"path\":\".\"}"}
+                }]},
+                {"role": "tool", "tool_call_id": "call_1", "content": "Cargo.toml"}
+            ],
+            "tools": [{"type": "function", "function": {
+                "name": "list_directory",
+                "description": "Lists a directory.",
+                "parameters": {"type": "object"}
+            }}]
+        });
+
+        let request = to_messages_request(&body);
+
+        assert_eq!(
+            request["messages"][1],
+            json!({"role": "assistant", "content": [
+                {"type": "tool_use", "id": "call_1", "name": "list_directory", "input": {"path": "."}}
+            ]})
+        );
+        assert_eq!(
+            request["messages"][2],
+            json!({"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "call_1", "content": "Cargo.toml"}
+            ]})
+        );
+        assert_eq!(
+            request["tools"],
+            json!([{"name": "list_directory", "description": "Lists a directory.", "input_schema": {"type": "object"}}])
+        );
+
+        let reply = json!({
+            "model": "claude-sonnet-4-5",
+            "content": [
+                {"type": "text", "text": "Let me look."},
+                {"type": "tool_use", "id": "toolu_1", "name": "list_directory", "input": {"path": "."}}
+            ],
+            "stop_reason": "tool_use",
+            "usage": {"input_tokens": 12, "output_tokens": 7}
+        });
+
+        let response = from_messages_response(&reply);
+
+        assert_eq!(response["choices"][0]["finish_reason"], "tool_calls");
+        assert_eq!(response["choices"][0]["message"]["content"], "Let me look.");
+        assert_eq!(
+            response["choices"][0]["message"]["tool_calls"],
+            json!([{"id": "toolu_1", "type": "function", "function": {
+                "name": "list_directory",
+                "arguments": "{\"path\":\".\"}"
+            }}])
+        );
+        assert_eq!(response["usage"], json!({"prompt_tokens": 12, "completion_tokens": 7}));
+    }
+
+    #[test]
+    fn attached_images_become_image_blocks() {
+        let body = json!({
+            "messages": [{"role": "user", "content": [
+                {"type": "text", "text": "What is this?"},
+                {"type": "image_url", "image_url": {"url": "data:image/png;base64,iVBORw0KGgo="}},
+                {"type": "image_url", "image_url": {"url": "https://example.com/cat.png"}}
+            ]}]
+        });
+
+        let request = to_messages_request(&body);
+
+        assert_eq!(
+            request["messages"][0]["content"],
+            json!([
+                {"type": "text", "text": "What is this?"},
+                {"type": "image", "source": {"type": "base64", "media_type": "image/png", "data": "iVBORw0KGgo="}}
+            ])
+        );
+    }
+
+    #[test]
+    fn replies_cut_off_at_the_token_limit_are_marked_as_such() {
+        let reply = json!({
+            "content": [{"type": "text", "text": "The answer is"}],
+            "stop_reason": "max_tokens",
+            "usage": {"input_tokens": 3, "output_tokens": 4096}
+        });
+
+        let response = from_messages_response(&reply);
+
+        assert_eq!(response["choices"][0]["finish_reason"], "length");
+        assert!(response["choices"][0]["message"].get("tool_calls").is_none());
+    }
+}
@@ -0,0 +1,394 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io::{self, IsTerminal, Write};
+
+use serde_json::{json, Value};
+
+use crate::{
+    chat::report_empty_reply,
+    cli::CliOptions,
+    client::{block_on, connect, handle_non_success, read_event_stream, resolve_api_endpoint, ApiEndpoint, CompletionBackend},
+    markdown::{render_markdown, wrap_width, MarkdownRenderer},
+    models::{ChatChoice, ChatChunk, ChatMessage, ChatResponse, Config, Usage},
+    openai::load_config,
+    theme::init_theme,
+    usage::{check_budget, describe_request_cost, estimate_usage, record_usage},
+    utils::{
+        estimate_tokens, install_interrupt_handler, read_piped_stdin, start_loading_animation, InterruptGuard,
+        LoadingAnimation,
+    },
+};
+
+/// The instructions sent with every question. Unlike the default mode, the model is not
+/// asked for a command.
+const ASK_SYSTEM_PROMPT: &str = "You are a helpful assistant answering a question in a terminal. Answer directly and concisely, using Markdown where it helps. Only suggest commands when the question asks for them.";
+
+/// Answers a single question and exits (`gptsh ask-ai <question>`). Nothing is executed
+/// and there is no follow-up prompt. The question can also be piped through stdin; when
+/// both are given, the piped text is attached to the question as context.
+///
+/// # Arguments
+///
+/// * `question` - The question given on the command line, possibly empty.
+/// * `options` - The command-line options, e.g. `--model` and `--cost`.
+///
+/// # Returns
+///
+/// * `bool` - `false` if the question could not be answered.
+pub(crate) fn run_ask_mode(question: &str, options: &CliOptions) -> bool {
+    install_interrupt_handler();
+    let piped_input = match read_piped_stdin() {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let question = match (question.trim(), piped_input) {
+        ("", Some(input)) => input.trim().to_string(),
+        ("", None) => {
+            eprintln!("Error: ask-ai needs a question: gptsh ask-ai <question>, or pipe it through stdin.");
+            return false;
+        }
+        (question, Some(input)) => format!("{}\n```\n{}\n```", question, input.trim_end()),
+        (question, None) => question.to_string(),
+    };
+    answer_question(&question, options, None).is_some()
+}
+
+/// Sends a question and prints the answer as it arrives, styled as Markdown when output is
+/// a terminal. Shell mode uses this for lines starting with `?`.
+///
+/// # Arguments
+///
+/// * `question` - The question, including any piped context.
+/// * `options` - The command-line options, e.g. `--model` and `--cost`.
+/// * `context` - What happened earlier in the session, put before the question.
+///
+/// # Returns
+///
+/// * `Option<String>` - The answer (empty for a dry run), or `None` if the question could
+///   not be answered.
+pub(crate) fn answer_question(question: &str, options: &CliOptions, context: Option<&str>) -> Option<String> {
+    let question = match context {
+        Some(context) => format!("{}\n\n{}", context, question),
+        None => question.to_string(),
+    };
+    let endpoint = match resolve_api_endpoint(options) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            eprintln!("{}", e);
+            return None;
+        }
+    };
+    let config = load_config().unwrap_or_default();
+    init_theme(&config);
+    let stream = config.stream.unwrap_or(true) && endpoint.capabilities.streaming;
+    let request_body = build_ask_request(&question, &endpoint, &config, options, stream);
+
+    if options.dry_run {
+        println!("Dry run: no request will be sent.");
+        println!("Endpoint: {}", endpoint.url);
+        println!("Model: {}", endpoint.model);
+        println!("Estimated prompt tokens: ~{}", estimate_tokens(&format!("{}\n{}", ASK_SYSTEM_PROMPT, question)));
+        println!("\n--- Question ---\n{}", question);
+        return Some(String::new());
+    }
+    if let Err(e) = check_budget(options.override_budget) {
+        eprintln!("{}", e);
+        return None;
+    }
+
+    let backend = match connect(options, endpoint) {
+        Ok(backend) => backend,
+        Err(e) => {
+            eprintln!("{}", e);
+            return None;
+        }
+    };
+    let render = config.render_markdown.unwrap_or(true) && io::stdout().is_terminal();
+    let (answer, usage) = block_on(send_question(backend.as_ref(), &request_body, render))?;
+
+    let usage = usage.unwrap_or_else(|| estimate_usage(&format!("{}\n{}", ASK_SYSTEM_PROMPT, question), &answer));
+    let model = &backend.endpoint().model;
+    record_usage(backend.as_ref(), usage);
+    if options.show_cost || config.show_cost.unwrap_or(false) {
+        eprintln!("{}", describe_request_cost(model, &usage, &config));
+    }
+    Some(answer)
+}
+
+/// Builds the request for a question, with the chat mode's sampling parameters.
+///
+/// # Arguments
+///
+/// * `question` - The question, including any piped context.
+/// * `endpoint` - The endpoint, whose model determines the request shape.
+/// * `config` - The loaded configuration.
+/// * `options` - The command-line options, for `--seed`.
+/// * `stream` - Whether to ask for the answer to be streamed.
+///
+/// # Returns
+///
+/// * `Value` - The JSON request body.
+fn build_ask_request(question: &str, endpoint: &ApiEndpoint, config: &Config, options: &CliOptions, stream: bool) -> Value {
+    let capabilities = endpoint.capabilities;
+    let messages = [
+        json!({ "role": "system", "content": ASK_SYSTEM_PROMPT }),
+        json!({ "role": "user", "content": question }),
+    ];
+    let mut body = json!({
+        "model": endpoint.model,
+        "messages": capabilities.adapt_json_messages(&messages),
+    });
+    if stream {
+        endpoint.request_streaming(&mut body);
+    }
+    if let Some(max_tokens) = config.max_tokens {
+        body[capabilities.token_limit_field()] = Value::from(max_tokens);
+    }
+    if let Some(seed) = options.seed.or(config.seed) {
+        body["seed"] = Value::from(seed);
+    }
+    let sampling = config.generation.as_ref().and_then(|generation| generation.chat);
+    if let Some(Value::Object(parameters)) = sampling.and_then(|sampling| serde_json::to_value(sampling).ok()) {
+        for (name, value) in parameters {
+            body[name] = value;
+        }
+    }
+    body
+}
+
+/// Sends a question and prints the answer, as it streams in when the request asks for
+/// streaming. The loading indicator is only shown on a terminal, so redirected answers
+/// stay clean. Ctrl-C cancels the request or stops the answer early; what was already
+/// shown is kept.
+///
+/// # Arguments
+///
+/// * `backend` - Where the request is sent.
+/// * `request_body` - The JSON request body.
+/// * `render` - Whether to style the answer as Markdown.
+///
+/// # Returns
+///
+/// * `Option<(String, Option<Usage>)>` - The answer and the tokens the API reported, or
+///   `None` if the request failed or was cancelled.
+async fn send_question(backend: &dyn CompletionBackend, request_body: &Value, render: bool) -> Option<(String, Option<Usage>)> {
+    let mut spinner = io::stdout().is_terminal().then(start_loading_animation);
+    let interrupt = InterruptGuard::new();
+    let response = match interrupt.run(backend.complete(request_body)).await {
+        Some(Ok(response)) => response,
+        Some(Err(e)) => {
+            stop_spinner(&mut spinner);
+            backend.report_request_error(&e);
+            return None;
+        }
+        None => {
+            stop_spinner(&mut spinner);
+            println!("Request cancelled.");
+            return None;
+        }
+    };
+    if !response.status().is_success() {
+        stop_spinner(&mut spinner);
+        handle_non_success(backend, response).await;
+        return None;
+    }
+
+    if !request_body["stream"].as_bool().unwrap_or(false) {
+        let parsed = interrupt.run(response.json::<ChatResponse>()).await;
+        stop_spinner(&mut spinner);
+        let response = match parsed {
+            Some(Ok(response)) => response,
+            Some(Err(e)) => {
+                eprintln!("Failed to parse the response: {}", e);
+                return None;
+            }
+            None => {
+                println!("Request cancelled.");
+                return None;
+            }
+        };
+        let Some(choice) = response.first_choice() else {
+            eprintln!("The response contains no choices.");
+            return None;
+        };
+        let answer = choice.message.content.as_deref().unwrap_or_default();
+        if answer.trim().is_empty() {
+            report_empty_reply(choice);
+            return None;
+        }
+        if render {
+            println!("{}", render_markdown(answer.trim(), wrap_width(), 0).trim_end());
+        } else {
+            println!("{}", answer.trim());
+        }
+        warn_if_truncated(choice.finish_reason.as_deref());
+        return Some((answer.to_string(), response.usage));
+    }
+
+    let mut renderer = render.then(|| MarkdownRenderer::wrapping(wrap_width(), 0));
+    let mut answer = String::new();
+    let mut refusal = String::new();
+    let mut finish_reason = None;
+    let mut usage = None;
+    let result = interrupt
+        .run(read_event_stream(response, |chunk| {
+            // Events that don't look like chat completions are skipped
+            let Ok(chunk) = serde_json::from_value::<ChatChunk>(chunk) else {
+                return true;
+            };
+            if chunk.usage.is_some() {
+                usage = chunk.usage;
+            }
+            let Some(choice) = chunk.choices.into_iter().next() else {
+                return true;
+            };
+            if let Some(token) = choice.delta.content.as_deref() {
+                let token = if answer.is_empty() { token.trim_start() } else { token };
+                if !token.is_empty() {
+                    stop_spinner(&mut spinner);
+                    match renderer.as_mut() {
+                        Some(renderer) => print!("{}", renderer.push(token)),
+                        None => print!("{}", token),
+                    }
+                    io::stdout().flush().unwrap();
+                    answer.push_str(token);
+                }
+            }
+            if let Some(text) = choice.delta.refusal {
+                refusal.push_str(&text);
+            }
+            if choice.finish_reason.is_some() {
+                finish_reason = choice.finish_reason;
+            }
+            true
+        }))
+        .await;
+    stop_spinner(&mut spinner);
+    // End the answer with a line break, unless its last line already has one
+    let last_line = match renderer.as_mut() {
+        Some(renderer) => renderer.finish(),
+        None if answer.ends_with('\n') => String::new(),
+        None => answer.lines().last().unwrap_or_default().to_string(),
+    };
+    if !last_line.is_empty() {
+        if renderer.is_some() {
+            print!("{}", last_line);
+        }
+        println!();
+    }
+
+    match result {
+        Some(Err(e)) => {
+            eprintln!("Error reading streamed response: {}", e);
+            return None;
+        }
+        None if answer.trim().is_empty() => {
+            println!("Request cancelled.");
+            return None;
+        }
+        None => println!("Answer interrupted."),
+        Some(Ok(())) if answer.trim().is_empty() => {
+            let choice = ChatChoice {
+                message: ChatMessage {
+                    refusal: (!refusal.is_empty()).then_some(refusal),
+                    ..ChatMessage::default()
+                },
+                finish_reason,
+            };
+            report_empty_reply(&choice);
+            return None;
+        }
+        Some(Ok(())) => warn_if_truncated(finish_reason.as_deref()),
+    }
+    Some((answer, usage))
+}
+
+/// Warns that an answer is incomplete when the model stopped at the token limit.
+///
+/// # Arguments
+///
+/// * `finish_reason` - Why the model stopped, if it said.
+fn warn_if_truncated(finish_reason: Option<&str>) {
+    if finish_reason == Some("length") {
+        eprintln!("Warning: The answer was cut off at the token limit. Raise `max_tokens` in .gptsh_config to get the rest.");
+    }
+}
+
+/// Stops the loading indicator if it is still running.
+fn stop_spinner(spinner: &mut Option<LoadingAnimation>) {
+    if let Some(spinner) = spinner.take() {
+        spinner.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::testing::CannedBackend;
+
+    /// Sends a question to a canned backend without rendering Markdown.
+    fn ask(backend: &CannedBackend, stream: bool) -> Option<(String, Option<Usage>)> {
+        let mut request_body = json!({ "model": "gpt-4", "messages": [] });
+        if stream {
+            backend.endpoint().request_streaming(&mut request_body);
+        }
+        block_on(send_question(backend, &request_body, false))
+    }
+
+    #[test]
+    fn answers_are_read_with_their_usage() {
+        let backend = CannedBackend::replying("Use `du -sh`.");
+
+        let (answer, usage) = ask(&backend, false).unwrap();
+
+        assert_eq!(answer, "Use `du -sh`.");
+        assert_eq!(usage.map(|usage| usage.prompt_tokens), Some(10));
+    }
+
+    #[test]
+    fn empty_answers_and_refusals_are_not_answers() {
+        let body = json!({
+            "choices": [{"message": {"role": "assistant", "content": null, "refusal": "I can't help with that."}, "finish_reason": "stop"}]
+        });
+        let backend = CannedBackend::new(&[(200, &body.to_string())]);
+        assert!(ask(&backend, false).is_none());
+
+        let backend = CannedBackend::new(&[(200, r#"{"choices": []}"#)]);
+        assert!(ask(&backend, false).is_none());
+    }
+
+    #[test]
+    fn streamed_answers_are_assembled() {
+        let events = [
+            json!({"choices": [{"delta": {"content": "Use "}}]}),
+            json!({"choices": [{"delta": {"content": "`df -h`."}, "finish_reason": "stop"}]}),
+            json!({"choices": [], "usage": {"prompt_tokens": 12, "completion_tokens": 4}}),
+        ];
+        let mut body: String = events.iter().map(|event| format!("data: {}\n\n", event)).collect();
+        body.push_str("data: [DONE]\n\n");
+        let backend = CannedBackend::new(&[(200, &body)]);
+
+        let (answer, usage) = ask(&backend, true).unwrap();
+
+        assert_eq!(answer, "Use `df -h`.");
+        assert_eq!(usage.map(|usage| usage.completion_tokens), Some(4));
+        assert_eq!(backend.requests.borrow()[0]["stream_options"]["include_usage"], true);
+    }
+}
@@ -0,0 +1,291 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use chrono::Local;
+
+use crate::{
+    cli::CliOptions,
+    client::{connect, resolve_api_endpoint},
+    command_shell::{command_shell, shebang},
+    models::CommandSuggestion,
+    openai::suggest_command,
+    usage::check_budget,
+};
+
+/// Translates every task in a file into a command and writes them to a script for review
+/// (`gptsh batch <file> --script <script>`). Nothing is executed. Blank lines and lines
+/// starting with `#` are skipped, and tasks that can't be translated are written as
+/// commented-out FIXME blocks so the rest of the batch still completes.
+///
+/// # Arguments
+///
+/// * `input_path` - The file of tasks, one per line.
+/// * `options` - The command-line options, including the `--script` path.
+///
+/// # Returns
+///
+/// * `bool` - `false` if the batch could not be run at all.
+pub(crate) fn run_batch_mode(input_path: &str, options: &CliOptions) -> bool {
+    let Some(output_path) = options.script.as_deref() else {
+        eprintln!("Error: batch needs a script to write: gptsh batch <file> --script <script>");
+        return false;
+    };
+    if is_same_file(input_path, output_path) {
+        eprintln!("Error: The script would replace the batch file {}; choose another name.", input_path);
+        return false;
+    }
+    let tasks = match fs::read_to_string(input_path) {
+        Ok(contents) => read_tasks(&contents),
+        Err(e) => {
+            eprintln!("Error: Could not read {}: {}", input_path, e);
+            return false;
+        }
+    };
+    if tasks.is_empty() {
+        eprintln!("Error: {} contains no tasks.", input_path);
+        return false;
+    }
+
+    let backend = match resolve_api_endpoint(options).and_then(|endpoint| connect(options, endpoint)) {
+        Ok(backend) => backend,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    let mut script = match create_script(output_path, input_path, command_shell()) {
+        Ok(script) => script,
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            eprintln!("Error: {} already exists; remove it or choose another name.", output_path);
+            return false;
+        }
+        Err(e) => {
+            eprintln!("Error: Could not write {}: {}", output_path, e);
+            return false;
+        }
+    };
+
+    let mut failures = 0;
+    for (index, task) in tasks.iter().enumerate() {
+        eprintln!("[{}/{}] {}", index + 1, tasks.len(), task);
+        let result = check_budget(options.override_budget)
+            .and_then(|_| suggest_command(backend.as_ref(), task, options));
+        if result.is_err() {
+            failures += 1;
+        }
+        if let Err(e) = write_block(&mut script, task, result) {
+            eprintln!("Error: Could not write {}: {}", output_path, e);
+            return false;
+        }
+    }
+
+    println!(
+        "Wrote {} commands to {}{}. Review the script, then make it executable with `chmod +x {}` to run it.",
+        tasks.len() - failures,
+        output_path,
+        if failures > 0 {
+            format!(" ({} marked FIXME)", failures)
+        } else {
+            String::new()
+        },
+        output_path
+    );
+    true
+}
+
+/// Checks whether two paths name the same existing file, however they are written.
+fn is_same_file(first: &str, second: &str) -> bool {
+    match (fs::canonicalize(first), fs::canonicalize(second)) {
+        (Ok(first), Ok(second)) => first == second,
+        _ => false,
+    }
+}
+
+/// Collects the tasks in a batch file, skipping blank lines and `#` comments.
+///
+/// # Arguments
+///
+/// * `contents` - The file's contents.
+///
+/// # Returns
+///
+/// * `Vec<String>` - The tasks, trimmed.
+fn read_tasks(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Creates the output script with a shebang for the command shell and a header. An existing file is never
+/// replaced, and the script is left for the user to make executable once reviewed.
+///
+/// # Arguments
+///
+/// * `path` - The path to the script.
+/// * `input_path` - The batch file the script is generated from, named in the header.
+/// * `shell` - The shell the commands are written for, named in the shebang.
+///
+/// # Returns
+///
+/// * `io::Result<File>` - The open script, or an I/O error, of kind `AlreadyExists` if
+///   there is a file at `path`.
+fn create_script(path: &str, input_path: &str, shell: &Path) -> io::Result<File> {
+    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    writeln!(file, "{}", shebang(shell))?;
+    writeln!(
+        file,
+        "# Generated by gptsh from {} at {}",
+        input_path,
+        Local::now().format("%Y-%m-%d %H:%M:%S")
+    )?;
+    writeln!(file, "# Review every command before running this script.")?;
+    Ok(file)
+}
+
+/// Writes one task to the script: its prompt as a comment, then the command, or a
+/// commented-out FIXME block explaining why there is none.
+///
+/// # Arguments
+///
+/// * `script` - The open script.
+/// * `task` - The task's prompt.
+/// * `result` - The suggested command, or why translation failed.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An empty result or an I/O error.
+fn write_block(script: &mut impl Write, task: &str, result: Result<CommandSuggestion, String>) -> io::Result<()> {
+    writeln!(script)?;
+    writeln!(script, "# {}", task)?;
+    match result {
+        Ok(suggestion) => {
+            if suggestion.needs_confirmation {
+                writeln!(script, "# Note: the model flagged this command as modifying or deleting data.")?;
+            }
+            writeln!(script, "{}", suggestion.command.trim())?;
+        }
+        Err(reason) => {
+            // The reason may quote a multi-line command; keep every line commented out
+            for (index, line) in reason.lines().enumerate() {
+                let prefix = if index == 0 { "FIXME: " } else { "" };
+                writeln!(script, "# {}{}", prefix, line)?;
+            }
+        }
+    }
+    script.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::testing::CannedBackend, command_shell::command_shell_name, utils::testing::test_dir};
+
+    fn suggestion(command: &str, needs_confirmation: bool) -> CommandSuggestion {
+        CommandSuggestion {
+            command: command.to_string(),
+            explanation: String::new(),
+            needs_confirmation,
+        }
+    }
+
+    fn block(task: &str, result: Result<CommandSuggestion, String>) -> String {
+        let mut script = Vec::new();
+        write_block(&mut script, task, result).unwrap();
+        String::from_utf8(script).unwrap()
+    }
+
+    #[test]
+    fn tasks_skip_blank_lines_and_comments() {
+        let contents = "# weekly maintenance\n\n  rotate the nginx logs  \n\t\n#old files\narchive /var/tmp\n";
+        assert_eq!(read_tasks(contents), ["rotate the nginx logs", "archive /var/tmp"]);
+        assert!(read_tasks("# only comments\n\n").is_empty());
+    }
+
+    #[test]
+    fn commands_are_written_below_their_task() {
+        assert_eq!(
+            block("rotate the nginx logs", Ok(suggestion(" logrotate -f /etc/logrotate.d/nginx\n", false))),
+            "\n# rotate the nginx logs\nlogrotate -f /etc/logrotate.d/nginx\n"
+        );
+        let flagged = block("clear the cache", Ok(suggestion("rm -rf ~/.cache/app", true)));
+        assert_eq!(
+            flagged,
+            "\n# clear the cache\n# Note: the model flagged this command as modifying or deleting data.\nrm -rf ~/.cache/app\n"
+        );
+    }
+
+    #[test]
+    fn failed_tasks_are_commented_out() {
+        assert_eq!(
+            block("wipe the disk", Err("the suggested command is banned: dd \\\n  of=/dev/sda".to_string())),
+            "\n# wipe the disk\n# FIXME: the suggested command is banned: dd \\\n#   of=/dev/sda\n"
+        );
+    }
+
+    #[test]
+    fn scripts_never_replace_a_file() {
+        let dir = test_dir("batch");
+        let tasks = dir.join("tasks.txt");
+        fs::write(&tasks, "rotate the nginx logs\n").unwrap();
+        let tasks = tasks.to_str().unwrap();
+
+        let bash = Path::new("/bin/bash");
+        let error = create_script(tasks, tasks, bash).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(tasks).unwrap(), "rotate the nginx logs\n");
+
+        let script = dir.join("maintenance.sh");
+        let _ = fs::remove_file(&script);
+        create_script(script.to_str().unwrap(), tasks, bash).unwrap();
+        assert!(fs::read_to_string(&script).unwrap().starts_with("#!/usr/bin/env bash\n# Generated by gptsh from "));
+        assert!(create_script(script.to_str().unwrap(), tasks, bash).is_err());
+
+        assert!(is_same_file(tasks, dir.join(".").join("tasks.txt").to_str().unwrap()));
+        assert!(!is_same_file(tasks, script.to_str().unwrap()));
+    }
+
+    #[test]
+    fn scripts_are_written_for_the_shell_they_run_with() {
+        let backend = CannedBackend::replying("ls");
+        suggest_command(&backend, "list files", &CliOptions::default()).unwrap();
+        let prompt = backend.requests.borrow()[0]["messages"].to_string();
+        let shell = command_shell_name();
+        assert!(prompt.contains(&format!("into a {} command", shell)), "{}", prompt);
+
+        let script = test_dir("batch-prompt-shell").join("tasks.sh");
+        create_script(script.to_str().unwrap(), "tasks.txt", command_shell()).unwrap();
+        let first_line = fs::read_to_string(&script).unwrap().lines().next().unwrap().to_string();
+        assert_eq!(first_line, format!("#!/usr/bin/env {}", shell));
+    }
+
+    #[test]
+    fn scripts_use_the_command_shell() {
+        let dir = test_dir("batch-shell");
+        let script = dir.join("maintenance.fish");
+        let _ = fs::remove_file(&script);
+        create_script(script.to_str().unwrap(), "tasks.txt", Path::new("/usr/bin/fish")).unwrap();
+        assert!(fs::read_to_string(&script).unwrap().starts_with("#!/usr/bin/env fish\n"));
+    }
+}
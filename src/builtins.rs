@@ -0,0 +1,432 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{env, path::PathBuf};
+
+/// A shell builtin that gptsh runs itself, since running it in a child shell would have
+/// no lasting effect.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Builtin {
+    /// Change directory: to the home directory if no path is given, or back with `-`.
+    Cd(Option<String>),
+    /// Set environment variables, or list them if none are given. A name without a value
+    /// leaves the variable as it is.
+    Export(Vec<(String, Option<String>)>),
+    /// Remove environment variables.
+    Unset(Vec<String>),
+}
+
+/// Parses a command as a builtin gptsh can run itself. A line of nothing but assignments,
+/// such as `FOO=bar`, is taken as `export FOO=bar`, so the variable lasts for the session.
+///
+/// # Arguments
+///
+/// * `command` - The command line. Quotes, `~`, and `$VAR` are handled as the shell
+///   would.
+///
+/// # Returns
+///
+/// * `Option<Result<Builtin, String>>` - The builtin, an error message if it is
+///   malformed, or `None` if the command isn't `cd`, `export`, `unset`, or assignments, or
+///   is combined with other commands (e.g. `cd src && make`), which only a real shell can
+///   run.
+pub(crate) fn parse_builtin(command: &str) -> Option<Result<Builtin, String>> {
+    let name = command.split_whitespace().next()?;
+    if is_assignment(name) {
+        return parse_assignments(command);
+    }
+    if !matches!(name, "cd" | "export" | "unset") {
+        return None;
+    }
+    let words = match split_words(command) {
+        Ok(Some(words)) => words,
+        Ok(None) => return None,
+        Err(e) => return Some(Err(e)),
+    };
+    // The name itself can't be quoted or expanded, so it is the first word as typed
+    let arguments = words[1..].to_vec();
+    Some(match name {
+        "cd" => parse_cd(arguments),
+        "export" => parse_export(arguments),
+        _ => parse_unset(arguments),
+    })
+}
+
+fn parse_cd(arguments: Vec<String>) -> Result<Builtin, String> {
+    let mut arguments = arguments.into_iter().filter(|argument| argument != "--");
+    let path = arguments.next();
+    if arguments.next().is_some() {
+        return Err("Error: cd: too many arguments.".to_string());
+    }
+    Ok(Builtin::Cd(path))
+}
+
+fn parse_export(arguments: Vec<String>) -> Result<Builtin, String> {
+    let mut variables = Vec::new();
+    for argument in arguments {
+        if argument.starts_with('-') {
+            return Err(format!("Error: export: option '{}' isn't supported here.", argument));
+        }
+        let (name, value) = match argument.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (argument, None),
+        };
+        if !is_variable_name(&name) {
+            return Err(format!("Error: export: '{}' is not a valid variable name.", name));
+        }
+        variables.push((name, value));
+    }
+    Ok(Builtin::Export(variables))
+}
+
+fn parse_unset(arguments: Vec<String>) -> Result<Builtin, String> {
+    let mut names = Vec::new();
+    for argument in arguments {
+        match argument.as_str() {
+            "-v" => {}
+            option if option.starts_with('-') => {
+                return Err(format!("Error: unset: option '{}' isn't supported here.", option));
+            }
+            name if !is_variable_name(name) => {
+                return Err(format!("Error: unset: '{}' is not a valid variable name.", name));
+            }
+            _ => names.push(argument),
+        }
+    }
+    Ok(Builtin::Unset(names))
+}
+
+/// Checks whether a word assigns a variable, such as `FOO=bar`.
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| is_variable_name(name))
+}
+
+/// Parses a line of assignments. A line that goes on to run a command, such as
+/// `FOO=bar make`, sets the variables for that command only, so it is left to the shell.
+fn parse_assignments(command: &str) -> Option<Result<Builtin, String>> {
+    let words = match split_words(command) {
+        Ok(Some(words)) => words,
+        Ok(None) => return None,
+        Err(e) => return Some(Err(e)),
+    };
+    let mut variables = Vec::new();
+    for word in words {
+        let (name, value) = word.split_once('=').filter(|(name, _)| is_variable_name(name))?;
+        variables.push((name.to_string(), Some(value.to_string())));
+    }
+    Some(Ok(Builtin::Export(variables)))
+}
+
+/// Checks whether a word is a valid environment variable name, such as `PATH` or `_x1`.
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Splits a command line into words the way the shell does: quotes group words and are
+/// removed, a backslash escapes the next character outside single quotes, `$VAR` and
+/// `${VAR}` are expanded outside single quotes, and `~` is expanded at the start of a
+/// word or of an assignment's value.
+///
+/// # Returns
+///
+/// * `Result<Option<Vec<String>>, String>` - The words; `None` if the line uses
+///   operators such as `&&`, `|`, or `;`, redirection, or command substitution; or an
+///   error message if a quote isn't closed.
+pub(crate) fn split_words(command: &str) -> Result<Option<Vec<String>>, String> {
+    split_words_with(command, |name| env::var(name).ok())
+}
+
+/// Splits a command line into words like `split_words`, looking variables up with
+/// `lookup` instead of in the environment.
+///
+/// # Arguments
+///
+/// * `command` - The command line.
+/// * `lookup` - Gets a variable's value, or `None` if it isn't set.
+fn split_words_with(command: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<Option<Vec<String>>, String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // Whether a word has started, so `''` still counts as an (empty) word
+    let mut in_word = false;
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(unclosed_quote(command)),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') if chars.peek().is_some_and(|c| matches!(c, '"' | '\\' | '$' | '`')) => {
+                            word.extend(chars.next());
+                        }
+                        Some('$') => match expand_variable(&mut chars, &lookup) {
+                            Some(value) => word.push_str(&value),
+                            None => return Ok(None),
+                        },
+                        Some('`') => return Ok(None),
+                        Some(c) => word.push(c),
+                        None => return Err(unclosed_quote(command)),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                word.extend(chars.next());
+            }
+            '$' => {
+                in_word = true;
+                match expand_variable(&mut chars, &lookup) {
+                    Some(value) => word.push_str(&value),
+                    None => return Ok(None),
+                }
+            }
+            '~' if !in_word || (word.ends_with('=') && is_variable_name(&word[..word.len() - 1])) => {
+                in_word = true;
+                if matches!(chars.peek(), None | Some('/')) || chars.peek().is_some_and(|c| c.is_whitespace()) {
+                    match dirs::home_dir() {
+                        Some(home) => word.push_str(&home.to_string_lossy()),
+                        None => word.push('~'),
+                    }
+                } else {
+                    word.push('~');
+                }
+            }
+            ';' | '&' | '|' | '<' | '>' | '(' | ')' | '`' => return Ok(None),
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(Some(words))
+}
+
+/// Expands the variable after a `$`, which has already been read; an unset variable
+/// expands to nothing, and a `$` not followed by a name is kept.
+///
+/// # Returns
+///
+/// * `Option<String>` - The value, or `None` for command substitution or another form
+///   only a real shell can expand, such as `$(pwd)` or `${VAR:-default}`.
+fn expand_variable(
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+    lookup: &impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    let name = match chars.peek() {
+        Some('{') => {
+            chars.next();
+            let mut name = String::new();
+            loop {
+                match chars.next()? {
+                    '}' => break,
+                    c => name.push(c),
+                }
+            }
+            if !is_variable_name(&name) {
+                return None;
+            }
+            name
+        }
+        Some('(') => return None,
+        _ => {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') || (name.is_empty() && c.is_ascii_digit()) {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            if name.is_empty() {
+                return Some("$".to_string());
+            }
+            name
+        }
+    };
+    Some(lookup(&name).unwrap_or_default())
+}
+
+fn unclosed_quote(command: &str) -> String {
+    format!("Error: The quote in '{}' is never closed.", command.trim())
+}
+
+/// Runs a builtin, changing gptsh's own directory or environment, which the commands it
+/// runs afterwards inherit.
+///
+/// # Arguments
+///
+/// * `builtin` - The builtin, as returned by [`parse_builtin`].
+///
+/// # Returns
+///
+/// * `Result<(), String>` - An error message if the builtin failed.
+pub(crate) fn run_builtin(builtin: Builtin) -> Result<(), String> {
+    match builtin {
+        Builtin::Cd(path) => change_directory(path),
+        Builtin::Export(variables) if variables.is_empty() => {
+            let mut variables: Vec<_> = env::vars().collect();
+            variables.sort();
+            for (name, value) in variables {
+                println!("export {}='{}'", name, value.replace('\'', "'\\''"));
+            }
+            Ok(())
+        }
+        Builtin::Export(variables) => {
+            for (name, value) in variables {
+                if let Some(value) = value {
+                    env::set_var(name, value);
+                }
+            }
+            Ok(())
+        }
+        Builtin::Unset(names) => {
+            for name in names {
+                env::remove_var(name);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Changes the working directory like `cd`, keeping `PWD` and `OLDPWD` up to date so
+/// `cd -` can go back.
+fn change_directory(path: Option<String>) -> Result<(), String> {
+    let target = match path.as_deref() {
+        None => dirs::home_dir().ok_or("Error: cd: The home directory is unknown.")?,
+        Some("-") => PathBuf::from(env::var("OLDPWD").map_err(|_| "Error: cd: OLDPWD is not set.")?),
+        Some(path) => PathBuf::from(path),
+    };
+    let previous = env::current_dir().ok();
+    env::set_current_dir(&target).map_err(|e| format!("Error: cd: {}: {}", target.display(), e))?;
+    if let Some(previous) = previous {
+        env::set_var("OLDPWD", previous);
+    }
+    if let Ok(current) = env::current_dir() {
+        // Like the shell, `cd -` says where it went
+        if path.as_deref() == Some("-") {
+            println!("{}", current.display());
+        }
+        env::set_var("PWD", current);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(command: &str) -> Vec<String> {
+        split_words(command).unwrap().unwrap()
+    }
+
+    fn export(variables: &[(&str, Option<&str>)]) -> Builtin {
+        Builtin::Export(
+            variables.iter().map(|(name, value)| (name.to_string(), value.map(str::to_string))).collect(),
+        )
+    }
+
+    #[test]
+    fn quotes_group_words_and_are_removed() {
+        assert_eq!(words("cd 'My Documents'"), ["cd", "My Documents"]);
+        assert_eq!(words(r#"export GREETING="hello world" X='a"b'"#), ["export", "GREETING=hello world", "X=a\"b"]);
+        assert_eq!(words(r#"cd My\ Documents"#), ["cd", "My Documents"]);
+        assert_eq!(words(r#"echo "a \"b\" \n" 'c\d' ''"#), ["echo", "a \"b\" \\n", "c\\d", ""]);
+        assert_eq!(words("  spaced   out  "), ["spaced", "out"]);
+    }
+
+    #[test]
+    fn variables_expand_outside_single_quotes() {
+        let lookup = |name: &str| (name == "WORDS").then(|| "value".to_string());
+        assert_eq!(
+            split_words_with("$WORDS \"${WORDS}/x\" '$WORDS' [$UNSET] $ $1 $WORDS_2", lookup),
+            Ok(Some(["value", "value/x", "$WORDS", "[]", "$", "$1", ""].map(String::from).to_vec()))
+        );
+    }
+
+    #[test]
+    fn tildes_expand_at_the_start_of_words_and_values() {
+        let home = dirs::home_dir().unwrap().to_string_lossy().to_string();
+        assert_eq!(words("cd ~"), ["cd", home.as_str()]);
+        assert_eq!(words("cd ~/src"), ["cd", format!("{}/src", home).as_str()]);
+        assert_eq!(words("X=~/bin"), [format!("X={}/bin", home)]);
+        assert_eq!(words("cd ~user a~ '~'"), ["cd", "~user", "a~", "~"]);
+    }
+
+    #[test]
+    fn lines_only_a_shell_can_run_are_left_to_it() {
+        for command in ["cd src && make", "cd src; ls", "export X=$(pwd)", "cd `pwd`", "export X=${Y:-z}", "cd > out"] {
+            assert_eq!(split_words(command), Ok(None), "{}", command);
+            assert_eq!(parse_builtin(command), None, "{}", command);
+        }
+    }
+
+    #[test]
+    fn unclosed_quotes_are_errors() {
+        assert_eq!(split_words("cd 'src"), Err("Error: The quote in 'cd 'src' is never closed.".to_string()));
+        assert!(matches!(parse_builtin("export X=\"y"), Some(Err(_))));
+    }
+
+    #[test]
+    fn cd_takes_at_most_one_path() {
+        assert_eq!(parse_builtin("cd"), Some(Ok(Builtin::Cd(None))));
+        assert_eq!(parse_builtin("cd -"), Some(Ok(Builtin::Cd(Some("-".to_string())))));
+        assert_eq!(parse_builtin("cd -- 'a b'"), Some(Ok(Builtin::Cd(Some("a b".to_string())))));
+        assert_eq!(parse_builtin("cd a b"), Some(Err("Error: cd: too many arguments.".to_string())));
+        // Other commands that merely start with a builtin's name are left alone
+        assert_eq!(parse_builtin("cdk deploy"), None);
+        assert_eq!(parse_builtin("ls"), None);
+    }
+
+    #[test]
+    fn export_and_unset_check_variable_names() {
+        assert_eq!(parse_builtin("export A=1 B= C"), Some(Ok(export(&[("A", Some("1")), ("B", Some("")), ("C", None)]))));
+        assert_eq!(parse_builtin("export"), Some(Ok(export(&[]))));
+        assert_eq!(parse_builtin("export URL=a=b"), Some(Ok(export(&[("URL", Some("a=b"))]))));
+        assert!(matches!(parse_builtin("export 1X=2"), Some(Err(_))));
+        assert!(matches!(parse_builtin("export -n X"), Some(Err(_))));
+        assert_eq!(parse_builtin("unset -v A _b"), Some(Ok(Builtin::Unset(vec!["A".to_string(), "_b".to_string()]))));
+        assert!(matches!(parse_builtin("unset A-B"), Some(Err(_))));
+    }
+
+    #[test]
+    fn bare_assignments_are_exports() {
+        assert_eq!(parse_builtin("FOO=bar"), Some(Ok(export(&[("FOO", Some("bar"))]))));
+        assert_eq!(parse_builtin("A=1 B='two words'"), Some(Ok(export(&[("A", Some("1")), ("B", Some("two words"))]))));
+        // Assignments in front of a command only apply to it
+        assert_eq!(parse_builtin("FOO=bar make"), None);
+    }
+}
@@ -0,0 +1,307 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use serde_json::{json, Value};
+
+use crate::models::{CapabilityOverrides, Message};
+
+/// How a model accepts the instructions that are normally sent with the `system` role.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SystemRole {
+    /// The classic `system` role.
+    System,
+    /// The `developer` role used by reasoning models.
+    Developer,
+    /// No instruction role at all; instructions are merged into the next user message.
+    User,
+}
+
+/// How a model can be asked to reply with JSON.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ResponseFormat {
+    /// `response_format` isn't accepted; replies are free text.
+    Text,
+    /// `{"type": "json_object"}`: any JSON object.
+    JsonObject,
+    /// `{"type": "json_schema"}`: JSON matching a given schema.
+    JsonSchema,
+}
+
+/// What a model accepts in a chat-completions request.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ModelCapabilities {
+    pub(crate) system_role: SystemRole,
+    /// Whether the model accepts `tools`.
+    pub(crate) tools: bool,
+    /// Whether the model accepts the deprecated `functions` field.
+    pub(crate) legacy_functions: bool,
+    /// Whether the model can stream its reply.
+    pub(crate) streaming: bool,
+    /// Whether the output limit is named `max_completion_tokens` instead of `max_tokens`.
+    pub(crate) max_completion_tokens: bool,
+    /// How structured replies can be requested.
+    pub(crate) response_format: ResponseFormat,
+    /// Whether the model accepts images in user messages.
+    pub(crate) vision: bool,
+}
+
+/// Capabilities of models that aren't in the table.
+const DEFAULT_CAPABILITIES: ModelCapabilities = ModelCapabilities {
+    system_role: SystemRole::System,
+    tools: true,
+    legacy_functions: true,
+    streaming: true,
+    max_completion_tokens: false,
+    response_format: ResponseFormat::JsonObject,
+    vision: false,
+};
+
+/// Models that also support JSON schemas.
+const STRUCTURED_CAPABILITIES: ModelCapabilities = ModelCapabilities {
+    response_format: ResponseFormat::JsonSchema,
+    ..DEFAULT_CAPABILITIES
+};
+
+/// The original GPT-4 snapshots, which predate `response_format`.
+const GPT_4_CAPABILITIES: ModelCapabilities = ModelCapabilities {
+    response_format: ResponseFormat::Text,
+    ..DEFAULT_CAPABILITIES
+};
+
+/// The first reasoning models, which accept neither instructions nor tools.
+const EARLY_REASONING_CAPABILITIES: ModelCapabilities = ModelCapabilities {
+    system_role: SystemRole::User,
+    tools: false,
+    legacy_functions: false,
+    streaming: false,
+    max_completion_tokens: true,
+    response_format: ResponseFormat::Text,
+    vision: false,
+};
+
+/// Later reasoning models, which take instructions with the `developer` role.
+const REASONING_CAPABILITIES: ModelCapabilities = ModelCapabilities {
+    system_role: SystemRole::Developer,
+    tools: true,
+    legacy_functions: false,
+    streaming: true,
+    max_completion_tokens: true,
+    response_format: ResponseFormat::JsonSchema,
+    vision: false,
+};
+
+/// Known model name prefixes, most specific first.
+const MODEL_TABLE: &[(&str, ModelCapabilities)] = &[
+    ("o1-mini", EARLY_REASONING_CAPABILITIES),
+    ("o1-preview", EARLY_REASONING_CAPABILITIES),
+    ("o1", REASONING_CAPABILITIES),
+    ("o3", REASONING_CAPABILITIES),
+    ("o4", REASONING_CAPABILITIES),
+    ("gpt-5", REASONING_CAPABILITIES),
+    ("gpt-4o", STRUCTURED_CAPABILITIES),
+    ("gpt-4.1", STRUCTURED_CAPABILITIES),
+    ("gpt-4-turbo", DEFAULT_CAPABILITIES),
+    ("gpt-4", GPT_4_CAPABILITIES),
+];
+
+/// Whether models accept images, by model name prefix, most specific first.
+const VISION_TABLE: &[(&str, bool)] = &[
+    ("o1-mini", false),
+    ("o1-preview", false),
+    ("o1", true),
+    ("o3-mini", false),
+    ("o3", true),
+    ("o4", true),
+    ("gpt-5", true),
+    ("gpt-4.1", true),
+    ("gpt-4o", true),
+    ("gpt-4-turbo", true),
+    ("claude", true),
+];
+
+/// Context window sizes in tokens, by model name prefix, most specific first.
+const CONTEXT_WINDOW_TABLE: &[(&str, usize)] = &[
+    ("o1-mini", 128_000),
+    ("o1-preview", 128_000),
+    ("o1", 200_000),
+    ("o3", 200_000),
+    ("o4", 200_000),
+    ("gpt-5", 400_000),
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo", 16_385),
+    ("claude", 200_000),
+];
+
+/// Looks up how many tokens fit in a model's context window. The `context_window`
+/// override takes precedence over the built-in table.
+///
+/// # Arguments
+///
+/// * `model` - The model name.
+/// * `overrides` - The `capabilities` section of the configuration file, if any.
+///
+/// # Returns
+///
+/// * `Option<usize>` - The context window in tokens, or `None` for unknown models.
+pub(crate) fn context_window_for(model: &str, overrides: Option<&CapabilityOverrides>) -> Option<usize> {
+    overrides.and_then(|overrides| overrides.context_window).or_else(|| {
+        CONTEXT_WINDOW_TABLE
+            .iter()
+            .find(|(prefix, _)| model.starts_with(prefix))
+            .map(|(_, tokens)| *tokens)
+    })
+}
+
+/// Looks up what a model accepts, applying any overrides from the configuration file.
+///
+/// # Arguments
+///
+/// * `model` - The model name.
+/// * `overrides` - The `capabilities` section of the configuration file, if any.
+///
+/// # Returns
+///
+/// * `ModelCapabilities` - The capabilities to build requests with.
+pub(crate) fn capabilities_for(model: &str, overrides: Option<&CapabilityOverrides>) -> ModelCapabilities {
+    let mut capabilities = MODEL_TABLE
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, capabilities)| *capabilities)
+        .unwrap_or(DEFAULT_CAPABILITIES);
+    capabilities.vision = VISION_TABLE
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .is_some_and(|(_, vision)| *vision);
+
+    if let Some(overrides) = overrides {
+        if let Some(role) = overrides.system_role.as_deref() {
+            capabilities.system_role = match role {
+                "developer" => SystemRole::Developer,
+                "user" => SystemRole::User,
+                _ => SystemRole::System,
+            };
+        }
+        capabilities.tools = overrides.tools.unwrap_or(capabilities.tools);
+        capabilities.legacy_functions = overrides.legacy_functions.unwrap_or(capabilities.legacy_functions);
+        capabilities.streaming = overrides.streaming.unwrap_or(capabilities.streaming);
+        capabilities.vision = overrides.vision.unwrap_or(capabilities.vision);
+        capabilities.max_completion_tokens = overrides
+            .max_completion_tokens
+            .unwrap_or(capabilities.max_completion_tokens);
+        if let Some(format) = overrides.response_format.as_deref() {
+            capabilities.response_format = match format {
+                "json_schema" => ResponseFormat::JsonSchema,
+                "json_object" => ResponseFormat::JsonObject,
+                _ => ResponseFormat::Text,
+            };
+        }
+    }
+
+    capabilities
+}
+
+impl ModelCapabilities {
+    /// The request field that limits the length of the reply.
+    pub(crate) fn token_limit_field(&self) -> &'static str {
+        if self.max_completion_tokens {
+            "max_completion_tokens"
+        } else {
+            "max_tokens"
+        }
+    }
+
+    /// Rewrites `system` messages into the form the model accepts.
+    ///
+    /// # Arguments
+    ///
+    /// * `messages` - The messages to adapt.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Message>` - The adapted messages.
+    pub(crate) fn adapt_messages(&self, messages: Vec<Message>) -> Vec<Message> {
+        let mut adapted: Vec<Message> = Vec::with_capacity(messages.len());
+        let mut pending_instructions: Vec<String> = Vec::new();
+
+        for mut message in messages {
+            if message.role == "system" {
+                match self.system_role {
+                    SystemRole::System => {}
+                    SystemRole::Developer => message.role = "developer".to_string(),
+                    SystemRole::User => {
+                        pending_instructions.push(message.content);
+                        continue;
+                    }
+                }
+            } else if message.role == "user" && !pending_instructions.is_empty() {
+                pending_instructions.push(message.content);
+                message.content = pending_instructions.join("\n\n");
+                pending_instructions.clear();
+            }
+            adapted.push(message);
+        }
+
+        adapted
+    }
+
+    /// Rewrites `system` messages in a JSON conversation into the form the model accepts.
+    ///
+    /// # Arguments
+    ///
+    /// * `messages` - The conversation to adapt.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Value>` - The adapted conversation.
+    pub(crate) fn adapt_json_messages(&self, messages: &[Value]) -> Vec<Value> {
+        let mut adapted: Vec<Value> = Vec::with_capacity(messages.len());
+        let mut pending_instructions: Vec<String> = Vec::new();
+
+        for message in messages {
+            let mut message = message.clone();
+            match message["role"].as_str() {
+                Some("system") => match self.system_role {
+                    SystemRole::System => {}
+                    SystemRole::Developer => message["role"] = Value::from("developer"),
+                    SystemRole::User => {
+                        pending_instructions.push(message["content"].as_str().unwrap_or_default().to_string());
+                        continue;
+                    }
+                },
+                Some("user") if !pending_instructions.is_empty() => {
+                    let instructions = pending_instructions.join("\n\n");
+                    pending_instructions.clear();
+                    match message["content"].as_array_mut() {
+                        // Images stay where they are; the instructions go before them
+                        Some(parts) => parts.insert(0, json!({ "type": "text", "text": instructions })),
+                        None => {
+                            let content = message["content"].as_str().unwrap_or_default();
+                            message["content"] = Value::from(format!("{}\n\n{}", instructions, content));
+                        }
+                    }
+                }
+                _ => {}
+            }
+            adapted.push(message);
+        }
+
+        adapted
+    }
+}
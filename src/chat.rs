@@ -14,62 +14,396 @@
  * limitations under the License.
  */
 
-use crate::openai::handle_non_success;
-use crate::utils::start_loading_animation;
-use reqwest::blocking::Client;
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    env, fs,
+    io::{self, BufRead, BufReader, IsTerminal, Read, Write},
+    mem,
+    path::PathBuf,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::Local;
+use colored::Colorize;
+use reqwest::{Response, StatusCode};
+use rustyline::DefaultEditor;
 use serde_json::Value;
-use std::env;
-use std::io::{self, Write};
-use std::process::Command;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+
+use crate::{
+    capabilities::context_window_for,
+    cli::{execute_command as run_direct_command, should_execute_command, CliOptions},
+    client::{
+        block_on, connect, is_context_length_error, read_event_stream, resolve_api_endpoint, CompletionBackend,
+        Provider,
+    },
+    command_shell::{init_command_shell, shell_command},
+    files::{
+        expand_glob, glob_to_regex, is_within, list_directory, read_text_file, resolve_path, resolve_target_path,
+        MAX_READ_FILE_BYTES,
+    },
+    images::{count_images, image_content, load_image, message_text, remove_images, IMAGE_TOKEN_ESTIMATE},
+    markdown::{find_code_blocks, render_markdown, wrap_width, MarkdownRenderer},
+    memory::{add_memory, load_memories, memory_file_display, memory_prompt, remove_memory, MEMORY_INTRO},
+    models::{ChatChoice, ChatChunk, ChatMessage, ChatResponse, Config, ToolCall, ToolCallDelta, ToolFunction, Usage},
+    openai::{
+        add_banned_command, command_prompt, confirm_allows_by_default, load_allowed_commands, load_banned_commands,
+        load_config, parse_command_answer, yes_no_options, CommandAnswer, CONTINUE_PROMPT, MAX_CONTINUATIONS,
+    },
+    personas::{load_persona, print_personas},
+    sessions::{list_sessions, load_latest_session, load_session, new_session_id, save_session, validate_session_name},
+    sysinfo::{collect_system_info, validate_fields, SYSTEM_INFO_FIELDS},
+    theme::{init_theme, theme},
+    transcript::{fenced, render_transcript, TIMESTAMP_FIELD},
+    usage::{
+        check_budget, describe_reproducibility, describe_request_cost, describe_session_cost, estimate_usage,
+        record_usage,
+    },
+    utils::{
+        decode_text, edit_distance, estimate_tokens, format_count, install_interrupt_handler, start_loading_animation,
+        ForegroundProgram, InputPrompt, InterruptGuard, LoadingAnimation,
+    },
+    web::{fetch_text, parse_fetch_url},
+};
 
 // Constants for configuration
-const OPENAI_API_URL: &str = "https://api.openai.com/v1/chat/completions";
-const MODEL_NAME: &str = "gpt-4";
 const SYSTEM_PROMPT: &str =
     "You are a helpful assistant chatting in a terminal, use proper formatting so that your answers are easy to read. Address the user as pal or buddy.";
+/// How many of the most recent turns are kept when the conversation outgrows the context window.
+const KEEP_RECENT_TURNS: usize = 4;
+/// The most bytes of command output sent to the assistant unless `max_command_output_bytes` is set.
+pub(crate) const DEFAULT_MAX_COMMAND_OUTPUT_BYTES: usize = 8 * 1024;
+/// Output without a line break is shown in pieces of this many bytes as it arrives.
+const MAX_STREAMED_LINE_BYTES: usize = 4096;
+/// How long after Ctrl-C, or after the command exits, gptsh waits for its output to end
+/// before moving on; something it started in the background may keep the output open.
+const DETACH_OUTPUT_AFTER: Duration = Duration::from_millis(500);
+/// How long a running command must be silent before the elapsed-time ticker is shown.
+const TICKER_AFTER_SILENCE: Duration = Duration::from_secs(1);
+/// The most functions the assistant may call in answer to one message unless `max_function_calls` is set.
+const DEFAULT_MAX_FUNCTION_CALLS: usize = 5;
+/// The most entries `list_directory` returns unless `list_directory_max_entries` is set.
+const DEFAULT_LIST_DIRECTORY_ENTRIES: usize = 200;
+/// Replacing a file larger than this with `write_file` needs a typed "yes".
+const LARGE_OVERWRITE_BYTES: usize = 64 * 1024;
+/// Lines of unchanged context shown around each change in a `write_file` diff.
+const DIFF_CONTEXT_LINES: usize = 3;
+/// The most lines of a `write_file` diff shown before it is cut short.
+const MAX_DIFF_PREVIEW_LINES: usize = 200;
+/// How many characters of a message `/undo` shows.
+const UNDO_PREVIEW_CHARS: usize = 60;
+/// The share of the context window past which `/tokens` warns that trimming is near.
+const CONTEXT_WARNING_RATIO: f64 = 0.75;
+/// How much `/retry hotter` raises the temperature.
+const RETRY_TEMPERATURE_STEP: f64 = 0.3;
+/// Opens and closes a block of multi-line input.
+const BLOCK_DELIMITER: &str = "\"\"\"";
+/// The question sent with an image attached without one.
+const ATTACHED_IMAGE_PROMPT: &str = "What does this image show?";
+/// The most file content `/files` adds to the conversation at once, in bytes. Each file is
+/// also cut off at [`MAX_READ_FILE_BYTES`].
+const MAX_ADDED_FILES_BYTES: usize = 128 * 1024;
+/// What each reply is shown after.
+const ASSISTANT_PREFIX: &str = "gptsh:";
+/// Runs a chat command, given its argument, the messages, the autosave session id, and
+/// the connection.
+type ChatCommandHandler = fn(&str, &mut Vec<Value>, &str, &ChatConnection);
+
+/// A command available at the chat prompt.
+struct ChatCommand {
+    /// What is typed to run it, e.g. `/save`.
+    name: &'static str,
+    /// How it is used, as listed by `/help`.
+    usage: &'static str,
+    /// What it does, as listed by `/help`.
+    description: &'static str,
+    /// Runs it, or `None` for commands the chat loop handles itself because they send a
+    /// request or end the session.
+    run: Option<ChatCommandHandler>,
+}
+
+/// The commands available at the chat prompt, in the order `/help` lists them.
+const CHAT_COMMANDS: &[ChatCommand] = &[
+    ChatCommand {
+        name: "/save",
+        usage: "/save <name>",
+        description: "Save the conversation under a name",
+        run: Some(|argument, messages, _, _| save_named_session(argument, messages)),
+    },
+    ChatCommand {
+        name: "/load",
+        usage: "/load [name]",
+        description: "Replace the conversation with a saved one, or list saved sessions",
+        run: Some(|argument, messages, session_id, connection| {
+            load_named_session(argument, messages, session_id, connection.confirm_allows_by_default)
+        }),
+    },
+    ChatCommand {
+        name: "/system",
+        usage: "/system [text]",
+        description: "Replace the system prompt, or show the current one",
+        run: Some(|argument, messages, session_id, _| replace_system_prompt(argument, messages, session_id)),
+    },
+    ChatCommand {
+        name: "/persona",
+        usage: "/persona [name]",
+        description: "Switch to a persona's system prompt, or list personas",
+        run: Some(|argument, messages, session_id, _| switch_persona(argument, messages, session_id)),
+    },
+    ChatCommand {
+        name: "/clear",
+        usage: "/clear [keep-last]",
+        description: "Start over from the system prompt, optionally keeping the last exchange",
+        run: Some(|argument, messages, session_id, _| clear_conversation(argument, messages, session_id)),
+    },
+    ChatCommand {
+        name: "/retry",
+        usage: "/retry [hotter]",
+        description: "Regenerate the last reply, optionally at a higher temperature",
+        run: None,
+    },
+    ChatCommand {
+        name: "/files",
+        usage: "/files <path>...",
+        description: "Add the contents of text files to the conversation; patterns such as src/*.rs are expanded",
+        run: Some(|argument, messages, session_id, _| add_files(argument, messages, session_id)),
+    },
+    ChatCommand {
+        name: "/remember",
+        usage: "/remember <fact>",
+        description: "Remember a fact in every chat session from now on",
+        run: Some(|argument, messages, _, _| remember_fact(argument, messages)),
+    },
+    ChatCommand {
+        name: "/memories",
+        usage: "/memories",
+        description: "List the remembered facts",
+        run: Some(|_, _, _, _| print_memories()),
+    },
+    ChatCommand {
+        name: "/forget",
+        usage: "/forget <n>",
+        description: "Forget remembered fact n",
+        run: Some(|argument, messages, _, _| forget_fact(argument, messages)),
+    },
+    ChatCommand {
+        name: "/attach",
+        usage: "/attach <image> [question]",
+        description: "Ask about a PNG, JPEG, or WebP image (vision models only)",
+        run: None,
+    },
+    ChatCommand {
+        name: "/undo",
+        usage: "/undo",
+        description: "Remove the last exchange from the conversation",
+        run: Some(|argument, messages, session_id, _| undo_last_exchange(argument, messages, session_id)),
+    },
+    ChatCommand {
+        name: "/code",
+        usage: "/code [n] [save <file>]",
+        description: "List the last reply's code blocks, print block n, or save it to a file",
+        run: Some(|argument, messages, _, _| show_code_blocks(argument, messages)),
+    },
+    ChatCommand {
+        name: "/tokens",
+        usage: "/tokens",
+        description: "Show the size of the conversation and the tokens used this session",
+        run: Some(|_, messages, _, connection| print_token_usage(messages, connection)),
+    },
+    ChatCommand {
+        name: "/export",
+        usage: "/export [--full] <file>",
+        description: "Write the conversation to a Markdown file, with --full including the system prompt",
+        run: Some(|argument, messages, _, _| export_transcript(argument, messages)),
+    },
+    ChatCommand {
+        name: "/shell",
+        usage: "/shell [--share] <command>",
+        description: "Run a command yourself (also !<command>), with --share adding its output to the conversation",
+        run: Some(run_shell_escape),
+    },
+    ChatCommand {
+        name: "/help",
+        usage: "/help",
+        description: "Show this list",
+        run: Some(|_, _, _, _| print_chat_commands()),
+    },
+    ChatCommand {
+        name: "exit",
+        usage: "exit, quit",
+        description: "End the session",
+        run: None,
+    },
+];
 
 /// Entry point for running the chat mode.
 ///
 /// # Arguments
 ///
-/// * `verbose` - A boolean flag to enable verbose output.
-pub(crate) fn run_chat_mode(verbose: bool) {
+/// * `options` - The command-line options, e.g. `verbose` to show more output.
+pub(crate) fn run_chat_mode(options: &CliOptions) {
     announce_entry_to_chat_mode();
 
-    let api_key = match fetch_api_key() {
-        Ok(key) => key,
+    let persona = match options.persona.as_deref().map(load_persona).transpose() {
+        Ok(persona) => persona,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let mut options = options.clone();
+    if options.model.is_none() {
+        options.model = persona.as_ref().and_then(|persona| persona.model.clone());
+    }
+    let options = &options;
+
+    let endpoint = match resolve_api_endpoint(options) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    let mut config = load_config().unwrap_or_default();
+    if let Some(persona) = persona {
+        config.chat_system_prompt = Some(persona.system_prompt);
+        if let Some(temperature) = persona.temperature {
+            let generation = config.generation.get_or_insert_with(Default::default);
+            generation.chat.get_or_insert_with(Default::default).temperature = Some(temperature);
+        }
+    }
+    init_theme(&config);
+    let capabilities = endpoint.capabilities;
+    let legacy_functions = config.legacy_functions.unwrap_or(false);
+    if legacy_functions && !capabilities.legacy_functions {
+        eprintln!(
+            "Error: The model '{}' does not accept the legacy `functions` format. Remove `legacy_functions` from .gptsh_config.",
+            endpoint.model
+        );
+        return;
+    }
+    if !capabilities.tools {
+        println!(
+            "Note: The model '{}' does not support function calling, so it cannot run commands for you.",
+            endpoint.model
+        );
+    }
+
+    let backend = match connect(options, endpoint) {
+        Ok(backend) => backend,
         Err(e) => {
             eprintln!("{}", e);
             return;
         }
     };
 
-    let client = Client::new();
-    let mut messages = initialize_messages_with_system_prompt();
+    let connection = ChatConnection {
+        backend,
+        stream: config.stream.unwrap_or(true) && capabilities.streaming,
+        max_tokens: config.max_tokens,
+        seed: options.seed.or(config.seed),
+        continue_truncated: config.continue_truncated.unwrap_or(false),
+        render_markdown: config.render_markdown.unwrap_or(true) && io::stdout().is_terminal(),
+        history_token_budget: config.history_token_budget,
+        history_trimmed: Cell::new(false),
+        request_cancelled: Cell::new(false),
+        allow_network_tools: config.allow_network_tools.unwrap_or(false),
+        max_command_output_bytes: config.max_command_output_bytes.unwrap_or(DEFAULT_MAX_COMMAND_OUTPUT_BYTES),
+        confirm_allows_by_default: confirm_allows_by_default(&config),
+        max_function_calls: config.max_function_calls.unwrap_or(DEFAULT_MAX_FUNCTION_CALLS),
+        system_info_fields: match &config.system_info_fields {
+            Some(fields) => validate_fields(fields),
+            None => SYSTEM_INFO_FIELDS.iter().map(|field| field.to_string()).collect(),
+        },
+        list_directory_max_entries: config.list_directory_max_entries.unwrap_or(DEFAULT_LIST_DIRECTORY_ENTRIES),
+        legacy_functions,
+        tools_enabled: Cell::new(capabilities.tools),
+        temperature_override: Cell::new(None),
+        show_cost: options.show_cost || config.show_cost.unwrap_or(false),
+        override_budget: options.override_budget,
+        session_usage: Cell::new(Usage::default()),
+        config,
+    };
+    let Some((session_id, mut messages)) = start_session(options.resume.as_ref(), &connection.config) else {
+        return;
+    };
+    // Warns now, rather than at the first command, if the shell to run commands is missing
+    init_command_shell(&connection.config);
+
+    install_interrupt_handler();
 
     loop {
-        let user_input = read_user_input().trim().to_string();
+        // The end of input leaves chat as `exit` does, rather than prompting forever
+        let user_input = match read_user_input() {
+            Some(input) => input.trim().to_string(),
+            None => {
+                println!();
+                "exit".to_string()
+            }
+        };
         if should_exit(&user_input) {
+            announce_saved_session(&session_id, &messages);
             println!("See you later pal.");
             break;
         }
 
-        if user_input.is_empty() {
+        // These commands send a request, so they are handled here rather than through their
+        // entries in CHAT_COMMANDS
+        let retry_argument = command_argument(&user_input, "/retry");
+        let attach_argument = command_argument(&user_input, "/attach");
+        if user_input.is_empty()
+            || (retry_argument.is_none()
+                && attach_argument.is_none()
+                && run_chat_command(&user_input, &mut messages, &session_id, &connection))
+        {
+            continue;
+        }
+
+        if !within_budget(&connection) {
             continue;
         }
 
-        add_user_message(&mut messages, &user_input);
-        let request_body = prepare_request_body(&messages);
+        let previous_reply = match (retry_argument, attach_argument) {
+            (Some(argument), _) => match prepare_retry(argument, &mut messages, &connection) {
+                Some(previous_reply) => previous_reply,
+                None => continue,
+            },
+            (None, Some(argument)) => {
+                if !attach_image(argument, &mut messages, &connection) {
+                    continue;
+                }
+                Vec::new()
+            }
+            (None, None) => {
+                add_user_message(&mut messages, &user_input);
+                Vec::new()
+            }
+        };
+        let completion = request_completion(&connection, &mut messages);
+        // A message cancelled with Ctrl-C is taken back, so it can be asked differently
+        if connection.request_cancelled.get() && previous_reply.is_empty() && messages.last().is_some_and(|message| message["role"] == "user") {
+            messages.pop();
+            continue;
+        }
+        let reply_start = messages.len();
+        let outcome = handle_response(completion, &mut messages, &connection, options.verbose);
+        // A retry that failed or came back empty leaves the previous reply in place
+        if messages.len() == reply_start && !previous_reply.is_empty() {
+            println!("Note: Kept the previous reply.");
+            messages.extend(previous_reply);
+        }
+        connection.temperature_override.set(None);
 
-        let stop_signal = start_loading_indicator();
-        let response = send_request(&client, &api_key, &request_body);
-        stop_loading_indicator(stop_signal);
+        // Saved after every exchange, so nothing is lost when Ctrl-C ends the program
+        autosave_session(&session_id, &messages);
 
-        match handle_response(response, &mut messages, &client, &api_key, verbose) {
+        match outcome {
             Some(true) => {
+                announce_saved_session(&session_id, &messages);
                 println!("See you later pal.");
                 break;
             }
@@ -79,415 +413,2837 @@ pub(crate) fn run_chat_mode(verbose: bool) {
     }
 }
 
-/// Announces entry into chat mode.
-fn announce_entry_to_chat_mode() {
-    println!("Entering chat mode. Type 'exit' or 'quit' to end the session.");
-}
-
-/// Fetches the OpenAI API key from environment variables.
-///
-/// # Returns
-///
-/// * `Result<String, String>` - The API key or an error message.
-fn fetch_api_key() -> Result<String, String> {
-    env::var("OPENAI_API_KEY")
-        .map_err(|_| "Error: OPENAI_API_KEY not set in environment.".to_string())
-}
-
-/// Initializes the conversation with the system prompt.
+/// Returns the argument of a chat command if the input is that command.
 ///
-/// # Returns
+/// # Arguments
 ///
-/// * `Vec<Value>` - A vector of JSON values representing the initial messages.
-fn initialize_messages_with_system_prompt() -> Vec<Value> {
-    vec![serde_json::json!({
-        "role": "system",
-        "content": SYSTEM_PROMPT
-    })]
-}
-
-/// Reads user input from the terminal.
+/// * `input` - The user's input.
+/// * `command` - The command, e.g. `/retry`.
 ///
 /// # Returns
 ///
-/// * `String` - The user's input.
-fn read_user_input() -> String {
-    print!("You: ");
-    io::stdout().flush().unwrap();
-    let mut input = String::new();
-    match io::stdin().read_line(&mut input) {
-        Ok(_) => (), // Successfully read input; do nothing
-        Err(_) => eprintln!("Failed to read input."),
+/// * `Option<&str>` - The trimmed argument, possibly empty, or `None` for other input.
+fn command_argument<'a>(input: &'a str, command: &str) -> Option<&'a str> {
+    match input.split_once(char::is_whitespace) {
+        Some((name, argument)) if name == command => Some(argument.trim()),
+        _ if input == command => Some(""),
+        _ => None,
     }
-    input
 }
 
-/// Determines if the user wants to exit the chat.
+/// Runs a `/command` typed at the chat prompt. Commands are never sent to the API.
+/// Input that merely starts with a path, such as `/etc/hosts is empty`, is left alone.
 ///
 /// # Arguments
 ///
 /// * `input` - The user's input.
+/// * `messages` - Mutable reference to the messages vector.
+/// * `session_id` - The id the conversation is autosaved under.
+/// * `connection` - The chat session's connection details.
 ///
 /// # Returns
 ///
-/// * `bool` - `true` if the user wants to exit, else `false`.
-fn should_exit(input: &str) -> bool {
-    if input.eq_ignore_ascii_case("exit") || input.eq_ignore_ascii_case("quit") {
-        println!("Exiting chat mode.");
-        true
+/// * `bool` - `true` if the input was a command, else `false`.
+fn run_chat_command(input: &str, messages: &mut Vec<Value>, session_id: &str, connection: &ChatConnection) -> bool {
+    if let Some(command) = input.strip_prefix('!') {
+        run_shell_escape(command.trim(), messages, session_id, connection);
+        return true;
+    }
+    let (command, argument) = match input.split_once(char::is_whitespace) {
+        Some((command, argument)) => (command, argument.trim()),
+        None => (input, ""),
+    };
+    if let Some(run) = CHAT_COMMANDS.iter().find(|entry| entry.name == command).and_then(|entry| entry.run) {
+        run(argument, messages, session_id, connection);
+    } else if command.starts_with('/') && !command[1..].contains('/') {
+        match closest_command(command) {
+            Some(suggestion) => println!("Unknown command '{}'. Did you mean {}? Type /help for the list of commands.", command, suggestion),
+            None => println!("Unknown command '{}'. Type /help for the list of commands.", command),
+        }
     } else {
-        false
+        return false;
     }
+    true
 }
 
-/// Adds the user's message to the conversation history.
+/// Runs a command the user typed with `/shell` or `!`, without confirmation, as shell
+/// mode runs direct commands; builtins are refused (see [`parse_shell_escape`]). With `--share`, the output is also captured and added to
+/// the conversation, so the assistant sees it with the next message.
 ///
 /// # Arguments
 ///
+/// * `argument` - The command, optionally preceded by `--share`.
 /// * `messages` - Mutable reference to the messages vector.
-/// * `user_input` - The user's input.
-fn add_user_message(messages: &mut Vec<Value>, user_input: &str) {
-    messages.push(serde_json::json!({
-        "role": "user",
-        "content": user_input
-    }));
-}
+/// * `session_id` - The id the conversation is autosaved under.
+/// * `connection` - The chat session's connection details.
+fn run_shell_escape(argument: &str, messages: &mut Vec<Value>, session_id: &str, connection: &ChatConnection) {
+    let (share, command) = match parse_shell_escape(argument) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            println!("{}", message);
+            return;
+        }
+    };
+    if !share {
+        let _foreground = ForegroundProgram::new();
+        run_direct_command(command, false);
+        return;
+    }
 
-/// Prepares the JSON request body for the OpenAI API.
-///
-/// # Arguments
-///
-/// * `messages` - Reference to the messages vector.
-///
-/// # Returns
-///
-/// * `Value` - The JSON request body.
-fn prepare_request_body(messages: &[Value]) -> Value {
-    serde_json::json!({
-        "model": MODEL_NAME,
-        "messages": messages,
-        "functions": get_function_definitions(),
-        "function_call": "auto"
-    })
+    let output_limit = connection.max_command_output_bytes;
+    match execute_shell_command(command, output_limit) {
+        Ok(output) => {
+            let result = describe_command_output(output, output_limit);
+            add_user_message(messages, &format!("I ran this command myself:\n$ {}\nResult: {}", command, result));
+            autosave_session(session_id, messages);
+            println!("Note: The output was added to the conversation; the assistant will see it with your next message.");
+        }
+        Err(e) => eprintln!("Failed to execute command: {}", e),
+    }
 }
 
-/// Defines the available functions that the assistant can call.
+/// Splits the argument of `/shell` into whether to share the output and the command.
+/// Builtins such as `cd` are refused with the same advice either way, since they would
+/// only change the state of a child shell.
 ///
 /// # Returns
 ///
-/// * `Vec<Value>` - A vector of JSON values representing function definitions.
-fn get_function_definitions() -> Vec<Value> {
-    vec![
-        serde_json::json!({
-            "name": "execute_command",
-            "description": "Executes a shell command and returns the output.",
-            "parameters": {
-                "type": "object",
-                "properties": {
-                    "command": {
-                        "type": "string",
-                        "description": "The shell command to execute."
-                    }
-                },
-                "required": ["command"]
-            }
-        }),
-        serde_json::json!({
-            "name": "exit_chat",
-            "description": "Signals that the user wants to exit the chat.",
-            "parameters": {
-                "type": "object",
-                "properties": {}
-            }
-        }),
-    ]
+/// * `Result<(bool, &str), String>` - Whether `--share` was given and the command, or
+///   the usage or advice to print instead.
+fn parse_shell_escape(argument: &str) -> Result<(bool, &str), String> {
+    let (share, command) = match argument.strip_prefix("--share") {
+        Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => (true, rest.trim()),
+        _ => (false, argument),
+    };
+    if command.is_empty() {
+        return Err("Usage: /shell [--share] <command>, or !<command>".to_string());
+    }
+    should_execute_command(command)?;
+    Ok((share, command))
 }
 
-/// Starts the loading indicator in a separate thread.
+/// Adds the contents of text files to the conversation for `/files`, each in a code fence
+/// labelled with its path, so the next message can refer to them. Binary files are
+/// skipped, and files past [`MAX_ADDED_FILES_BYTES`] in total are cut off or left out.
 ///
-/// # Returns
+/// # Arguments
 ///
-/// * `Arc<Mutex<bool>>` - A shared signal to stop the loading indicator.
-fn start_loading_indicator() -> Arc<Mutex<bool>> {
-    let stop_signal = Arc::new(Mutex::new(false));
-    let signal_clone = Arc::clone(&stop_signal);
+/// * `argument` - The paths, separated by whitespace; each may be a pattern like `src/*.rs`.
+/// * `messages` - Mutable reference to the messages vector.
+/// * `session_id` - The id the conversation is autosaved under.
+fn add_files(argument: &str, messages: &mut Vec<Value>, session_id: &str) {
+    if argument.is_empty() {
+        println!("Usage: /files <path>... (patterns such as src/*.rs are expanded)");
+        return;
+    }
+    let mut paths = Vec::new();
+    for pattern in argument.split_whitespace() {
+        match expand_glob(pattern) {
+            Ok(matches) => paths.extend(matches.into_iter().filter(|path| !paths.contains(path)).collect::<Vec<_>>()),
+            Err(e) => println!("{}", e),
+        }
+    }
 
-    thread::spawn(move || {
-        start_loading_animation(signal_clone);
-    });
+    let mut contents = String::from("Here are the contents of some files for reference.");
+    let mut added = 0;
+    let mut remaining = MAX_ADDED_FILES_BYTES;
+    for path in paths {
+        let mut text = match read_text_file(&path) {
+            Ok(text) => text,
+            Err(e) => {
+                println!("Skipped: {}", e.trim_start_matches("Error: "));
+                continue;
+            }
+        };
+        if remaining == 0 {
+            println!("Skipped '{}': the {}KB limit for /files was reached.", path.display(), MAX_ADDED_FILES_BYTES / 1024);
+            continue;
+        }
+        let size = fs::metadata(&path).map_or(0, |metadata| metadata.len() as usize);
+        let mut note = String::new();
+        if size > MAX_READ_FILE_BYTES {
+            note = format!(", cut off at {}KB", MAX_READ_FILE_BYTES / 1024);
+        }
+        if text.len() > remaining {
+            let mut end = remaining;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            text.truncate(end);
+            text.push_str(&format!("\n[... file truncated to fit the {}KB limit for /files ...]", MAX_ADDED_FILES_BYTES / 1024));
+            note = format!(", cut off at the {}KB limit for /files", MAX_ADDED_FILES_BYTES / 1024);
+        }
+        remaining = remaining.saturating_sub(text.len());
+        contents.push_str(&format!("\n\n{}", fenced(&path.display().to_string(), text.trim_end())));
+        println!("Added {} ({} bytes{})", path.display(), format_count(size), note);
+        added += 1;
+    }
 
-    stop_signal
+    if added == 0 {
+        println!("No files were added.");
+        return;
+    }
+    add_user_message(messages, &contents);
+    autosave_session(session_id, messages);
+    println!("Note: The assistant will see {} with your next message.", if added == 1 { "the file" } else { "these files" });
 }
 
-/// Sends the prepared request to the OpenAI API.
+/// Adds a user message with an image for `/attach`, if the model accepts images.
 ///
 /// # Arguments
 ///
-/// * `client` - Reference to the HTTP client.
-/// * `api_key` - The OpenAI API key.
-/// * `request_body` - Reference to the JSON request body.
+/// * `argument` - The image's path, optionally followed by a question about it.
+/// * `messages` - Mutable reference to the messages vector.
+/// * `connection` - The chat session's connection details.
 ///
 /// # Returns
 ///
-/// * `reqwest::Result<reqwest::blocking::Response>` - The HTTP response.
-fn send_request(
-    client: &Client,
-    api_key: &str,
-    request_body: &Value,
-) -> reqwest::Result<reqwest::blocking::Response> {
-    client
-        .post(OPENAI_API_URL)
-        .bearer_auth(api_key)
-        .json(request_body)
-        .send()
-}
-
-/// Stops the loading indicator.
-///
-/// # Arguments
-///
-/// * `stop_signal` - The shared signal to stop the loading indicator.
-fn stop_loading_indicator(stop_signal: Arc<Mutex<bool>>) {
-    if let Ok(mut signal) = stop_signal.lock() {
-        *signal = true;
+/// * `bool` - `true` if the message was added and should be sent.
+fn attach_image(argument: &str, messages: &mut Vec<Value>, connection: &ChatConnection) -> bool {
+    let (path, question) = match argument.split_once(char::is_whitespace) {
+        Some((path, question)) => (path, question.trim()),
+        None => (argument, ""),
+    };
+    if path.is_empty() {
+        println!("Usage: /attach <image> [question]");
+        return false;
     }
-    thread::sleep(Duration::from_millis(100));
-    print!("\x1b[2K\r"); // Clear the current line
-    io::stdout().flush().unwrap();
+    let endpoint = connection.backend.endpoint();
+    if !endpoint.capabilities.vision {
+        println!(
+            "Error: The model '{}' does not accept images. Use a vision model such as gpt-4o, or set \"vision\": true under capabilities in .gptsh_config if it does.",
+            endpoint.model
+        );
+        return false;
+    }
+    let data_url = match load_image(path) {
+        Ok(data_url) => data_url,
+        Err(e) => {
+            println!("{}", e);
+            return false;
+        }
+    };
+
+    let question = if question.is_empty() { ATTACHED_IMAGE_PROMPT } else { question };
+    messages.push(serde_json::json!({
+        "role": "user",
+        "content": image_content(question, &data_url),
+        TIMESTAMP_FIELD: current_timestamp()
+    }));
+    true
 }
 
-/// Handles the API response.
+/// Replaces the images in all but the latest user message with a note. Images take up
+/// far more of the context window than text, so they are dropped before whole turns.
 ///
 /// # Arguments
 ///
-/// * `response` - The API response.
-/// * `messages` - Mutable reference to the messages vector.
-/// * `client` - Reference to the HTTP client.
-/// * `api_key` - The OpenAI API key.
-/// * `verbose` - Verbose flag.
+/// * `messages` - The conversation to change.
 ///
 /// # Returns
 ///
-/// * `Option<bool>` - Signals whether to exit the chat.
-fn handle_response(
-    response: reqwest::Result<reqwest::blocking::Response>,
-    messages: &mut Vec<Value>,
-    client: &Client,
-    api_key: &str,
-    verbose: bool,
-) -> Option<bool> {
-    match response {
-        Ok(resp) if resp.status().is_success() => {
-            let openai_response: Value = match resp.json() {
-                Ok(json) => json,
-                Err(e) => {
-                    eprintln!("Failed to parse JSON response: {}", e);
-                    return None;
-                }
-            };
-            process_openai_response(openai_response, messages, client, api_key, verbose)
-        }
-        Ok(resp) => {
-            handle_non_success(resp);
-            None
-        }
-        Err(e) => {
-            eprintln!("Error communicating with OpenAI API: {}", e);
-            None
-        }
-    }
+/// * `usize` - How many images were removed.
+fn remove_old_images(messages: &mut [Value]) -> usize {
+    let Some(latest) = messages.iter().rposition(|message| message["role"] == "user") else {
+        return 0;
+    };
+    messages[..latest].iter_mut().map(remove_images).sum()
 }
 
-/// Processes the successful OpenAI API response.
+/// Sets up `/retry` by dropping the assistant's final reply so it can be generated again.
+/// Function calls and their results are kept, so commands that were already run aren't run
+/// again; only the text that followed them is regenerated. With `hotter`, the new reply is
+/// sampled at a higher temperature.
 ///
 /// # Arguments
 ///
-/// * `response` - The parsed JSON response.
+/// * `argument` - Empty, or `hotter`.
 /// * `messages` - Mutable reference to the messages vector.
-/// * `client` - Reference to the HTTP client.
-/// * `api_key` - The OpenAI API key.
-/// * `verbose` - Verbose flag.
+/// * `connection` - The chat session's connection details.
 ///
 /// # Returns
 ///
-/// * `Option<bool>` - Signals whether to exit the chat.
-fn process_openai_response(
-    response: Value,
-    messages: &mut Vec<Value>,
-    client: &Client,
-    api_key: &str,
-    verbose: bool,
-) -> Option<bool> {
-    let choices = match response["choices"].as_array() {
-        Some(arr) => arr,
-        None => {
-            eprintln!("Unexpected response format: 'choices' field is missing.");
+/// * `Option<Vec<Value>>` - The dropped reply, to restore if the new request fails, or
+///   `None` if there is nothing to retry.
+fn prepare_retry(argument: &str, messages: &mut Vec<Value>, connection: &ChatConnection) -> Option<Vec<Value>> {
+    let hotter = match argument {
+        "" => false,
+        "hotter" => true,
+        _ => {
+            println!("Usage: /retry [hotter]");
             return None;
         }
     };
-
-    if choices.is_empty() {
-        eprintln!("No choices found in the response.");
+    if !messages.iter().any(|message| message["role"] == "user") {
+        println!("There is no reply to retry yet.");
         return None;
     }
 
-    let choice = &choices[0];
-    let message = &choice["message"];
-
-    let mut assistant_message = serde_json::json!({ "role": "assistant" });
-
-    if let Some(content) = message["content"].as_str() {
-        assistant_message["content"] = Value::String(content.to_string());
-    }
-
-    if let Some(function_call) = message.get("function_call") {
-        assistant_message["function_call"] = function_call.clone();
-    }
-
-    messages.push(assistant_message);
+    let is_final_text = |message: &Value| {
+        message["role"] == "assistant" && message.get("tool_calls").is_none() && message.get("function_call").is_none()
+    };
+    let keep = messages.len() - messages.iter().rev().take_while(|message| is_final_text(message)).count();
+    let previous_reply = messages.split_off(keep);
 
-    if let Some(function_call) = message.get("function_call") {
-        handle_function_call(function_call, messages, client, api_key, verbose)
-    } else {
-        if let Some(content) = message["content"].as_str() {
-            println!("\ngptsh: {}\n", content.trim());
+    if hotter {
+        let sampling = connection.config.generation.as_ref().and_then(|generation| generation.chat);
+        let current = sampling.and_then(|sampling| sampling.temperature).unwrap_or(1.0);
+        // Both APIs default to 1; Anthropic's accepts no more than that
+        let maximum = if connection.backend.endpoint().provider == Provider::Anthropic { 1.0 } else { 2.0 };
+        let temperature = (current + RETRY_TEMPERATURE_STEP).min(maximum);
+        if temperature <= current {
+            println!("Note: The temperature is already at the maximum of {}.", maximum);
         }
-        None
+        connection.temperature_override.set(Some(temperature));
+        println!("Regenerating the last reply at temperature {:.1}.", temperature);
+    } else {
+        println!("Regenerating the last reply.");
     }
+    Some(previous_reply)
 }
 
-/// Handles function calls requested by the assistant.
+/// Removes the last exchange: the latest user message and everything after it. Function
+/// calls are removed together with their results and the reply that followed, so the
+/// history never ends with a call that has no answer.
 ///
 /// # Arguments
 ///
-/// * `function_call` - The function call object.
+/// * `argument` - Must be empty.
 /// * `messages` - Mutable reference to the messages vector.
-/// * `client` - Reference to the HTTP client.
-/// * `api_key` - The OpenAI API key.
-/// * `verbose` - Verbose flag.
-///
-/// # Returns
-///
-/// * `Option<bool>` - Signals whether to exit the chat.
-fn handle_function_call(
-    function_call: &Value,
-    messages: &mut Vec<Value>,
-    client: &Client,
-    api_key: &str,
-    verbose: bool,
-) -> Option<bool> {
-    let function_name = match function_call["name"].as_str() {
-        Some(name) => name,
-        None => {
-            eprintln!("Function call missing 'name' field.");
-            return None;
-        }
+/// * `session_id` - The id the conversation is autosaved under.
+fn undo_last_exchange(argument: &str, messages: &mut Vec<Value>, session_id: &str) {
+    if !argument.is_empty() {
+        println!("Usage: /undo");
+        return;
+    }
+    let Some(start) = messages.iter().rposition(|message| message["role"] == "user") else {
+        println!("There is nothing to undo.");
+        return;
     };
 
-    match function_name {
-        "execute_command" => {
-            execute_command(function_call, messages, verbose);
-            // Prepare and send a new request after executing the command
-            let request_body = prepare_request_body(&messages);
-            let stop_signal = start_loading_indicator();
-            let response = send_request(client, api_key, &request_body);
-            stop_loading_indicator(stop_signal);
-            handle_response(response, messages, client, api_key, verbose)
-        }
-        "exit_chat" => Some(true),
-        _ => {
-            eprintln!("Error: Assistant requested an unknown function '{}'.", function_name);
-            None
-        }
+    let removed = messages.split_off(start);
+    let text = message_text(&removed[0]);
+    let mut preview: String = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if preview.chars().count() > UNDO_PREVIEW_CHARS {
+        preview = format!("{}…", preview.chars().take(UNDO_PREVIEW_CHARS).collect::<String>().trim_end());
     }
+    let replies = removed.len() - 1;
+    println!(
+        "Removed \"{}\" and {} message{} after it.",
+        preview,
+        replies,
+        if replies == 1 { "" } else { "s" }
+    );
+    autosave_session(session_id, messages);
 }
 
-/// Executes a shell command as per the function call.
+/// Lists the code blocks in the assistant's last reply, prints one exactly as written so it
+/// can be copied, or saves one to a file.
 ///
 /// # Arguments
 ///
-/// * `function_call` - The function call object.
-/// * `messages` - Mutable reference to the messages vector.
-/// * `verbose` - Verbose flag.
-fn execute_command(function_call: &Value, messages: &mut Vec<Value>, verbose: bool) {
-    let arguments_str = function_call["arguments"].as_str().unwrap_or_default();
-    let arguments: Value = match serde_json::from_str(arguments_str) {
-        Ok(args) => args,
-        Err(e) => {
-            eprintln!("Failed to parse function arguments: {}", e);
-            return;
-        }
-    };
-
-    let command = arguments["command"].as_str().unwrap_or_default();
-
-    if command.is_empty() {
-        eprintln!("No command provided to execute.");
+/// * `argument` - Empty to list the blocks, `<n>` to print block n, or `<n> save <file>`.
+/// * `messages` - The conversation.
+fn show_code_blocks(argument: &str, messages: &[Value]) {
+    let reply = messages
+        .iter()
+        .rev()
+        .filter(|message| message["role"] == "assistant")
+        .find_map(|message| message["content"].as_str().filter(|content| !content.is_empty()));
+    let blocks = find_code_blocks(reply.unwrap_or_default());
+    if blocks.is_empty() {
+        println!("The last reply has no code blocks.");
         return;
     }
 
-    println!("About to execute command: '{}'", command);
-    println!("Do you want to proceed? [Y/n]");
-
-    let confirmation = read_confirmation();
-
-    if confirmation {
-        let adjusted_command = adjust_command(command);
-        match execute_shell_command(&adjusted_command) {
-            Ok(output) => {
-                if verbose {
-                    if !output.stdout.is_empty() {
-                        println!("Command output:\n{:?}", output.stdout);
-                    }
-                    if !output.stderr.is_empty() {
-                        eprintln!("Command error:\n{:?}", output.stderr);
-                    }
-                }
-
-                // Add the command's output to messages for further processing or display
-                messages.push(serde_json::json!({
-                    "role": "function",
-                    "name": "execute_command",
-                    "content": output.stdout
-                }));
-            }
-            Err(e) => {
-                eprintln!("Failed to execute command: {}", e);
-            }
+    let (number, rest) = match argument.split_once(char::is_whitespace) {
+        Some((number, rest)) => (number, rest.trim()),
+        None => (argument, ""),
+    };
+    if number.is_empty() {
+        for (index, block) in blocks.iter().enumerate() {
+            let language = if block.language.is_empty() { "text" } else { block.language };
+            let first_line = block.code.lines().map(str::trim).find(|line| !line.is_empty()).unwrap_or_default();
+            println!("{:>3}. {:<10} {}", index + 1, language, first_line);
         }
+        return;
+    }
+    let Some(block) = number.parse::<usize>().ok().and_then(|n| n.checked_sub(1)).and_then(|n| blocks.get(n)) else {
+        println!("There is no code block {}; the last reply has {}.", number, blocks.len());
+        return;
+    };
 
-        // Ensure all output is written to the terminal
-        io::stdout().flush().expect("Failed to flush stdout");
-    } else {
-        println!("Command execution cancelled.");
+    match rest.split_once(char::is_whitespace) {
+        _ if rest.is_empty() => println!("{}", block.code),
+        Some(("save", file)) => save_code_block(block.code, file.trim()),
+        _ => println!("Usage: /code [n] [save <file>]"),
     }
 }
 
-/// Reads and interprets user confirmation.
+/// Writes a code block to a file, asking first if the file already exists.
 ///
-/// # Returns
+/// # Arguments
 ///
-/// * `bool` - `true` if the user confirmed, else `false`.
-fn read_confirmation() -> bool {
-    let mut input = String::new();
-    if let Err(_) = io::stdin().read_line(&mut input) {
-        eprintln!("Failed to read input.");
-        return false;
+/// * `code` - The block's contents.
+/// * `file` - The file to write.
+fn save_code_block(code: &str, file: &str) {
+    if !confirm_overwrite(file) {
+        println!("Not saved.");
+        return;
+    }
+    match fs::write(file, format!("{}\n", code)) {
+        Ok(()) => println!("Saved {} lines to '{}'.", code.lines().count(), file),
+        Err(e) => eprintln!("Error: Could not write '{}': {}", file, e),
     }
-
-    input.trim().is_empty() || input.trim().eq_ignore_ascii_case("y")
 }
 
-/// Adjusts specific commands for compatibility or desired behavior.
+/// Prints how large the conversation is compared with the model's context window, and
+/// the tokens and estimated cost of the session so far.
 ///
 /// # Arguments
 ///
-/// * `command` - The original command.
-///
-/// # Returns
-///
-/// * `&str` - The adjusted command.
-fn adjust_command(command: &str) -> &str {
+/// * `messages` - The conversation.
+/// * `connection` - The chat session's connection details.
+fn print_token_usage(messages: &[Value], connection: &ChatConnection) {
+    let model = &connection.backend.endpoint().model;
+    let context: usize = messages.iter().map(estimate_message_tokens).sum();
+    println!(
+        "Conversation: {} message{}, about {} tokens",
+        messages.len(),
+        if messages.len() == 1 { "" } else { "s" },
+        format_count(context)
+    );
+    if let Some(budget) = connection.history_token_budget {
+        println!("History budget: {} tokens; older messages are left out beyond it", format_count(budget));
+    }
+
+    let session = connection.session_usage.get();
+    if session.prompt_tokens + session.completion_tokens > 0 {
+        println!("This session: {}", describe_request_cost(model, &session, &connection.config));
+    } else {
+        println!("This session: no requests yet");
+    }
+
+    let Some(window) = context_window_for(model, connection.config.capabilities.as_ref()) else {
+        println!(
+            "Context window: unknown for '{}'; set `context_window` under `capabilities` in .gptsh_config",
+            model
+        );
+        return;
+    };
+    let used = context as f64 / window as f64;
+    println!("Context window: {} tokens ({:.0}% used)", format_count(window), used * 100.0);
+    if used >= CONTEXT_WARNING_RATIO {
+        println!(
+            "Warning: The conversation fills over {:.0}% of the context window; the oldest messages will soon be trimmed. Use /clear or /undo to make room.",
+            CONTEXT_WARNING_RATIO * 100.0
+        );
+    }
+}
+
+/// Writes the conversation to a file as Markdown, asking first if the file already exists.
+///
+/// # Arguments
+///
+/// * `argument` - The file, optionally preceded by `--full` to include the system prompt.
+/// * `messages` - The conversation.
+fn export_transcript(argument: &str, messages: &[Value]) {
+    let (include_system, file) = match argument.strip_prefix("--full") {
+        Some(file) if file.is_empty() || file.starts_with(char::is_whitespace) => (true, file.trim()),
+        _ => (false, argument),
+    };
+    if file.is_empty() {
+        println!("Usage: /export [--full] <file>");
+        return;
+    }
+    if !confirm_overwrite(file) {
+        println!("Not exported.");
+        return;
+    }
+    match fs::write(file, render_transcript(messages, include_system)) {
+        Ok(()) => println!("Exported the conversation to '{}'.", file),
+        Err(e) => eprintln!("Error: Could not write '{}': {}", file, e),
+    }
+}
+
+/// Asks whether to replace a file, if it exists.
+///
+/// # Arguments
+///
+/// * `file` - The file about to be written.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the file doesn't exist or the user agreed to replace it.
+fn confirm_overwrite(file: &str) -> bool {
+    if fs::metadata(file).is_err() {
+        return true;
+    }
+    print!("'{}' already exists. Overwrite it? [y/N] ", file);
+    io::stdout().flush().unwrap();
+    read_explicit_confirmation()
+}
+
+/// Drops the conversation so far, keeping the system prompt. With `keep-last`, the last
+/// exchange (the latest user message and everything after it) is kept too.
+///
+/// # Arguments
+///
+/// * `argument` - Empty, or `keep-last`.
+/// * `messages` - Mutable reference to the messages vector.
+/// * `session_id` - The id the conversation is autosaved under.
+fn clear_conversation(argument: &str, messages: &mut Vec<Value>, session_id: &str) {
+    let keep_from = match argument {
+        "" => messages.len(),
+        "keep-last" => messages
+            .iter()
+            .rposition(|message| message["role"] == "user")
+            .unwrap_or(messages.len()),
+        _ => {
+            println!("Usage: /clear [keep-last]");
+            return;
+        }
+    };
+    let start = messages.iter().take_while(|message| message["role"] == "system").count().min(keep_from);
+    let discarded = keep_from - start;
+    messages.drain(start..keep_from);
+    println!("Cleared the conversation ({} messages discarded).", discarded);
+    autosave_session(session_id, messages);
+}
+
+/// Replaces the system prompt for the rest of the session, or prints it when no text is given.
+///
+/// # Arguments
+///
+/// * `text` - The new system prompt, or empty to show the current one.
+/// * `messages` - Mutable reference to the messages vector, which starts with the system prompt.
+/// * `session_id` - The id the conversation is autosaved under.
+fn replace_system_prompt(text: &str, messages: &mut Vec<Value>, session_id: &str) {
+    let has_system_prompt = messages.first().is_some_and(|message| message["role"] == "system" && !is_memory_message(message));
+    if text.is_empty() {
+        match messages.first().filter(|_| has_system_prompt) {
+            Some(message) => println!("System prompt: {}", message["content"].as_str().unwrap_or_default()),
+            None => println!("There is no system prompt."),
+        }
+        return;
+    }
+    if !has_system_prompt {
+        messages.insert(0, serde_json::json!({ "role": "system", "content": "" }));
+    }
+    messages[0]["content"] = Value::String(text.to_string());
+    println!("System prompt updated.");
+    autosave_session(session_id, messages);
+}
+
+/// Replaces the system prompt with a persona's, or lists the personas when no name is given.
+/// The model and temperature chosen when the session started stay in effect.
+///
+/// # Arguments
+///
+/// * `name` - The persona to switch to.
+/// * `messages` - Mutable reference to the messages vector.
+/// * `session_id` - The id the conversation is autosaved under.
+fn switch_persona(name: &str, messages: &mut Vec<Value>, session_id: &str) {
+    if name.is_empty() {
+        print_personas();
+        return;
+    }
+    match load_persona(name) {
+        Ok(persona) => {
+            replace_system_prompt(&expand_prompt_placeholders(&persona.system_prompt), messages, session_id);
+            println!("Now chatting with the '{}' persona.", name);
+            if persona.model.is_some() || persona.temperature.is_some() {
+                println!("Note: Its model and temperature apply from the next session, e.g. `gptsh --persona {}`.", name);
+            }
+        }
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+/// Prints the commands available at the chat prompt.
+fn print_chat_commands() {
+    let width = CHAT_COMMANDS.iter().map(|entry| entry.usage.len()).max().unwrap_or_default();
+    println!("Commands:");
+    for entry in CHAT_COMMANDS {
+        println!("  {:<width$}  {}", entry.usage, entry.description);
+    }
+}
+
+/// Finds the chat command a mistyped one was most likely meant to be: one a couple of
+/// typos away, or one it is the start of.
+///
+/// # Arguments
+///
+/// * `command` - The unknown command, e.g. `/hepl`.
+///
+/// # Returns
+///
+/// * `Option<&'static str>` - The closest command, or `None` if none is close.
+fn closest_command(command: &str) -> Option<&'static str> {
+    let command = command.to_lowercase();
+    CHAT_COMMANDS
+        .iter()
+        .map(|entry| entry.name)
+        .filter(|name| name.starts_with('/'))
+        .map(|name| (edit_distance(&command, name), name))
+        .filter(|(distance, name)| *distance <= 2 || (command.len() > 2 && name.starts_with(command.as_str())))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, name)| name)
+}
+
+/// Saves the conversation under a name for `/load` or `--resume`.
+///
+/// # Arguments
+///
+/// * `name` - The name to save under; an earlier save with that name is replaced.
+/// * `messages` - The conversation.
+fn save_named_session(name: &str, messages: &[Value]) {
+    if name.is_empty() {
+        println!("Usage: /save <name>");
+        return;
+    }
+    if let Err(e) = validate_session_name(name) {
+        eprintln!("{}", e);
+        return;
+    }
+    match save_session(name, messages) {
+        Ok(()) => println!("Saved the conversation as '{}'.", name),
+        Err(e) => eprintln!("Error: Could not save the conversation: {}", e),
+    }
+}
+
+/// Replaces the conversation with a saved one, after the user confirms.
+/// Without a name, or with one that isn't saved, the saved sessions are listed.
+///
+/// # Arguments
+///
+/// * `name` - The session to load.
+/// * `messages` - Mutable reference to the messages vector.
+/// * `session_id` - The id the conversation is autosaved under.
+/// * `allow_by_default` - Whether pressing Enter at the confirmation loads the session.
+fn load_named_session(name: &str, messages: &mut Vec<Value>, session_id: &str, allow_by_default: bool) {
+    if name.is_empty() {
+        print_saved_sessions();
+        return;
+    }
+    if let Err(e) = validate_session_name(name) {
+        eprintln!("{}", e);
+        return;
+    }
+    let saved = match load_session(name) {
+        Ok(saved) => saved,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!("Error: There is no chat session named '{}'.", name);
+            print_saved_sessions();
+            return;
+        }
+        Err(e) => {
+            eprintln!("Error: Could not load chat session '{}': {}", name, e);
+            return;
+        }
+    };
+
+    print!(
+        "Loading '{}' discards the current conversation ({} messages). Continue? [{}] ",
+        name,
+        count_conversation_messages(messages),
+        yes_no_options(allow_by_default)
+    );
+    io::stdout().flush().unwrap();
+    if !read_default_confirmation(allow_by_default) {
+        println!("Kept the current conversation.");
+        return;
+    }
+
+    *messages = saved;
+    refresh_memory_message(messages);
+    println!("Loaded '{}' ({} messages).", name, count_conversation_messages(messages));
+    autosave_session(session_id, messages);
+}
+
+/// Saves the conversation under its session id, warning if that fails.
+///
+/// # Arguments
+///
+/// * `session_id` - The id the conversation is autosaved under.
+/// * `messages` - The conversation.
+fn autosave_session(session_id: &str, messages: &[Value]) {
+    if let Err(e) = save_session(session_id, messages) {
+        eprintln!("Warning: Could not save the chat session: {}", e);
+    }
+}
+
+/// Connection details shared by every request in a chat session.
+struct ChatConnection {
+    /// Where requests are sent.
+    backend: Box<dyn CompletionBackend>,
+    /// Whether replies are streamed token by token.
+    stream: bool,
+    /// The maximum number of tokens in a reply, if limited.
+    max_tokens: Option<u32>,
+    /// The seed sent with every request, if any.
+    seed: Option<u64>,
+    /// Whether replies cut off at the token limit are continued with follow-up requests.
+    continue_truncated: bool,
+    /// Whether replies are styled as Markdown instead of printed as plain text.
+    render_markdown: bool,
+    /// The estimated tokens of history sent with each request, if limited.
+    history_token_budget: Option<usize>,
+    /// Set once history has been left out of a request, so the user is told only once.
+    history_trimmed: Cell<bool>,
+    /// Set when Ctrl-C cancels a request before any of the reply arrived.
+    request_cancelled: Cell<bool>,
+    /// The most bytes of a command's output sent back to the assistant; `0` is unlimited.
+    max_command_output_bytes: usize,
+    /// Whether pressing Enter at a command confirmation runs the command.
+    confirm_allows_by_default: bool,
+    /// The most functions the assistant may call in answer to one message.
+    max_function_calls: usize,
+    /// Whether the assistant is offered `fetch_url`.
+    allow_network_tools: bool,
+    /// The facts `get_system_info` reports; the function isn't offered when empty.
+    system_info_fields: Vec<String>,
+    /// The most entries `list_directory` returns to the assistant.
+    list_directory_max_entries: usize,
+    /// Whether to use the deprecated `functions` request format instead of `tools`,
+    /// for older OpenAI-compatible endpoints.
+    legacy_functions: bool,
+    /// Whether function definitions are sent. Cleared when an OpenAI-compatible server
+    /// rejects them, so the session continues with plain replies.
+    tools_enabled: Cell<bool>,
+    /// The temperature used instead of the configured one, set for a `/retry hotter`.
+    temperature_override: Cell<Option<f64>>,
+    /// Whether to print the tokens and estimated cost of every request.
+    show_cost: bool,
+    /// Whether requests are sent even when the budget is spent, from `--override-budget`.
+    override_budget: bool,
+    /// The tokens used so far in this session.
+    session_usage: Cell<Usage>,
+    /// The configuration loaded when the session started.
+    config: Config,
+}
+
+/// A function the assistant asked to call, from either a `tool_calls` entry
+/// or a legacy `function_call`.
+struct FunctionCall {
+    /// The tool call id, which the result message must echo. `None` for legacy calls.
+    id: Option<String>,
+    name: String,
+    /// The JSON-encoded arguments.
+    arguments: String,
+}
+
+/// A completed API response, normalized to the non-streaming response shape.
+struct Completion {
+    response: ChatResponse,
+    /// Whether the reply content was already printed while streaming.
+    streamed: bool,
+}
+
+/// Announces entry into chat mode.
+fn announce_entry_to_chat_mode() {
+    println!("Entering chat mode. Type 'exit' or 'quit' to end the session, or /help for commands.");
+}
+
+/// Initializes the conversation with the system prompt: `chat_system_prompt` from the
+/// config file if set, or the built-in prompt. `${USER}` and `${PWD}` in a configured prompt
+/// are replaced with the user name and the working directory. A configured empty prompt
+/// means no system message at all.
+///
+/// # Arguments
+///
+/// * `config` - The configuration loaded when the session started.
+///
+/// # Returns
+///
+/// * `Vec<Value>` - A vector of JSON values representing the initial messages.
+fn initialize_messages_with_system_prompt(config: &Config) -> Vec<Value> {
+    let prompt = match &config.chat_system_prompt {
+        Some(prompt) => expand_prompt_placeholders(prompt),
+        None => SYSTEM_PROMPT.to_string(),
+    };
+    let mut messages = Vec::new();
+    if !prompt.trim().is_empty() {
+        messages.push(serde_json::json!({
+            "role": "system",
+            "content": prompt
+        }));
+    }
+    messages.extend(load_memory_message());
+    messages
+}
+
+/// Builds the system message with the facts remembered with `/remember`, noting when
+/// some had to be left out.
+///
+/// # Returns
+///
+/// * `Option<Value>` - The message, or `None` if nothing is remembered.
+fn load_memory_message() -> Option<Value> {
+    let memories = match load_memories() {
+        Ok(memories) => memories,
+        Err(e) => {
+            eprintln!("Warning: Could not read the memory file: {}", e);
+            return None;
+        }
+    };
+    let (prompt, left_out) = memory_prompt(&memories)?;
+    if left_out > 0 {
+        println!(
+            "Note: {} of {} remembered facts were left out to keep the prompt short; use /forget or edit {}.",
+            left_out,
+            memories.len(),
+            memory_file_display()
+        );
+    }
+    Some(serde_json::json!({ "role": "system", "content": prompt }))
+}
+
+/// Checks whether a message is the one holding the remembered facts.
+fn is_memory_message(message: &Value) -> bool {
+    message["role"] == "system" && message["content"].as_str().is_some_and(|content| content.starts_with(MEMORY_INTRO))
+}
+
+/// Replaces the remembered facts in the conversation with those in the memory file, after
+/// the system prompt.
+///
+/// # Arguments
+///
+/// * `messages` - Mutable reference to the messages vector.
+fn refresh_memory_message(messages: &mut Vec<Value>) {
+    messages.retain(|message| !is_memory_message(message));
+    if let Some(message) = load_memory_message() {
+        let position = messages.iter().take_while(|message| message["role"] == "system").count();
+        messages.insert(position, message);
+    }
+}
+
+/// Remembers a fact for `/remember`, in this session and every later one.
+///
+/// # Arguments
+///
+/// * `fact` - The fact, e.g. `our servers run Ubuntu 22.04`.
+/// * `messages` - Mutable reference to the messages vector.
+fn remember_fact(fact: &str, messages: &mut Vec<Value>) {
+    if fact.is_empty() {
+        println!("Usage: /remember <fact>");
+        return;
+    }
+    match add_memory(fact) {
+        Ok(count) => {
+            refresh_memory_message(messages);
+            println!("Remembered as fact {}; the assistant will know it in every chat session.", count);
+        }
+        Err(e) => eprintln!("Error: Could not update the memory file: {}", e),
+    }
+}
+
+/// Lists the remembered facts with their numbers for `/memories`.
+fn print_memories() {
+    match load_memories() {
+        Ok(memories) if memories.is_empty() => println!("Nothing is remembered yet. Add a fact with /remember <fact>."),
+        Ok(memories) => {
+            println!("Remembered facts ({}):", memory_file_display());
+            for (index, fact) in memories.iter().enumerate() {
+                println!("  {}. {}", index + 1, fact);
+            }
+        }
+        Err(e) => eprintln!("Error: Could not read the memory file: {}", e),
+    }
+}
+
+/// Forgets a remembered fact for `/forget`.
+///
+/// # Arguments
+///
+/// * `argument` - The fact's number, as listed by `/memories`.
+/// * `messages` - Mutable reference to the messages vector.
+fn forget_fact(argument: &str, messages: &mut Vec<Value>) {
+    let Ok(number) = argument.parse::<usize>() else {
+        println!("Usage: /forget <n>, where n is a number listed by /memories");
+        return;
+    };
+    match remove_memory(number) {
+        Ok(fact) => {
+            refresh_memory_message(messages);
+            println!("Forgot: {}", fact);
+        }
+        Err(e) => println!("{}", e),
+    }
+}
+
+/// Replaces `${USER}` and `${PWD}` in a system prompt.
+///
+/// # Arguments
+///
+/// * `prompt` - The configured system prompt.
+///
+/// # Returns
+///
+/// * `String` - The prompt with the placeholders filled in.
+fn expand_prompt_placeholders(prompt: &str) -> String {
+    let user = env::var("USER").or_else(|_| env::var("USERNAME")).unwrap_or_default();
+    let pwd = env::current_dir()
+        .map(|dir| dir.display().to_string())
+        .unwrap_or_default();
+    prompt.replace("${USER}", &user).replace("${PWD}", &pwd)
+}
+
+/// Counts the messages in a conversation, not counting system messages.
+fn count_conversation_messages(messages: &[Value]) -> usize {
+    messages.iter().filter(|message| message["role"] != "system").count()
+}
+
+/// Starts a new conversation, or continues a saved one when `--resume` was given.
+/// A resumed conversation gets the current system prompt instead of the one it was saved with.
+///
+/// # Arguments
+///
+/// * `resume` - The `--resume` option: `Some(None)` for the most recent session.
+/// * `config` - The configuration loaded when the session started.
+///
+/// # Returns
+///
+/// * `Option<(String, Vec<Value>)>` - The session id and the conversation, or `None` if the
+///   requested session could not be loaded.
+fn start_session(resume: Option<&Option<String>>, config: &Config) -> Option<(String, Vec<Value>)> {
+    let (id, saved) = match resume {
+        None => return Some((new_session_id(), initialize_messages_with_system_prompt(config))),
+        Some(None) => match load_latest_session() {
+            Some(session) => session,
+            None => {
+                println!("Note: There is no saved chat session to resume; starting a new one.");
+                return Some((new_session_id(), initialize_messages_with_system_prompt(config)));
+            }
+        },
+        Some(Some(id)) if validate_session_name(id).is_err() => {
+            eprintln!("Error: There is no chat session named '{}'.", id);
+            return None;
+        }
+        Some(Some(id)) => match load_session(id) {
+            Ok(messages) => (id.clone(), messages),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                eprintln!("Error: There is no chat session named '{}'.", id);
+                print_saved_sessions();
+                return None;
+            }
+            Err(e) => {
+                eprintln!("Error: Could not load chat session '{}': {}", id, e);
+                return None;
+            }
+        },
+    };
+
+    let mut messages = initialize_messages_with_system_prompt(config);
+    messages.extend(saved.into_iter().filter(|message| message["role"] != "system"));
+    println!("Resumed chat session {} ({} messages).", id, count_conversation_messages(&messages));
+    Some((id, messages))
+}
+
+/// Prints the ids of the saved chat sessions, most recent first.
+fn print_saved_sessions() {
+    let sessions = list_sessions();
+    if sessions.is_empty() {
+        println!("There are no saved chat sessions.");
+    } else {
+        println!("Saved chat sessions:");
+        for id in sessions {
+            println!("  {}", id);
+        }
+    }
+}
+
+/// Tells the user how to come back to the session, if anything was said in it.
+///
+/// # Arguments
+///
+/// * `id` - The session id.
+/// * `messages` - The conversation.
+fn announce_saved_session(id: &str, messages: &[Value]) {
+    if messages.iter().any(|message| message["role"] == "user") {
+        println!("Session saved as {}; continue it with `gptsh --resume {}`.", id, id);
+    }
+}
+
+/// Reads user input from the terminal. A line ending in a backslash continues on the next
+/// line, and text between two `"""` lines is read as one block, so pasted text with line
+/// breaks becomes a single message.
+///
+/// # Returns
+///
+/// * `Option<String>` - The user's input, or `None` once input has ended (Ctrl-D, or the end
+///   of piped input) with nothing typed.
+fn read_user_input() -> Option<String> {
+    let prompt = InputPrompt::new(&format!("{} ", theme().user_prefix("You:")));
+    let mut input = MultiLineInput::default();
+    loop {
+        let mut line = String::new();
+        let ended = match io::stdin().read_line(&mut line) {
+            Ok(0) => true,
+            Ok(_) => false,
+            Err(_) => {
+                eprintln!("Failed to read input.");
+                true
+            }
+        };
+        if ended {
+            // Send whatever was typed so far; the next read ends the chat
+            let message = input.finish();
+            return (!message.is_empty()).then_some(message);
+        }
+        if let Some(message) = input.push(line.trim_end_matches(['\n', '\r'])) {
+            return Some(message);
+        }
+        prompt.show("...  ");
+    }
+}
+
+/// Assembles a message from the lines typed at the `You:` prompt.
+#[derive(Default)]
+struct MultiLineInput {
+    lines: Vec<String>,
+    /// Whether a `"""` block is open.
+    in_block: bool,
+}
+
+impl MultiLineInput {
+    /// Adds a line of input.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The line, without its line break.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - The complete message, or `None` if more lines are needed.
+    fn push(&mut self, line: &str) -> Option<String> {
+        if self.in_block {
+            match line.trim_end().strip_suffix(BLOCK_DELIMITER) {
+                Some(last) => {
+                    if !last.trim().is_empty() {
+                        self.lines.push(last.to_string());
+                    }
+                    Some(self.finish())
+                }
+                None => {
+                    self.lines.push(line.to_string());
+                    None
+                }
+            }
+        } else if self.lines.is_empty() && line.trim_start().starts_with(BLOCK_DELIMITER) {
+            self.in_block = true;
+            let first = &line.trim_start()[BLOCK_DELIMITER.len()..];
+            if first.trim().is_empty() {
+                None
+            } else {
+                // The block may open and close on one line
+                self.push(first)
+            }
+        } else if let Some(continued) = line.strip_suffix('\\') {
+            self.lines.push(continued.to_string());
+            None
+        } else {
+            self.lines.push(line.to_string());
+            Some(self.finish())
+        }
+    }
+
+    /// Returns the lines read so far as one message.
+    fn finish(&mut self) -> String {
+        self.in_block = false;
+        std::mem::take(&mut self.lines).join("\n")
+    }
+}
+
+/// Determines if the user wants to exit the chat.
+///
+/// # Arguments
+///
+/// * `input` - The user's input.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the user wants to exit, else `false`.
+fn should_exit(input: &str) -> bool {
+    if input.eq_ignore_ascii_case("exit") || input.eq_ignore_ascii_case("quit") {
+        println!("Exiting chat mode.");
+        true
+    } else {
+        false
+    }
+}
+
+/// Adds the user's message to the conversation history.
+///
+/// # Arguments
+///
+/// * `messages` - Mutable reference to the messages vector.
+/// * `user_input` - The user's input.
+fn add_user_message(messages: &mut Vec<Value>, user_input: &str) {
+    let mut message = serde_json::json!({
+        "role": "user",
+        "content": user_input
+    });
+    message[TIMESTAMP_FIELD] = current_timestamp();
+    messages.push(message);
+}
+
+/// Returns the current time for a message's timestamp, which `/export` shows.
+fn current_timestamp() -> Value {
+    Value::from(Local::now().to_rfc3339())
+}
+
+/// Drops the oldest turns of the conversation so it fits the context window again.
+/// System messages are always kept, as are the last `KEEP_RECENT_TURNS` turns (a turn starts
+/// with a user message); with that few turns left, only the oldest turn is dropped.
+/// Whole turns are dropped so no tool result is left without its call.
+///
+/// # Arguments
+///
+/// * `messages` - Mutable reference to the messages vector.
+///
+/// # Returns
+///
+/// * `usize` - How many messages were dropped; `0` if only the latest turn is left.
+fn trim_old_messages(messages: &mut Vec<Value>) -> usize {
+    let turn_starts: Vec<usize> = messages
+        .iter()
+        .enumerate()
+        .filter(|(_, message)| message["role"] == "user")
+        .map(|(index, _)| index)
+        .collect();
+    let keep = KEEP_RECENT_TURNS.min(turn_starts.len().saturating_sub(1));
+    if keep == 0 {
+        return 0;
+    }
+
+    let cut = turn_starts[turn_starts.len() - keep];
+    let before = messages.len();
+    let mut index = 0;
+    messages.retain(|message| {
+        let keep = index >= cut || message["role"] == "system";
+        index += 1;
+        keep
+    });
+    before - messages.len()
+}
+
+/// Estimates the tokens a message takes up in a request, counting its content, any
+/// function calls, and a few tokens of per-message overhead.
+///
+/// # Arguments
+///
+/// * `message` - A message in the chat-completions format.
+///
+/// # Returns
+///
+/// * `usize` - The estimated token count.
+fn estimate_message_tokens(message: &Value) -> usize {
+    const MESSAGE_OVERHEAD: usize = 4;
+    let text = match &message["content"] {
+        Value::String(content) if message.get("tool_calls").is_none() && message.get("function_call").is_none() => {
+            content.clone()
+        }
+        // Images are counted by their typical cost, not the size of their encoding
+        Value::Array(_) => message_text(message),
+        _ => message.to_string(),
+    };
+    estimate_tokens(&text) + count_images(message) * IMAGE_TOKEN_ESTIMATE + MESSAGE_OVERHEAD
+}
+
+/// Selects the part of the conversation to send so its estimated size stays within a budget.
+/// System messages and the latest turn are always kept; earlier turns (a turn starts with a
+/// user message) are added from newest to oldest while they fit. Whole turns are dropped so
+/// no tool result is left without its call.
+///
+/// # Arguments
+///
+/// * `messages` - The whole conversation.
+/// * `budget` - The estimated number of tokens the selection should stay within.
+///
+/// # Returns
+///
+/// * `Vec<Value>` - The messages to send, in their original order.
+fn fit_history(messages: &[Value], budget: usize) -> Vec<Value> {
+    // Anything before the first user message is treated as a turn of its own
+    let turn_starts: Vec<usize> = messages
+        .iter()
+        .enumerate()
+        .filter(|(index, message)| *index == 0 || message["role"] == "user")
+        .map(|(index, _)| index)
+        .collect();
+    let Some(&latest) = turn_starts.last() else {
+        return Vec::new();
+    };
+
+    let system_tokens: usize = messages
+        .iter()
+        .filter(|message| message["role"] == "system")
+        .map(estimate_message_tokens)
+        .sum();
+    let turn_tokens = |range: &[Value]| -> usize {
+        range
+            .iter()
+            .filter(|message| message["role"] != "system")
+            .map(estimate_message_tokens)
+            .sum()
+    };
+
+    let mut used = system_tokens + turn_tokens(&messages[latest..]);
+    let mut cut = latest;
+    for window in turn_starts.windows(2).rev() {
+        let tokens = turn_tokens(&messages[window[0]..window[1]]);
+        if used + tokens > budget {
+            break;
+        }
+        used += tokens;
+        cut = window[0];
+    }
+
+    messages
+        .iter()
+        .enumerate()
+        .filter(|(index, message)| *index >= cut || message["role"] == "system")
+        .map(|(_, message)| message.clone())
+        .collect()
+}
+
+/// Prepares the JSON request body for the OpenAI API.
+///
+/// # Arguments
+///
+/// * `messages` - Reference to the messages vector.
+/// * `connection` - The chat session's connection details.
+///
+/// # Returns
+///
+/// * `Value` - The JSON request body.
+fn prepare_request_body(messages: &[Value], connection: &ChatConnection) -> Value {
+    let capabilities = connection.backend.endpoint().capabilities;
+    let mut history = match connection.history_token_budget {
+        Some(budget) => {
+            let history = fit_history(messages, budget);
+            // Leave out earlier images before leaving out earlier messages
+            let mut lighter = messages.to_vec();
+            if history.len() < messages.len() && remove_old_images(&mut lighter) > 0 {
+                fit_history(&lighter, budget)
+            } else {
+                history
+            }
+        }
+        None => messages.to_vec(),
+    };
+    for message in &mut history {
+        if let Some(message) = message.as_object_mut() {
+            message.remove(TIMESTAMP_FIELD);
+        }
+    }
+    if history.len() < messages.len() && !connection.history_trimmed.replace(true) {
+        eprintln!(
+            "Note: Older messages are no longer sent, to keep the conversation within history_token_budget ({} tokens).",
+            connection.history_token_budget.unwrap_or_default()
+        );
+    }
+    let messages = capabilities.adapt_json_messages(&history);
+    let mut body = if !connection.tools_enabled.get() {
+        serde_json::json!({
+            "model": connection.backend.endpoint().model,
+            "messages": messages
+        })
+    } else if connection.legacy_functions {
+        serde_json::json!({
+            "model": connection.backend.endpoint().model,
+            "messages": messages,
+            "functions": get_function_definitions(connection),
+            "function_call": "auto"
+        })
+    } else {
+        let tools: Vec<Value> = get_function_definitions(connection)
+            .into_iter()
+            .map(|function| serde_json::json!({ "type": "function", "function": function }))
+            .collect();
+        serde_json::json!({
+            "model": connection.backend.endpoint().model,
+            "messages": messages,
+            "tools": tools,
+            "tool_choice": "auto"
+        })
+    };
+    if connection.stream {
+        connection.backend.endpoint().request_streaming(&mut body);
+    }
+    if let Some(max_tokens) = connection.max_tokens {
+        body[capabilities.token_limit_field()] = Value::from(max_tokens);
+    }
+    if let Some(seed) = connection.seed {
+        body["seed"] = Value::from(seed);
+    }
+    let sampling = connection.config.generation.as_ref().and_then(|generation| generation.chat);
+    if let Some(Value::Object(parameters)) = sampling.and_then(|sampling| serde_json::to_value(sampling).ok()) {
+        for (name, value) in parameters {
+            body[name] = value;
+        }
+    }
+    if let Some(temperature) = connection.temperature_override.get() {
+        body["temperature"] = Value::from(temperature);
+    }
+    body
+}
+
+/// Defines the available functions that the assistant can call.
+///
+/// # Arguments
+///
+/// * `connection` - The chat session's connection details, which say which optional
+///   functions are enabled.
+///
+/// # Returns
+///
+/// * `Vec<Value>` - A vector of JSON values representing function definitions.
+fn get_function_definitions(connection: &ChatConnection) -> Vec<Value> {
+    let mut definitions = vec![
+        serde_json::json!({
+            "name": "execute_command",
+            "description": "Executes a shell command and returns the output.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "command": {
+                        "type": "string",
+                        "description": "The shell command to execute."
+                    }
+                },
+                "required": ["command"]
+            }
+        }),
+        serde_json::json!({
+            "name": "list_directory",
+            "description": "Lists the files in a directory with their types and sizes, as JSON. Use this instead of running ls; it needs no confirmation. Only directories inside the user's current directory can be listed.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The directory to list, relative to the current directory. Defaults to the current directory."
+                    },
+                    "glob": {
+                        "type": "string",
+                        "description": "Only list names matching this pattern, e.g. *.log."
+                    },
+                    "include_hidden": {
+                        "type": "boolean",
+                        "description": "Whether to include names starting with a dot. Defaults to false."
+                    }
+                }
+            }
+        }),
+        serde_json::json!({
+            "name": "read_file",
+            "description": "Reads a text file on the user's machine and returns its contents. Relative paths are resolved against the user's current directory. The user is asked before each read.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path of the file to read."
+                    }
+                },
+                "required": ["path"]
+            }
+        }),
+        serde_json::json!({
+            "name": "write_file",
+            "description": "Creates or replaces a text file on the user's machine. Relative paths are resolved against the user's current directory. The user reviews a diff and must approve each write.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "The path of the file to write."
+                    },
+                    "content": {
+                        "type": "string",
+                        "description": "The complete new content of the file."
+                    }
+                },
+                "required": ["path", "content"]
+            }
+        }),
+        serde_json::json!({
+            "name": "exit_chat",
+            "description": "Signals that the user wants to exit the chat.",
+            "parameters": {
+                "type": "object",
+                "properties": {}
+            }
+        }),
+    ];
+
+    if !connection.system_info_fields.is_empty() {
+        definitions.push(serde_json::json!({
+            "name": "get_system_info",
+            "description": format!(
+                "Returns facts about the user's environment ({}) as JSON. Call it before answering questions that depend on the operating system, shell, or installed tools. Needs no confirmation.",
+                connection.system_info_fields.join(", ")
+            ),
+            "parameters": {
+                "type": "object",
+                "properties": {}
+            }
+        }));
+    }
+    if connection.allow_network_tools {
+        definitions.push(serde_json::json!({
+            "name": "fetch_url",
+            "description": "Downloads a web page (GET) and returns its text, e.g. to consult documentation. The user is asked before each fetch.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "url": {
+                        "type": "string",
+                        "description": "The http or https URL to fetch."
+                    }
+                },
+                "required": ["url"]
+            }
+        }));
+    }
+    definitions
+}
+
+/// Why a chat request produced no completion.
+enum CompletionFailure {
+    /// The API returned an error status; holds the status and the unreported error body.
+    Rejected(StatusCode, String),
+    /// Ctrl-C was pressed before the reply arrived.
+    Cancelled,
+    /// The failure has already been reported.
+    Failed,
+}
+
+/// Sends the conversation to the OpenAI API and waits for the complete reply.
+/// The loading indicator runs until the reply (or, when streaming, its first token) arrives,
+/// and Ctrl-C cancels the request while it is in flight.
+/// When the conversation no longer fits the context window, old messages are trimmed and
+/// the request is sent again.
+///
+/// # Arguments
+///
+/// * `connection` - The chat session's connection details.
+/// * `messages` - Mutable reference to the messages vector.
+///
+/// # Returns
+///
+/// * `Option<Completion>` - The completed response, or `None` if the request failed.
+fn request_completion(connection: &ChatConnection, messages: &mut Vec<Value>) -> Option<Completion> {
+    connection.request_cancelled.set(false);
+    let request_body = prepare_request_body(messages, connection);
+    let result = {
+        let interrupt = InterruptGuard::new();
+        block_on(fetch_completion(connection, &request_body, &interrupt))
+    };
+
+    match result {
+        Ok(mut completion) => {
+            record_completion_usage(connection, messages, &mut completion);
+            Some(completion)
+        }
+        Err(CompletionFailure::Rejected(status, error_text)) => {
+            if status == StatusCode::BAD_REQUEST && is_context_length_error(&error_text) {
+                let removed = remove_old_images(messages);
+                if removed > 0 {
+                    eprintln!("Note: Removed {} images from earlier messages to fit the context window.", removed);
+                    return request_completion(connection, messages);
+                }
+                let dropped = trim_old_messages(messages);
+                if dropped > 0 {
+                    eprintln!("Note: Trimmed {} old messages to fit the context window.", dropped);
+                    return request_completion(connection, messages);
+                }
+            } else if rejects_tools(connection, status) {
+                return retry_without_tools(connection, messages);
+            }
+            connection.backend.report_api_error(status, &error_text);
+            None
+        }
+        Err(CompletionFailure::Cancelled) => {
+            println!("Request cancelled.");
+            connection.request_cancelled.set(true);
+            None
+        }
+        Err(CompletionFailure::Failed) => None,
+    }
+}
+
+/// Checks the budget before a request is sent, reporting why it may not be.
+///
+/// # Arguments
+///
+/// * `connection` - The chat session's connection details.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the request may be sent.
+fn within_budget(connection: &ChatConnection) -> bool {
+    match check_budget(connection.override_budget) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("{}", e);
+            false
+        }
+    }
+}
+
+/// Sends a prepared request and reads the reply, streamed or not.
+///
+/// # Arguments
+///
+/// * `connection` - The chat session's connection details.
+/// * `request_body` - The JSON request body.
+/// * `interrupt` - Guard that cancels the request when Ctrl-C is pressed.
+///
+/// # Returns
+///
+/// * `Result<Completion, CompletionFailure>` - The completed response, or why there is none.
+async fn fetch_completion(
+    connection: &ChatConnection,
+    request_body: &Value,
+    interrupt: &InterruptGuard,
+) -> Result<Completion, CompletionFailure> {
+    let spinner = start_loading_animation();
+    let response = match interrupt.run(connection.backend.complete(request_body)).await {
+        Some(Ok(response)) => response,
+        Some(Err(e)) => {
+            stop_loading_indicator(spinner);
+            connection.backend.report_request_error(&e);
+            return Err(CompletionFailure::Failed);
+        }
+        None => {
+            stop_loading_indicator(spinner);
+            return Err(CompletionFailure::Cancelled);
+        }
+    };
+
+    let status = response.status();
+    if !status.is_success() {
+        stop_loading_indicator(spinner);
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(CompletionFailure::Rejected(status, error_text));
+    }
+
+    if connection.stream {
+        return read_streamed_response(response, spinner, interrupt, connection.render_markdown)
+            .await
+            .ok_or_else(|| if interrupt.interrupted() { CompletionFailure::Cancelled } else { CompletionFailure::Failed });
+    }
+
+    let parsed = interrupt.run(response.json::<ChatResponse>()).await;
+    stop_loading_indicator(spinner);
+    match parsed {
+        Some(Ok(response)) => Ok(Completion {
+            response,
+            streamed: false,
+        }),
+        Some(Err(e)) => {
+            eprintln!("{}", theme().error(&format!("Failed to parse JSON response: {}", e)));
+            Err(CompletionFailure::Failed)
+        }
+        None => Err(CompletionFailure::Cancelled),
+    }
+}
+
+/// Records the tokens used by a completed request, estimating them when the response
+/// didn't report any.
+///
+/// # Arguments
+///
+/// * `connection` - The chat session's connection details.
+/// * `messages` - The conversation that was sent.
+/// * `completion` - The completed response.
+fn record_completion_usage(connection: &ChatConnection, messages: &[Value], completion: &mut Completion) {
+    let response = &mut completion.response;
+    let usage = response.usage.unwrap_or_else(|| {
+        let prompt = Value::from(messages).to_string();
+        let reply = response
+            .first_choice()
+            .and_then(|choice| serde_json::to_string(&choice.message).ok())
+            .unwrap_or_default();
+        estimate_usage(&prompt, &reply)
+    });
+    response.usage = Some(usage);
+    record_usage(connection.backend.as_ref(), usage);
+}
+
+/// Prints the tokens and estimated cost of a request along with the session total,
+/// followed by the seed and system fingerprint, when cost display is enabled.
+///
+/// # Arguments
+///
+/// * `connection` - The chat session's connection details.
+/// * `response` - The completed response.
+fn report_cost(connection: &ChatConnection, response: &ChatResponse) {
+    let Some(usage) = response.usage else {
+        return;
+    };
+    let mut session = connection.session_usage.get();
+    session.prompt_tokens += usage.prompt_tokens;
+    session.completion_tokens += usage.completion_tokens;
+    connection.session_usage.set(session);
+
+    if connection.show_cost {
+        let model = &connection.backend.endpoint().model;
+        println!(
+            "{} · {}",
+            describe_request_cost(model, &usage, &connection.config),
+            describe_session_cost(model, &session, &connection.config)
+        );
+        if let Some(reproducibility) =
+            describe_reproducibility(connection.seed, response.system_fingerprint.as_deref())
+        {
+            println!("{}", reproducibility);
+        }
+        println!();
+    }
+}
+
+/// Checks whether a failed request was most likely rejected because of its function definitions.
+/// Only OpenAI-compatible servers are given the benefit of the doubt.
+///
+/// # Arguments
+///
+/// * `connection` - The chat session's connection details.
+/// * `status` - The status code of the failed response.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the request should be retried without function definitions.
+fn rejects_tools(connection: &ChatConnection, status: StatusCode) -> bool {
+    connection.backend.endpoint().provider == Provider::OpenAiCompatible
+        && connection.tools_enabled.get()
+        && matches!(
+            status,
+            StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND | StatusCode::UNPROCESSABLE_ENTITY
+        )
+}
+
+/// Turns off function definitions for the rest of the session and sends the request again.
+///
+/// # Arguments
+///
+/// * `connection` - The chat session's connection details.
+/// * `messages` - Mutable reference to the messages vector.
+///
+/// # Returns
+///
+/// * `Option<Completion>` - The completed response, or `None` if the request failed.
+fn retry_without_tools(connection: &ChatConnection, messages: &mut Vec<Value>) -> Option<Completion> {
+    eprintln!("Note: This server does not support function calling; continuing with plain replies.");
+    connection.tools_enabled.set(false);
+    request_completion(connection, messages)
+}
+
+/// Reads a streamed (server-sent events) reply, printing content tokens as they arrive.
+/// When rendering Markdown, content is printed a line at a time instead, once each line is complete.
+/// Tool-call and function-call fragments are accumulated until the stream ends.
+/// Ctrl-C stops reading and keeps whatever content has already been shown.
+///
+/// # Arguments
+///
+/// * `response` - The successful streaming HTTP response.
+/// * `spinner` - The loading indicator to stop once the first token arrives.
+/// * `interrupt` - Guard that stops reading when Ctrl-C is pressed.
+/// * `render_markdown` - Whether to style the content as Markdown.
+///
+/// # Returns
+///
+/// * `Option<Completion>` - The assembled response, or `None` if nothing usable was received.
+async fn read_streamed_response(
+    response: Response,
+    spinner: LoadingAnimation,
+    interrupt: &InterruptGuard,
+    render_markdown: bool,
+) -> Option<Completion> {
+    let mut spinner = Some(spinner);
+    let mut renderer = render_markdown.then(|| MarkdownRenderer::wrapping(wrap_width(), ASSISTANT_PREFIX.len() + 1));
+    let mut content = String::new();
+    let mut function_call: Option<ToolFunction> = None;
+    let mut tool_calls: Vec<ToolCall> = Vec::new();
+    let mut finish_reason = None;
+    let mut usage = None;
+    let mut system_fingerprint = None;
+    let mut refusal = String::new();
+
+    let result = interrupt.run(read_event_stream(response, |chunk| {
+        // Events that don't look like chat completions are skipped
+        let Ok(chunk) = serde_json::from_value::<ChatChunk>(chunk) else {
+            return true;
+        };
+        if chunk.system_fingerprint.is_some() {
+            system_fingerprint = chunk.system_fingerprint;
+        }
+        if chunk.usage.is_some() {
+            usage = chunk.usage;
+        }
+        let Some(choice) = chunk.choices.into_iter().next() else {
+            return true;
+        };
+        let delta = choice.delta;
+
+        if let Some(token) = delta.content.as_deref() {
+            let token = if content.is_empty() { token.trim_start() } else { token };
+            if !token.is_empty() {
+                if let Some(spinner) = spinner.take() {
+                    stop_loading_indicator(spinner);
+                    print!("\n{} ", theme().assistant_prefix(ASSISTANT_PREFIX));
+                }
+                match renderer.as_mut() {
+                    Some(renderer) => print!("{}", renderer.push(token)),
+                    None => print!("{}", token),
+                }
+                io::stdout().flush().unwrap();
+                content.push_str(token);
+            }
+        }
+
+        if let Some(text) = delta.refusal {
+            refusal.push_str(&text);
+        }
+        if let Some(fragment) = delta.function_call {
+            let call = function_call.get_or_insert_with(ToolFunction::default);
+            call.name.push_str(fragment.name.as_deref().unwrap_or_default());
+            call.arguments.push_str(fragment.arguments.as_deref().unwrap_or_default());
+        }
+        for fragment in delta.tool_calls.into_iter().flatten() {
+            accumulate_tool_call(&mut tool_calls, fragment);
+        }
+        if choice.finish_reason.is_some() {
+            finish_reason = choice.finish_reason;
+        }
+        true
+    }))
+    .await;
+    if let Some(Err(e)) = result {
+        eprintln!("\n{}", theme().error(&format!("Error reading streamed response: {}", e)));
+    }
+
+    match spinner.take() {
+        Some(spinner) => stop_loading_indicator(spinner),
+        None => {
+            if let Some(renderer) = renderer.as_mut() {
+                print!("{}", renderer.finish());
+            }
+            println!("\n");
+        }
+    }
+
+    let mut message = ChatMessage {
+        content: (!content.is_empty()).then(|| content.trim_end().to_string()),
+        refusal: (!refusal.is_empty()).then_some(refusal),
+        ..ChatMessage::default()
+    };
+
+    if interrupt.interrupted() {
+        // A partial function call cannot be run, so only keep the text that was shown.
+        if content.is_empty() {
+            return None;
+        }
+        println!("Response interrupted.");
+    } else if !tool_calls.is_empty() {
+        message.tool_calls = Some(tool_calls);
+    } else {
+        message.function_call = function_call.filter(|call| !call.name.is_empty());
+    }
+
+    Some(Completion {
+        response: ChatResponse {
+            choices: Some(vec![ChatChoice { message, finish_reason }]),
+            usage,
+            system_fingerprint,
+        },
+        streamed: true,
+    })
+}
+
+/// Merges a streamed tool-call fragment into the tool calls assembled so far.
+/// Fragments are matched by their `index`; names and arguments arrive in pieces.
+///
+/// # Arguments
+///
+/// * `tool_calls` - The tool calls assembled so far.
+/// * `fragment` - A `tool_calls` entry from a stream delta.
+fn accumulate_tool_call(tool_calls: &mut Vec<ToolCall>, fragment: ToolCallDelta) {
+    if tool_calls.len() <= fragment.index {
+        tool_calls.resize_with(fragment.index + 1, || ToolCall {
+            kind: "function".to_string(),
+            ..ToolCall::default()
+        });
+    }
+
+    let call = &mut tool_calls[fragment.index];
+    if fragment.id.is_some() {
+        call.id = fragment.id;
+    }
+    if let Some(function) = fragment.function {
+        call.function.name.push_str(function.name.as_deref().unwrap_or_default());
+        call.function.arguments.push_str(function.arguments.as_deref().unwrap_or_default());
+    }
+}
+
+/// Stops the loading indicator.
+///
+/// # Arguments
+///
+/// * `spinner` - The running loading indicator.
+fn stop_loading_indicator(spinner: LoadingAnimation) {
+    spinner.stop();
+    print!("\x1b[2K\r"); // Clear the current line
+    io::stdout().flush().unwrap();
+}
+
+/// Handles the API response. While the assistant calls functions, their results are sent
+/// back and the next response is handled in turn, until it replies with text or has made
+/// `max_function_calls` calls.
+///
+/// # Arguments
+///
+/// * `completion` - The completed API response, if the request succeeded.
+/// * `messages` - Mutable reference to the messages vector.
+/// * `connection` - The chat session's connection details.
+/// * `verbose` - Verbose flag.
+///
+/// # Returns
+///
+/// * `Option<bool>` - Signals whether to exit the chat.
+fn handle_response(
+    mut completion: Option<Completion>,
+    messages: &mut Vec<Value>,
+    connection: &ChatConnection,
+    verbose: bool,
+) -> Option<bool> {
+    let mut calls_made = 0;
+    let mut retried_empty_reply = false;
+    loop {
+        match process_openai_response(completion?, messages, connection) {
+            Reply::Done => return None,
+            Reply::Empty if retried_empty_reply => return None,
+            Reply::Empty => {
+                print!("Send the request again? [{}] ", yes_no_options(connection.confirm_allows_by_default));
+                io::stdout().flush().unwrap();
+                if !read_default_confirmation(connection.confirm_allows_by_default) {
+                    return None;
+                }
+                retried_empty_reply = true;
+            }
+            Reply::FunctionCalls(function_calls) => {
+                if let Some(outcome) = handle_function_calls(function_calls, messages, connection, verbose, &mut calls_made) {
+                    return Some(outcome);
+                }
+            }
+        }
+        // Send a new request so the assistant can respond to the results, or try again
+        if !within_budget(connection) {
+            return None;
+        }
+        completion = request_completion(connection, messages);
+    }
+}
+
+/// What a response from the assistant asks for next.
+enum Reply {
+    /// Nothing: the reply was shown, or the response could not be used.
+    Done,
+    /// These functions are to be called and their results sent back.
+    FunctionCalls(Vec<FunctionCall>),
+    /// The reply was empty, which has been reported; the request may be worth sending again.
+    Empty,
+}
+
+/// Processes the successful OpenAI API response.
+///
+/// # Arguments
+///
+/// * `completion` - The completed API response.
+/// * `messages` - Mutable reference to the messages vector.
+/// * `connection` - The chat session's connection details.
+///
+/// # Returns
+///
+/// * `Reply` - What to do next: call functions, offer to retry an empty reply, or nothing.
+fn process_openai_response(completion: Completion, messages: &mut Vec<Value>, connection: &ChatConnection) -> Reply {
+    let response = completion.response;
+    let Some(choices) = &response.choices else {
+        eprintln!("{}", theme().error("Unexpected response format: 'choices' field is missing."));
+        return Reply::Done;
+    };
+    let Some(choice) = choices.first() else {
+        eprintln!("{}", theme().error("No choices found in the response."));
+        return Reply::Done;
+    };
+    let message = &choice.message;
+
+    // An empty reply is kept out of the history, where it would only confuse later requests
+    let function_calls = parse_function_calls(message);
+    if function_calls.is_empty() && message.content.as_deref().unwrap_or_default().trim().is_empty() {
+        report_empty_reply(choice);
+        report_cost(connection, &response);
+        return Reply::Empty;
+    }
+
+    messages.push(assistant_history_message(message));
+
+    let truncated = is_truncated(choice);
+    if !function_calls.is_empty() && truncated {
+        // The arguments are cut off, so the calls are answered without being run
+        eprintln!("Warning: The reply was cut off at the token limit; its commands will not be run.");
+        for function_call in &function_calls {
+            let result = "Error: the call was cut off at the token limit and was not run.".to_string();
+            push_function_result(messages, function_call, result);
+        }
+        report_cost(connection, &response);
+        Reply::Done
+    } else if !function_calls.is_empty() {
+        report_cost(connection, &response);
+        Reply::FunctionCalls(function_calls)
+    } else {
+        if let Some(content) = &message.content {
+            if !completion.streamed {
+                print_reply(connection, content);
+            }
+        }
+        report_cost(connection, &response);
+        if truncated {
+            continue_truncated_reply(connection, messages);
+        }
+        Reply::Done
+    }
+}
+
+/// Tells the user that the model replied with neither text nor function calls, with why
+/// it stopped and what it said if it refused.
+///
+/// # Arguments
+///
+/// * `choice` - The first choice of the response.
+pub(crate) fn report_empty_reply(choice: &ChatChoice) {
+    let reason = match choice.finish_reason.as_deref() {
+        Some(reason) => format!(" (finish reason: {})", reason),
+        None => String::new(),
+    };
+    println!("\n{}", theme().error(&format!("The model returned an empty reply{}.", reason)));
+    if let Some(refusal) = choice.message.refusal.as_deref().filter(|refusal| !refusal.trim().is_empty()) {
+        println!("It declined to answer: {}", refusal.trim());
+    }
+}
+
+/// Builds the history entry for an assistant message: its text and any function calls.
+///
+/// # Arguments
+///
+/// * `message` - The assistant message of a response.
+///
+/// # Returns
+///
+/// * `Value` - The message to add to the conversation.
+fn assistant_history_message(message: &ChatMessage) -> Value {
+    let mut assistant_message = serde_json::json!({ "role": "assistant" });
+    if let Some(content) = &message.content {
+        assistant_message["content"] = Value::String(content.clone());
+    }
+    if let Some(tool_calls) = &message.tool_calls {
+        assistant_message["tool_calls"] = serde_json::to_value(tool_calls).unwrap_or_default();
+    }
+    if let Some(function_call) = &message.function_call {
+        assistant_message["function_call"] = serde_json::to_value(function_call).unwrap_or_default();
+    }
+    assistant_message
+}
+
+/// Checks whether a reply was cut off because it reached the token limit.
+///
+/// # Arguments
+///
+/// * `choice` - The first choice of a response.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the reply is incomplete.
+fn is_truncated(choice: &ChatChoice) -> bool {
+    choice.finish_reason.as_deref() == Some("length")
+}
+
+/// Handles a text reply that was cut off at the token limit. When `continue_truncated` is set,
+/// the model is asked to continue up to `MAX_CONTINUATIONS` times; each piece is shown as it
+/// arrives and joined into the last assistant message, so the history holds one whole answer.
+/// Otherwise the user is warned that the answer is incomplete.
+///
+/// # Arguments
+///
+/// * `connection` - The chat session's connection details.
+/// * `messages` - Mutable reference to the messages vector, ending with the truncated reply.
+fn continue_truncated_reply(connection: &ChatConnection, messages: &mut Vec<Value>) {
+    if !connection.continue_truncated {
+        eprintln!(
+            "Warning: The reply was cut off at the token limit. \
+             Raise `max_tokens` or set `continue_truncated` in .gptsh_config to get the rest."
+        );
+        return;
+    }
+
+    for _ in 0..MAX_CONTINUATIONS {
+        if !within_budget(connection) {
+            return;
+        }
+        eprintln!("Note: The reply was cut off at the token limit; asking the model to continue.");
+        messages.push(serde_json::json!({ "role": "user", "content": CONTINUE_PROMPT }));
+        let completion = request_completion(connection, messages);
+        messages.pop();
+        let Some(completion) = completion else {
+            return;
+        };
+
+        let choice = completion.response.first_choice();
+        let piece = choice.and_then(|choice| choice.message.content.as_deref()).unwrap_or_default();
+        if !completion.streamed {
+            print_reply(connection, piece);
+        }
+        report_cost(connection, &completion.response);
+
+        if let Some(last) = messages.last_mut() {
+            let mut joined = last["content"].as_str().unwrap_or_default().to_string();
+            joined.push_str(piece);
+            last["content"] = Value::String(joined);
+        }
+        if !choice.is_some_and(is_truncated) {
+            return;
+        }
+    }
+    eprintln!("Warning: The reply is still incomplete after {} continuations.", MAX_CONTINUATIONS);
+}
+
+/// Prints a reply that wasn't streamed, styled as Markdown if enabled.
+///
+/// # Arguments
+///
+/// * `connection` - The chat session's connection details.
+/// * `content` - The reply content.
+fn print_reply(connection: &ChatConnection, content: &str) {
+    let prefix = theme().assistant_prefix(ASSISTANT_PREFIX);
+    if connection.render_markdown {
+        let rendered = render_markdown(content.trim(), wrap_width(), ASSISTANT_PREFIX.len() + 1);
+        println!("\n{} {}\n", prefix, rendered.trim_end());
+    } else {
+        println!("\n{} {}\n", prefix, content.trim());
+    }
+}
+
+/// Extracts the function calls requested in an assistant message, supporting both the
+/// `tool_calls` array and the legacy `function_call` object.
+///
+/// # Arguments
+///
+/// * `message` - The assistant message.
+///
+/// # Returns
+///
+/// * `Vec<FunctionCall>` - The requested calls, in order.
+fn parse_function_calls(message: &ChatMessage) -> Vec<FunctionCall> {
+    if let Some(tool_calls) = &message.tool_calls {
+        return tool_calls
+            .iter()
+            .map(|call| FunctionCall {
+                id: call.id.clone(),
+                name: call.function.name.clone(),
+                arguments: call.function.arguments.clone(),
+            })
+            .collect();
+    }
+
+    match &message.function_call {
+        Some(call) => vec![FunctionCall {
+            id: None,
+            name: call.name.clone(),
+            arguments: call.arguments.clone(),
+        }],
+        None => Vec::new(),
+    }
+}
+
+/// Handles function calls requested by the assistant.
+/// Every call gets a result message, since the API rejects unanswered tool calls.
+/// Calls beyond `max_function_calls` in one turn aren't run; the assistant is told why and
+/// the user gets control back. Calls after `exit_chat` are skipped.
+///
+/// # Arguments
+///
+/// * `function_calls` - The requested function calls.
+/// * `messages` - Mutable reference to the messages vector.
+/// * `connection` - The chat session's connection details.
+/// * `verbose` - Verbose flag.
+/// * `calls_made` - How many functions have been called in this turn so far.
+///
+/// # Returns
+///
+/// * `Option<bool>` - `Some(true)` to exit the chat, `Some(false)` to stop and wait for the
+///   user, or `None` to send the results back to the assistant.
+fn handle_function_calls(
+    function_calls: Vec<FunctionCall>,
+    messages: &mut Vec<Value>,
+    connection: &ChatConnection,
+    verbose: bool,
+    calls_made: &mut usize,
+) -> Option<bool> {
+    let mut limit_reached = false;
+    let mut exiting = false;
+    for function_call in &function_calls {
+        if exiting {
+            push_function_result(messages, function_call, "Error: not run. The chat ended.".to_string());
+            continue;
+        }
+        if *calls_made >= connection.max_function_calls {
+            limit_reached = true;
+            let result = format!(
+                "Error: not run. The limit of {} function calls per user message was reached. Tell the user what you were doing and what is left, and wait for them to reply.",
+                connection.max_function_calls
+            );
+            push_function_result(messages, function_call, result);
+            continue;
+        }
+        *calls_made += 1;
+        if verbose {
+            println!("Function call: {} {}", function_call.name, function_call.arguments);
+        }
+        let result = match function_call.name.as_str() {
+            "execute_command" => {
+                execute_command(
+                    &function_call.arguments,
+                    connection.max_command_output_bytes,
+                    connection.confirm_allows_by_default,
+                )
+            }
+            "list_directory" => list_directory_for_assistant(&function_call.arguments, connection.list_directory_max_entries),
+            "read_file" => read_file(&function_call.arguments),
+            "fetch_url" if connection.allow_network_tools => fetch_url(&function_call.arguments),
+            "get_system_info" if !connection.system_info_fields.is_empty() => {
+                println!("The assistant looked up {}.", connection.system_info_fields.join(", "));
+                collect_system_info(&connection.system_info_fields).to_string()
+            }
+            "write_file" => write_file(&function_call.arguments),
+            "exit_chat" => {
+                exiting = true;
+                "The chat ended.".to_string()
+            }
+            "" => {
+                eprintln!("Function call missing 'name' field.");
+                "Error: the function call was missing its name.".to_string()
+            }
+            name => {
+                eprintln!("Error: Assistant requested an unknown function '{}'.", name);
+                format!("Error: unknown function '{}'.", name)
+            }
+        };
+        push_function_result(messages, function_call, result);
+    }
+
+    if exiting {
+        return Some(true);
+    }
+    if limit_reached {
+        println!(
+            "Note: The assistant reached the limit of {} function calls for one message. Reply to let it continue (`max_function_calls` in .gptsh_config).",
+            connection.max_function_calls
+        );
+        return Some(false);
+    }
+    None
+}
+
+/// Adds the result of a function call to the conversation, as a `tool` message for tool
+/// calls or a legacy `function` message otherwise.
+///
+/// # Arguments
+///
+/// * `messages` - Mutable reference to the messages vector.
+/// * `function_call` - The call the result belongs to.
+/// * `content` - The result content.
+fn push_function_result(messages: &mut Vec<Value>, function_call: &FunctionCall, content: String) {
+    let mut message = match &function_call.id {
+        Some(id) => serde_json::json!({
+            "role": "tool",
+            "tool_call_id": id,
+            "content": content
+        }),
+        None => serde_json::json!({
+            "role": "function",
+            "name": function_call.name,
+            "content": content
+        }),
+    };
+    message[TIMESTAMP_FIELD] = current_timestamp();
+    messages.push(message);
+}
+
+/// Parses the JSON-encoded arguments of a function call.
+///
+/// # Arguments
+///
+/// * `arguments` - The JSON-encoded function arguments.
+///
+/// # Returns
+///
+/// * `Result<Value, String>` - The arguments, or the error to report back to the assistant.
+fn parse_function_arguments(arguments: &str) -> Result<Value, String> {
+    serde_json::from_str(arguments).map_err(|e| {
+        eprintln!("Failed to parse function arguments: {}", e);
+        format!("Error: could not parse the function arguments: {}", e)
+    })
+}
+
+/// Returns the current directory, resolved, which the file tools keep the assistant within.
+///
+/// # Returns
+///
+/// * `Result<PathBuf, String>` - The directory, or the error to report back to the assistant.
+fn project_base() -> Result<PathBuf, String> {
+    env::current_dir().and_then(|dir| dir.canonicalize()).map_err(|e| {
+        eprintln!("Error: Could not determine the current directory: {}", e);
+        format!("Error: could not determine the current directory: {}", e)
+    })
+}
+
+/// Executes a shell command as per the function call. The command's output is shown as
+/// it arrives, and Ctrl-C stops the command without leaving the chat.
+///
+/// # Arguments
+///
+/// * `arguments` - The JSON-encoded function arguments.
+/// * `output_limit` - The most bytes of stdout and of stderr reported; `0` is unlimited.
+/// * `allow_by_default` - Whether pressing Enter at the confirmation runs the command.
+///
+/// # Returns
+///
+/// * `String` - The function result to report back to the assistant: a JSON object with
+///   the command's `stdout`, `stderr`, and `exit_code` (only the exit code for a command
+///   run interactively), or a message if it didn't run.
+fn execute_command(arguments: &str, output_limit: usize, allow_by_default: bool) -> String {
+    let arguments = match parse_function_arguments(arguments) {
+        Ok(arguments) => arguments,
+        Err(e) => return e,
+    };
+
+    let proposed = arguments["command"].as_str().unwrap_or_default();
+
+    if proposed.is_empty() {
+        eprintln!("No command provided to execute.");
+        return "Error: no command was provided.".to_string();
+    }
+
+    let banned_commands = load_banned_commands().unwrap_or_else(|e| {
+        eprintln!("Error loading banned commands: {}", e);
+        Vec::new()
+    });
+    let allowed_commands = load_allowed_commands().unwrap_or_else(|e| {
+        eprintln!("Error loading allowed commands: {}", e);
+        Vec::new()
+    });
+    let mut command = proposed.to_string();
+    let mut interactive = false;
+    loop {
+        if banned_commands.iter().any(|banned| banned == command.trim()) {
+            println!("Warning: The command \"{}\" is banned and will not be executed.", command);
+            return "The user has banned this command, so it was not run. Do not suggest it again.".to_string();
+        }
+        // An edited command is always confirmed again, even if it is on the allowed list
+        if command == proposed && allowed_commands.iter().any(|allowed| allowed == command.trim()) {
+            println!("Executing allowed command: '{}'", command);
+            break;
+        }
+
+        println!("About to execute command: '{}'", command);
+        print!("{}", command_prompt(allow_by_default, true, false));
+        io::stdout().flush().unwrap();
+
+        match parse_command_answer(&read_command_confirmation(), allow_by_default) {
+            CommandAnswer::Run => break,
+            CommandAnswer::Interactive => {
+                interactive = true;
+                break;
+            }
+            CommandAnswer::Edit => match edit_command(&command) {
+                Some(edited) => command = edited,
+                None => {
+                    println!("Edit cancelled. Command execution cancelled.");
+                    return "The user declined to run this command.".to_string();
+                }
+            },
+            CommandAnswer::Ban => {
+                return match add_banned_command(&command) {
+                    Ok(()) => {
+                        println!("Command \"{}\" has been banned.", command.trim());
+                        "The user banned this command, so it was not run. Do not suggest it again.".to_string()
+                    }
+                    Err(e) => {
+                        eprintln!("Error banning the command: {}", e);
+                        "The user declined to run this command.".to_string()
+                    }
+                };
+            }
+            CommandAnswer::Cancel => {
+                println!("Command execution cancelled.");
+                return "The user declined to run this command.".to_string();
+            }
+            CommandAnswer::Background | CommandAnswer::Invalid => {
+                println!("Invalid input. Command execution cancelled.");
+                return "The user declined to run this command.".to_string();
+            }
+        }
+    }
+
+    if !interactive && is_interactive_command(&command) {
+        println!("Note: This looks like an interactive program, so it gets the terminal; only its exit status is sent to the assistant.");
+        interactive = true;
+    }
+    let result = if interactive {
+        run_interactive_command(&command).map(|exit_code| {
+            serde_json::json!({
+                "exit_code": exit_code,
+                "interactive": true,
+                "note": "The command ran with the user's terminal attached, so its output was not captured.",
+            })
+        })
+    } else {
+        execute_shell_command(adjust_command(&command), output_limit)
+            .map(|output| describe_command_output(output, output_limit))
+    };
+    let result = match result {
+        Ok(mut result) => {
+            // Tell the assistant what actually ran, so it doesn't reason about its own version
+            if command != proposed {
+                result["command_edited_by_user"] = Value::String(command.clone());
+            }
+            result.to_string()
+        }
+        Err(e) => {
+            eprintln!("Failed to execute command: {}", e);
+            format!("Error: failed to execute the command: {}", e)
+        }
+    };
+
+    // Ensure all output is written to the terminal
+    io::stdout().flush().expect("Failed to flush stdout");
+    result
+}
+
+/// Describes a finished command for the assistant. It sees stderr and the exit code too,
+/// so it can tell when a command failed.
+///
+/// # Arguments
+///
+/// * `output` - What the command printed and how it ended.
+/// * `output_limit` - The most bytes of stdout and of stderr reported; `0` is unlimited.
+///
+/// # Returns
+///
+/// * `Value` - A JSON object with the command's `stdout`, `stderr`, and `exit_code`.
+fn describe_command_output(output: CommandOutput, output_limit: usize) -> Value {
+    let mut result = serde_json::json!({
+        "stdout": cap_command_output(output.stdout, output_limit),
+        "stderr": cap_command_output(output.stderr, output_limit),
+        "exit_code": output.exit_code,
+    });
+    if output.interrupted {
+        println!("Command interrupted.");
+        result["interrupted_by_user"] = Value::Bool(true);
+    }
+    result
+}
+
+/// Lists a directory for the assistant as per the function call. Listing changes nothing,
+/// so it isn't confirmed, but only directories inside the current directory tree are listed.
+///
+/// # Arguments
+///
+/// * `arguments` - The JSON-encoded function arguments.
+/// * `max_entries` - The most entries to return.
+///
+/// # Returns
+///
+/// * `String` - The listing as JSON, or a message explaining why there is none.
+fn list_directory_for_assistant(arguments: &str, max_entries: usize) -> String {
+    let arguments = match parse_function_arguments(arguments) {
+        Ok(arguments) => arguments,
+        Err(e) => return e,
+    };
+
+    let path = arguments["path"].as_str().filter(|path| !path.is_empty()).unwrap_or(".");
+    let pattern = match arguments["glob"].as_str().filter(|glob| !glob.is_empty()).map(glob_to_regex) {
+        Some(Ok(pattern)) => Some(pattern),
+        Some(Err(e)) => return e,
+        None => None,
+    };
+    let include_hidden = arguments["include_hidden"].as_bool().unwrap_or(false);
+
+    let base = match project_base() {
+        Ok(base) => base,
+        Err(e) => return e,
+    };
+    let dir = match resolve_path(path, &base) {
+        Ok(dir) if is_within(&dir, &base) => dir,
+        Ok(dir) => {
+            return format!(
+                "Error: '{}' is outside the current directory. Ask the user, or use execute_command so they can approve it.",
+                dir.display()
+            )
+        }
+        Err(e) => return format!("Error: could not open '{}': {}", path, e),
+    };
+
+    println!("The assistant listed '{}'.", dir.display());
+    match list_directory(&dir, pattern.as_ref(), include_hidden, max_entries) {
+        Ok(listing) => listing.to_string(),
+        Err(e) => e,
+    }
+}
+
+/// Shortens a command's output before it is sent back to the assistant, keeping the start
+/// and the end (where errors and summaries usually are) around a marker saying how much
+/// was left out, so one `cat` of a big log doesn't fill the context window.
+///
+/// # Arguments
+///
+/// * `captured` - The command's output, as collected while it ran.
+/// * `limit` - The most bytes to keep; `0` keeps everything.
+///
+/// # Returns
+///
+/// * `String` - The output, shortened if it was over the limit.
+pub(crate) fn cap_command_output(captured: CapturedOutput, limit: usize) -> String {
+    let (output, dropped) = captured.into_text();
+    if limit == 0 || output.len() <= limit {
+        return output;
+    }
+
+    let (head, tail, omitted) = split_head_tail(&output, limit);
+    let total = output.len() + dropped;
+    let omitted = omitted + dropped;
+    println!(
+        "Note: The command printed {} bytes; only the first and last parts were sent to the assistant.",
+        format_count(total)
+    );
+    format!(
+        "{}{}... {} bytes truncated ...\n{}\n[The output was {} bytes and has been truncated. To see the rest, run a command that prints only the part you need, e.g. with grep, head, tail, or sed -n.]",
+        head,
+        if head.ends_with('\n') { "" } else { "\n" },
+        format_count(omitted),
+        tail,
+        format_count(total)
+    )
+}
+
+/// Splits text that is too long into its start and its end, each about half the limit,
+/// cutting at line breaks when there is one nearby, so no line is kept half.
+///
+/// # Arguments
+///
+/// * `text` - The text to split.
+/// * `limit` - The most bytes to keep in all.
+///
+/// # Returns
+///
+/// * `(&str, &str, usize)` - The start, the end, and how many bytes between them were
+///   left out.
+pub(crate) fn split_head_tail(text: &str, limit: usize) -> (&str, &str, usize) {
+    let mut head_end = (limit / 2).min(text.len());
+    while !text.is_char_boundary(head_end) {
+        head_end -= 1;
+    }
+    let mut tail_start = text.len().saturating_sub(limit / 2).max(head_end);
+    while !text.is_char_boundary(tail_start) {
+        tail_start += 1;
+    }
+    if let Some(end) = text[..head_end].rfind('\n').filter(|&end| end >= head_end / 2) {
+        head_end = end + 1;
+    }
+    if let Some(start) = text[tail_start..].find('\n').filter(|&start| start < limit / 4) {
+        tail_start += start + 1;
+    }
+    (&text[..head_end], &text[tail_start..], tail_start - head_end)
+}
+
+/// Reads a file for the assistant as per the function call, after asking the user.
+/// Files outside the current directory tree are only read if the user explicitly agrees.
+///
+/// # Arguments
+///
+/// * `arguments` - The JSON-encoded function arguments.
+///
+/// # Returns
+///
+/// * `String` - The file's contents, or a message explaining why it wasn't read.
+fn read_file(arguments: &str) -> String {
+    let arguments = match parse_function_arguments(arguments) {
+        Ok(arguments) => arguments,
+        Err(e) => return e,
+    };
+
+    let path = arguments["path"].as_str().unwrap_or_default();
+    if path.is_empty() {
+        eprintln!("No path provided to read.");
+        return "Error: no path was provided.".to_string();
+    }
+
+    let base = match project_base() {
+        Ok(base) => base,
+        Err(e) => return e,
+    };
+    let resolved = match resolve_path(path, &base) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            eprintln!("Error: The assistant asked to read '{}', which could not be opened: {}", path, e);
+            return format!("Error: could not open '{}': {}", path, e);
+        }
+    };
+
+    if is_within(&resolved, &base) {
+        println!("The assistant wants to read '{}'.", resolved.display());
+    } else {
+        println!(
+            "Warning: The assistant wants to read '{}', which is outside the current directory.",
+            resolved.display()
+        );
+    }
+    println!("Do you want to allow this? [y/N]");
+    if !read_explicit_confirmation() {
+        println!("File read cancelled.");
+        return "The user declined to share this file.".to_string();
+    }
+
+    match read_text_file(&resolved) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("{}", e);
+            e
+        }
+    }
+}
+
+/// Fetches a web page for the assistant as per the function call, after asking the user.
+///
+/// # Arguments
+///
+/// * `arguments` - The JSON-encoded function arguments.
+///
+/// # Returns
+///
+/// * `String` - The page's text, or a message explaining why it wasn't fetched.
+fn fetch_url(arguments: &str) -> String {
+    let arguments = match parse_function_arguments(arguments) {
+        Ok(arguments) => arguments,
+        Err(e) => return e,
+    };
+
+    let url = match parse_fetch_url(arguments["url"].as_str().unwrap_or_default()) {
+        Ok(url) => url,
+        Err(e) => {
+            eprintln!("{}", e);
+            return e;
+        }
+    };
+
+    println!("The assistant wants to fetch {}", url);
+    println!("Do you want to allow this? [y/N]");
+    if !read_explicit_confirmation() {
+        println!("Fetch cancelled.");
+        return "The user declined to fetch this URL.".to_string();
+    }
+
+    let result = {
+        let interrupt = InterruptGuard::new();
+        block_on(async {
+            let spinner = start_loading_animation();
+            let result = interrupt.run(fetch_text(url)).await;
+            stop_loading_indicator(spinner);
+            result
+        })
+    };
+
+    match result {
+        Some(Ok(text)) => text,
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            e
+        }
+        None => {
+            println!("Fetch cancelled.");
+            "The user cancelled the fetch.".to_string()
+        }
+    }
+}
+
+/// Writes a file for the assistant as per the function call. The change is shown as a
+/// unified diff (or the full content for a new file) and written only if the user answers
+/// `y`; replacing a large file or writing outside the current directory tree also needs
+/// a typed `yes`.
+///
+/// # Arguments
+///
+/// * `arguments` - The JSON-encoded function arguments.
+///
+/// # Returns
+///
+/// * `String` - What happened, to report back to the assistant.
+fn write_file(arguments: &str) -> String {
+    let arguments = match parse_function_arguments(arguments) {
+        Ok(arguments) => arguments,
+        Err(e) => return e,
+    };
+
+    let path = arguments["path"].as_str().unwrap_or_default();
+    let Some(content) = arguments["content"].as_str().filter(|_| !path.is_empty()) else {
+        eprintln!("No path or content provided to write.");
+        return "Error: both a path and the content to write are required.".to_string();
+    };
+
+    let base = match project_base() {
+        Ok(base) => base,
+        Err(e) => return e,
+    };
+    let target = match resolve_target_path(path, &base) {
+        Ok(target) => target,
+        Err(e) => {
+            eprintln!("Error: The assistant asked to write '{}', which is not a usable path: {}", path, e);
+            return format!("Error: could not write '{}': {}", path, e);
+        }
+    };
+    if target.is_dir() {
+        eprintln!("Error: The assistant asked to write '{}', which is a directory.", target.display());
+        return format!("Error: '{}' is a directory.", target.display());
+    }
+
+    let existing = match fs::read(&target) {
+        Ok(bytes) => Some(bytes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            eprintln!("Error: Could not read '{}': {}", target.display(), e);
+            return format!("Error: could not read the existing file '{}': {}", target.display(), e);
+        }
+    };
+
+    match &existing {
+        Some(bytes) if bytes.as_slice() == content.as_bytes() => {
+            println!("Note: '{}' already has the content the assistant proposed.", target.display());
+            return format!("'{}' already has this content; nothing was written.", target.display());
+        }
+        Some(bytes) => {
+            println!("The assistant wants to change '{}':", target.display());
+            match decode_text(bytes.clone(), false) {
+                Some(old) => print_diff(&old, content, &target.display().to_string()),
+                None => {
+                    println!("Warning: The existing file is binary and would be replaced by:");
+                    print_diff("", content, &target.display().to_string());
+                }
+            }
+        }
+        None => {
+            println!("The assistant wants to create '{}' (new file):", target.display());
+            print_diff("", content, &target.display().to_string());
+        }
+    }
+
+    let parent = target.parent().filter(|dir| !dir.exists());
+    if let Some(dir) = parent {
+        println!("Note: The directory '{}' will be created.", dir.display());
+    }
+
+    println!("Do you want to write this file? [y/N]");
+    if !read_explicit_confirmation() {
+        println!("File write cancelled.");
+        return "The user declined to write this file.".to_string();
+    }
+
+    let outside = !is_within(&target, &base);
+    let large = existing.as_ref().is_some_and(|bytes| bytes.len() > LARGE_OVERWRITE_BYTES);
+    if outside || large {
+        let reason = if outside {
+            "is outside the current directory"
+        } else {
+            "is a large file that will be replaced"
+        };
+        println!("Warning: '{}' {}. Type 'yes' to write it anyway:", target.display(), reason);
+        if !read_typed_yes() {
+            println!("File write cancelled.");
+            return "The user declined to write this file.".to_string();
+        }
+    }
+
+    let result = parent
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&target, content));
+    match result {
+        Ok(()) => {
+            let verb = if existing.is_some() { "Updated" } else { "Created" };
+            println!("{} '{}'.", verb, target.display());
+            format!("{} '{}' ({} bytes).", verb, target.display(), content.len())
+        }
+        Err(e) => {
+            eprintln!("Error: Could not write '{}': {}", target.display(), e);
+            format!("Error: could not write '{}': {}", target.display(), e)
+        }
+    }
+}
+
+/// Prints a unified diff between two versions of a file, with added lines in green and
+/// removed lines in red. A new file is shown as a diff against empty content.
+///
+/// # Arguments
+///
+/// * `old` - The current content.
+/// * `new` - The proposed content.
+/// * `name` - The file name shown in the diff header.
+fn print_diff(old: &str, new: &str, name: &str) {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let from = if old.is_empty() { "/dev/null" } else { name };
+    let diff = difflib::unified_diff(&old_lines, &new_lines, from, name, "", "", DIFF_CONTEXT_LINES);
+    for line in diff.iter().take(MAX_DIFF_PREVIEW_LINES) {
+        let line = line.trim_end_matches(['\n', '\t']);
+        if line.starts_with("+++") || line.starts_with("---") {
+            println!("{}", line.bold());
+        } else if line.starts_with('+') {
+            println!("{}", line.green());
+        } else if line.starts_with('-') {
+            println!("{}", line.red());
+        } else if line.starts_with("@@") {
+            println!("{}", line.cyan());
+        } else {
+            println!("{}", line);
+        }
+    }
+    if diff.len() > MAX_DIFF_PREVIEW_LINES {
+        println!("... {} more lines of the diff not shown", diff.len() - MAX_DIFF_PREVIEW_LINES);
+    } else if !new.is_empty() && !new.ends_with('\n') {
+        println!("\\ No newline at end of file");
+    }
+}
+
+/// Reads a yes/no answer where pressing Enter follows `confirm_default`.
+///
+/// # Arguments
+///
+/// * `allow_by_default` - Whether an empty answer means yes.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the user confirmed, else `false`; always `false` at the end of input.
+fn read_default_confirmation(allow_by_default: bool) -> bool {
+    let mut input = String::new();
+    match io::stdin().read_line(&mut input) {
+        Ok(0) => false,
+        Ok(_) => is_confirmed(&input, allow_by_default),
+        Err(_) => {
+            eprintln!("Failed to read input.");
+            false
+        }
+    }
+}
+
+/// Interprets an answer to a yes/no question.
+///
+/// # Arguments
+///
+/// * `answer` - The line the user typed.
+/// * `allow_by_default` - Whether an empty answer means yes.
+///
+/// # Returns
+///
+/// * `bool` - `true` for yes, `false` for anything else.
+fn is_confirmed(answer: &str, allow_by_default: bool) -> bool {
+    match answer.trim().to_ascii_lowercase().as_str() {
+        "y" | "yes" => true,
+        "" => allow_by_default,
+        _ => false,
+    }
+}
+
+/// Reads the answer to a command confirmation, which may also ban the command.
+///
+/// # Returns
+///
+/// * `String` - The answer as typed; `n` if it couldn't be read.
+fn read_command_confirmation() -> String {
+    let mut input = String::new();
+    match io::stdin().read_line(&mut input) {
+        // End of input must never count as approval
+        Ok(0) => "n".to_string(),
+        Ok(_) => input,
+        Err(_) => {
+            eprintln!("Failed to read input.");
+            "n".to_string()
+        }
+    }
+}
+
+/// Lets the user edit a command before it runs. In a terminal the command is put on an
+/// editable line; otherwise the next line of input replaces it.
+///
+/// # Arguments
+///
+/// * `command` - The command to edit.
+///
+/// # Returns
+///
+/// * `Option<String>` - The edited command, or `None` if the edit was cancelled with
+///   Ctrl-C or Ctrl-D, or left empty.
+fn edit_command(command: &str) -> Option<String> {
+    let edited = if io::stdin().is_terminal() {
+        let mut editor = DefaultEditor::new().ok()?;
+        editor.readline_with_initial("Edit command: ", (command, "")).ok()?
+    } else {
+        print!("Edit command: ");
+        io::stdout().flush().unwrap();
+        let mut input = String::new();
+        match io::stdin().read_line(&mut input) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => input,
+        }
+    };
+    let edited = edited.trim();
+    (!edited.is_empty()).then(|| edited.to_string())
+}
+
+/// Reads a confirmation that must be typed out in full as `yes`.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the user typed `yes`, else `false`.
+fn read_typed_yes() -> bool {
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input).is_err() {
+        eprintln!("Failed to read input.");
+        return false;
+    }
+
+    input.trim().eq_ignore_ascii_case("yes")
+}
+
+/// Reads a confirmation that must be given explicitly; an empty answer means no.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the user answered yes, else `false`.
+fn read_explicit_confirmation() -> bool {
+    read_default_confirmation(false)
+}
+
+/// Adjusts specific commands for compatibility or desired behavior.
+///
+/// # Arguments
+///
+/// * `command` - The original command.
+///
+/// # Returns
+///
+/// * `&str` - The adjusted command.
+fn adjust_command(command: &str) -> &str {
     if command.trim() == "ls" {
         "ls -C"
     } else {
@@ -495,15 +3251,793 @@ fn adjust_command(command: &str) -> &str {
     }
 }
 
-/// Executes a shell command.
+/// Programs that take over the terminal whatever their arguments: editors, pagers, and
+/// full-screen tools.
+const INTERACTIVE_PROGRAMS: &[&str] = &[
+    "vi", "vim", "nvim", "view", "nano", "pico", "micro", "emacs", "less", "more", "most", "man", "top", "htop",
+    "btop", "atop", "iotop", "nload", "watch", "tmux", "screen", "visudo", "vipw", "vigr", "sudoedit", "mc",
+    "ranger", "nnn", "ncdu", "tig", "fzf", "nmtui", "alsamixer", "passwd",
+];
+
+/// Programs that start an interactive session when they're given no arguments, such as
+/// shells and language prompts.
+const INTERACTIVE_WITHOUT_ARGUMENTS: &[&str] = &[
+    "sh", "bash", "zsh", "fish", "python", "python3", "node", "irb", "ghci", "mysql", "psql", "sqlite3", "redis-cli",
+];
+
+/// The `ssh` options that take a value, so it isn't mistaken for the host or the command.
+const SSH_OPTIONS_WITH_VALUES: &str = "BbcDEeFIiJLlmOoPpQRSWw";
+
+/// Guesses whether a command runs a program that needs the terminal, such as an editor,
+/// a pager, or `ssh` without a remote command. Each part of a pipeline or command list is
+/// checked, after `sudo`, `env`, and variable assignments in front of the program.
+///
+/// # Arguments
+///
+/// * `command` - The command line.
+///
+/// # Returns
+///
+/// * `bool` - `true` if the command looks interactive.
+pub(crate) fn is_interactive_command(command: &str) -> bool {
+    command.split(['|', ';', '&', '\n']).any(|part| {
+        let mut words = part.split_whitespace().skip_while(|word| {
+            matches!(*word, "sudo" | "env" | "exec" | "time" | "nohup") || (word.contains('=') && !word.starts_with('-'))
+        });
+        let Some(program) = words.next() else { return false };
+        let program = program.rsplit('/').next().unwrap_or(program);
+        let arguments: Vec<&str> = words.collect();
+        match program {
+            "ssh" => !ssh_has_remote_command(&arguments),
+            "crontab" => arguments.contains(&"-e"),
+            "git" => match arguments.first() {
+                Some(&"commit") => !arguments.iter().any(|argument| {
+                    argument.starts_with("-m") || argument.starts_with("--message") || matches!(*argument, "-F" | "--file" | "--no-edit")
+                }),
+                Some(&"rebase") => arguments.iter().any(|argument| matches!(*argument, "-i" | "--interactive")),
+                Some(&"add") => arguments.iter().any(|argument| matches!(*argument, "-p" | "--patch" | "-i" | "--interactive")),
+                _ => false,
+            },
+            _ if INTERACTIVE_PROGRAMS.contains(&program) => true,
+            _ => arguments.is_empty() && INTERACTIVE_WITHOUT_ARGUMENTS.contains(&program),
+        }
+    })
+}
+
+/// Checks whether `ssh` arguments name a command to run after the host.
+fn ssh_has_remote_command(arguments: &[&str]) -> bool {
+    let mut positional = 0;
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        match argument.strip_prefix('-') {
+            // An option such as `-p 2222`, whose value is the next word
+            Some(option) if option.len() == 1 && SSH_OPTIONS_WITH_VALUES.contains(option) => {
+                arguments.next();
+            }
+            Some(_) => {}
+            None => positional += 1,
+        }
+    }
+    positional > 1
+}
+
+/// Runs a command with the terminal attached, as `/shell` does, so programs that need a
+/// TTY work. Ctrl-C goes to the program and doesn't leave the chat.
+///
+/// # Arguments
+///
+/// * `command` - The command to execute.
+///
+/// # Returns
+///
+/// * `io::Result<Option<i32>>` - The exit code, `None` if the program was ended by a
+///   signal, or an error if it couldn't be started.
+fn run_interactive_command(command: &str) -> io::Result<Option<i32>> {
+    let _foreground = ForegroundProgram::new();
+    let status = shell_command(command).status()?;
+    if !status.success() {
+        match status.code() {
+            Some(code) => eprintln!("Command exited with status {}.", code),
+            None => eprintln!("Command was ended by a signal."),
+        }
+    }
+    Ok(status.code())
+}
+
+/// What a command run for the assistant printed, and how it ended.
+struct CommandOutput {
+    stdout: CapturedOutput,
+    stderr: CapturedOutput,
+    /// The exit code, or `None` if the command was ended by a signal.
+    exit_code: Option<i32>,
+    /// Whether the user stopped the command with Ctrl-C.
+    interrupted: bool,
+}
+
+/// Executes a shell command, showing its output as it arrives. Each line is printed behind
+/// a gutter (red for stderr) so it stands apart from the assistant's replies. Ctrl-C kills
+/// the command and returns what it printed so far.
 ///
 /// # Arguments
 ///
 /// * `command` - The command to execute.
+/// * `output_limit` - The output limit the result will be capped to; `0` is unlimited.
+///
+/// # Returns
+///
+/// * `io::Result<CommandOutput>` - The command's output or an error.
+fn execute_shell_command(command: &str, output_limit: usize) -> io::Result<CommandOutput> {
+    let mut child = shell_command(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Keeping the limit at each end is enough for cap_command_output to cut from
+    let keep = if output_limit == 0 { usize::MAX } else { output_limit };
+    let stdout = Arc::new(Mutex::new(CapturedOutput::new(keep)));
+    let stderr = Arc::new(Mutex::new(CapturedOutput::new(keep)));
+    let display = Arc::new(Mutex::new(OutputDisplay::new()));
+    let readers = [
+        stream_output(child.stdout.take(), false, Arc::clone(&stdout), Arc::clone(&display)),
+        stream_output(child.stderr.take(), true, Arc::clone(&stderr), Arc::clone(&display)),
+    ];
+
+    let interrupt = InterruptGuard::new();
+    let started = Instant::now();
+    let show_ticker = io::stdout().is_terminal();
+    let mut status = None;
+    let mut exited_at: Option<Instant> = None;
+    let mut killed_at: Option<Instant> = None;
+    loop {
+        if show_ticker {
+            lock_display(&display).tick(started);
+        }
+        if status.is_none() {
+            status = child.try_wait()?;
+            exited_at = status.map(|_| Instant::now());
+        }
+        if status.is_some() && readers.iter().all(|reader| reader.is_finished()) {
+            break;
+        }
+        // A process the command left running, e.g. `server &`, keeps the output open
+        if exited_at.is_some_and(|exited_at| exited_at.elapsed() > DETACH_OUTPUT_AFTER) {
+            lock_display(&display).detached = true;
+            break;
+        }
+        if interrupt.interrupted() {
+            match killed_at {
+                None => {
+                    let _ = child.kill();
+                    killed_at = Some(Instant::now());
+                }
+                // Something the command started in the background may keep its output
+                // open; stop waiting for it
+                Some(killed_at) if killed_at.elapsed() > DETACH_OUTPUT_AFTER => {
+                    lock_display(&display).detached = true;
+                    status = status.or_else(|| child.wait().ok());
+                    break;
+                }
+                Some(_) => {}
+            }
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    lock_display(&display).clear_ticker();
+
+    let take = |captured: &Mutex<CapturedOutput>| {
+        let mut captured = captured.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        mem::replace(&mut *captured, CapturedOutput::new(keep))
+    };
+    Ok(CommandOutput {
+        stdout: take(&stdout),
+        stderr: take(&stderr),
+        exit_code: status.and_then(|status| status.code()),
+        interrupted: interrupt.interrupted(),
+    })
+}
+
+/// Prints a child's output stream line by line on a separate thread while collecting it.
+///
+/// # Arguments
+///
+/// * `stream` - The child's stdout or stderr.
+/// * `is_stderr` - Whether the stream is stderr, which is printed to stderr in red.
+/// * `captured` - Where the output is collected.
+/// * `display` - The terminal state shared with the elapsed-time ticker.
 ///
 /// # Returns
 ///
-/// * `Result<std::process::Output, std::io::Error>` - The command's output or an error.
-fn execute_shell_command(command: &str) -> Result<std::process::Output, std::io::Error> {
-    Command::new("sh").arg("-c").arg(command).output()
+/// * `thread::JoinHandle<()>` - The thread, which ends when the stream does.
+fn stream_output<R: Read + Send + 'static>(
+    stream: Option<R>,
+    is_stderr: bool,
+    captured: Arc<Mutex<CapturedOutput>>,
+    display: Arc<Mutex<OutputDisplay>>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let Some(stream) = stream else {
+            return;
+        };
+        let mut reader = BufReader::new(stream);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            // A very long line is shown in pieces rather than held back until it ends
+            match reader.by_ref().take(MAX_STREAMED_LINE_BYTES as u64).read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            // Holding the display while printing keeps the ticker from being drawn mid-line
+            let mut display = lock_display(&display);
+            if display.detached {
+                break;
+            }
+            display.clear_ticker();
+            display.last_output = Instant::now();
+            if let Ok(mut captured) = captured.lock() {
+                captured.push(&line);
+            }
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches(['\n', '\r']);
+            if is_stderr {
+                eprintln!("{} {}", theme().error("│"), text);
+            } else {
+                println!("{} {}", "│".dimmed(), text);
+            }
+        }
+    })
+}
+
+/// What the terminal shows while a command runs: its output, and an elapsed-time ticker
+/// while it is silent, so a slow command doesn't look hung.
+struct OutputDisplay {
+    /// The elapsed seconds the ticker shows, if it is on screen.
+    ticker: Option<u64>,
+    /// When the command last printed anything.
+    last_output: Instant,
+    /// Set once gptsh has stopped waiting for the output; nothing more is printed.
+    detached: bool,
+}
+
+impl OutputDisplay {
+    fn new() -> Self {
+        Self {
+            ticker: None,
+            last_output: Instant::now(),
+            detached: false,
+        }
+    }
+
+    /// Shows or updates the ticker if the command has been silent for a while.
+    fn tick(&mut self, started: Instant) {
+        let elapsed = started.elapsed().as_secs();
+        if self.detached || self.last_output.elapsed() < TICKER_AFTER_SILENCE || self.ticker == Some(elapsed) {
+            return;
+        }
+        print!("\r\x1b[2K{}", format!("Running… {}s (Ctrl-C to stop)", elapsed).dimmed());
+        io::stdout().flush().unwrap();
+        self.ticker = Some(elapsed);
+    }
+
+    /// Removes the ticker from the screen, if it is shown.
+    fn clear_ticker(&mut self) {
+        if self.ticker.take().is_some() {
+            print!("\r\x1b[2K");
+            io::stdout().flush().unwrap();
+        }
+    }
+}
+
+/// Locks the display shared by a command's output readers, even if a reader panicked.
+fn lock_display(display: &Mutex<OutputDisplay>) -> std::sync::MutexGuard<'_, OutputDisplay> {
+    display.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// A command's output as collected while it runs. Only the start and the end are kept,
+/// so a command that prints without end can't use up memory; the middle would be cut
+/// from the assistant's copy anyway.
+#[derive(Clone)]
+pub(crate) struct CapturedOutput {
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    /// How many bytes are kept at each end.
+    keep: usize,
+    /// How many bytes were printed in all.
+    total: usize,
+}
+
+impl CapturedOutput {
+    pub(crate) fn new(keep: usize) -> Self {
+        Self {
+            head: Vec::new(),
+            tail: VecDeque::new(),
+            keep,
+            total: 0,
+        }
+    }
+
+    /// Adds output as it is read.
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        self.total += bytes.len();
+        let room = self.keep.saturating_sub(self.head.len()).min(bytes.len());
+        self.head.extend_from_slice(&bytes[..room]);
+        self.tail.extend(&bytes[room..]);
+        if self.tail.len() > self.keep {
+            self.tail.drain(..self.tail.len() - self.keep);
+        }
+    }
+
+    /// Returns the collected output as text, along with how many bytes from the middle
+    /// were not kept.
+    pub(crate) fn into_text(self) -> (String, usize) {
+        let dropped = self.total - self.head.len() - self.tail.len();
+        let mut head = self.head;
+        if dropped == 0 {
+            // Nothing is missing, so decode it whole; a character may span the two parts
+            head.extend(self.tail);
+            return (String::from_utf8_lossy(&head).into_owned(), 0);
+        }
+        let mut text = String::from_utf8_lossy(&head).into_owned();
+        text.push_str(&String::from_utf8_lossy(&Vec::from(self.tail)));
+        (text, dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn message(role: &str, content: &str) -> Value {
+        json!({"role": role, "content": content})
+    }
+
+    /// A turn in which the assistant calls a function before answering.
+    fn tool_turn(turn: usize) -> Vec<Value> {
+        let id = format!("call_{}", turn);
+        vec![
+            message("user", &format!("question {}", turn)),
+            json!({
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{"id": id, "type": "function", "function": {"name": "execute_command", "arguments": "{\"command\":\"ls\"}"}}]
+            }),
+            json!({"role": "tool", "tool_call_id": id, "content": "Cargo.toml\nsrc"}),
+            message("assistant", &format!("answer {}", turn)),
+        ]
+    }
+
+    /// A turn with a plain question and answer.
+    fn plain_turn(turn: usize) -> Vec<Value> {
+        vec![message("user", &format!("question {}", turn)), message("assistant", &format!("answer {}", turn))]
+    }
+
+    /// Checks that every tool result follows the assistant message that called it.
+    fn assert_tool_results_have_calls(messages: &[Value]) {
+        for (index, message) in messages.iter().enumerate().filter(|(_, message)| message["role"] == "tool") {
+            let id = &message["tool_call_id"];
+            assert!(
+                messages[..index]
+                    .iter()
+                    .flat_map(|earlier| earlier["tool_calls"].as_array().into_iter().flatten())
+                    .any(|call| &call["id"] == id),
+                "tool result {} has no call",
+                id
+            );
+        }
+    }
+
+    fn user_contents(messages: &[Value]) -> Vec<&str> {
+        messages
+            .iter()
+            .filter(|message| message["role"] == "user")
+            .filter_map(|message| message["content"].as_str())
+            .collect()
+    }
+
+    #[test]
+    fn trimming_keeps_the_system_message_and_recent_turns() {
+        let mut messages = vec![message("system", "Be brief.")];
+        messages.extend((1..=6).flat_map(plain_turn));
+
+        assert_eq!(trim_old_messages(&mut messages), 4);
+
+        assert_eq!(messages[0], message("system", "Be brief."));
+        assert_eq!(user_contents(&messages), ["question 3", "question 4", "question 5", "question 6"]);
+    }
+
+    #[test]
+    fn trimming_never_separates_tool_results_from_their_calls() {
+        let mut messages = vec![message("system", "Be brief.")];
+        messages.extend((1..=6).flat_map(tool_turn));
+
+        assert_eq!(trim_old_messages(&mut messages), 8);
+
+        assert_eq!(messages.len(), 1 + KEEP_RECENT_TURNS * 4);
+        assert_eq!(messages[1]["role"], "user");
+        assert_tool_results_have_calls(&messages);
+    }
+
+    #[test]
+    fn trimming_a_short_conversation_drops_only_the_oldest_turn() {
+        let mut messages = vec![message("system", "Be brief.")];
+        messages.extend((1..=3).flat_map(tool_turn));
+
+        assert_eq!(trim_old_messages(&mut messages), 4);
+
+        assert_eq!(user_contents(&messages), ["question 2", "question 3"]);
+        assert_tool_results_have_calls(&messages);
+    }
+
+    #[test]
+    fn trimming_keeps_the_latest_turn() {
+        let mut messages = vec![message("system", "Be brief.")];
+        messages.extend(tool_turn(1));
+        let before = messages.clone();
+
+        assert_eq!(trim_old_messages(&mut messages), 0);
+
+        assert_eq!(messages, before);
+    }
+
+    fn parse_response(body: Value) -> ChatResponse {
+        serde_json::from_value(body).unwrap()
+    }
+
+    #[test]
+    fn tool_calls_are_read_from_a_response() {
+        let message = tool_turn(1).remove(1);
+        let response = parse_response(json!({
+            "choices": [{"message": message, "finish_reason": "tool_calls"}],
+            "usage": {"prompt_tokens": 10, "completion_tokens": 5}
+        }));
+
+        let choice = response.first_choice().unwrap();
+        let calls = parse_function_calls(&choice.message);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id.as_deref(), Some("call_1"));
+        assert_eq!(calls[0].name, "execute_command");
+        assert_eq!(calls[0].arguments, "{\"command\":\"ls\"}");
+        assert!(!is_truncated(choice));
+        assert_eq!(response.usage.unwrap().completion_tokens, 5);
+
+        // The history keeps the call as it was sent, so its result can refer to it
+        let mut expected = message;
+        expected.as_object_mut().unwrap().remove("content");
+        assert_eq!(assistant_history_message(&choice.message), expected);
+    }
+
+    #[test]
+    fn legacy_function_calls_are_read_from_a_response() {
+        let response = parse_response(json!({
+            "choices": [{
+                "message": {"role": "assistant", "content": null, "function_call": {"name": "read_file", "arguments": "{}"}},
+                "finish_reason": "function_call"
+            }]
+        }));
+
+        let message = &response.first_choice().unwrap().message;
+        let calls = parse_function_calls(message);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].id, None);
+        assert_eq!(calls[0].name, "read_file");
+        assert_eq!(
+            assistant_history_message(message),
+            json!({"role": "assistant", "function_call": {"name": "read_file", "arguments": "{}"}})
+        );
+    }
+
+    #[test]
+    fn text_replies_are_read_from_a_response() {
+        let response = parse_response(json!({
+            "choices": [{"message": {"role": "assistant", "content": "Hello, pal."}, "finish_reason": "length"}],
+            "usage": null,
+            "system_fingerprint": "fp_123"
+        }));
+
+        let choice = response.first_choice().unwrap();
+        assert!(parse_function_calls(&choice.message).is_empty());
+        assert!(is_truncated(choice));
+        assert!(response.usage.is_none());
+        assert_eq!(response.system_fingerprint.as_deref(), Some("fp_123"));
+        assert_eq!(assistant_history_message(&choice.message), message("assistant", "Hello, pal."));
+    }
+
+    #[test]
+    fn responses_without_choices_are_told_apart() {
+        assert!(parse_response(json!({})).choices.is_none());
+        let empty = parse_response(json!({"choices": []}));
+        assert!(empty.choices.is_some());
+        assert!(empty.first_choice().is_none());
+        assert!(serde_json::from_value::<ChatResponse>(json!({"choices": "none"})).is_err());
+    }
+
+    #[test]
+    fn streamed_tool_calls_are_assembled_from_fragments() {
+        let chunks = [
+            json!({"choices": [{"delta": {"tool_calls": [{"index": 0, "id": "call_a", "type": "function", "function": {"name": "exec", "arguments": ""}}]}}]}),
+            json!({"choices": [{"delta": {"tool_calls": [{"index": 0, "function": {"name": "ute_command", "arguments": "{\"comm"}}]}}]}),
+            json!({"choices": [{"delta": {"tool_calls": [{"index": 1, "id": "call_b", "function": {"name": "read_file", "arguments": "{}"}}]}}]}),
+            json!({"choices": [{"delta": {"tool_calls": [{"index": 0, "function": {"arguments": "and\":\"ls\"}"}}]}}]}),
+            json!({"choices": [{"delta": {}, "finish_reason": "tool_calls"}]}),
+        ];
+
+        let mut tool_calls = Vec::new();
+        for chunk in chunks {
+            let chunk: ChatChunk = serde_json::from_value(chunk).unwrap();
+            for choice in chunk.choices {
+                for fragment in choice.delta.tool_calls.into_iter().flatten() {
+                    accumulate_tool_call(&mut tool_calls, fragment);
+                }
+            }
+        }
+
+        let message = ChatMessage {
+            tool_calls: Some(tool_calls),
+            ..ChatMessage::default()
+        };
+        let calls = parse_function_calls(&message);
+        assert_eq!(calls.len(), 2);
+        assert_eq!((calls[0].id.as_deref(), calls[0].name.as_str()), (Some("call_a"), "execute_command"));
+        assert_eq!(calls[0].arguments, "{\"command\":\"ls\"}");
+        assert_eq!((calls[1].id.as_deref(), calls[1].name.as_str()), (Some("call_b"), "read_file"));
+        assert_eq!(assistant_history_message(&message)["tool_calls"][1]["type"], "function");
+    }
+
+    /// Pushes lines until a message is complete, returning it and how many lines it took.
+    fn assemble(lines: &[&str]) -> Option<(String, usize)> {
+        let mut input = MultiLineInput::default();
+        lines
+            .iter()
+            .enumerate()
+            .find_map(|(index, line)| input.push(line).map(|message| (message, index + 1)))
+    }
+
+    #[test]
+    fn single_lines_are_messages() {
+        assert_eq!(assemble(&["hello there"]), Some(("hello there".to_string(), 1)));
+        // Enter on its own gives an empty message, which the prompt skips
+        assert_eq!(assemble(&[""]), Some((String::new(), 1)));
+    }
+
+    #[test]
+    fn trailing_backslashes_continue_the_message() {
+        assert_eq!(
+            assemble(&["first \\", "second\\", "third", "ignored"]),
+            Some(("first \nsecond\nthird".to_string(), 3))
+        );
+    }
+
+    #[test]
+    fn blank_lines_end_a_continued_message() {
+        assert_eq!(assemble(&["first\\", "", "ignored"]), Some(("first\n".to_string(), 2)));
+    }
+
+    #[test]
+    fn blocks_keep_lines_until_the_closing_delimiter() {
+        let lines = ["\"\"\"", "Traceback (most recent call last):", "", "  x = 1 \\", "\"\"\"", "ignored"];
+        assert_eq!(
+            assemble(&lines),
+            Some(("Traceback (most recent call last):\n\n  x = 1 \\".to_string(), 5))
+        );
+    }
+
+    #[test]
+    fn blocks_can_start_and_end_beside_text() {
+        assert_eq!(assemble(&["\"\"\"first", "last\"\"\""]), Some(("first\nlast".to_string(), 2)));
+        assert_eq!(assemble(&["  \"\"\"all at once\"\"\""]), Some(("all at once".to_string(), 1)));
+    }
+
+    #[test]
+    fn unfinished_input_is_kept_until_finished() {
+        let mut input = MultiLineInput::default();
+        assert_eq!(input.push("\"\"\""), None);
+        assert_eq!(input.push("partial"), None);
+        assert_eq!(input.finish(), "partial");
+        // Finishing resets the block, so the next line is a message of its own
+        assert_eq!(input.push("next"), Some("next".to_string()));
+    }
+
+    /// A plain turn whose question and answer are each 16 characters, or 8 estimated tokens.
+    fn sized_turn(turn: usize) -> Vec<Value> {
+        vec![message("user", &format!("question {:>7}", turn)), message("assistant", &format!("answer {:>9}", turn))]
+    }
+
+    /// A system message of 6 estimated tokens followed by three 16-token turns.
+    fn sized_conversation() -> Vec<Value> {
+        let mut messages = vec![message("system", "Be brief")];
+        messages.extend((1..=3).flat_map(sized_turn));
+        messages
+    }
+
+    #[test]
+    fn history_fits_exactly_at_the_budget() {
+        let messages = sized_conversation();
+        assert_eq!(messages.iter().map(estimate_message_tokens).sum::<usize>(), 6 + 3 * 16);
+
+        // The system message, the latest turn, and one earlier turn
+        assert_eq!(user_contents(&fit_history(&messages, 38)), ["question       2", "question       3"]);
+        assert_eq!(user_contents(&fit_history(&messages, 37)), ["question       3"]);
+        assert_eq!(fit_history(&messages, 54), messages);
+    }
+
+    #[test]
+    fn history_keeps_an_oversized_latest_turn() {
+        let mut messages = sized_conversation();
+        messages.push(message("user", &"x".repeat(4000)));
+
+        let fitted = fit_history(&messages, 100);
+
+        assert_eq!(fitted, [messages[0].clone(), messages[7].clone()]);
+    }
+
+    #[test]
+    fn history_stops_at_an_oversized_earlier_turn() {
+        let mut messages = sized_conversation();
+        messages[4] = message("assistant", &"x".repeat(4000));
+
+        // The first turn would fit, but is older than the one that doesn't
+        assert_eq!(user_contents(&fit_history(&messages, 1000)), ["question       3"]);
+    }
+
+    #[test]
+    fn history_always_keeps_system_messages() {
+        let mut messages = sized_conversation();
+        messages.insert(3, message("system", "Remember: the user prefers fish."));
+
+        let fitted = fit_history(&messages, 0);
+
+        assert_eq!(fitted[0], messages[0]);
+        assert_eq!(fitted[1], messages[3]);
+        assert_eq!(user_contents(&fitted), ["question       3"]);
+        assert!(fit_history(&[], 100).is_empty());
+    }
+
+    #[test]
+    fn command_results_report_stderr_and_the_exit_code() {
+        let output = execute_shell_command("echo listed; echo 'no such file' >&2; exit 2", 0).unwrap();
+        assert_eq!(
+            describe_command_output(output, 0),
+            serde_json::json!({ "stdout": "listed\n", "stderr": "no such file\n", "exit_code": 2 })
+        );
+
+        let output = execute_shell_command("printf quiet", 0).unwrap();
+        assert_eq!(
+            describe_command_output(output, 0),
+            serde_json::json!({ "stdout": "quiet", "stderr": "", "exit_code": 0 })
+        );
+    }
+
+    #[test]
+    fn command_results_are_capped_to_the_output_limit() {
+        let output = execute_shell_command("seq 1 2000; seq 1 2000 >&2; exit 1", 0).unwrap();
+        let result = describe_command_output(output, 200);
+        assert_eq!(result["exit_code"], 1);
+        for stream in ["stdout", "stderr"] {
+            let text = result[stream].as_str().unwrap();
+            assert!(text.starts_with("1\n2\n"), "{}", text);
+            assert!(text.contains("bytes truncated ...\n"), "{}", text);
+            assert!(text.contains("1999\n2000\n\n[The output was 8,893 bytes"), "{}", text);
+            assert!(!text.contains("\n1000\n"), "{}", text);
+        }
+    }
+
+    #[test]
+    fn chat_commands_are_listed_under_the_name_they_run_by() {
+        for entry in CHAT_COMMANDS {
+            assert_eq!(entry.usage.split([' ', ',']).next(), Some(entry.name));
+            assert_eq!(CHAT_COMMANDS.iter().filter(|other| other.name == entry.name).count(), 1, "{}", entry.name);
+        }
+        // Only the commands the chat loop handles itself have no handler
+        let unhandled: Vec<_> = CHAT_COMMANDS.iter().filter(|entry| entry.run.is_none()).map(|entry| entry.name).collect();
+        assert_eq!(unhandled, ["/retry", "/attach", "exit"]);
+        assert!(should_exit("exit") && should_exit("quit"));
+        assert_eq!(closest_command("/hepl"), Some("/help"));
+    }
+
+    #[test]
+    fn long_text_is_split_at_nearby_line_breaks() {
+        let text = "first line\nsecond line\nthird line\nfourth line\n";
+        let (head, tail, omitted) = split_head_tail(text, 30);
+        assert_eq!((head, tail), ("first line\n", "fourth line\n"));
+        assert_eq!(head.len() + omitted + tail.len(), text.len());
+
+        // Cuts never land inside a character
+        let (head, tail, omitted) = split_head_tail("ééééé", 5);
+        assert_eq!((head, tail, omitted), ("é", "é", 6));
+    }
+
+    #[test]
+    fn enter_follows_the_confirm_default() {
+        assert!(is_confirmed("\n", true));
+        assert!(!is_confirmed("\n", false));
+        assert!(is_confirmed(" Y\n", false));
+        assert!(is_confirmed("yes\n", false));
+        assert!(!is_confirmed("n\n", true));
+        assert!(!is_confirmed("maybe\n", true));
+        assert_eq!(yes_no_options(true), "Y/n");
+        assert_eq!(yes_no_options(false), "y/N");
+    }
+
+    /// A connection to a backend that must not be sent anything.
+    fn offline_connection() -> ChatConnection {
+        ChatConnection {
+            backend: Box::new(crate::client::testing::CannedBackend::new(&[])),
+            stream: false,
+            max_tokens: None,
+            seed: None,
+            continue_truncated: false,
+            render_markdown: false,
+            history_token_budget: None,
+            history_trimmed: Cell::new(false),
+            request_cancelled: Cell::new(false),
+            max_command_output_bytes: DEFAULT_MAX_COMMAND_OUTPUT_BYTES,
+            confirm_allows_by_default: false,
+            max_function_calls: DEFAULT_MAX_FUNCTION_CALLS,
+            allow_network_tools: false,
+            system_info_fields: Vec::new(),
+            list_directory_max_entries: DEFAULT_LIST_DIRECTORY_ENTRIES,
+            legacy_functions: false,
+            tools_enabled: Cell::new(true),
+            temperature_override: Cell::new(None),
+            show_cost: false,
+            override_budget: false,
+            session_usage: Cell::new(Usage::default()),
+            config: Config::default(),
+        }
+    }
+
+    #[test]
+    fn calls_after_exit_chat_are_answered_too() {
+        let call = |id: &str, name: &str| FunctionCall {
+            id: Some(id.to_string()),
+            name: name.to_string(),
+            arguments: "{}".to_string(),
+        };
+        let calls = vec![call("call_1", "exit_chat"), call("call_2", "execute_command"), call("call_3", "read_file")];
+        let mut messages = vec![message("user", "bye")];
+        let mut calls_made = 0;
+
+        let outcome = handle_function_calls(calls, &mut messages, &offline_connection(), false, &mut calls_made);
+        assert_eq!(outcome, Some(true));
+        let answered: Vec<&str> = messages[1..].iter().map(|message| message["tool_call_id"].as_str().unwrap()).collect();
+        assert_eq!(answered, ["call_1", "call_2", "call_3"]);
+        assert_eq!(messages[2]["content"], "Error: not run. The chat ended.");
+        assert_eq!(calls_made, 1);
+    }
+
+    #[test]
+    fn shell_escapes_refuse_builtins() {
+        let advice = should_execute_command("cd /tmp").unwrap_err();
+        assert_eq!(parse_shell_escape("cd /tmp"), Err(advice));
+        assert_eq!(parse_shell_escape("ls -la"), Ok((false, "ls -la")));
+    }
+
+    #[test]
+    fn shared_shell_escapes_refuse_builtins() {
+        let advice = should_execute_command("cd /tmp").unwrap_err();
+        assert_eq!(parse_shell_escape("--share cd /tmp"), Err(advice));
+        assert_eq!(parse_shell_escape("--share  make test"), Ok((true, "make test")));
+        assert!(parse_shell_escape("--share").unwrap_err().starts_with("Usage:"));
+    }
+
+    #[test]
+    fn background_processes_do_not_hold_up_the_result() {
+        let started = Instant::now();
+        let output = execute_shell_command("sleep 5 & echo started", 0).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(3), "{:?}", started.elapsed());
+        let result = describe_command_output(output, 0);
+        assert_eq!(result["stdout"], "started\n");
+        assert_eq!(result["exit_code"], 0);
+    }
+
+    #[test]
+    fn commands_run_with_the_configured_shell() {
+        let output = execute_shell_command("printf %s \"$0\"", 0).unwrap();
+        let result = describe_command_output(output, 0);
+        assert_eq!(result["stdout"], crate::command_shell::command_shell().to_string_lossy().as_ref());
+    }
 }
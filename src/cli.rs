@@ -16,29 +16,75 @@
 
 use std::{
     env,
-    process::{Command, ExitStatus},
+    io::{self, Read, Write},
+    process::{ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
 };
 
 use dotenv::dotenv;
 
 use crate::{
-    chat::run_chat_mode,
-    openai::process_prompt,
+    ask::run_ask_mode,
+    batch::run_batch_mode,
+    builtins::{parse_builtin, run_builtin},
+    chat::{is_interactive_command, run_chat_mode, CapturedOutput},
+    command_shell::shell_command,
+    job_control::{spawn_in_foreground, wait_in_foreground},
+    models::Secret,
+    openai::{load_config, process_prompt, OutputFormat},
+    personas::print_personas,
+    profiles::print_provider_profiles,
+    session_log::LogFormat,
     shell::run_shell_mode,
+    usage::print_usage_report,
+    utils::read_piped_stdin,
 };
 
 /// Determines and runs the appropriate mode based on command-line arguments.
 /// Returns `true` if the program should exit immediately.
 pub(crate) fn run_mode() -> bool {
-    if let Some((continuous_mode, chat_mode, no_execute, prompt_args)) = parse_arguments() {
+    if let Some(options) = parse_arguments() {
+        // Modes read settings with defaults as a fallback, so a broken config is caught here
+        if let Err(e) = load_config() {
+            eprintln!("Error: Could not load the configuration from {}", e);
+            std::process::exit(1);
+        }
         // Execute the appropriate mode
-        if chat_mode {
-            run_chat_mode(false);
-        } else if continuous_mode {
-            run_shell_mode(no_execute);
-        } else if !prompt_args.is_empty() {
-            let prompt = prompt_args.join(" ");
-            process_prompt(&prompt, no_execute);
+        if options.chat_mode {
+            run_chat_mode(&options);
+        } else if options.continuous_mode {
+            run_shell_mode(&options);
+        } else if options.prompt_args == ["usage"] {
+            print_usage_report();
+        } else if options.prompt_args == ["providers", "list"] {
+            print_provider_profiles();
+        } else if options.prompt_args == ["persona", "list"] {
+            print_personas();
+        } else if options.prompt_args.first().is_some_and(|arg| arg == "ask-ai") {
+            if !run_ask_mode(&options.prompt_args[1..].join(" "), &options) {
+                std::process::exit(1);
+            }
+        } else if options.prompt_args.len() == 2 && options.prompt_args[0] == "batch" {
+            if !run_batch_mode(&options.prompt_args[1], &options) {
+                std::process::exit(1);
+            }
+        } else if !options.prompt_args.is_empty() {
+            let prompt = options.prompt_args.join(" ");
+            let piped_input = match read_piped_stdin() {
+                Ok(input) => input,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            if !process_prompt(&prompt, &options, piped_input.as_deref()) {
+                std::process::exit(1);
+            }
         } else {
             eprintln!("Error: No prompt provided.\n");
             print_help();
@@ -54,19 +100,57 @@ pub(crate) fn run_mode() -> bool {
 /// Prints the help message for the command-line tool.
 pub(crate) fn print_help() {
     println!(
-        "Usage: gptsh [OPTIONS] [PROMPT]\n\
+        "Usage: gptsh [OPTIONS] [PROMPT]\n       gptsh usage\n       gptsh providers list\n       gptsh persona list\n       gptsh batch <file> --script <script>\n       gptsh ask-ai <question>\n\
          Options:\n\
-           --help, -h        Show this help message\n\
-           --shell           Run in continuous shell mode\n\
-           --chat            Run in chat mode with GPT-4\n\
-           --no-execute      Output the generated command without executing it"
+           --help, -h                 Show this help message\n\
+           --shell                    Run in continuous shell mode\n\
+           --no-rc                    Start shell mode without reading ~/.gptshrc\n\
+           --log-session <file>       Append what happens in shell mode to a log file\n\
+           --log-format <format>      Write the session log as text (default) or jsonl\n\
+           --chat                     Run in chat mode (chat_system_prompt in .gptsh_config\n\
+                                      sets its instructions; ${{USER}} and ${{PWD}} are expanded)\n\
+           --persona <name>           Chat with a persona (see gptsh persona list; implies --chat)\n\
+           --resume [id]              Continue the most recent chat session, or the one given (implies --chat)\n\
+           --no-execute               Output the generated command without executing it\n\
+           --raw                      Print the model's unmodified reply (implies --no-execute)\n\
+           --output <format>          Print the reply as text (default) or json (implies --no-execute)\n\
+           --dry-run                  Preview the API request without sending it\n\
+           --file <path>              Attach a file to the prompt as context; repeatable\n\
+           --context <text>           Attach a note to the prompt as context; repeatable\n\
+           --cost                     Show the tokens and estimated cost of each request\n\
+           --verbose                  Show the raw reply in shell mode, and each function call in chat mode\n\
+           --no-wait                  Fail at once when rate limited instead of waiting for the limit to reset\n\
+           --seed <n>                 Sample deterministically where supported (shown with --cost)\n\
+           --override-budget          Send requests even when the spend budget is used up\n\
+           --append-to <file>         Append approved commands to a runbook script\n\
+           --script <file>            Script written by batch; it must not exist yet\n\
+           --endpoint <url>           Use a different OpenAI-compatible API base URL\n\
+           --model <name>             Model to use (default gpt-4; claude-sonnet-4-5 for anthropic)\n\
+           --provider <profile>       Use a provider profile from the providers config key\n\
+           --api-key <key>            API key to use, overriding OPENAI_API_KEY and .env (visible to others in `ps`)\n\
+           --header <name:value>      Extra header for every request; repeatable, ${{VAR}} expands from the environment\n\
+         \n\
+         Environment:\n\
+           OPENAI_API_KEY             API key used to authenticate requests (comma-separate several to rotate)\n\
+           OPENAI_API_KEY_FILE        File to read the API key from when OPENAI_API_KEY is unset\n\
+           OPENAI_BASE_URL            API base URL (overridden by --endpoint)\n\
+           OPENAI_ORG_ID              OpenAI organization to bill requests to\n\
+           OPENAI_PROJECT_ID          OpenAI project to bill requests to\n\
+           GPTSH_PROVIDER             API provider: openai (default), azure, openai-compatible, or anthropic\n\
+           GPTSH_MODEL                Model to use (overridden by --model)\n\
+           AZURE_OPENAI_ENDPOINT      Azure resource endpoint (overridden by --endpoint)\n\
+           AZURE_OPENAI_DEPLOYMENT    Azure deployment name\n\
+           AZURE_OPENAI_API_VERSION   Azure API version (default 2024-06-01)\n\
+           AZURE_OPENAI_API_KEY       Azure API key, checked before OPENAI_API_KEY\n\
+           ANTHROPIC_API_KEY          API key for the anthropic provider\n\
+           ANTHROPIC_BASE_URL         Anthropic API base URL (overridden by --endpoint)"
     );
 }
 
 /// Checks if a given command is a shell built-in that affects the shell's state.
 pub(crate) fn is_shell_builtin(command: &str) -> bool {
     const SHELL_BUILTINS: &[&str] = &["cd", "export", "alias", "source", "unset"];
-    if let Some(first_word) = command.trim().split_whitespace().next() {
+    if let Some(first_word) = command.split_whitespace().next() {
         SHELL_BUILTINS.contains(&first_word)
     } else {
         false
@@ -87,31 +171,255 @@ pub(crate) fn should_execute_command(command: &str) -> Result<(), String> {
     }
 }
 
-/// Executes a given command using Bash if it is safe to do so.
+/// Executes a given command with the user's shell (see [`command_shell`]) if it is safe to
+/// do so.
 /// Prints an error message if the command cannot be executed.
-pub(crate) fn execute_command(command: &str) {
+///
+/// # Arguments
+///
+/// * `command` - The command to execute.
+/// * `keeps_running` - Whether gptsh keeps running afterwards, as in shell mode and chat
+///   mode. Then `cd`, `export`, and `unset` change gptsh's own state, which later commands
+///   inherit; otherwise they are refused with advice, as they would have no effect.
+///
+/// # Returns
+///
+/// * `Option<i32>` - The exit status as a shell reports it (`128` plus the signal for a
+///   command ended by one, `127` if it couldn't be started), or `None` if it was refused.
+pub(crate) fn execute_command(command: &str, keeps_running: bool) -> Option<i32> {
+    if keeps_running {
+        if let Some(builtin) = parse_builtin(command) {
+            if let Err(message) = builtin.and_then(run_builtin) {
+                eprintln!("{}", message);
+                return Some(1);
+            }
+            return Some(0);
+        }
+    }
     if let Err(message) = should_execute_command(command) {
         println!("{}", message);
-        return;
+        return None;
+    }
+
+    let capture = CAPTURE_OUTPUT.load(Ordering::SeqCst);
+    // Editors and pagers are expected to run as long as the user keeps them open
+    let notice_after = match SLOW_COMMAND_SECONDS.load(Ordering::SeqCst) {
+        0 => None,
+        _ if is_interactive_command(command) => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    };
+    // Programs that need the terminal can't have their output captured
+    let result = if capture && !is_interactive_command(command) {
+        run_captured(command, notice_after)
+    } else {
+        spawn_in_foreground(&mut shell_command(command))
+            .and_then(|mut child| wait_in_foreground(&mut child, notice_after))
+            .map(|status| FinishedCommand {
+                command: command.to_string(),
+                output: None,
+                exit_code: status.code(),
+                status_code: shell_status_code(status),
+            })
+    };
+    match result {
+        Ok(finished) => {
+            let status_code = finished.status_code;
+            // Like a shell, go on after the ^C the terminal echoed on a line of its own
+            if finished.exit_code.is_none() && status_code == 130 {
+                println!();
+            }
+            handle_command_status(status_code == 0);
+            if capture {
+                if let Ok(mut last) = LAST_COMMAND.lock() {
+                    *last = Some(finished);
+                }
+            }
+            Some(status_code)
+        }
+        Err(e) => {
+            eprintln!("Failed to execute command: {}", e);
+            Some(127)
+        }
     }
+}
 
-    match Command::new("bash").arg("-c").arg(command).status() {
-        Ok(status) => handle_command_status(status),
-        Err(e) => eprintln!("Failed to execute command: {}", e),
+/// Returns a command's exit status as a shell reports it in `$?`: the exit code, or 128
+/// plus the number of the signal that ended it.
+pub(crate) fn shell_status_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
     }
+    status.code().unwrap_or(1)
+}
+
+/// A command run in shell mode while its output is shared with the model.
+pub(crate) struct FinishedCommand {
+    pub(crate) command: String,
+    /// What it printed to stdout and stderr, or `None` if it had the terminal to itself.
+    pub(crate) output: Option<(CapturedOutput, CapturedOutput)>,
+    /// The exit code, or `None` if the command was ended by a signal.
+    pub(crate) exit_code: Option<i32>,
+    /// The exit status as a shell reports it.
+    status_code: i32,
+}
+
+/// Set while commands are run with their output captured, to share with the model.
+static CAPTURE_OUTPUT: AtomicBool = AtomicBool::new(false);
+/// The last command run while output was captured, until it is taken.
+static LAST_COMMAND: Mutex<Option<FinishedCommand>> = Mutex::new(None);
+/// The most bytes of each of stdout and stderr kept of a captured command.
+static CAPTURE_LIMIT: AtomicUsize = AtomicUsize::new(0);
+/// How many seconds a command runs before the user is offered to kill it; `0` never.
+static SLOW_COMMAND_SECONDS: AtomicU64 = AtomicU64::new(0);
+
+/// Starts or stops capturing the output of the commands [`execute_command`] runs, so it
+/// can be shared with the model. The output is still shown as it arrives.
+///
+/// # Arguments
+///
+/// * `capture` - Whether to capture output.
+/// * `limit` - The most bytes of each of stdout and stderr kept from a command's start and
+///   end; `0` keeps everything.
+pub(crate) fn capture_command_output(capture: bool, limit: usize) {
+    CAPTURE_OUTPUT.store(capture, Ordering::SeqCst);
+    CAPTURE_LIMIT.store(limit, Ordering::SeqCst);
+    if !capture {
+        take_last_command();
+    }
+}
+
+/// Sets how long the commands [`execute_command`] runs may take before the user is told
+/// they are still running and offered to kill them.
+///
+/// # Arguments
+///
+/// * `seconds` - The number of seconds; `0` waits quietly however long a command takes.
+pub(crate) fn notice_slow_commands(seconds: u64) {
+    SLOW_COMMAND_SECONDS.store(seconds, Ordering::SeqCst);
+}
+
+/// Takes the last command run while output was captured, if one ran since the last call.
+pub(crate) fn take_last_command() -> Option<FinishedCommand> {
+    LAST_COMMAND.lock().ok().and_then(|mut last| last.take())
+}
+
+/// Runs a command, copying its output to the terminal as it arrives and keeping the start
+/// and end of it.
+fn run_captured(command: &str, notice_after: Option<Duration>) -> io::Result<FinishedCommand> {
+    let keep = match CAPTURE_LIMIT.load(Ordering::SeqCst) {
+        0 => usize::MAX,
+        limit => limit,
+    };
+    let mut child =
+        spawn_in_foreground(shell_command(command).stdout(Stdio::piped()).stderr(Stdio::piped()))?;
+    let stdout = tee(child.stdout.take(), io::stdout, keep);
+    let stderr = tee(child.stderr.take(), io::stderr, keep);
+    let status = wait_in_foreground(&mut child, notice_after)?;
+    let join = |reader: thread::JoinHandle<CapturedOutput>| reader.join().unwrap_or_else(|_| CapturedOutput::new(keep));
+    Ok(FinishedCommand {
+        command: command.to_string(),
+        output: Some((join(stdout), join(stderr))),
+        exit_code: status.code(),
+        status_code: shell_status_code(status),
+    })
+}
+
+/// Copies a command's output to one of gptsh's own streams on a separate thread, keeping
+/// a copy of it.
+fn tee<R, W>(source: Option<R>, destination: fn() -> W, keep: usize) -> thread::JoinHandle<CapturedOutput>
+where
+    R: Read + Send + 'static,
+    W: Write + 'static,
+{
+    thread::spawn(move || {
+        let mut captured = CapturedOutput::new(keep);
+        let Some(mut source) = source else { return captured };
+        let mut buffer = [0; 8192];
+        while let Ok(read) = source.read(&mut buffer) {
+            if read == 0 {
+                break;
+            }
+            let mut destination = destination();
+            let _ = destination.write_all(&buffer[..read]);
+            let _ = destination.flush();
+            captured.push(&buffer[..read]);
+        }
+        captured
+    })
 }
 
 /// Handles the exit status of a command execution.
-fn handle_command_status(status: ExitStatus) {
-    if !status.success() {
+fn handle_command_status(success: bool) {
+    if !success {
         eprintln!("Command exited with non-zero status.");
     }
 }
 
-/// Parses command-line arguments and returns a tuple containing:
-/// (continuous_mode, chat_mode, no_execute, prompt_args).
+/// Options collected from the command-line arguments.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct CliOptions {
+    /// Run in continuous shell mode.
+    pub(crate) continuous_mode: bool,
+    /// Run in chat mode.
+    pub(crate) chat_mode: bool,
+    /// Start shell mode without reading `~/.gptshrc`.
+    pub(crate) no_rc: bool,
+    /// Output generated commands instead of executing them.
+    pub(crate) no_execute: bool,
+    /// Print the model's reply verbatim, without parsing or executing it.
+    pub(crate) raw: bool,
+    /// How the result of a prompt is printed.
+    pub(crate) output: OutputFormat,
+    /// Print the assembled request instead of sending it.
+    pub(crate) dry_run: bool,
+    /// Send requests even when the spend budget is exhausted.
+    pub(crate) override_budget: bool,
+    /// Print the tokens and estimated cost of every request.
+    pub(crate) show_cost: bool,
+    /// Print the raw model reply before it is parsed, and in chat mode each function call
+    /// and the full output of the commands it runs.
+    pub(crate) verbose: bool,
+    /// Fail rate-limited requests at once instead of waiting for the limit to reset.
+    pub(crate) no_wait: bool,
+    /// File shell mode logs every line typed to, with what came of it.
+    pub(crate) log_session: Option<String>,
+    /// How the session log is written, overriding `session_log_format`.
+    pub(crate) log_format: Option<LogFormat>,
+    /// Files attached to a prompt as context.
+    pub(crate) files: Vec<String>,
+    /// Notes attached to a prompt as context.
+    pub(crate) context: Vec<String>,
+    /// Script file that approved commands are appended to.
+    pub(crate) append_to: Option<String>,
+    /// Script file that `batch` writes its commands to.
+    pub(crate) script: Option<String>,
+    /// API key given on the command line, taking precedence over the environment.
+    pub(crate) api_key: Option<Secret>,
+    /// Base URL replacing the default OpenAI API endpoint.
+    pub(crate) endpoint: Option<String>,
+    /// Model name replacing the default model.
+    pub(crate) model: Option<String>,
+    /// Name of the provider profile to use, overriding the mode's `default_provider`.
+    pub(crate) provider: Option<String>,
+    /// Seed for best-effort deterministic sampling, taking precedence over the config file.
+    pub(crate) seed: Option<u64>,
+    /// Name of the chat persona to use.
+    pub(crate) persona: Option<String>,
+    /// Continue a saved chat session: `Some(None)` for the most recent one, or `Some(Some(id))`.
+    pub(crate) resume: Option<Option<String>>,
+    /// Extra `Name: value` headers sent with every request, taking precedence over the config file.
+    pub(crate) headers: Vec<Secret>,
+    /// The remaining arguments, which together form the prompt.
+    pub(crate) prompt_args: Vec<String>,
+}
+
+/// Parses command-line arguments into `CliOptions`.
 /// Returns `None` if the program should exit (e.g., after printing help).
-pub(crate) fn parse_arguments() -> Option<(bool, bool, bool, Vec<String>)> {
+pub(crate) fn parse_arguments() -> Option<CliOptions> {
     // Load environment variables from .env file if present
     dotenv().ok();
 
@@ -124,21 +432,94 @@ pub(crate) fn parse_arguments() -> Option<(bool, bool, bool, Vec<String>)> {
         return None;
     }
 
-    // Check for flags
-    let continuous_mode = args.contains(&"--shell".to_string());
-    let chat_mode = args.contains(&"--chat".to_string());
-    let no_execute = args.contains(&"--no-execute".to_string());
+    let mut options = CliOptions::default();
+    let mut args = args.into_iter().skip(1).peekable(); // Skip the program name
 
-    // Define recognized flags
-    const FLAGS: &[&str] = &["--no-execute", "--shell", "--chat", "--help", "-h"];
+    // Check for flags; everything else is part of the prompt
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--shell" => options.continuous_mode = true,
+            "--chat" => options.chat_mode = true,
+            "--no-rc" => options.no_rc = true,
+            "--no-execute" => options.no_execute = true,
+            "--dry-run" => options.dry_run = true,
+            "--raw" => options.raw = true,
+            "--override-budget" => options.override_budget = true,
+            "--cost" => options.show_cost = true,
+            "--no-wait" => options.no_wait = true,
+            "--verbose" => options.verbose = true,
+            "--log-session" => options.log_session = Some(require_value(&arg, args.next())),
+            "--log-format" => options.log_format = Some(parse_log_format(&require_value(&arg, args.next()))),
+            "--output" => options.output = parse_output_format(&require_value(&arg, args.next())),
+            "--file" => options.files.push(require_value(&arg, args.next())),
+            "--context" => options.context.push(require_value(&arg, args.next())),
+            "--append-to" => options.append_to = Some(require_value(&arg, args.next())),
+            "--script" => options.script = Some(require_value(&arg, args.next())),
+            "--api-key" => options.api_key = Some(Secret::new(require_value(&arg, args.next()))),
+            "--endpoint" => options.endpoint = Some(require_value(&arg, args.next())),
+            "--model" => options.model = Some(require_value(&arg, args.next())),
+            "--provider" => options.provider = Some(require_value(&arg, args.next())),
+            "--seed" => options.seed = Some(parse_seed(&require_value(&arg, args.next()))),
+            "--persona" => {
+                options.chat_mode = true;
+                options.persona = Some(require_value(&arg, args.next()));
+            }
+            "--resume" => {
+                options.chat_mode = true;
+                options.resume = Some(args.next_if(|next| !next.starts_with('-')));
+            }
+            "--header" => options.headers.push(Secret::new(require_value(&arg, args.next()))),
+            _ => options.prompt_args.push(arg),
+        }
+    }
 
-    // Filter out flags to get the prompt arguments
-    let prompt_args: Vec<String> = args
-        .iter()
-        .skip(1) // Skip the program name
-        .filter(|arg| !FLAGS.contains(&arg.as_str()))
-        .cloned()
-        .collect();
+    Some(options)
+}
+
+/// Returns the value following a flag, exiting with an error if it is missing.
+fn require_value(flag: &str, value: Option<String>) -> String {
+    match value {
+        Some(value) => value,
+        None => {
+            eprintln!("Error: {} requires a value.\n", flag);
+            print_help();
+            std::process::exit(1);
+        }
+    }
+}
 
-    Some((continuous_mode, chat_mode, no_execute, prompt_args))
+/// Parses the value of `--log-format`, exiting with an error if it isn't a known format.
+fn parse_log_format(value: &str) -> LogFormat {
+    match LogFormat::parse(value) {
+        Some(format) => format,
+        None => {
+            eprintln!("Error: --log-format must be text or jsonl, got '{}'.\n", value);
+            print_help();
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parses the value of `--output`, exiting with an error if it isn't a known format.
+fn parse_output_format(value: &str) -> OutputFormat {
+    match OutputFormat::parse(value) {
+        Some(format) => format,
+        None => {
+            eprintln!("Error: --output must be text or json, got '{}'.\n", value);
+            print_help();
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parses the value of `--seed`, exiting with an error if it isn't a non-negative integer.
+fn parse_seed(value: &str) -> u64 {
+    match value.parse() {
+        Ok(seed) => seed,
+        Err(_) => {
+            eprintln!("Error: --seed must be a non-negative integer, got '{}'.\n", value);
+            print_help();
+            std::process::exit(1);
+        }
+    }
 }
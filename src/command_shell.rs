@@ -0,0 +1,194 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+use crate::{models::Config, openai::load_config};
+
+/// The shell commands are run with when neither `command_shell` nor `$SHELL` names one
+/// that can be found. `sh` is used on systems without it.
+const FALLBACK_SHELL: &str = "bash";
+
+/// The shell that runs commands, chosen the first time it is needed.
+static COMMAND_SHELL: OnceLock<PathBuf> = OnceLock::new();
+
+/// Chooses the shell that runs commands: `command_shell` from the configuration, or else
+/// the user's own shell from `$SHELL`, or else bash. A shell that can't be found is
+/// warned about, and the next one tried. Only the first call has an effect.
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration, with `command_shell`.
+pub(crate) fn init_command_shell(config: &Config) {
+    COMMAND_SHELL.get_or_init(|| resolve_command_shell(config));
+}
+
+/// Returns the shell that runs commands, choosing it from `.gptsh_config` if
+/// [`init_command_shell`] hasn't been called.
+pub(crate) fn command_shell() -> &'static Path {
+    COMMAND_SHELL.get_or_init(|| resolve_command_shell(&load_config().unwrap_or_default()))
+}
+
+/// Returns the name of the shell that runs commands, e.g. `zsh`, to tell the model which
+/// syntax to use.
+pub(crate) fn command_shell_name() -> String {
+    let shell = command_shell();
+    shell.file_name().unwrap_or(shell.as_os_str()).to_string_lossy().into_owned()
+}
+
+/// Returns the shebang line for a script run by `shell`, e.g. `#!/usr/bin/env zsh`, so
+/// saved commands run with the same shell whose syntax the model was asked for.
+///
+/// # Arguments
+///
+/// * `shell` - The shell, by path or name.
+pub(crate) fn shebang(shell: &Path) -> String {
+    format!("#!/usr/bin/env {}", shell.file_name().unwrap_or(shell.as_os_str()).to_string_lossy())
+}
+
+/// Builds the process that runs a command line with the shell, as `<shell> -c <command>`.
+///
+/// # Arguments
+///
+/// * `command` - The command line.
+///
+/// # Returns
+///
+/// * `Command` - The process, ready for its streams to be set up and to be started.
+pub(crate) fn shell_command(command: &str) -> Command {
+    let mut process = Command::new(command_shell());
+    process.arg("-c").arg(command);
+    process
+}
+
+/// Picks the first shell that can be found, warning about the ones asked for that can't.
+fn resolve_command_shell(config: &Config) -> PathBuf {
+    choose_command_shell(config, |name| env::var_os(name))
+}
+
+/// Picks the first shell that can be found, with `$SHELL` and `PATH` looked up by `env`.
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration, with `command_shell`.
+/// * `env` - Gets an environment variable, or `None` if it isn't set.
+///
+/// # Returns
+///
+/// * `PathBuf` - The shell; `sh` if none of the others can be found.
+fn choose_command_shell(config: &Config, env: impl Fn(&str) -> Option<OsString>) -> PathBuf {
+    let path = env("PATH");
+    let candidates = [
+        (config.command_shell.clone(), "set by command_shell in .gptsh_config"),
+        (env("SHELL").and_then(|shell| shell.into_string().ok()), "from $SHELL"),
+    ];
+    let mut missing = Vec::new();
+    let mut chosen = None;
+    for (shell, source) in candidates {
+        let Some(shell) = shell.map(|shell| shell.trim().to_string()).filter(|shell| !shell.is_empty()) else { continue };
+        chosen = find_program(&shell, path.as_deref());
+        if chosen.is_some() {
+            break;
+        }
+        missing.push((shell, source));
+    }
+    let chosen = chosen.or_else(|| find_program(FALLBACK_SHELL, path.as_deref())).unwrap_or_else(|| PathBuf::from("sh"));
+    for (shell, source) in missing {
+        eprintln!(
+            "Warning: The shell '{}' {} was not found; running commands with {} instead.",
+            shell,
+            source,
+            chosen.display()
+        );
+    }
+    chosen
+}
+
+/// Finds a program given by path, or by name in `path`, which is laid out like `PATH`.
+fn find_program(program: &str, path: Option<&OsStr>) -> Option<PathBuf> {
+    let program = Path::new(program);
+    if program.components().count() > 1 {
+        return program.is_file().then(|| program.to_path_buf());
+    }
+    env::split_paths(path?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::utils::testing::test_dir;
+
+    /// Makes a directory holding (empty) programs with the given names.
+    fn bin_dir(name: &str, programs: &[&str]) -> PathBuf {
+        let dir = test_dir(name);
+        for program in programs {
+            fs::write(dir.join(program), "").unwrap();
+        }
+        dir
+    }
+
+    fn choose(command_shell: Option<&str>, shell: Option<&str>, path: &Path) -> PathBuf {
+        let config = Config {
+            command_shell: command_shell.map(str::to_string),
+            ..Config::default()
+        };
+        choose_command_shell(&config, |name| match name {
+            "SHELL" => shell.map(OsString::from),
+            "PATH" => Some(path.as_os_str().to_os_string()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn shells_are_tried_in_order() {
+        let bin = bin_dir("command-shell", &["bash", "zsh", "fish"]);
+        assert_eq!(choose(Some("fish"), Some("zsh"), &bin), bin.join("fish"));
+        assert_eq!(choose(None, Some("zsh"), &bin), bin.join("zsh"));
+        assert_eq!(choose(Some("  "), Some(&bin.join("zsh").to_string_lossy()), &bin), bin.join("zsh"));
+        assert_eq!(choose(Some("nu"), Some("zsh"), &bin), bin.join("zsh"));
+        assert_eq!(choose(Some("nu"), Some("/no/such/zsh"), &bin), bin.join("bash"));
+        assert_eq!(choose(None, None, &bin), bin.join("bash"));
+
+        let without_bash = bin_dir("command-shell-without-bash", &["zsh"]);
+        assert_eq!(choose(Some("nu"), None, &without_bash), PathBuf::from("sh"));
+    }
+
+    #[test]
+    fn commands_run_with_the_shell() {
+        let process = shell_command("echo one; echo two");
+        assert_eq!(process.get_program(), command_shell().as_os_str());
+        assert_eq!(process.get_args().collect::<Vec<_>>(), ["-c", "echo one; echo two"]);
+
+        let output = shell_command("echo one; echo two").output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "one\ntwo\n");
+    }
+
+    #[test]
+    fn shebang_names_the_shell() {
+        assert_eq!(shebang(Path::new("/usr/bin/fish")), "#!/usr/bin/env fish");
+        assert_eq!(shebang(Path::new("zsh")), "#!/usr/bin/env zsh");
+    }
+}
@@ -0,0 +1,212 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    borrow::Cow,
+    collections::BTreeSet,
+    env, fs,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use rustyline::{
+    completion::{Completer, FilenameCompleter, Pair},
+    highlight::Highlighter,
+    hint::Hinter,
+    validate::Validator,
+    Cmd, ConditionalEventHandler, Context, Event, EventContext, Helper, Movement, RepeatCount,
+};
+
+/// Builtins shell mode runs itself, completed along with the programs on `PATH` and the
+/// mode-switch word.
+const SHELL_MODE_COMMANDS: &[&str] = &["cd", "exit", "export", "unset"];
+
+/// Tab completion for shell mode. Paths are completed anywhere on the line, in either
+/// mode, with spaces escaped or inside the quote the path was started with. The first word
+/// of a direct command is completed as a program name instead.
+pub(crate) struct ShellHelper {
+    files: FilenameCompleter,
+    /// Whether the first word is completed as a program name, as in Direct Command Mode.
+    pub(crate) complete_commands: bool,
+    /// The words shell mode handles itself, including the configured mode-switch word.
+    shell_commands: Vec<String>,
+    /// Whether a Ctrl-R history search is showing, shared with [`AcceptSearchMatch`].
+    searching: Arc<AtomicBool>,
+}
+
+impl ShellHelper {
+    /// Creates the helper.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode_switch` - The word that switches modes, completed like a builtin.
+    ///
+    /// # Returns
+    ///
+    /// * `ShellHelper` - The helper.
+    pub(crate) fn new(mode_switch: &str) -> Self {
+        let mut shell_commands: Vec<String> = SHELL_MODE_COMMANDS.iter().map(|name| name.to_string()).collect();
+        shell_commands.push(mode_switch.to_string());
+        Self {
+            files: FilenameCompleter::new(),
+            complete_commands: false,
+            shell_commands,
+            searching: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Returns the handler to bind to Enter, so it ends a Ctrl-R search with the match left
+    /// on the line for editing instead of running it.
+    pub(crate) fn accept_search_match(&self) -> AcceptSearchMatch {
+        AcceptSearchMatch(Arc::clone(&self.searching))
+    }
+}
+
+/// Makes Enter during a Ctrl-R history search put the match on the line, with the cursor
+/// at its end, rather than run it; Enter runs the line as usual otherwise.
+pub(crate) struct AcceptSearchMatch(Arc<AtomicBool>);
+
+impl ConditionalEventHandler for AcceptSearchMatch {
+    fn handle(&self, _evt: &Event, _n: RepeatCount, _positive: bool, _ctx: &EventContext) -> Option<Cmd> {
+        // Moving the cursor ends a search and keeps the match
+        self.0.swap(false, Ordering::SeqCst).then_some(Cmd::Move(Movement::EndOfLine))
+    }
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, mut paths) = self.files.complete_path(line, pos)?;
+        let word = &line[start..pos];
+        if self.complete_commands && line[..start].trim().is_empty() && !word.contains('/') && !word.is_empty() {
+            return Ok((start, complete_program(word, &self.shell_commands)));
+        }
+        // A single file is finished off like the shell does, closing the quote the path was
+        // started with; a directory is left open for the next part of the path
+        if let [path] = paths.as_mut_slice() {
+            if !path.replacement.ends_with('/') {
+                if let Some(quote) = unclosed_quote(&line[..start]) {
+                    path.replacement.push(quote);
+                }
+                path.replacement.push(' ');
+            }
+        }
+        Ok((start, paths))
+    }
+}
+
+/// Returns the quote a path being completed was started with, if it is still open.
+fn unclosed_quote(before_path: &str) -> Option<char> {
+    let quote = before_path.chars().last().filter(|c| matches!(c, '"' | '\''))?;
+    (before_path.matches(quote).count() % 2 == 1).then_some(quote)
+}
+
+/// Lists the programs on `PATH`, and the words shell mode handles itself, that start with
+/// a prefix.
+///
+/// # Arguments
+///
+/// * `prefix` - What has been typed of the program name.
+/// * `shell_commands` - The words shell mode handles itself.
+///
+/// # Returns
+///
+/// * `Vec<Pair>` - The matching names in order, each followed by a space if it is the
+///   only match.
+fn complete_program(prefix: &str, shell_commands: &[String]) -> Vec<Pair> {
+    let mut names: BTreeSet<String> =
+        shell_commands.iter().filter(|name| name.starts_with(prefix)).cloned().collect();
+    let path = env::var_os("PATH").unwrap_or_default();
+    for dir in env::split_paths(&path) {
+        let Ok(entries) = fs::read_dir(dir) else { continue };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(prefix) && !names.contains(&name) && is_executable(&entry) {
+                names.insert(name);
+            }
+        }
+    }
+
+    let only = names.len() == 1;
+    names
+        .into_iter()
+        .map(|name| {
+            let escaped = name.replace(' ', "\\ ");
+            Pair {
+                display: name,
+                replacement: if only { format!("{} ", escaped) } else { escaped },
+            }
+        })
+        .collect()
+}
+
+/// Checks whether a directory entry is a program that can be run.
+#[cfg(unix)]
+fn is_executable(entry: &fs::DirEntry) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    // Follows symlinks, which is how many programs are installed
+    fs::metadata(entry.path()).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(entry: &fs::DirEntry) -> bool {
+    fs::metadata(entry.path()).is_ok_and(|metadata| metadata.is_file())
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {
+    // The search shows its own prompt in place of the usual one, which is how a search is
+    // told apart from editing
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(&'s self, prompt: &'p str, default: bool) -> Cow<'b, str> {
+        self.searching.store(!default, Ordering::SeqCst);
+        Cow::Borrowed(prompt)
+    }
+}
+
+impl Validator for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completions(prefix: &str, mode_switch: &str) -> Vec<String> {
+        complete_program(prefix, &ShellHelper::new(mode_switch).shell_commands)
+            .into_iter()
+            .map(|pair| pair.replacement)
+            .collect()
+    }
+
+    #[test]
+    fn the_configured_mode_switch_word_is_completed() {
+        assert_eq!(completions(":mo", ":mode"), [":mode "]);
+        assert!(completions(":mo", "youdu").is_empty());
+        assert!(completions("youd", ":mode").iter().all(|name| !name.starts_with("youdu")));
+    }
+
+    #[test]
+    fn builtins_are_completed() {
+        assert!(completions("expo", "youdu").iter().any(|name| name.trim_end() == "export"));
+        assert!(completions("youd", "youdu").iter().any(|name| name.trim_end() == "youdu"));
+    }
+}
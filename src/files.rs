@@ -0,0 +1,391 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    cmp::Ordering,
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+use serde_json::{json, Value};
+
+use crate::utils::decode_text;
+
+/// Maximum number of bytes of a file sent to the assistant by `read_file`.
+pub(crate) const MAX_READ_FILE_BYTES: usize = 64 * 1024;
+
+/// Resolves a path requested by the assistant against a base directory, following
+/// symbolic links and `..` so the result can be checked with [`is_within`].
+/// A leading `~/` refers to the home directory.
+///
+/// # Arguments
+///
+/// * `path` - The path as given by the assistant.
+/// * `base` - The directory relative paths are resolved against, already canonical.
+///
+/// # Returns
+///
+/// * `io::Result<PathBuf>` - The canonical path, or an error if it doesn't exist.
+pub(crate) fn resolve_path(path: &str, base: &Path) -> io::Result<PathBuf> {
+    base.join(expand_home(path)).canonicalize()
+}
+
+/// Resolves a path the assistant wants to write, which may not exist yet. The deepest
+/// part of the path that exists is resolved as in [`resolve_path`] and the missing
+/// directories and file name are appended to it.
+///
+/// # Arguments
+///
+/// * `path` - The path as given by the assistant.
+/// * `base` - The directory relative paths are resolved against, already canonical.
+///
+/// # Returns
+///
+/// * `io::Result<PathBuf>` - The absolute path, or an error if it can't be resolved,
+///   e.g. because it uses `..` below a directory that doesn't exist.
+pub(crate) fn resolve_target_path(path: &str, base: &Path) -> io::Result<PathBuf> {
+    let full = base.join(expand_home(path));
+    let mut existing = full.as_path();
+    let mut missing = Vec::new();
+    // symlink_metadata rather than exists(), so a dangling link is resolved (and fails)
+    // instead of being written through to wherever it points.
+    while fs::symlink_metadata(existing).is_err() {
+        let (Some(name), Some(parent)) = (existing.file_name(), existing.parent()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the path uses '..' below a directory that doesn't exist",
+            ));
+        };
+        missing.push(name);
+        existing = parent;
+    }
+
+    let mut resolved = existing.canonicalize()?;
+    resolved.extend(missing.iter().rev());
+    Ok(resolved)
+}
+
+/// Expands a leading `~/` to the home directory.
+pub(crate) fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Checks whether a path lies inside a directory tree. Both paths must be canonical;
+/// the comparison is by whole components, so `/srv/app2` is not inside `/srv/app`.
+///
+/// # Arguments
+///
+/// * `path` - The canonical path to check.
+/// * `base` - The canonical directory.
+///
+/// # Returns
+///
+/// * `bool` - `true` if `path` is `base` or below it.
+pub(crate) fn is_within(path: &Path, base: &Path) -> bool {
+    path.starts_with(base)
+}
+
+/// Reads a text file for the assistant. Files larger than [`MAX_READ_FILE_BYTES`] are
+/// truncated with a notice, and binary files are rejected.
+///
+/// # Arguments
+///
+/// * `path` - The file to read.
+///
+/// # Returns
+///
+/// * `Result<String, String>` - The file's text, or an error message.
+pub(crate) fn read_text_file(path: &Path) -> Result<String, String> {
+    let read_error = |e: io::Error| format!("Error: Could not read '{}': {}", path.display(), e);
+
+    let metadata = fs::metadata(path).map_err(read_error)?;
+    if !metadata.is_file() {
+        return Err(format!("Error: '{}' is not a regular file.", path.display()));
+    }
+
+    let mut buffer = Vec::new();
+    fs::File::open(path)
+        .and_then(|file| file.take(MAX_READ_FILE_BYTES as u64 + 1).read_to_end(&mut buffer))
+        .map_err(read_error)?;
+
+    let truncated = buffer.len() > MAX_READ_FILE_BYTES;
+    buffer.truncate(MAX_READ_FILE_BYTES);
+
+    let mut text = decode_text(buffer, truncated).ok_or_else(|| {
+        format!(
+            "Error: '{}' appears to be a binary file. Only text files can be read.",
+            path.display()
+        )
+    })?;
+
+    if truncated {
+        text.push_str(&format!(
+            "\n[... file truncated to the first {} of {} bytes ...]",
+            MAX_READ_FILE_BYTES,
+            metadata.len()
+        ));
+    }
+    Ok(text)
+}
+
+/// Converts a shell-style glob (`*`, `?`, and `[...]`) for file names into a regex.
+///
+/// # Arguments
+///
+/// * `glob` - The pattern, e.g. `*.log`.
+///
+/// # Returns
+///
+/// * `Result<Regex, String>` - The compiled pattern, or an error message.
+pub(crate) fn glob_to_regex(glob: &str) -> Result<Regex, String> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            '[' => {
+                pattern.push('[');
+                if chars.next_if(|&c| c == '!' || c == '^').is_some() {
+                    pattern.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if matches!(c, '\\' | '[' | '&' | '~') {
+                        pattern.push('\\');
+                    }
+                    pattern.push(c);
+                }
+                pattern.push(']');
+            }
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).map_err(|_| format!("Error: '{}' is not a valid glob pattern.", glob))
+}
+
+/// Expands a path pattern such as `src/*.rs` into the files it matches, sorted by path.
+/// Any component may use the wildcards of [`glob_to_regex`]; `*` doesn't match `/`, and
+/// names starting with a dot are only matched by a pattern that starts with one.
+/// A path without wildcards is returned as it is, whether it exists or not.
+///
+/// # Arguments
+///
+/// * `pattern` - The pattern; a leading `~/` refers to the home directory.
+///
+/// # Returns
+///
+/// * `Result<Vec<PathBuf>, String>` - The matching files, or an error message if there
+///   are none.
+pub(crate) fn expand_glob(pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let is_glob = |text: &str| text.contains(['*', '?', '[']);
+    let path = expand_home(pattern);
+    if !is_glob(pattern) {
+        return Ok(vec![path]);
+    }
+
+    let mut matches = vec![PathBuf::new()];
+    let components: Vec<_> = path.components().collect();
+    for (index, component) in components.iter().enumerate() {
+        let name = component.as_os_str().to_string_lossy();
+        if !is_glob(&name) {
+            matches.iter_mut().for_each(|path| path.push(component));
+            continue;
+        }
+        let regex = glob_to_regex(&name)?;
+        let last = index == components.len() - 1;
+        let mut expanded = Vec::new();
+        for dir in &matches {
+            let listing = if dir.as_os_str().is_empty() { Path::new(".") } else { dir.as_path() };
+            let Ok(entries) = fs::read_dir(listing) else { continue };
+            for entry in entries.flatten() {
+                let entry_name = entry.file_name().to_string_lossy().into_owned();
+                if entry_name.starts_with('.') && !name.starts_with('.') || !regex.is_match(&entry_name) {
+                    continue;
+                }
+                let path = dir.join(&entry_name);
+                // Only files are matched by the last component, and only directories before it
+                if if last { path.is_file() } else { path.is_dir() } {
+                    expanded.push(path);
+                }
+            }
+        }
+        matches = expanded;
+    }
+
+    if matches.is_empty() {
+        return Err(format!("Error: No files match '{}'.", pattern));
+    }
+    matches.sort();
+    Ok(matches)
+}
+
+/// Lists a directory for the assistant, sorted by name.
+///
+/// # Arguments
+///
+/// * `dir` - The directory to list.
+/// * `pattern` - Only names matching this pattern are listed, if given.
+/// * `include_hidden` - Whether names starting with a dot are listed.
+/// * `max_entries` - The most entries returned; the result says when there were more.
+///
+/// # Returns
+///
+/// * `Result<Value, String>` - The listing as JSON, with each entry's `name`, `type`
+///   (`file`, `directory`, `symlink`, or `other`), and `size` in bytes, or an error message.
+pub(crate) fn list_directory(
+    dir: &Path,
+    pattern: Option<&Regex>,
+    include_hidden: bool,
+    max_entries: usize,
+) -> Result<Value, String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("Error: Could not list '{}': {}", dir.display(), e))?;
+
+    let mut listed: Vec<(String, fs::Metadata)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !include_hidden && name.starts_with('.') {
+                return None;
+            }
+            if pattern.is_some_and(|pattern| !pattern.is_match(&name)) {
+                return None;
+            }
+            // DirEntry::metadata doesn't follow symbolic links, so links are reported as such
+            Some((name, entry.metadata().ok()?))
+        })
+        .collect();
+    listed.sort_by(|a, b| match a.0.to_lowercase().cmp(&b.0.to_lowercase()) {
+        Ordering::Equal => a.0.cmp(&b.0),
+        order => order,
+    });
+
+    let total = listed.len();
+    let entries: Vec<Value> = listed
+        .into_iter()
+        .take(max_entries)
+        .map(|(name, metadata)| {
+            let file_type = metadata.file_type();
+            let kind = if file_type.is_symlink() {
+                "symlink"
+            } else if file_type.is_dir() {
+                "directory"
+            } else if file_type.is_file() {
+                "file"
+            } else {
+                "other"
+            };
+            json!({ "name": name, "type": kind, "size": metadata.len() })
+        })
+        .collect();
+
+    let mut listing = json!({ "path": dir.display().to_string(), "entries": entries });
+    if total > max_entries {
+        listing["truncated"] = json!(true);
+        listing["total_entries"] = json!(total);
+    }
+    Ok(listing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::test_dir;
+
+    /// Creates a project directory with a sibling whose name shares its prefix and a file
+    /// outside both, returning the canonical project directory.
+    fn project_tree(name: &str) -> PathBuf {
+        let root = test_dir(name);
+        let project = root.join("project");
+        fs::create_dir_all(project.join("sub")).unwrap();
+        fs::create_dir_all(root.join("project2")).unwrap();
+        fs::write(project.join("notes.txt"), "notes").unwrap();
+        fs::write(project.join("sub").join("inner.txt"), "inner").unwrap();
+        fs::write(root.join("project2").join("file.txt"), "sibling").unwrap();
+        fs::write(root.join("outside.txt"), "outside").unwrap();
+        project.canonicalize().unwrap()
+    }
+
+    /// Resolves a path against the project and reports whether it stays inside.
+    fn resolves_within(path: &str, base: &Path) -> bool {
+        is_within(&resolve_path(path, base).unwrap(), base)
+    }
+
+    #[test]
+    fn relative_paths_inside_the_directory_are_within() {
+        let base = project_tree("within");
+        assert!(resolves_within("notes.txt", &base));
+        assert!(resolves_within("./sub/inner.txt", &base));
+        assert!(resolves_within("sub/../notes.txt", &base));
+        assert!(resolves_within("../project/notes.txt", &base));
+        assert!(resolves_within(".", &base));
+    }
+
+    #[test]
+    fn parent_directories_are_outside() {
+        let base = project_tree("parent");
+        assert!(!resolves_within("../outside.txt", &base));
+        assert!(!resolves_within("sub/../../outside.txt", &base));
+        assert!(!resolves_within("..", &base));
+        // Compared by whole components, not as strings
+        assert!(!resolves_within("../project2/file.txt", &base));
+    }
+
+    #[test]
+    fn absolute_paths_are_checked_too() {
+        let base = project_tree("absolute");
+        assert!(resolves_within(base.join("notes.txt").to_str().unwrap(), &base));
+        assert!(!resolves_within(base.parent().unwrap().join("outside.txt").to_str().unwrap(), &base));
+        assert!(!resolves_within("/", &base));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symbolic_links_are_followed() {
+        let base = project_tree("symlinks");
+        let _ = fs::remove_file(base.join("escape"));
+        let _ = fs::remove_file(base.join("shortcut"));
+        std::os::unix::fs::symlink("../outside.txt", base.join("escape")).unwrap();
+        std::os::unix::fs::symlink("sub/inner.txt", base.join("shortcut")).unwrap();
+
+        assert_eq!(resolve_path("escape", &base).unwrap(), base.parent().unwrap().join("outside.txt"));
+        assert!(!resolves_within("escape", &base));
+        assert!(resolves_within("shortcut", &base));
+    }
+
+    #[test]
+    fn missing_files_are_errors() {
+        let base = project_tree("missing");
+        assert_eq!(resolve_path("nope.txt", &base).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn new_files_resolve_through_their_existing_parent() {
+        let base = project_tree("target");
+        assert_eq!(resolve_target_path("new/dir/file.txt", &base).unwrap(), base.join("new/dir/file.txt"));
+        assert!(is_within(&resolve_target_path("sub/new.txt", &base).unwrap(), &base));
+        assert!(!is_within(&resolve_target_path("../new.txt", &base).unwrap(), &base));
+        assert!(resolve_target_path("new/../../escape.txt", &base).is_err());
+    }
+}
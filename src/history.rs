@@ -0,0 +1,280 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use rustyline::config::Builder;
+
+use crate::{files::expand_home, models::Config};
+
+/// The history file gptsh used to write to whatever directory it was started in, still
+/// used with `project_history`.
+const PROJECT_HISTORY_FILE: &str = ".gptsh_history";
+
+/// The most entries kept in the history unless `history_max` is set; the oldest are
+/// dropped first.
+const DEFAULT_HISTORY_MAX: usize = 1000;
+
+/// The first line of a history file in rustyline's format, in which backslashes and line
+/// breaks in entries are escaped.
+const HISTORY_HEADER: &str = "#V2";
+
+/// Works out where shell mode keeps its history: `history_file` if set, `.gptsh_history`
+/// in the working directory with `project_history`, and otherwise `gptsh/history` in the
+/// data directory (`$XDG_DATA_HOME`, or `~/.local/share`).
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration.
+///
+/// # Returns
+///
+/// * `Option<PathBuf>` - The absolute path of the history file, or `None` if there is no
+///   home directory to keep it in.
+pub(crate) fn history_path(config: &Config) -> Option<PathBuf> {
+    // Made absolute now, since `cd` in shell mode changes the working directory
+    let current_dir = env::current_dir().ok()?;
+    if let Some(path) = &config.history_file {
+        return Some(current_dir.join(expand_home(path)));
+    }
+    if config.project_history.unwrap_or(false) {
+        return Some(current_dir.join(PROJECT_HISTORY_FILE));
+    }
+    let data_dir = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| dirs::home_dir().map(|home| home.join(".local").join("share")))?;
+    Some(data_dir.join("gptsh").join("history"))
+}
+
+/// Applies the history settings to the line editor's configuration: only the newest
+/// `history_max` entries are kept, in memory and in the file, and an entry repeating the one
+/// before it is skipped unless `history_ignore_dups` is `false`.
+///
+/// # Arguments
+///
+/// * `builder` - The line editor's configuration so far.
+/// * `config` - The loaded configuration.
+///
+/// # Returns
+///
+/// * `rustyline::Result<Builder>` - The configuration, or an error if the limit is invalid.
+pub(crate) fn configure_history(builder: Builder, config: &Config) -> rustyline::Result<Builder> {
+    builder
+        .max_history_size(config.history_max.unwrap_or(DEFAULT_HISTORY_MAX))?
+        .history_ignore_dups(config.history_ignore_dups.unwrap_or(true))
+}
+
+/// Works out what a line adds to the history. Like a shell's `HISTCONTROL=ignorespace`, a
+/// line typed after a space can be kept out of it.
+///
+/// # Arguments
+///
+/// * `line` - The line as typed.
+/// * `ignore_space` - Whether `history_ignore_space` is set.
+///
+/// # Returns
+///
+/// * `Option<&str>` - The entry, without surrounding whitespace, or `None` if the line is
+///   blank or kept out.
+pub(crate) fn history_entry(line: &str, ignore_space: bool) -> Option<&str> {
+    let entry = line.trim();
+    let kept_out = entry.is_empty() || (ignore_space && line.starts_with(' '));
+    (!kept_out).then_some(entry)
+}
+
+/// Prepares the history file: creates its directory, and moves the entries of a
+/// `.gptsh_history` left in the working directory by earlier versions into it, deleting
+/// the old file. Problems are reported as warnings, since shell mode works without history.
+///
+/// # Arguments
+///
+/// * `path` - The history file, as returned by [`history_path`].
+pub(crate) fn prepare_history_file(path: &Path) {
+    if let Some(dir) = path.parent() {
+        if let Err(e) = fs::create_dir_all(dir) {
+            eprintln!("Warning: Could not create {}: {}", dir.display(), e);
+            return;
+        }
+    }
+
+    let project_file = match env::current_dir() {
+        Ok(dir) => dir.join(PROJECT_HISTORY_FILE),
+        Err(_) => return,
+    };
+    if project_file == path || !project_file.is_file() {
+        return;
+    }
+    match merge_history(&project_file, path).and_then(|()| fs::remove_file(&project_file)) {
+        Ok(()) => println!(
+            "Note: Moved the shell history in {} to {}.",
+            PROJECT_HISTORY_FILE,
+            path.display()
+        ),
+        Err(e) => eprintln!(
+            "Warning: Could not move the shell history in {} to {}: {}",
+            PROJECT_HISTORY_FILE,
+            path.display(),
+            e
+        ),
+    }
+}
+
+/// Appends the entries of one history file to another, so they become the most recent.
+fn merge_history(from: &Path, to: &Path) -> io::Result<()> {
+    let contents = fs::read_to_string(from)?;
+    let mut lines = contents.lines().peekable();
+    // Files from before rustyline's current format hold entries as they are
+    let escaped = lines.next_if(|line| *line == HISTORY_HEADER).is_some();
+    let entries: Vec<String> = lines
+        .filter(|line| !line.is_empty())
+        .map(|line| if escaped { line.to_string() } else { line.replace('\\', "\\\\") })
+        .collect();
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    let is_new = fs::metadata(to).map_or(true, |metadata| metadata.len() == 0);
+    let mut file = OpenOptions::new().create(true).append(true).open(to)?;
+    if is_new {
+        writeln!(file, "{}", HISTORY_HEADER)?;
+    }
+    for entry in entries {
+        writeln!(file, "{}", entry)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustyline::history::{FileHistory, History};
+
+    use crate::utils::testing::test_dir;
+
+    /// Returns a path in the temporary directory for a test's history file, removing any
+    /// file left by an earlier run.
+    fn history_file(name: &str) -> PathBuf {
+        test_dir(&format!("history-{}", name)).join(name)
+    }
+
+    /// Starts a session's history with the given settings, loading the file if it exists.
+    fn open(path: &Path, history_max: usize, ignore_dups: bool) -> FileHistory {
+        let config = Config { history_max: Some(history_max), history_ignore_dups: Some(ignore_dups), ..Config::default() };
+        let mut history = FileHistory::with_config(configure_history(rustyline::Config::builder(), &config).unwrap().build());
+        if path.exists() {
+            history.load(path).unwrap();
+        }
+        history
+    }
+
+    fn add(history: &mut FileHistory, entries: &[&str]) {
+        for entry in entries {
+            history.add(entry).unwrap();
+        }
+    }
+
+    fn entries(history: &FileHistory) -> Vec<&str> {
+        history.iter().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn the_oldest_entries_are_dropped_past_the_limit() {
+        let path = history_file("limit");
+        let mut history = open(&path, 3, true);
+        add(&mut history, &["one", "two", "three", "four", "five"]);
+        assert_eq!(entries(&history), ["three", "four", "five"]);
+        history.append(&path).unwrap();
+
+        assert_eq!(entries(&open(&path, 3, true)), ["three", "four", "five"]);
+        // A lower limit trims what an earlier session saved
+        assert_eq!(entries(&open(&path, 2, true)), ["four", "five"]);
+    }
+
+    #[test]
+    fn sessions_add_to_the_saved_history() {
+        let path = history_file("sessions");
+        let mut first = open(&path, 4, true);
+        let mut second = open(&path, 4, true);
+        add(&mut first, &["one", "two", "three"]);
+        add(&mut second, &["four", "five"]);
+        first.append(&path).unwrap();
+        second.append(&path).unwrap();
+
+        assert_eq!(entries(&open(&path, 4, true)), ["two", "three", "four", "five"]);
+    }
+
+    #[test]
+    fn repeated_entries_are_kept_once() {
+        let path = history_file("dups");
+        let mut history = open(&path, 10, true);
+        add(&mut history, &["ls", "ls", "ls", "git status", "ls"]);
+        assert_eq!(entries(&history), ["ls", "git status", "ls"]);
+        history.append(&path).unwrap();
+        assert_eq!(entries(&open(&path, 10, true)), ["ls", "git status", "ls"]);
+
+        let mut history = open(&history_file("all"), 10, false);
+        add(&mut history, &["ls", "ls"]);
+        assert_eq!(entries(&history), ["ls", "ls"]);
+    }
+
+    #[test]
+    fn multi_line_entries_survive_saving() {
+        let path = history_file("multi");
+        let mut history = open(&path, 10, true);
+        add(&mut history, &["for f in *; do\n  echo \"$f\\n\"\ndone"]);
+        history.append(&path).unwrap();
+        assert_eq!(entries(&open(&path, 10, true)), ["for f in *; do\n  echo \"$f\\n\"\ndone"]);
+    }
+
+    #[test]
+    fn lines_after_a_space_can_be_kept_out() {
+        assert_eq!(history_entry("  ls -la ", false), Some("ls -la"));
+        assert_eq!(history_entry(" export TOKEN=secret", true), None);
+        assert_eq!(history_entry("ls", true), Some("ls"));
+        assert_eq!(history_entry("   ", false), None);
+    }
+
+    #[test]
+    fn old_project_histories_are_merged() {
+        let old = history_file("old");
+        let new = history_file("new");
+        fs::write(&old, "ls\n\necho a\\b\n").unwrap();
+        let mut history = open(&new, 10, true);
+        add(&mut history, &["pwd"]);
+        history.append(&new).unwrap();
+
+        merge_history(&old, &new).unwrap();
+        assert_eq!(entries(&open(&new, 10, true)), ["pwd", "ls", "echo a\\b"]);
+    }
+
+    #[test]
+    fn saving_after_every_entry_keeps_each_once() {
+        let path = history_file("every");
+        let mut history = open(&path, 10, true);
+        for entry in ["one", "two", "three"] {
+            add(&mut history, &[entry]);
+            history.append(&path).unwrap();
+        }
+        history.append(&path).unwrap();
+        assert_eq!(entries(&open(&path, 10, true)), ["one", "two", "three"]);
+    }
+}
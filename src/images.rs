@@ -0,0 +1,151 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fs;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+
+use crate::files::expand_home;
+
+/// The largest image that can be attached, which is the most any supported provider accepts.
+const MAX_IMAGE_BYTES: u64 = 5 * 1024 * 1024;
+
+/// Roughly how many tokens an attached image takes up in a request, for trimming history
+/// and `/tokens`. Detailed images cost about this much with OpenAI and Anthropic alike.
+pub(crate) const IMAGE_TOKEN_ESTIMATE: usize = 1_000;
+
+/// What an image in an earlier message is replaced with once it has been dropped.
+const REMOVED_IMAGE_NOTE: &str = "[An image was attached here; it was removed to save space.]";
+
+/// Reads an image to attach to a chat message. PNG, JPEG, and WebP are accepted; the type
+/// is taken from the file's contents, not its name.
+///
+/// # Arguments
+///
+/// * `path` - The path as typed by the user; a leading `~/` refers to the home directory.
+///
+/// # Returns
+///
+/// * `Result<String, String>` - The image as a base64 `data:` URL, or an error message.
+pub(crate) fn load_image(path: &str) -> Result<String, String> {
+    let path = expand_home(path);
+    let metadata = fs::metadata(&path).map_err(|e| format!("Error: Could not read '{}': {}", path.display(), e))?;
+    if !metadata.is_file() {
+        return Err(format!("Error: '{}' is not a file.", path.display()));
+    }
+    if metadata.len() > MAX_IMAGE_BYTES {
+        return Err(format!(
+            "Error: '{}' is {:.1}MB; images can be at most {}MB.",
+            path.display(),
+            metadata.len() as f64 / (1024.0 * 1024.0),
+            MAX_IMAGE_BYTES / (1024 * 1024)
+        ));
+    }
+    let bytes = fs::read(&path).map_err(|e| format!("Error: Could not read '{}': {}", path.display(), e))?;
+    let media_type = detect_media_type(&bytes).ok_or_else(|| {
+        format!("Error: '{}' is not a PNG, JPEG, or WebP image.", path.display())
+    })?;
+    Ok(format!("data:{};base64,{}", media_type, STANDARD.encode(bytes)))
+}
+
+/// Identifies a PNG, JPEG, or WebP image from its first bytes.
+fn detect_media_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// Builds the multi-part `content` of a user message with an image.
+///
+/// # Arguments
+///
+/// * `text` - What the user asked about the image.
+/// * `data_url` - The image, as returned by [`load_image`].
+///
+/// # Returns
+///
+/// * `Value` - The content array: the text, then the image.
+pub(crate) fn image_content(text: &str, data_url: &str) -> Value {
+    json!([
+        { "type": "text", "text": text },
+        { "type": "image_url", "image_url": { "url": data_url } }
+    ])
+}
+
+/// Returns the text of a message, whether its content is a string or a list of parts.
+///
+/// # Arguments
+///
+/// * `message` - A message in the chat-completions format.
+///
+/// # Returns
+///
+/// * `String` - The text, with the text parts of a multi-part message joined by blank lines.
+pub(crate) fn message_text(message: &Value) -> String {
+    match &message["content"] {
+        Value::Array(parts) => parts
+            .iter()
+            .filter_map(|part| part["text"].as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n"),
+        content => content.as_str().unwrap_or_default().to_string(),
+    }
+}
+
+/// Counts the images in a message.
+pub(crate) fn count_images(message: &Value) -> usize {
+    message["content"]
+        .as_array()
+        .map_or(0, |parts| parts.iter().filter(|part| part["type"] == "image_url").count())
+}
+
+/// Replaces the images in a message with a note, turning it back into a plain text message.
+///
+/// # Arguments
+///
+/// * `message` - The message to change.
+///
+/// # Returns
+///
+/// * `usize` - How many images were removed.
+pub(crate) fn remove_images(message: &mut Value) -> usize {
+    let removed = count_images(message);
+    if removed > 0 {
+        let text = message_text(message);
+        message["content"] = Value::from(format!("{}\n\n{}", text, REMOVED_IMAGE_NOTE).trim_start());
+    }
+    removed
+}
+
+/// Splits a base64 `data:` URL into its media type and data.
+///
+/// # Arguments
+///
+/// * `url` - The URL, e.g. `data:image/png;base64,iVBOR...`.
+///
+/// # Returns
+///
+/// * `Option<(&str, &str)>` - The media type and the base64 data, or `None` for other URLs.
+pub(crate) fn split_data_url(url: &str) -> Option<(&str, &str)> {
+    url.strip_prefix("data:")?.split_once(";base64,")
+}
@@ -0,0 +1,478 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicI32, Ordering};
+#[cfg(unix)]
+use std::thread;
+use std::time::Duration;
+#[cfg(unix)]
+use std::time::Instant;
+
+use crate::command_shell::shell_command;
+
+/// The process group of the command running in the foreground, or 0 if none is.
+static FOREGROUND_GROUP: AtomicI32 = AtomicI32::new(0);
+
+/// How often a command is checked on while there is more to do than wait for it.
+#[cfg(unix)]
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Starts a command in a process group of its own and gives it the terminal, the way a
+/// shell starts a foreground job, so Ctrl-C and Ctrl-Z reach the command alone instead of
+/// gptsh too. Wait for it with [`wait_in_foreground`], which takes the terminal back.
+///
+/// # Arguments
+///
+/// * `command` - The command, with its arguments and streams set up.
+///
+/// # Returns
+///
+/// * `io::Result<Child>` - The running command, or an error if it couldn't be started.
+pub(crate) fn spawn_in_foreground(command: &mut Command) -> io::Result<Child> {
+    let child = spawn_in_own_group(command)?;
+    bring_to_foreground(&child);
+    Ok(child)
+}
+
+/// Starts a command in a process group of its own, which signals from the terminal don't
+/// reach unless it is given the terminal.
+#[cfg(unix)]
+fn spawn_in_own_group(command: &mut Command) -> io::Result<Child> {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0).spawn()
+}
+
+/// Starts a command.
+#[cfg(not(unix))]
+fn spawn_in_own_group(command: &mut Command) -> io::Result<Child> {
+    command.spawn()
+}
+
+/// Makes a command started in its own process group the one in the foreground: it gets
+/// the terminal, if gptsh has it, and Ctrl-C is sent on to it.
+#[cfg(unix)]
+fn bring_to_foreground(child: &Child) {
+    use rustix::process::{getpgrp, Pid};
+
+    let group = Pid::from_child(child);
+    FOREGROUND_GROUP.store(group.as_raw_nonzero().get(), Ordering::SeqCst);
+    // If gptsh was started in the background, the terminal isn't its to give
+    if terminal_group() == Some(getpgrp()) {
+        set_terminal_group(group);
+    }
+}
+
+/// Does nothing; only unix has process groups.
+#[cfg(not(unix))]
+fn bring_to_foreground(_child: &Child) {}
+
+/// Waits for a command started with [`spawn_in_foreground`] and takes the terminal back.
+/// If the command is stopped with Ctrl-Z, gptsh stops too, as it did when they shared a
+/// process group, and continues the command when it is continued itself.
+///
+/// # Arguments
+///
+/// * `child` - The running command.
+/// * `notice_after` - How long the command may run before the user is told it is still
+///   running and asked whether to kill it, or `None` to wait quietly however long it takes.
+///
+/// # Returns
+///
+/// * `io::Result<ExitStatus>` - How the command ended, whether it exited or was ended by a
+///   signal.
+#[cfg(unix)]
+pub(crate) fn wait_in_foreground(child: &mut Child, notice_after: Option<Duration>) -> io::Result<ExitStatus> {
+    use std::os::unix::process::ExitStatusExt;
+    use rustix::io::Errno;
+    use rustix::process::{getpgrp, getpid, kill_process, kill_process_group, waitpid, Pid, Signal, WaitOptions};
+
+    let group = Pid::from_child(child);
+    let started = Instant::now();
+    let mut notice_after = notice_after;
+    // Set while the user is asked whether to kill the command
+    let mut kill_prompt: Option<KillPrompt> = None;
+    let result = loop {
+        // Blocking is only right when there is nothing to do until the command changes
+        let options = if notice_after.is_some() || kill_prompt.is_some() {
+            WaitOptions::NOHANG | WaitOptions::UNTRACED
+        } else {
+            WaitOptions::UNTRACED
+        };
+        let status = match waitpid(Some(group), options) {
+            Ok(Some(status)) => status,
+            Ok(None) if options.contains(WaitOptions::NOHANG) => {
+                if notice_after.is_some_and(|after| started.elapsed() >= after) {
+                    notice_after = None;
+                    kill_prompt = KillPrompt::show(group, started.elapsed());
+                }
+                match kill_prompt.as_ref().map(KillPrompt::read_answer) {
+                    Some(Some(kill)) => {
+                        // Hands the terminal back before the command goes on or is killed
+                        kill_prompt = None;
+                        if kill {
+                            println!("Killing it.");
+                            let _ = kill_process_group(group, Signal::Kill);
+                        }
+                    }
+                    Some(None) => {}
+                    None => thread::sleep(POLL_INTERVAL),
+                }
+                continue;
+            }
+            Ok(None) | Err(Errno::INTR) => continue,
+            Err(e) => break Err(io::Error::from(e)),
+        };
+        let Some(signal) = status.stopping_signal() else {
+            break Ok(ExitStatus::from_raw(status.as_raw() as i32));
+        };
+        let tried_terminal = signal == Signal::Ttin as u32 || signal == Signal::Ttou as u32;
+        // A command that used the terminal while the user is being asked about it waits
+        // for the answer, and is continued when it gets the terminal back
+        if tried_terminal && kill_prompt.is_some() {
+            continue;
+        }
+        // A command that used the terminal in the moment before it was handed over only
+        // needs to be continued; anything else is Ctrl-Z or a stop signal
+        if !(tried_terminal && terminal_group() == Some(group)) {
+            let had_terminal = terminal_group() == Some(group);
+            if had_terminal {
+                set_terminal_group(getpgrp());
+            }
+            let _ = kill_process(getpid(), Signal::Tstp);
+            if had_terminal {
+                set_terminal_group(group);
+            }
+        }
+        let _ = kill_process_group(group, Signal::Cont);
+    };
+    drop(kill_prompt);
+    FOREGROUND_GROUP.store(0, Ordering::SeqCst);
+    if terminal_group() == Some(group) {
+        set_terminal_group(getpgrp());
+    }
+    result
+}
+
+/// Waits for a command. There is no way to ask about one that runs long without taking
+/// the terminal from it, so it is waited for quietly.
+#[cfg(not(unix))]
+pub(crate) fn wait_in_foreground(child: &mut Child, _notice_after: Option<Duration>) -> io::Result<ExitStatus> {
+    child.wait()
+}
+
+/// The question asked when a command runs long, whether to kill it. The terminal is taken
+/// from the command to read the answer, a key at a time without echo, and given back with
+/// its settings restored when the value is dropped.
+#[cfg(unix)]
+struct KillPrompt {
+    group: rustix::process::Pid,
+    settings: rustix::termios::Termios,
+}
+
+#[cfg(unix)]
+impl KillPrompt {
+    /// Says that a command is still running and, if it has the terminal, asks whether to
+    /// kill it.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - The command's process group.
+    /// * `elapsed` - How long it has been running.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Self>` - The question, or `None` if there is no terminal to read an
+    ///   answer from.
+    fn show(group: rustix::process::Pid, elapsed: Duration) -> Option<Self> {
+        use rustix::termios::{tcgetattr, tcsetattr, LocalModes, OptionalActions, SpecialCodeIndex};
+
+        let notice = format!("Still running after {}s (pid {}).", elapsed.as_secs(), group.as_raw_nonzero());
+        let settings = tcgetattr(io::stdin()).ok().filter(|_| terminal_group() == Some(group));
+        let Some(settings) = settings else {
+            println!("\n{}", notice);
+            return None;
+        };
+        set_terminal_group(rustix::process::getpgrp());
+        let mut keys = settings.clone();
+        keys.local_modes.remove(LocalModes::ICANON | LocalModes::ECHO);
+        keys.special_codes[SpecialCodeIndex::VMIN] = 1;
+        keys.special_codes[SpecialCodeIndex::VTIME] = 0;
+        let _ = tcsetattr(io::stdin(), OptionalActions::Now, &keys);
+        println!("\n{} Press k to kill it, or Enter to keep waiting.", notice);
+        Some(Self { group, settings })
+    }
+
+    /// Reads the answer if a key was pressed within [`POLL_INTERVAL`].
+    ///
+    /// # Returns
+    ///
+    /// * `Option<bool>` - `Some(true)` for `k`, to kill the command, `Some(false)` for
+    ///   Enter, to keep waiting, or `None` if neither was pressed.
+    fn read_answer(&self) -> Option<bool> {
+        use rustix::event::{poll, PollFd, PollFlags};
+
+        let stdin = io::stdin();
+        let mut ready = [PollFd::new(&stdin, PollFlags::IN)];
+        if poll(&mut ready, POLL_INTERVAL.as_millis() as i32).ok()? == 0 {
+            return None;
+        }
+        let mut key = [0];
+        rustix::io::read(&stdin, &mut key).ok()?;
+        match key[0] {
+            b'k' | b'K' => Some(true),
+            b'\r' | b'\n' => Some(false),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for KillPrompt {
+    fn drop(&mut self) {
+        use rustix::process::{kill_process_group, Signal};
+        use rustix::termios::{tcsetattr, OptionalActions};
+
+        let _ = tcsetattr(io::stdin(), OptionalActions::Now, &self.settings);
+        set_terminal_group(self.group);
+        // It may have stopped when it tried to use the terminal meanwhile
+        let _ = kill_process_group(self.group, Signal::Cont);
+    }
+}
+
+/// Sends Ctrl-C's SIGINT on to the command running in the foreground, if there is one. It
+/// normally gets it from the terminal itself, but not when gptsh's input isn't the
+/// terminal, or a signal is sent to gptsh with `kill`.
+///
+/// # Returns
+///
+/// * `bool` - Whether a command was running to send it to.
+pub(crate) fn interrupt_foreground_command() -> bool {
+    let group = FOREGROUND_GROUP.load(Ordering::SeqCst);
+    #[cfg(unix)]
+    if let Some(group) = rustix::process::Pid::from_raw(group) {
+        let _ = rustix::process::kill_process_group(group, rustix::process::Signal::Int);
+    }
+    group != 0
+}
+
+/// Returns the process group the terminal belongs to, or `None` if stdin isn't a terminal.
+#[cfg(unix)]
+fn terminal_group() -> Option<rustix::process::Pid> {
+    rustix::termios::tcgetpgrp(io::stdin()).ok()
+}
+
+/// Gives the terminal to a process group.
+#[cfg(unix)]
+fn set_terminal_group(group: rustix::process::Pid) {
+    // Taking the terminal back while a command has it would stop gptsh with SIGTTOU, as
+    // for any background process touching the terminal, unless the signal is ignored
+    // SAFETY: Only the disposition of SIGTTOU is changed, and it is restored right after.
+    unsafe {
+        let previous = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        let _ = rustix::termios::tcsetpgrp(io::stdin(), group);
+        libc::signal(libc::SIGTTOU, previous);
+    }
+}
+
+/// Where a background job is at.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum JobState {
+    Running,
+    /// Stopped, usually because it tried to read from the terminal, until it is brought to
+    /// the foreground.
+    Stopped,
+    Finished(ExitStatus),
+}
+
+/// A command started in the background with `&`.
+pub(crate) struct Job {
+    /// The number `:fg` takes.
+    pub(crate) number: usize,
+    pub(crate) command: String,
+    pub(crate) state: JobState,
+    child: Child,
+}
+
+impl Job {
+    /// Returns the process id of the job, which is also its process group.
+    pub(crate) fn id(&self) -> u32 {
+        self.child.id()
+    }
+}
+
+/// The jobs running in the background in a shell session, numbered from 1 as in a shell.
+#[derive(Default)]
+pub(crate) struct Jobs {
+    jobs: Vec<Job>,
+}
+
+impl Jobs {
+    /// Starts a command in the background, in a process group of its own. Its output goes
+    /// to the terminal, but if it reads from it, it is stopped until brought to the
+    /// foreground.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command line, run with the user's shell.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<&Job>` - The job, or an error if the command couldn't be started.
+    pub(crate) fn start(&mut self, command: &str) -> io::Result<&Job> {
+        let child = spawn_in_own_group(&mut shell_command(command))?;
+        let number = self.jobs.last().map_or(1, |job| job.number + 1);
+        self.jobs.push(Job {
+            number,
+            command: command.to_string(),
+            state: JobState::Running,
+            child,
+        });
+        Ok(&self.jobs[self.jobs.len() - 1])
+    }
+
+    /// Returns the jobs that haven't finished, oldest first, as of the last [`Jobs::poll`].
+    pub(crate) fn list(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    /// Checks on the jobs without waiting, and forgets the ones that have finished.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<(usize, String, JobState)>` - The number, command, and new state of each job
+    ///   that finished or stopped since the last check.
+    pub(crate) fn poll(&mut self) -> Vec<(usize, String, JobState)> {
+        let mut changes = Vec::new();
+        for job in &mut self.jobs {
+            let Some(state) = check_job(&mut job.child) else { continue };
+            if !matches!((job.state, state), (JobState::Stopped, JobState::Stopped) | (_, JobState::Running)) {
+                changes.push((job.number, job.command.clone(), state));
+            }
+            job.state = state;
+        }
+        self.jobs.retain(|job| !matches!(job.state, JobState::Finished(_)));
+        changes
+    }
+
+    /// Takes a job out of the table, to bring it to the foreground with [`wait_for_job`].
+    ///
+    /// # Arguments
+    ///
+    /// * `number` - The job's number, or `None` for the most recent job.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Job>` - The job, or `None` if there is no such job.
+    pub(crate) fn take(&mut self, number: Option<usize>) -> Option<Job> {
+        let index = match number {
+            Some(number) => self.jobs.iter().position(|job| job.number == number)?,
+            None => self.jobs.len().checked_sub(1)?,
+        };
+        Some(self.jobs.remove(index))
+    }
+}
+
+/// Checks whether a background job has stopped, continued, or finished, without waiting.
+#[cfg(unix)]
+fn check_job(child: &mut Child) -> Option<JobState> {
+    use std::os::unix::process::ExitStatusExt;
+    use rustix::process::{waitpid, Pid, WaitOptions};
+
+    let options = WaitOptions::NOHANG | WaitOptions::UNTRACED | WaitOptions::CONTINUED;
+    match waitpid(Some(Pid::from_child(child)), options) {
+        Ok(Some(status)) if status.stopped() => Some(JobState::Stopped),
+        Ok(Some(status)) if status.continued() => Some(JobState::Running),
+        Ok(Some(status)) => Some(JobState::Finished(ExitStatus::from_raw(status.as_raw() as i32))),
+        Ok(None) => None,
+        // Someone else reaped it, so how it ended is unknown
+        Err(_) => Some(JobState::Finished(ExitStatus::default())),
+    }
+}
+
+/// Checks whether a background job has finished, without waiting.
+#[cfg(not(unix))]
+fn check_job(child: &mut Child) -> Option<JobState> {
+    match child.try_wait() {
+        Ok(Some(status)) => Some(JobState::Finished(status)),
+        Ok(None) => None,
+        Err(_) => Some(JobState::Finished(ExitStatus::default())),
+    }
+}
+
+/// Brings a background job to the foreground, continuing it if it was stopped, and waits
+/// for it like [`wait_in_foreground`].
+///
+/// # Arguments
+///
+/// * `job` - The job, as taken by [`Jobs::take`].
+///
+/// # Returns
+///
+/// * `io::Result<ExitStatus>` - How the job ended.
+pub(crate) fn wait_for_job(job: &mut Job) -> io::Result<ExitStatus> {
+    bring_to_foreground(&job.child);
+    #[cfg(unix)]
+    {
+        use rustix::process::{kill_process_group, Pid, Signal};
+        let _ = kill_process_group(Pid::from_child(&job.child), Signal::Cont);
+    }
+    wait_in_foreground(&mut job.child, None)
+}
+
+/// Splits the `&` off a command that ends with one to run it in the background. `&&`,
+/// `|&`, `>&`, and an escaped `\&` don't count.
+///
+/// # Arguments
+///
+/// * `command` - The command line.
+///
+/// # Returns
+///
+/// * `Option<&str>` - The command without the `&`, or `None` if it doesn't end with one.
+pub(crate) fn strip_background(command: &str) -> Option<&str> {
+    let rest = command.trim_end().strip_suffix('&')?;
+    if rest.ends_with(['&', '|', '>', '<', '\\']) {
+        return None;
+    }
+    let rest = rest.trim_end();
+    (!rest.is_empty()).then_some(rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trailing_ampersands_run_in_the_background() {
+        assert_eq!(strip_background("sleep 10 &"), Some("sleep 10"));
+        assert_eq!(strip_background("sleep 10&  "), Some("sleep 10"));
+        assert_eq!(strip_background("make 2>&1 | tee log &"), Some("make 2>&1 | tee log"));
+    }
+
+    #[test]
+    fn other_ampersands_are_left_alone() {
+        assert_eq!(strip_background("make&&make install"), None);
+        assert_eq!(strip_background("make &&"), None);
+        assert_eq!(strip_background("make |&"), None);
+        assert_eq!(strip_background("echo '&'"), None);
+        assert_eq!(strip_background("echo \"&\""), None);
+        assert_eq!(strip_background("echo \\&"), None);
+        assert_eq!(strip_background(" & "), None);
+        assert_eq!(strip_background("ls"), None);
+    }
+}
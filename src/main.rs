@@ -14,16 +14,41 @@
  * limitations under the License.
  */
 
-mod cli;
-mod shell;
+mod aliases;
+mod anthropic;
+mod ask;
+mod batch;
+mod builtins;
+mod capabilities;
 mod chat;
-mod openai;
+mod cli;
+mod client;
+mod command_shell;
+mod completion;
+mod files;
+mod history;
+mod images;
+mod job_control;
+mod markdown;
+mod memory;
 mod models;
+mod openai;
+mod personas;
+mod profiles;
+mod prompt;
+mod session_log;
+mod sessions;
+mod shell;
+mod sysinfo;
+mod theme;
+mod transcript;
+mod usage;
 mod utils;
+mod web;
 
 use crate::cli::run_mode;
 
 fn main() {
-    if run_mode() { return; }
+    run_mode();
 }
 
@@ -0,0 +1,504 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::mem;
+
+use colored::{ColoredString, Colorize};
+use unicode_width::UnicodeWidthChar;
+
+use crate::theme::theme;
+use crate::utils::terminal_width;
+
+/// Columns left free at the right edge of the terminal when wrapping.
+const WRAP_MARGIN: usize = 2;
+/// Text is never wrapped narrower than this, however small the terminal.
+const MIN_WRAP_WIDTH: usize = 20;
+/// The width of the gutter in front of code lines.
+const CODE_GUTTER_WIDTH: usize = 2;
+
+/// Styles Markdown for the terminal one line at a time, so replies can be rendered while
+/// they stream in. Headings, emphasis, lists, quotes, and inline code are styled; code
+/// blocks get a gutter but their lines are otherwise printed exactly as written, never
+/// wrapped or reflowed, so they can be copied and pasted.
+#[derive(Default)]
+pub(crate) struct MarkdownRenderer {
+    /// Text received since the last line break.
+    pending: String,
+    /// The fence (e.g. "```") of the code block being rendered, if any.
+    fence: Option<String>,
+    /// The width prose is wrapped to and code lines are cut off at, if any.
+    width: Option<usize>,
+    /// Columns already taken on the first line, e.g. by `gptsh: `.
+    start_column: usize,
+}
+
+impl MarkdownRenderer {
+    /// Creates a renderer that wraps prose at word boundaries to fit a width. Code lines
+    /// are never wrapped; those wider than the terminal are cut off with a `…`.
+    ///
+    /// # Arguments
+    ///
+    /// * `width` - The width to fit, usually [`wrap_width`]; `None` leaves lines as they are.
+    /// * `start_column` - Columns already taken on the first line, e.g. by a prefix.
+    ///
+    /// # Returns
+    ///
+    /// * `MarkdownRenderer` - The renderer.
+    pub(crate) fn wrapping(width: Option<usize>, start_column: usize) -> Self {
+        Self { width, start_column, ..Self::default() }
+    }
+
+    /// Adds text and returns the rendered lines it completed, each ending in a line break.
+    pub(crate) fn push(&mut self, text: &str) -> String {
+        self.pending.push_str(text);
+        let mut rendered = String::new();
+        while let Some(end) = self.pending.find('\n') {
+            let line: String = self.pending.drain(..=end).collect();
+            rendered.push_str(&self.render_line(line.trim_end_matches(['\n', '\r'])));
+            rendered.push('\n');
+        }
+        rendered
+    }
+
+    /// Renders whatever is left after the last line break.
+    pub(crate) fn finish(&mut self) -> String {
+        let line = std::mem::take(&mut self.pending);
+        if line.is_empty() {
+            String::new()
+        } else {
+            self.render_line(&line)
+        }
+    }
+
+    /// Renders a single line, tracking whether it opens, closes, or lies inside a code block.
+    fn render_line(&mut self, line: &str) -> String {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        // Only the first line shares its row with a prefix
+        let start_column = mem::take(&mut self.start_column);
+        let first_width = self.width.map(|width| width.saturating_sub(start_column).max(MIN_WRAP_WIDTH / 2));
+        let wrap = |prefix: &str, body: String, continuation: &str| match (first_width, self.width) {
+            (Some(first_width), Some(width)) => wrap_styled(prefix, &body, continuation, first_width, width),
+            _ => format!("{}{}", prefix, body),
+        };
+
+        if let Some(fence) = &self.fence {
+            if trimmed.starts_with(fence.as_str()) && trimmed[fence.len()..].trim().is_empty() {
+                self.fence = None;
+                return format!("{}{}", indent, theme().code_border("╰─"));
+            }
+            let code_width = first_width.map(|width| width.saturating_sub(CODE_GUTTER_WIDTH));
+            let (code, cut_off) = match code_width {
+                Some(code_width) => cut_off_line(line, code_width),
+                None => (line, false),
+            };
+            let marker = if cut_off { theme().code_border("…") } else { String::new() };
+            return format!("{} {}{}", theme().code_border("│"), theme().code_line(highlight_code_line(code)), marker);
+        }
+
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            let language = trimmed.trim_start_matches(['`', '~']);
+            self.fence = Some(trimmed[..trimmed.len() - language.len()].to_string());
+            return format!("{}{}", indent, theme().code_border(format!("╭─ {}", language.trim()).trim_end()));
+        }
+
+        if let Some((level, heading)) = parse_heading(trimmed) {
+            let heading = render_inline(heading).bold();
+            let heading = if level == 1 { heading.underline() } else { heading };
+            return wrap("", heading.to_string(), "");
+        }
+
+        if matches!(trimmed.trim_end(), "---" | "***" | "___") {
+            return "─".repeat(first_width.unwrap_or(40).min(40)).dimmed().to_string();
+        }
+
+        if let Some(quote) = trimmed.strip_prefix('>') {
+            let prefix = format!("{}{} ", indent, "│".dimmed());
+            return wrap(&prefix, render_inline(quote.trim_start()).italic().to_string(), &prefix);
+        }
+
+        if let Some(item) = ["- ", "* ", "+ "].into_iter().find_map(|bullet| trimmed.strip_prefix(bullet)) {
+            return wrap(&format!("{}• ", indent), render_inline(item), &format!("{}  ", indent));
+        }
+
+        // Lines of a numbered item continue under its text rather than its number
+        let number = trimmed.len() - trimmed.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let marker_width = if number > 0 && trimmed[number..].starts_with(". ") { number + 2 } else { 0 };
+        wrap(indent, render_inline(trimmed), &format!("{}{}", indent, " ".repeat(marker_width)))
+    }
+}
+
+/// Returns the width replies are wrapped to: the terminal's, less a small margin.
+/// The terminal is asked every time, so resizing the window takes effect with the next reply.
+///
+/// # Returns
+///
+/// * `Option<usize>` - The width, or `None` if stdout isn't a terminal of known width.
+pub(crate) fn wrap_width() -> Option<usize> {
+    terminal_width().map(|width| width.saturating_sub(WRAP_MARGIN).max(MIN_WRAP_WIDTH))
+}
+
+/// Wraps a styled line at spaces so each row fits a width. Words wider than the width,
+/// such as long URLs, are left whole. A line that already fits is returned unchanged.
+///
+/// # Arguments
+///
+/// * `prefix` - What the first row starts with, e.g. an indent and a bullet.
+/// * `body` - The styled text after it.
+/// * `continuation` - What the other rows start with, e.g. spaces as wide as the bullet.
+/// * `first_width` - The width of the first row.
+/// * `width` - The width of the other rows.
+///
+/// # Returns
+///
+/// * `String` - The rows, separated by line breaks.
+fn wrap_styled(prefix: &str, body: &str, continuation: &str, first_width: usize, width: usize) -> String {
+    let mut wrapped = prefix.to_string();
+    if visible_width(prefix) + visible_width(body) <= first_width {
+        wrapped.push_str(body);
+        return wrapped;
+    }
+
+    let mut row_width = visible_width(prefix);
+    let mut row_limit = first_width;
+    let mut row_is_empty = true;
+    for word in body.split(' ').filter(|word| !word.is_empty()) {
+        let word_width = visible_width(word);
+        if !row_is_empty && row_width + 1 + word_width > row_limit {
+            wrapped.push('\n');
+            wrapped.push_str(continuation);
+            row_width = visible_width(continuation);
+            row_limit = width;
+            row_is_empty = true;
+        }
+        if !row_is_empty {
+            wrapped.push(' ');
+            row_width += 1;
+        }
+        wrapped.push_str(word);
+        row_width += word_width;
+        row_is_empty = false;
+    }
+    wrapped
+}
+
+/// Cuts a line of code off at a width.
+///
+/// # Arguments
+///
+/// * `line` - The line, without styling.
+/// * `width` - The most columns it may take up.
+///
+/// # Returns
+///
+/// * `(&str, bool)` - The part that fits, with room left for a `…`, and whether anything
+///   was cut off.
+fn cut_off_line(line: &str, width: usize) -> (&str, bool) {
+    if visible_width(line) <= width {
+        return (line, false);
+    }
+    let mut used = 0;
+    for (index, c) in line.char_indices() {
+        used += c.width().unwrap_or(0);
+        if used + 1 > width {
+            return (&line[..index], true);
+        }
+    }
+    (line, false)
+}
+
+/// Measures how many columns text takes up in the terminal, not counting the escape
+/// sequences that style it.
+fn visible_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip a sequence like `\x1b[1;34m`
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            width += c.width().unwrap_or(0);
+        }
+    }
+    width
+}
+
+/// Renders a complete Markdown text.
+///
+/// # Arguments
+///
+/// * `text` - The Markdown to render.
+/// * `width` - The width to wrap prose to, if any; see [`MarkdownRenderer::wrapping`].
+/// * `start_column` - Columns already taken on the first line, e.g. by a prefix.
+///
+/// # Returns
+///
+/// * `String` - The text with terminal styling applied.
+pub(crate) fn render_markdown(text: &str, width: Option<usize>, start_column: usize) -> String {
+    let mut renderer = MarkdownRenderer::wrapping(width, start_column);
+    let mut rendered = renderer.push(text);
+    rendered.push_str(&renderer.finish());
+    rendered
+}
+
+/// A fenced code block found in a reply.
+pub(crate) struct CodeBlock<'a> {
+    /// The language tag after the opening fence, e.g. `bash`; empty if there is none.
+    pub(crate) language: &'a str,
+    /// The block's contents, without the fences or the final line break.
+    pub(crate) code: &'a str,
+}
+
+/// Finds the fenced (```` ``` ```` or `~~~`) code blocks in a reply, using the same rules as
+/// [`MarkdownRenderer`]. A block that is never closed, e.g. because the reply was cut off,
+/// runs to the end of the text.
+///
+/// # Arguments
+///
+/// * `text` - The reply.
+///
+/// # Returns
+///
+/// * `Vec<CodeBlock>` - The code blocks, in the order they appear.
+pub(crate) fn find_code_blocks(text: &str) -> Vec<CodeBlock<'_>> {
+    let mut blocks = Vec::new();
+    // The open block's fence, its language, and where its contents start
+    let mut open: Option<(&str, &str, usize)> = None;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+        let trimmed = line.trim_end_matches(['\n', '\r']).trim_start();
+        match open {
+            Some((fence, language, code_start))
+                if trimmed.starts_with(fence) && trimmed[fence.len()..].trim().is_empty() =>
+            {
+                let code = without_line_break(&text[code_start..line_start]);
+                blocks.push(CodeBlock { language, code });
+                open = None;
+            }
+            Some(_) => {}
+            None if trimmed.starts_with("```") || trimmed.starts_with("~~~") => {
+                let language = trimmed.trim_start_matches(['`', '~']);
+                open = Some((&trimmed[..trimmed.len() - language.len()], language.trim(), offset));
+            }
+            None => {}
+        }
+    }
+    if let Some((_, language, code_start)) = open {
+        let code = without_line_break(&text[code_start..]);
+        blocks.push(CodeBlock { language, code });
+    }
+    blocks
+}
+
+/// Removes one trailing line break, if there is one.
+fn without_line_break(text: &str) -> &str {
+    let text = text.strip_suffix('\n').unwrap_or(text);
+    text.strip_suffix('\r').unwrap_or(text)
+}
+
+/// Splits an ATX heading (`## Title`) into its level and text.
+fn parse_heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.len() - line.trim_start_matches('#').len();
+    let text = line[level..].strip_prefix(' ')?;
+    (1..=6).contains(&level).then_some((level, text.trim_end_matches([' ', '#'])))
+}
+
+/// Dims comment lines in a code block; everything else is left exactly as written.
+fn highlight_code_line(line: &str) -> ColoredString {
+    let code = line.trim_start();
+    if code.starts_with("# ") || code == "#" || code.starts_with("//") || code.starts_with("--") {
+        line.dimmed()
+    } else {
+        line.normal()
+    }
+}
+
+/// Styles inline code spans, `**bold**`/`__bold__`, and `*italic*` text.
+/// Unmatched markers are printed as they are.
+fn render_inline(text: &str) -> String {
+    let mut rendered = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(['`', '*', '_']) {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let marker = if rest.starts_with("**") || rest.starts_with("__") {
+            &rest[..2]
+        } else if rest.starts_with('`') || rest.starts_with('*') {
+            &rest[..1]
+        } else {
+            // A single underscore is usually part of a name, such as snake_case
+            rendered.push('_');
+            rest = &rest[1..];
+            continue;
+        };
+
+        let inner_start = marker.len();
+        let closing = rest[inner_start..].find(marker).map(|end| inner_start + end);
+        match closing {
+            Some(end) if end > inner_start && is_emphasis(marker, &rest[inner_start..end]) => {
+                let inner = &rest[inner_start..end];
+                let styled = match marker {
+                    "`" => theme().inline_code(inner),
+                    "*" => render_inline(inner).italic().to_string(),
+                    _ => render_inline(inner).bold().to_string(),
+                };
+                rendered.push_str(&styled);
+                rest = &rest[end + marker.len()..];
+            }
+            _ => {
+                rendered.push_str(marker);
+                rest = &rest[marker.len()..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Checks that text between two markers reads as emphasis rather than, say, the spaces
+/// around `*` in `2 * 3 * 4`. Code spans may contain anything.
+fn is_emphasis(marker: &str, inner: &str) -> bool {
+    marker == "`" || (!inner.starts_with(char::is_whitespace) && !inner.ends_with(char::is_whitespace))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lists the language and code of each block in a reply.
+    fn blocks(text: &str) -> Vec<(&str, &str)> {
+        find_code_blocks(text).into_iter().map(|block| (block.language, block.code)).collect()
+    }
+
+    #[test]
+    fn code_blocks_keep_their_language_tags() {
+        let reply = "Try this:\n```bash\nls -la\n```\nor\n~~~ python \nprint(1)\n~~~\nand\n```\nplain\n```\n";
+        assert_eq!(blocks(reply), [("bash", "ls -la"), ("python", "print(1)"), ("", "plain")]);
+    }
+
+    #[test]
+    fn code_blocks_are_kept_exactly_as_written() {
+        let reply = "```sh\n  indented\n\n# comment with **stars**\n```";
+        assert_eq!(blocks(reply), [("sh", "  indented\n\n# comment with **stars**")]);
+        assert_eq!(blocks("```\r\nwindows\r\n```\r\n"), [("", "windows")]);
+        assert_eq!(blocks("```\n```\n"), [("", "")]);
+    }
+
+    #[test]
+    fn longer_fences_contain_shorter_ones() {
+        let reply = "````markdown\nUse:\n```bash\nls\n```\n````\n";
+        assert_eq!(blocks(reply), [("markdown", "Use:\n```bash\nls\n```")]);
+        // A block opened with backticks isn't closed by tildes, nor by a fence followed by text
+        assert_eq!(blocks("```\n~~~\n``` not yet\n```\n"), [("", "~~~\n``` not yet")]);
+    }
+
+    #[test]
+    fn indented_fences_open_and_close_blocks() {
+        assert_eq!(blocks("1. Run:\n   ```bash\n   make\n   ```\n"), [("bash", "   make")]);
+    }
+
+    #[test]
+    fn unterminated_blocks_run_to_the_end() {
+        assert_eq!(blocks("```rust\nfn main() {\n"), [("rust", "fn main() {")]);
+        assert_eq!(blocks("```\n```\n```js"), [("", ""), ("js", "")]);
+    }
+
+    #[test]
+    fn text_without_fences_has_no_blocks() {
+        assert!(blocks("Use `ls -la` to list files.\n").is_empty());
+        assert!(blocks("").is_empty());
+    }
+
+    /// Removes the escape sequences that style text, leaving what the terminal shows.
+    fn plain(text: &str) -> String {
+        let mut plain = String::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|c| c.is_ascii_alphabetic());
+            } else {
+                plain.push(c);
+            }
+        }
+        plain
+    }
+
+    /// Renders a reply at a width, without styling.
+    fn render(text: &str, width: usize, start_column: usize) -> String {
+        plain(&render_markdown(text, Some(width), start_column))
+    }
+
+    #[test]
+    fn prose_is_wrapped_at_word_boundaries() {
+        let reply = "The quick brown fox jumps over the lazy dog and keeps on running.";
+        assert_eq!(render(reply, 20, 0), "The quick brown fox\njumps over the lazy\ndog and keeps on\nrunning.");
+        // The first row leaves room for the `gptsh: ` prefix
+        assert_eq!(render(reply, 20, 7), "The quick\nbrown fox jumps over\nthe lazy dog and\nkeeps on running.");
+        assert_eq!(plain(&render_markdown(reply, None, 0)), reply);
+    }
+
+    #[test]
+    fn words_wider_than_the_width_are_left_whole() {
+        let reply = "See https://example.com/a/very/long/path/to/a/page for details";
+        assert_eq!(render(reply, 20, 0), "See\nhttps://example.com/a/very/long/path/to/a/page\nfor details");
+    }
+
+    #[test]
+    fn list_items_and_quotes_continue_under_their_text() {
+        assert_eq!(render("- first item that wraps around", 20, 0), "• first item that\n  wraps around");
+        assert_eq!(render("12. numbered item that wraps", 20, 0), "12. numbered item\n    that wraps");
+        assert_eq!(render("> quoted text that wraps around", 20, 0), "│ quoted text that\n│ wraps around");
+    }
+
+    #[test]
+    fn code_blocks_are_never_wrapped() {
+        let reply = "Run this command to find it:\n```bash\nfind / -name '*.log' -size +100M\nls\n```\nIt may take a while to finish.";
+        assert_eq!(
+            render(reply, 20, 0),
+            "Run this command to\nfind it:\n╭─ bash\n│ find / -name '*.l…\n│ ls\n╰─\nIt may take a while\nto finish."
+        );
+        // Without a width, long code lines are shown whole
+        assert!(plain(&render_markdown(reply, None, 0)).contains("│ find / -name '*.log' -size +100M\n"));
+    }
+
+    #[test]
+    fn fence_like_lines_inside_code_are_not_wrapped_either() {
+        let reply = "````md\n```\na long line of code inside a nested fence\n```\n````\nafter the block";
+        assert_eq!(
+            render(reply, 20, 0),
+            "╭─ md\n│ ```\n│ a long line of co…\n│ ```\n╰─\nafter the block"
+        );
+    }
+
+    #[test]
+    fn streamed_replies_render_like_whole_ones() {
+        let reply = "Some text that wraps\n```\ncode line that is long\n```\nmore text after it ends";
+        let mut renderer = MarkdownRenderer::wrapping(Some(20), 0);
+        let mut streamed = String::new();
+        for piece in reply.as_bytes().chunks(3) {
+            streamed.push_str(&renderer.push(std::str::from_utf8(piece).unwrap()));
+        }
+        streamed.push_str(&renderer.finish());
+        assert_eq!(plain(&streamed), render(reply, 20, 0));
+    }
+}
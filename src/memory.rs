@@ -0,0 +1,165 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{fs, io, path::PathBuf};
+
+/// The file facts are remembered in: plain text, one fact per line. Blank lines and lines
+/// starting with `#` are ignored, so the file can be edited by hand.
+const MEMORY_FILE: &str = "memories.txt";
+
+/// The most of the remembered facts sent with a request, in bytes. Facts past it are
+/// left out, so a long file doesn't crowd out the conversation.
+const MAX_MEMORY_BYTES: usize = 4 * 1024;
+
+/// How the message with the remembered facts starts, which is also how it is recognized.
+pub(crate) const MEMORY_INTRO: &str = "Facts the user asked you to remember:";
+
+/// Returns the path of the file facts are remembered in.
+fn memory_file_path() -> io::Result<PathBuf> {
+    dirs::config_dir()
+        .map(|dir| dir.join("gptsh").join(MEMORY_FILE))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no configuration directory"))
+}
+
+/// Checks whether a line of the memory file holds a fact.
+fn is_fact(line: &str) -> bool {
+    let line = line.trim();
+    !line.is_empty() && !line.starts_with('#')
+}
+
+/// Reads the memory file, as an empty file if it doesn't exist yet.
+fn read_memory_file() -> io::Result<String> {
+    match fs::read_to_string(memory_file_path()?) {
+        Ok(contents) => Ok(contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Loads the remembered facts, in the order they were added.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<String>>` - The facts, or an error if the file could not be read.
+pub(crate) fn load_memories() -> io::Result<Vec<String>> {
+    Ok(read_memory_file()?
+        .lines()
+        .filter(|line| is_fact(line))
+        .map(|line| line.trim().to_string())
+        .collect())
+}
+
+/// Appends a fact to the memory file, creating it if needed.
+///
+/// # Arguments
+///
+/// * `fact` - The fact; line breaks are replaced with spaces so it stays one entry.
+///
+/// # Returns
+///
+/// * `io::Result<usize>` - How many facts are remembered now.
+pub(crate) fn add_memory(fact: &str) -> io::Result<usize> {
+    let path = memory_file_path()?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut contents = read_memory_file()?;
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(&fact.split_whitespace().collect::<Vec<_>>().join(" "));
+    contents.push('\n');
+    fs::write(path, &contents)?;
+    Ok(contents.lines().filter(|line| is_fact(line)).count())
+}
+
+/// Removes a fact from the memory file, leaving comments and blank lines in place.
+///
+/// # Arguments
+///
+/// * `number` - The fact's number, starting at 1, as listed by `/memories`.
+///
+/// # Returns
+///
+/// * `Result<String, String>` - The fact that was removed, or an error message.
+pub(crate) fn remove_memory(number: usize) -> Result<String, String> {
+    let contents = read_memory_file().map_err(|e| format!("Error: Could not read the memory file: {}", e))?;
+    let mut lines: Vec<&str> = contents.lines().collect();
+    let Some(index) = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| is_fact(line))
+        .nth(number.wrapping_sub(1))
+        .map(|(index, _)| index)
+    else {
+        return Err(format!("Error: There is no memory {}. Type /memories to list them.", number));
+    };
+    let fact = lines.remove(index).trim().to_string();
+    let mut contents = lines.join("\n");
+    if !contents.is_empty() {
+        contents.push('\n');
+    }
+    memory_file_path()
+        .and_then(|path| fs::write(path, contents))
+        .map_err(|e| format!("Error: Could not update the memory file: {}", e))?;
+    Ok(fact)
+}
+
+/// Builds the message text that tells the model the remembered facts, keeping to
+/// [`MAX_MEMORY_BYTES`].
+///
+/// # Arguments
+///
+/// * `memories` - The facts, as returned by [`load_memories`].
+///
+/// # Returns
+///
+/// * `Option<(String, usize)>` - The text and how many facts were left out to keep within
+///   the limit, or `None` if there is nothing to remember.
+pub(crate) fn memory_prompt(memories: &[String]) -> Option<(String, usize)> {
+    let mut prompt = MEMORY_INTRO.to_string();
+    let mut included = 0;
+    for fact in memories {
+        let line = format!("\n- {}", fact);
+        if prompt.len() + line.len() > MAX_MEMORY_BYTES {
+            break;
+        }
+        prompt.push_str(&line);
+        included += 1;
+    }
+    (included > 0).then(|| (prompt, memories.len() - included))
+}
+
+/// Loads the remembered facts as a message text for a request. Problems reading the file
+/// are reported as a warning, since a request can go ahead without them.
+///
+/// # Returns
+///
+/// * `Option<String>` - The text, or `None` if there is nothing to remember.
+pub(crate) fn load_memory_prompt() -> Option<String> {
+    match load_memories() {
+        Ok(memories) => memory_prompt(&memories).map(|(prompt, _)| prompt),
+        Err(e) => {
+            eprintln!("Warning: Could not read the memory file: {}", e);
+            None
+        }
+    }
+}
+
+/// Returns where facts are remembered, for messages to the user.
+pub(crate) fn memory_file_display() -> String {
+    memory_file_path().map_or_else(|_| MEMORY_FILE.to_string(), |path| path.display().to_string())
+}
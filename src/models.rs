@@ -14,6 +14,8 @@
  * limitations under the License.
  */
 
+use std::{collections::HashMap, fmt};
+
 use serde::{Deserialize, Serialize};
 
 // Data structures for OpenAI API request and response
@@ -23,29 +25,485 @@ pub(crate) struct Message {
     pub(crate) content: String,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub(crate) struct OpenAIRequest {
     pub(crate) model: String,
     pub(crate) messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) max_completion_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) response_format: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub(crate) sampling: SamplingConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) seed: Option<u64>,
+}
+
+/// A generated command, as returned in a structured (JSON) reply.
+#[derive(Deserialize, Debug)]
+pub(crate) struct CommandSuggestion {
+    pub(crate) command: String,
+    #[serde(default)]
+    pub(crate) explanation: String,
+    /// Whether the model considers the command risky (it modifies or deletes data).
+    #[serde(default)]
+    pub(crate) needs_confirmation: bool,
 }
 
 #[derive(Deserialize)]
 pub(crate) struct OpenAIResponse {
     pub(crate) choices: Vec<Choice>,
+    #[serde(default)]
+    pub(crate) usage: Option<Usage>,
+    /// Identifies the backend configuration that served the request; not every model reports it.
+    #[serde(default)]
+    pub(crate) system_fingerprint: Option<String>,
+}
+
+impl OpenAIResponse {
+    /// Whether the reply was cut off because it reached the token limit.
+    pub(crate) fn is_truncated(&self) -> bool {
+        self.choices.first().and_then(|choice| choice.finish_reason.as_deref()) == Some("length")
+    }
+}
+
+/// The error body returned by the API.
+#[derive(Deserialize)]
+pub(crate) struct ApiErrorResponse {
+    /// The standard `{"error": {...}}` shape.
+    pub(crate) error: Option<ApiError>,
+    /// A top-level message, as some Azure errors return.
+    pub(crate) message: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ApiError {
+    #[serde(default)]
+    pub(crate) message: String,
+    #[serde(rename = "type")]
+    pub(crate) kind: Option<String>,
+    /// Usually a string, but some servers send a number.
+    pub(crate) code: Option<serde_json::Value>,
+}
+
+impl ApiError {
+    /// Returns the error code if it is a string.
+    pub(crate) fn code_str(&self) -> Option<&str> {
+        self.code.as_ref().and_then(|code| code.as_str())
+    }
+}
+
+/// Token counts reported for a request.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub(crate) struct Usage {
+    #[serde(default)]
+    pub(crate) prompt_tokens: u64,
+    #[serde(default)]
+    pub(crate) completion_tokens: u64,
 }
 
 #[derive(Deserialize)]
 pub(crate) struct Choice {
     pub(crate) message: MessageContent,
+    /// Why generation stopped: `stop`, `length` when the token limit was reached, and so on.
+    #[serde(default)]
+    pub(crate) finish_reason: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub(crate) struct MessageContent {
+    // Some OpenAI-compatible servers omit the content or send `null`
+    #[serde(default, deserialize_with = "null_as_empty")]
     pub(crate) content: String,
 }
 
+/// Deserializes a string that may be `null` as an empty string.
+fn null_as_empty<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Option::<String>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// A chat-completions response as chat mode reads it, with any function calls.
+/// Streamed replies are assembled into the same shape.
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct ChatResponse {
+    /// `None` when the field is missing altogether, which is reported differently from no choices.
+    #[serde(default)]
+    pub(crate) choices: Option<Vec<ChatChoice>>,
+    #[serde(default)]
+    pub(crate) usage: Option<Usage>,
+    #[serde(default)]
+    pub(crate) system_fingerprint: Option<String>,
+}
+
+impl ChatResponse {
+    /// Returns the first choice, the only one requested.
+    pub(crate) fn first_choice(&self) -> Option<&ChatChoice> {
+        self.choices.as_deref()?.first()
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct ChatChoice {
+    #[serde(default)]
+    pub(crate) message: ChatMessage,
+    /// Why generation stopped: `stop`, `length` when the token limit was reached, and so on.
+    #[serde(default)]
+    pub(crate) finish_reason: Option<String>,
+}
+
+/// The assistant message of a choice.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub(crate) struct ChatMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) content: Option<String>,
+    /// Why the model declined to answer, when it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) refusal: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tool_calls: Option<Vec<ToolCall>>,
+    /// The deprecated single function call, from servers that don't support `tools`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) function_call: Option<ToolFunction>,
+}
+
+/// A function the assistant asked to call, as a `tool_calls` entry.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub(crate) struct ToolCall {
+    /// Echoed by the result message. Some OpenAI-compatible servers leave it out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<String>,
+    #[serde(rename = "type", default = "function_type")]
+    pub(crate) kind: String,
+    #[serde(default)]
+    pub(crate) function: ToolFunction,
+}
+
+/// The only tool type the API defines.
+fn function_type() -> String {
+    "function".to_string()
+}
+
+/// The name and JSON-encoded arguments of a function call.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub(crate) struct ToolFunction {
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub(crate) name: String,
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub(crate) arguments: String,
+}
+
+/// An event of a streamed chat-completions response.
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct ChatChunk {
+    #[serde(default)]
+    pub(crate) choices: Vec<ChunkChoice>,
+    #[serde(default)]
+    pub(crate) usage: Option<Usage>,
+    #[serde(default)]
+    pub(crate) system_fingerprint: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct ChunkChoice {
+    #[serde(default)]
+    pub(crate) delta: ChunkDelta,
+    #[serde(default)]
+    pub(crate) finish_reason: Option<String>,
+}
+
+/// The part of the assistant message added by a stream event.
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct ChunkDelta {
+    #[serde(default)]
+    pub(crate) content: Option<String>,
+    #[serde(default)]
+    pub(crate) refusal: Option<String>,
+    #[serde(default)]
+    pub(crate) function_call: Option<FunctionDelta>,
+    #[serde(default)]
+    pub(crate) tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+/// A piece of a streamed tool call. Pieces with the same `index` belong to one call.
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct ToolCallDelta {
+    #[serde(default)]
+    pub(crate) index: usize,
+    #[serde(default)]
+    pub(crate) id: Option<String>,
+    #[serde(default)]
+    pub(crate) function: Option<FunctionDelta>,
+}
+
+/// Pieces of a streamed function call's name and arguments.
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct FunctionDelta {
+    #[serde(default)]
+    pub(crate) name: Option<String>,
+    #[serde(default)]
+    pub(crate) arguments: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Config {
     /// Additional context provided to the LLM to tailor command generation.
     pub context: Option<String>,
+    /// The system prompt for chat mode. `${USER}` and `${PWD}` are expanded; empty means no system prompt.
+    pub chat_system_prompt: Option<String>,
+    /// How many times a rejected command may be refined with feedback before giving up.
+    pub max_refinements: Option<u32>,
+    /// Whether replies are streamed as they are generated (defaults to `true`).
+    pub stream: Option<bool>,
+    /// Whether chat replies are styled as Markdown in the terminal (defaults to `true`).
+    pub render_markdown: Option<bool>,
+    /// The colors of chat output: `default`, `solarized`, or `mono`.
+    pub theme: Option<String>,
+    /// Colors that replace the theme's for particular parts of chat output.
+    pub theme_colors: Option<ThemeColors>,
+    /// Send the output and exit status of the last command run in shell mode with the next prompt (defaults to `false`).
+    pub share_command_output: Option<bool>,
+    /// Whether answers to `?` questions in shell mode are sent with later prompts (defaults to `true`).
+    pub shell_answers_in_context: Option<bool>,
+    /// The format of the shell-mode prompt, e.g. `{mode_icon} {cwd} {git_branch} ❯ `; placeholders and color tags are in braces.
+    pub shell_prompt: Option<String>,
+    /// The word that switches between shell mode's LLM Suggestion and Direct Command modes (defaults to `youdu`).
+    pub mode_switch: Option<String>,
+    /// What starts a line in shell mode's Direct Command Mode that the model should turn into a command (defaults to `#?`).
+    pub suggest_prefix: Option<String>,
+    /// Put suggested commands in shell mode on an editable line instead of asking `[Y/n]` (defaults to `true`).
+    pub prefill_suggestions: Option<bool>,
+    /// The shell that runs commands, by name or path, e.g. `zsh` (defaults to `$SHELL`, or bash).
+    pub command_shell: Option<String>,
+    /// Seconds a command may run in shell mode before gptsh says so and offers to kill it; `0` never does (defaults to `60`).
+    pub slow_command_seconds: Option<u64>,
+    /// Aliases expanded at the start of direct commands in shell mode, e.g. `{"gs": "git status"}`.
+    pub aliases: Option<HashMap<String, String>>,
+    /// Tell the model about the aliases in shell mode, so prompts that mention them make sense (defaults to `false`).
+    pub aliases_in_context: Option<bool>,
+    /// A file shell mode appends every line typed to, with the command it led to, whether it ran, its exit status, and its output.
+    pub session_log: Option<String>,
+    /// How `session_log` is written: `text` or `jsonl` (defaults to `text`).
+    pub session_log_format: Option<String>,
+    /// Where shell mode keeps its history, overriding the default in the data directory.
+    pub history_file: Option<String>,
+    /// Keep shell mode's history in `.gptsh_history` in the working directory instead (defaults to `false`).
+    pub project_history: Option<bool>,
+    /// The most entries shell mode keeps in its history, dropping the oldest first (defaults to `1000`).
+    pub history_max: Option<usize>,
+    /// Leave a line out of the history when it repeats the one before (defaults to `true`).
+    pub history_ignore_dups: Option<bool>,
+    /// Leave lines that start with a space out of the history (defaults to `false`).
+    pub history_ignore_space: Option<bool>,
+    /// How many times rate-limited or transient server errors are retried (defaults to 3).
+    pub max_retries: Option<u32>,
+    /// The estimated tokens of conversation history sent with each chat request; older turns are left out beyond it.
+    pub history_token_budget: Option<usize>,
+    /// The most bytes of a command's output sent back to the chat assistant (defaults to 8192; 0 is unlimited).
+    pub max_command_output_bytes: Option<usize>,
+    /// The most functions the chat assistant may call in answer to one message (defaults to 5).
+    pub max_function_calls: Option<usize>,
+    /// What an empty answer to chat mode's command confirmation means: `deny` (default) or `allow`.
+    pub confirm_default: Option<String>,
+    /// Whether the chat assistant may fetch web pages with `fetch_url` (defaults to `false`).
+    pub allow_network_tools: Option<bool>,
+    /// The facts the chat assistant's `get_system_info` reports (defaults to all; empty disables it).
+    pub system_info_fields: Option<Vec<String>>,
+    /// The most entries the chat assistant's `list_directory` returns (defaults to 200).
+    pub list_directory_max_entries: Option<usize>,
+    /// Use the deprecated `functions` format in chat mode instead of `tools` (defaults to `false`).
+    pub legacy_functions: Option<bool>,
+    /// The API provider: `openai` (default), `azure`, or `openai-compatible`.
+    pub provider: Option<String>,
+    /// The model to request, overriding the default.
+    pub model: Option<String>,
+    /// The API base URL, used when neither `--endpoint` nor `OPENAI_BASE_URL` is set.
+    pub base_url: Option<String>,
+    /// The organization requests are billed to (`OpenAI-Organization` header).
+    pub organization: Option<String>,
+    /// The project requests are billed to (`OpenAI-Project` header).
+    pub project: Option<String>,
+    /// Proxy URL for every request, overriding `HTTPS_PROXY`/`ALL_PROXY`.
+    pub proxy: Option<String>,
+    /// API keys to rotate through when one is rate limited, used when no key is set in the environment.
+    pub api_keys: Option<Vec<String>>,
+    /// Extra headers sent with every request. `${VAR}` in a value is replaced with that environment variable.
+    pub headers: Option<HashMap<String, String>>,
+    /// The maximum number of tokens in a reply.
+    pub max_tokens: Option<u32>,
+    /// Ask the model to continue a reply cut off at the token limit, instead of refusing it (defaults to `false`).
+    pub continue_truncated: Option<bool>,
+    /// Print the tokens and estimated cost of every request.
+    pub show_cost: Option<bool>,
+    /// Spend limits in US dollars, checked before every request.
+    pub budget: Option<BudgetConfig>,
+    /// Prices in US dollars per million tokens, keyed by model name, overriding the built-in table.
+    pub prices: Option<HashMap<String, PriceConfig>>,
+    /// Overrides for what the selected model accepts.
+    pub capabilities: Option<CapabilityOverrides>,
+    /// Settings for Azure OpenAI deployments.
+    pub azure: Option<AzureConfig>,
+    /// Sampling parameters for command translation and chat mode.
+    pub generation: Option<GenerationConfig>,
+    /// Seed for best-effort deterministic sampling, overridden by `--seed`.
+    pub seed: Option<u64>,
+    /// Named provider profiles, selected with `--provider`.
+    pub providers: Option<HashMap<String, ProviderProfile>>,
+    /// The profile each mode uses when `--provider` isn't given, keyed by `command`, `shell`, or `chat`.
+    pub default_provider: Option<HashMap<String, String>>,
+}
+
+/// A named set of provider settings. When a profile is selected, it replaces the top-level
+/// `provider`, `base_url`, and `model` keys and the environment variables that set them.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ProviderProfile {
+    /// The API provider: `openai` (default), `azure`, `openai-compatible`, or `anthropic`.
+    pub provider: Option<String>,
+    /// The API base URL, e.g. `http://localhost:11434/v1`.
+    pub base_url: Option<String>,
+    /// The environment variable holding the API key, e.g. `WORK_API_KEY`.
+    pub api_key_env: Option<String>,
+    /// The model to request.
+    pub model: Option<String>,
+    /// Extra headers, added to the top-level `headers`.
+    pub headers: Option<HashMap<String, String>>,
+}
+
+/// Colors for parts of chat output. Each is a name such as `green` or `bright blue`, a
+/// hex color such as `#268bd2`, or `none`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ThemeColors {
+    /// The `gptsh:` before each reply.
+    pub assistant_prefix: Option<String>,
+    /// The `You:` prompt.
+    pub user_prefix: Option<String>,
+    /// The lines around code blocks and the gutter beside them.
+    pub code_border: Option<String>,
+    /// The background of code lines.
+    pub code_background: Option<String>,
+    /// Inline code in replies.
+    pub inline_code: Option<String>,
+    /// Errors, and what commands print to stderr.
+    pub error: Option<String>,
+}
+
+/// Sampling parameters for each mode.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct GenerationConfig {
+    /// Used when translating prompts into commands.
+    pub command: Option<SamplingConfig>,
+    /// Used in chat mode.
+    pub chat: Option<SamplingConfig>,
+}
+
+impl GenerationConfig {
+    /// Checks that every parameter is within the range the API accepts.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), String>` - `Ok`, or a message naming the offending key.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        for (mode, sampling) in [("command", &self.command), ("chat", &self.chat)] {
+            let Some(sampling) = sampling else { continue };
+            let parameters = [
+                ("temperature", sampling.temperature, 0.0, 2.0),
+                ("top_p", sampling.top_p, 0.0, 1.0),
+                ("frequency_penalty", sampling.frequency_penalty, -2.0, 2.0),
+                ("presence_penalty", sampling.presence_penalty, -2.0, 2.0),
+            ];
+            for (name, value, min, max) in parameters {
+                if let Some(value) = value.filter(|value| !(min..=max).contains(value)) {
+                    return Err(format!(
+                        "generation.{}.{} must be between {} and {}, but is {}",
+                        mode, name, min, max, value
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sampling parameters sent with a request. Unset parameters are left to the API's defaults.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct SamplingConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f64>,
+}
+
+/// Daily and monthly spend limits.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BudgetConfig {
+    pub daily: Option<f64>,
+    pub monthly: Option<f64>,
+}
+
+/// The price of a model in US dollars per million tokens.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct PriceConfig {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+/// Overrides for the built-in model capability table, for models it doesn't know.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct CapabilityOverrides {
+    /// The role instructions are sent with: `system`, `developer`, or `user`.
+    pub system_role: Option<String>,
+    /// Whether the model accepts `tools`.
+    pub tools: Option<bool>,
+    /// Whether the model accepts the deprecated `functions` field.
+    pub legacy_functions: Option<bool>,
+    /// Whether the model can stream its reply.
+    pub streaming: Option<bool>,
+    /// Whether the output limit is named `max_completion_tokens` instead of `max_tokens`.
+    pub max_completion_tokens: Option<bool>,
+    /// How structured replies can be requested: `json_schema`, `json_object`, or `text`.
+    pub response_format: Option<String>,
+    /// How many tokens fit in the model's context window, shown by `/tokens`.
+    pub context_window: Option<usize>,
+    /// Whether the model accepts images, which `/attach` sends in chat mode.
+    pub vision: Option<bool>,
+}
+
+/// Azure OpenAI settings, each of which can also be set through an environment variable.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct AzureConfig {
+    /// The resource endpoint, e.g. `https://my-resource.openai.azure.com` (`AZURE_OPENAI_ENDPOINT`).
+    pub endpoint: Option<String>,
+    /// The deployment name, used in place of a model (`AZURE_OPENAI_DEPLOYMENT`).
+    pub deployment: Option<String>,
+    /// The API version query parameter (`AZURE_OPENAI_API_VERSION`).
+    pub api_version: Option<String>,
+}
+
+/// A secret value, such as an API key, that is never revealed in debug output.
+#[derive(Clone, Default)]
+pub(crate) struct Secret(String);
+
+impl Secret {
+    pub(crate) fn new(value: String) -> Self {
+        Self(value)
+    }
+
+    /// Returns the underlying secret value.
+    pub(crate) fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
 }
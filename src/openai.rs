@@ -15,43 +15,48 @@
  */
 
 use std::{
-    env,
     fs::{self, OpenOptions},
-    io::{self, BufRead, BufReader, Write},
-    path::PathBuf,
-    sync::{Arc, Mutex},
-    thread,
+    io::{self, BufRead, BufReader, IsTerminal, Write},
+    path::{Path, PathBuf},
 };
 
-use reqwest::blocking::{Client, Response};
+use chrono::Local;
+use reqwest::StatusCode;
 
 use crate::{
-    cli::execute_command,
-    models::{Config, Message, OpenAIRequest, OpenAIResponse},
-    utils::start_loading_animation,
+    capabilities::ResponseFormat,
+    cli::{execute_command, CliOptions},
+    client::{
+        block_on, connect, handle_non_success, is_context_length_error, read_event_stream,
+        resolve_api_endpoint, ApiEndpoint, CompletionBackend,
+    },
+    command_shell::{command_shell, command_shell_name, shebang},
+    markdown::find_code_blocks,
+    memory::load_memory_prompt,
+    models::{Choice, CommandSuggestion, Config, Message, MessageContent, OpenAIRequest, OpenAIResponse},
+    transcript::fenced,
+    usage::{check_budget, describe_reproducibility, describe_request_cost, estimate_usage, record_usage},
+    utils::{estimate_tokens, read_context_file, read_terminal_line, start_loading_animation, InterruptGuard},
 };
 
 /// Constants for configuration file paths.
 const BANNED_COMMANDS_FILE: &str = ".gptsh_banned";
 const ALLOWED_COMMANDS_FILE: &str = ".gptsh_allowed";
 const CONFIG_FILE: &str = ".gptsh_config";
-const OPENAI_API_URL: &str = "https://api.openai.com/v1/chat/completions";
-const MODEL_NAME: &str = "gpt-4";
-
-/// Handles non-success responses from the OpenAI API by logging the error and exiting the application.
-///
-/// # Arguments
-///
-/// * `response` - The HTTP response from the OpenAI API.
-pub(crate) fn handle_non_success(response: Response) {
-    eprintln!(
-        "Error: Received non-success status code from OpenAI API: {}",
-        response.status()
-    );
-    let error_text = response.text().unwrap_or_default();
-    eprintln!("Response body: {}", error_text);
-    std::process::exit(1);
-}
+const DEFAULT_MAX_REFINEMENTS: u32 = 3;
+const PIPED_INPUT_INTRO: &str = "The following input was piped to this request. Use it as context:";
+/// How a `--file` attachment starts and ends its first line, around the file's path.
+const FILE_ATTACHMENT_INTRO: (&str, &str) = ("The file `", "` is attached as context:");
+/// How a `--context` note starts.
+const CONTEXT_NOTE_INTRO: &str = "Additional context:";
+const TRUNCATION_MARKER: &str = "\n[... truncated to fit the context window]";
+/// Piped input or an attachment shorter than this is not truncated any further.
+const MIN_TRUNCATED_CHARS: usize = 256;
+/// How many follow-up requests may extend a reply cut off at the token limit.
+pub(crate) const MAX_CONTINUATIONS: u32 = 3;
+/// The follow-up message asking the model to extend a reply cut off at the token limit.
+pub(crate) const CONTINUE_PROMPT: &str =
+    "Your reply was cut off at the token limit. Continue exactly where it stopped, without repeating anything.";
 
 /// Initializes the necessary configuration and command files if they do not exist.
 /// This should be called during the application's initialization phase.
@@ -82,7 +87,7 @@ fn initialize_file(file_path: &str) {
 /// # Returns
 ///
 /// * `io::Result<Vec<String>>` - A vector of banned commands or an I/O error.
-fn load_banned_commands() -> io::Result<Vec<String>> {
+pub(crate) fn load_banned_commands() -> io::Result<Vec<String>> {
     load_commands_from_file(BANNED_COMMANDS_FILE)
 }
 
@@ -95,7 +100,7 @@ fn load_banned_commands() -> io::Result<Vec<String>> {
 /// # Returns
 ///
 /// * `io::Result<()>` - An empty result or an I/O error.
-fn add_banned_command(command: &str) -> io::Result<()> {
+pub(crate) fn add_banned_command(command: &str) -> io::Result<()> {
     append_command_to_file(BANNED_COMMANDS_FILE, command)
 }
 
@@ -105,10 +110,34 @@ fn add_banned_command(command: &str) -> io::Result<()> {
 /// # Returns
 ///
 /// * `io::Result<Vec<String>>` - A vector of allowed commands or an I/O error.
-fn load_allowed_commands() -> io::Result<Vec<String>> {
+pub(crate) fn load_allowed_commands() -> io::Result<Vec<String>> {
     load_commands_from_file(ALLOWED_COMMANDS_FILE)
 }
 
+/// The commands that run without confirmation and the ones that never run.
+#[derive(Default)]
+struct CommandLists {
+    /// Commands from `.gptsh_allowed`.
+    allowed: Vec<String>,
+    /// Commands from `.gptsh_banned`.
+    banned: Vec<String>,
+}
+
+impl CommandLists {
+    /// Loads both lists, treating one that can't be read as empty.
+    fn load() -> Self {
+        let allowed = load_allowed_commands().unwrap_or_else(|err| {
+            eprintln!("Error loading allowed commands: {}", err);
+            Vec::new()
+        });
+        let banned = load_banned_commands().unwrap_or_else(|err| {
+            eprintln!("Error loading banned commands: {}", err);
+            Vec::new()
+        });
+        Self { allowed, banned }
+    }
+}
+
 /// Loads commands from a specified file, returning an empty vector if the file does not exist.
 ///
 /// # Arguments
@@ -126,9 +155,12 @@ fn load_commands_from_file(file_path: &str) -> io::Result<Vec<String>> {
 
     let file = fs::File::open(path)?;
     let reader = BufReader::new(file);
+    // A line that isn't valid UTF-8 is skipped rather than ending the list; only a read
+    // error stops it
     let commands = reader
-        .lines()
-        .filter_map(Result::ok)
+        .split(b'\n')
+        .map_while(Result::ok)
+        .filter_map(|line| String::from_utf8(line).ok())
         .map(|line| line.trim().to_string())
         .filter(|line| !line.is_empty())
         .collect::<Vec<String>>();
@@ -154,6 +186,49 @@ fn append_command_to_file(file_path: &str, command: &str) -> io::Result<()> {
     Ok(())
 }
 
+/// Loads the configuration from the `.gptsh_config` file.
+/// Returns the default configuration if the file does not exist or is empty.
+///
+/// # Returns
+///
+/// * `io::Result<Config>` - The configuration, or an error naming the file.
+pub(crate) fn load_config() -> io::Result<Config> {
+    read_config(Path::new(CONFIG_FILE))
+}
+
+/// Reads a configuration file. A file that can't be parsed, has a setting of the wrong type,
+/// or has an out-of-range setting is an error rather than the default configuration, so that
+/// limits such as the spend budget are never silently dropped.
+///
+/// # Arguments
+///
+/// * `path` - The path to the configuration file.
+///
+/// # Returns
+///
+/// * `io::Result<Config>` - The configuration, or an error whose message starts with the path.
+fn read_config(path: &Path) -> io::Result<Config> {
+    let with_path = |kind, e: &dyn std::fmt::Display| io::Error::new(kind, format!("{}: {}", path.display(), e));
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(with_path(e.kind(), &e)),
+    };
+    // `initialize_files` creates the file empty
+    if contents.trim().is_empty() {
+        return Ok(Config::default());
+    }
+
+    let config: Config =
+        serde_json::from_str(&contents).map_err(|e| with_path(io::ErrorKind::InvalidData, &e))?;
+    if let Some(generation) = &config.generation {
+        generation
+            .validate()
+            .map_err(|e| with_path(io::ErrorKind::InvalidData, &e))?;
+    }
+    Ok(config)
+}
+
 /// Loads the context from the `.gptsh_config` file.
 /// Returns an empty string if the file does not exist or if the context is not set.
 ///
@@ -161,56 +236,284 @@ fn append_command_to_file(file_path: &str, command: &str) -> io::Result<()> {
 ///
 /// * `io::Result<String>` - The context string or an I/O error.
 fn load_context() -> io::Result<String> {
-    let path = PathBuf::from(CONFIG_FILE);
-    if !path.exists() {
-        return Ok(String::new());
-    }
+    Ok(load_config()?.context.unwrap_or_default())
+}
 
-    let file = fs::File::open(&path)?;
-    let reader = BufReader::new(file);
-    let config: Config = serde_json::from_reader(reader).unwrap_or_default();
-    Ok(config.context.unwrap_or_default())
+/// Loads the maximum number of times a rejected command may be refined with feedback.
+///
+/// # Returns
+///
+/// * `u32` - The configured limit, or the default if none is set.
+fn load_max_refinements() -> u32 {
+    load_config()
+        .ok()
+        .and_then(|config| config.max_refinements)
+        .unwrap_or(DEFAULT_MAX_REFINEMENTS)
 }
 
-/// Extracts a bash command from a code block formatted string.
+/// Extracts a command from a code block formatted string, labeled `bash` or with the name
+/// of the shell that runs commands.
 /// For example, it will extract `ls -la` from "```bash\nls -la\n```".
 ///
 /// # Arguments
 ///
-/// * `input` - The input string potentially containing a bash code block.
+/// * `input` - The input string potentially containing a shell code block.
 ///
 /// # Returns
 ///
 /// * `Option<&str>` - The extracted command if a code block is present, else `None`.
 fn extract_command(input: &str) -> Option<&str> {
     let trimmed = input.trim();
-    if trimmed.starts_with("```bash") && trimmed.ends_with("```") {
-        trimmed
-            .strip_prefix("```bash\n")
-            .and_then(|s| s.strip_suffix("\n```"))
+    let shell = command_shell_name();
+    let is_shell_block = |language: &str| language == "bash" || language == shell;
+    let language = trimmed.strip_prefix("```").map(|rest| rest.lines().next().unwrap_or_default().trim());
+    if language.is_some_and(is_shell_block) && trimmed.ends_with("```") {
+        match find_code_blocks(trimmed).as_slice() {
+            [block] if is_shell_block(block.language) => Some(block.code),
+            _ => None,
+        }
     } else {
         Some(trimmed)
     }
 }
 
-/// Processes the user prompt by interacting with the OpenAI API, managing command execution,
-/// and handling banned and allowed commands.
+/// Incremental counterpart to `extract_command` for streamed replies.
+/// Tokens are pushed as they arrive and the displayable part of the command is returned,
+/// with an opening code fence (and its language tag) and the closing fence stripped.
+#[derive(Default)]
+struct StreamingCommandExtractor {
+    /// Text received but not yet returned.
+    pending: String,
+    /// Whether the start of the reply has been examined for an opening fence.
+    started: bool,
+    /// Whether the reply opened with a fence, so a closing one is expected.
+    fenced: bool,
+}
+
+impl StreamingCommandExtractor {
+    /// Adds a token to the stream and returns the text that can be displayed so far.
+    fn push(&mut self, token: &str) -> String {
+        self.pending.push_str(token);
+
+        if !self.started {
+            let trimmed = self.pending.trim_start();
+            if trimmed.starts_with("```") {
+                // Wait for the rest of the opening fence line, e.g. "```bash\n"
+                match trimmed.find('\n') {
+                    Some(newline) => self.pending = trimmed[newline + 1..].to_string(),
+                    None => return String::new(),
+                }
+                self.fenced = true;
+            } else if "```".starts_with(trimmed) {
+                // Could still become an opening fence
+                return String::new();
+            } else {
+                self.pending = trimmed.to_string();
+            }
+            self.started = true;
+        }
+
+        // Hold back a trailing line that might be the start of the closing fence
+        let hold_from = match self.pending.rfind('\n') {
+            Some(newline) if "```".starts_with(self.pending[newline + 1..].trim_end()) => newline,
+            _ if "```".starts_with(self.pending.as_str()) => 0,
+            _ => self.pending.len(),
+        };
+        let ready = self.pending[..hold_from].to_string();
+        self.pending.drain(..hold_from);
+        ready
+    }
+
+    /// Returns any remaining displayable text once the stream has ended.
+    fn finish(self) -> String {
+        let trimmed = self.pending.trim_end();
+        let trimmed = trimmed.strip_suffix("```").unwrap_or(trimmed);
+        // A shorter run of backticks held back at the end was a closing fence cut short
+        let (before, last_line) = trimmed.rsplit_once('\n').unwrap_or(("", trimmed));
+        let last_line = last_line.trim();
+        let trimmed = if self.fenced && !last_line.is_empty() && "```".starts_with(last_line) { before } else { trimmed };
+        trimmed.trim_end().to_string()
+    }
+}
+
+/// Incremental extractor for the `command` field of a streamed JSON suggestion, so the command
+/// can be shown as it is generated without the surrounding JSON.
+#[derive(Default)]
+struct StreamingJsonCommandExtractor {
+    /// The reply received so far.
+    received: String,
+    /// How many characters of the decoded command have already been returned.
+    emitted: usize,
+}
+
+impl StreamingJsonCommandExtractor {
+    /// Adds a token to the stream and returns the newly decoded part of the command.
+    fn push(&mut self, token: &str) -> String {
+        self.received.push_str(token);
+        let ready: String = partial_json_string(&self.received, "command")
+            .chars()
+            .skip(self.emitted)
+            .collect();
+        self.emitted += ready.chars().count();
+        ready
+    }
+}
+
+/// Decodes as much of a JSON string field as has been received so far,
+/// e.g. `ls -l` from `{"command": "ls -l`.
 ///
 /// # Arguments
 ///
-/// * `prompt` - The user's input prompt.
-/// * `no_execute` - If `true`, the command will not be executed but printed instead.
-pub(crate) fn process_prompt(prompt: &str, no_execute: bool) {
-    let api_key = match env::var("OPENAI_API_KEY") {
-        Ok(key) => key,
-        Err(_) => {
-            eprintln!("Error: OPENAI_API_KEY not set in environment.");
-            std::process::exit(1);
-        }
+/// * `json` - The partial JSON text.
+/// * `field` - The name of the string field.
+///
+/// # Returns
+///
+/// * `String` - The decoded value so far, or an empty string if it hasn't started.
+fn partial_json_string(json: &str, field: &str) -> String {
+    let key = format!("\"{}\"", field);
+    let value = json.find(&key).and_then(|start| {
+        json[start + key.len()..]
+            .trim_start()
+            .strip_prefix(':')
+            .and_then(|rest| rest.trim_start().strip_prefix('"'))
+    });
+    let Some(value) = value else {
+        return String::new();
     };
 
-    let client = Client::new();
+    let mut decoded = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some('n') => decoded.push('\n'),
+                Some('t') => decoded.push('\t'),
+                Some('r') => decoded.push('\r'),
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    // Stop at an escape that hasn't been fully received yet
+                    match u32::from_str_radix(&hex, 16).ok().filter(|_| hex.len() == 4) {
+                        Some(code) => decoded.extend(char::from_u32(code)),
+                        None => break,
+                    }
+                }
+                Some(escaped) => decoded.push(escaped),
+                None => break,
+            },
+            c => decoded.push(c),
+        }
+    }
+    decoded
+}
+
+/// Strips whatever wraps a command in a streamed reply, depending on the reply's format.
+enum CommandStream {
+    Fenced(StreamingCommandExtractor),
+    Json(StreamingJsonCommandExtractor),
+}
+
+impl CommandStream {
+    /// Adds a token to the stream and returns the text that can be displayed so far.
+    fn push(&mut self, token: &str) -> String {
+        match self {
+            CommandStream::Fenced(extractor) => extractor.push(token),
+            CommandStream::Json(extractor) => extractor.push(token),
+        }
+    }
+
+    /// Returns any remaining displayable text once the stream has ended.
+    fn finish(self) -> String {
+        match self {
+            CommandStream::Fenced(extractor) => extractor.finish(),
+            CommandStream::Json(_) => String::new(),
+        }
+    }
+}
+
+/// Returns the `response_format` that asks for a structured command suggestion.
+///
+/// # Arguments
+///
+/// * `format` - How the model can be asked for JSON.
+///
+/// # Returns
+///
+/// * `Option<serde_json::Value>` - The `response_format` value, or `None` for free-text replies.
+fn command_response_format(format: ResponseFormat) -> Option<serde_json::Value> {
+    match format {
+        ResponseFormat::Text => None,
+        ResponseFormat::JsonObject => Some(serde_json::json!({ "type": "json_object" })),
+        ResponseFormat::JsonSchema => Some(serde_json::json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "command_suggestion",
+                "strict": true,
+                "schema": {
+                    "type": "object",
+                    "properties": {
+                        "command": { "type": "string" },
+                        "explanation": { "type": "string" },
+                        "needs_confirmation": { "type": "boolean" }
+                    },
+                    "required": ["command", "explanation", "needs_confirmation"],
+                    "additionalProperties": false
+                }
+            }
+        })),
+    }
+}
+
+/// Parses a command suggestion from a reply. Structured (JSON) replies are read with serde;
+/// anything else falls back to stripping a code fence with `extract_command`.
+///
+/// # Arguments
+///
+/// * `reply` - The model's reply.
+///
+/// # Returns
+///
+/// * `CommandSuggestion` - The suggested command, with an explanation when one was given.
+fn parse_command_suggestion(reply: &str) -> CommandSuggestion {
+    let trimmed = reply.trim();
+    let json = trimmed
+        .strip_prefix("```json")
+        .and_then(|rest| rest.strip_suffix("```"))
+        .unwrap_or(trimmed);
+    match serde_json::from_str::<CommandSuggestion>(json.trim()) {
+        Ok(suggestion) => CommandSuggestion {
+            command: suggestion.command.trim().to_string(),
+            ..suggestion
+        },
+        Err(_) => CommandSuggestion {
+            command: extract_command(trimmed).unwrap_or(trimmed).trim().to_string(),
+            explanation: String::new(),
+            needs_confirmation: false,
+        },
+    }
+}
 
+/// Builds the request that translates a prompt into a shell command, without sending it.
+///
+/// # Arguments
+///
+/// * `prompt` - The user's input prompt.
+/// * `piped_input` - Text piped to gptsh through stdin, attached as additional context.
+/// * `attachments` - Context given with `--file` and `--context`, one message each.
+/// * `endpoint` - The endpoint, whose model determines the request shape.
+/// * `shell` - The name of the shell the command is for, e.g. `zsh`, so its syntax is used.
+///
+/// # Returns
+///
+/// * `OpenAIRequest` - The assembled request, including any configured context.
+fn build_prompt_request(
+    prompt: &str,
+    piped_input: Option<&str>,
+    attachments: &[String],
+    endpoint: &ApiEndpoint,
+    shell: &str,
+) -> OpenAIRequest {
     // Load the context from the configuration file
     let context = match load_context() {
         Ok(ctx) => ctx,
@@ -225,162 +528,1574 @@ pub(crate) fn process_prompt(prompt: &str, no_execute: bool) {
     if !context.is_empty() {
         messages.push(Message {
             role: "system".to_string(),
-            content: context.clone(),
+            content: context,
+        });
+    }
+    if let Some(memories) = load_memory_prompt() {
+        messages.push(Message {
+            role: "system".to_string(),
+            content: memories,
         });
     }
 
+    if let Some(input) = piped_input {
+        messages.push(Message {
+            role: "user".to_string(),
+            content: format!("{}\n```\n{}\n```", PIPED_INPUT_INTRO, input),
+        });
+    }
+    for attachment in attachments {
+        messages.push(Message {
+            role: "user".to_string(),
+            content: attachment.clone(),
+        });
+    }
+
+    let capabilities = endpoint.capabilities;
+    let response_format = command_response_format(capabilities.response_format);
+    let instruction = if response_format.is_some() {
+        format!(
+            "Translate the following prompt into a {} command. Reply with a JSON object with the fields \
+             \"command\" (the {} command), \"explanation\" (one short sentence), and \
+             \"needs_confirmation\" (true if the command modifies or deletes data):",
+            shell, shell
+        )
+    } else {
+        format!("Translate the following prompt into a {} command without explanation:", shell)
+    };
     messages.push(Message {
         role: "user".to_string(),
-        content: format!(
-            "Translate the following prompt into a bash command without explanation:\n{}",
-            prompt
-        ),
+        content: format!("{}\n{}", instruction, prompt),
     });
 
-    let request_body = OpenAIRequest {
-        model: MODEL_NAME.to_string(),
-        messages
-    };
+    let config = load_config().unwrap_or_default();
+    let max_tokens = config.max_tokens;
+    let seed = config.seed;
+    let sampling = config
+        .generation
+        .and_then(|generation| generation.command)
+        .unwrap_or_default();
+    OpenAIRequest {
+        model: endpoint.model.clone(),
+        messages: capabilities.adapt_messages(messages),
+        max_tokens: max_tokens.filter(|_| !capabilities.max_completion_tokens),
+        max_completion_tokens: max_tokens.filter(|_| capabilities.max_completion_tokens),
+        response_format,
+        sampling,
+        seed,
+    }
+}
+
+/// Reads the context given with `--file` and `--context` into the messages that attach it
+/// to a request: each file's text in a code fence under its path, then each note as given.
+/// Blank files are left out.
+///
+/// # Arguments
+///
+/// * `options` - The command-line options, whose `files` and `context` are read.
+///
+/// # Returns
+///
+/// * `Result<Vec<String>, String>` - The messages, or an error naming a file that could
+///   not be used.
+fn read_attachments(options: &CliOptions) -> Result<Vec<String>, String> {
+    let mut attachments = Vec::new();
+    for path in &options.files {
+        if let Some(text) = read_context_file(path)? {
+            let (before, after) = FILE_ATTACHMENT_INTRO;
+            attachments.push(format!("{}{}{}\n{}", before, path, after, fenced("", &text)));
+        }
+    }
+    for note in &options.context {
+        attachments.push(format!("{}\n{}", CONTEXT_NOTE_INTRO, note));
+    }
+    Ok(attachments)
+}
 
-    // Start loading animation
-    let stop_signal = Arc::new(Mutex::new(false));
-    let loading_handle = {
-        let stop_signal_clone = Arc::clone(&stop_signal);
-        thread::spawn(move || {
-            start_loading_animation(stop_signal_clone);
+/// Halves the longest piped input, `--file` attachment, or `--context` note in a request
+/// so it fits the context window, keeping its beginning. The prompt itself and the
+/// configured context are left alone.
+///
+/// # Arguments
+///
+/// * `messages` - The request's messages.
+///
+/// # Returns
+///
+/// * `Option<usize>` - How many characters were dropped, or `None` if nothing attached is
+///   long enough to truncate.
+fn truncate_attached_input(messages: &mut [Message]) -> Option<usize> {
+    let (message, start, end, length) = messages
+        .iter_mut()
+        .filter_map(|message| {
+            let (start, end) = attached_material(&message.content)?;
+            let input = &message.content[start..end];
+            let length = input.strip_suffix(TRUNCATION_MARKER).unwrap_or(input).chars().count();
+            Some((message, start, end, length))
         })
-    };
+        .filter(|(_, _, _, length)| *length >= MIN_TRUNCATED_CHARS)
+        .max_by_key(|(_, _, _, length)| *length)?;
 
-    // Send the request to OpenAI API
-    let response = client
-        .post(OPENAI_API_URL)
-        .bearer_auth(api_key)
-        .json(&request_body)
-        .send();
+    let kept: String = message.content[start..end].chars().take(length / 2).collect();
+    message.content = format!(
+        "{}{}{}{}",
+        &message.content[..start],
+        kept,
+        TRUNCATION_MARKER,
+        &message.content[end..]
+    );
+    Some(length - length / 2)
+}
 
-    // Stop loading animation
-    {
-        let mut stop = stop_signal.lock().unwrap();
-        *stop = true;
+/// Finds the material attached in a message that may be shortened: piped input or a file
+/// between their code fences, or the text of a `--context` note.
+///
+/// # Returns
+///
+/// * `Option<(usize, usize)>` - The byte range of the material, or `None` if the message
+///   attaches nothing.
+fn attached_material(content: &str) -> Option<(usize, usize)> {
+    let (first_line, _) = content.split_once('\n')?;
+    let (before, after) = FILE_ATTACHMENT_INTRO;
+    if first_line == CONTEXT_NOTE_INTRO {
+        return Some((first_line.len() + 1, content.len()));
+    }
+    if first_line != PIPED_INPUT_INTRO && !(first_line.starts_with(before) && first_line.ends_with(after)) {
+        return None;
     }
-    loading_handle.join().unwrap();
+    // Files are fenced with more backticks than they contain, so find the fence first
+    let fence_start = first_line.len() + 1;
+    let fence_end = fence_start + content[fence_start..].find('\n')?;
+    let end = content.rfind(&format!("\n{}", &content[fence_start..fence_end]))?;
+    (end > fence_end).then_some((fence_end + 1, end))
+}
 
-    match response {
-        Ok(resp) => {
-            if resp.status().is_success() {
-                let openai_response: OpenAIResponse = match resp.json() {
-                    Ok(json) => json,
-                    Err(e) => {
-                        eprintln!("Failed to parse OpenAI response: {}", e);
-                        std::process::exit(1);
-                    }
-                };
+/// Prints a preview of a request instead of sending it.
+///
+/// # Arguments
+///
+/// * `api_url` - The URL the request would be sent to.
+/// * `request` - The request that would be sent.
+fn print_dry_run(api_url: &str, request: &OpenAIRequest) {
+    println!("{}", format_dry_run(api_url, request));
+}
 
-                if openai_response.choices.is_empty() {
-                    eprintln!("OpenAI response contains no choices.");
-                    std::process::exit(1);
-                }
+/// Describes a request for [`print_dry_run`]: where it would go, the model, an estimate of
+/// its prompt tokens, and every message.
+fn format_dry_run(api_url: &str, request: &OpenAIRequest) -> String {
+    let estimated_tokens: usize = request
+        .messages
+        .iter()
+        .map(|message| estimate_tokens(&message.content))
+        .sum();
 
-                let command_with_block = openai_response.choices[0]
-                    .message
-                    .content
-                    .trim()
-                    .to_string();
-
-                // Extract the pure command without the code block
-                let parsed_command = extract_command(&command_with_block).unwrap_or(&command_with_block).trim().to_string();
-
-                // Load allowed and banned commands
-                let allowed_commands = match load_allowed_commands() {
-                    Ok(commands) => commands,
-                    Err(err) => {
-                        eprintln!("Error loading allowed commands: {}", err);
-                        Vec::new()
-                    }
-                };
+    let mut preview = format!(
+        "Dry run: no request will be sent.\nEndpoint: {}\nModel: {}\nEstimated prompt tokens: ~{}",
+        api_url, request.model, estimated_tokens
+    );
+    for (index, message) in request.messages.iter().enumerate() {
+        preview.push_str(&format!("\n\n--- Message {} ({}) ---\n{}", index + 1, message.role, message.content));
+    }
+    preview
+}
 
-                let banned_commands = match load_banned_commands() {
-                    Ok(commands) => commands,
-                    Err(err) => {
-                        eprintln!("Error loading banned commands: {}", err);
-                        Vec::new()
-                    }
-                };
-
-                // Check if the command is in the allowed list
-                if allowed_commands.iter().any(|a| a == &parsed_command) {
-                    if no_execute {
-                        println!("{}", parsed_command);
-                    } else {
-                        println!("\nGenerated Command:\n```bash\n{}\n```", parsed_command);
-                        execute_command(&parsed_command);
-                    }
-                    return;
-                }
+/// Processes the user prompt by interacting with the OpenAI API, managing command execution,
+/// and handling banned and allowed commands.
+///
+/// # Arguments
+///
+/// * `prompt` - The user's input prompt.
+/// * `options` - The command-line options, e.g. `no_execute` to print instead of executing.
+/// * `piped_input` - Text piped to gptsh through stdin, attached as additional context.
+///
+/// # Returns
+///
+/// * `bool` - `false` if the request could not be completed.
+pub(crate) fn process_prompt(prompt: &str, options: &CliOptions, piped_input: Option<&str>) -> bool {
+    let attachments = match read_attachments(options) {
+        Ok(attachments) => attachments,
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    process_prompt_with_context(prompt, options, piped_input, &attachments, None).is_some()
+}
 
-                // Check if the command is banned
-                if banned_commands.iter().any(|b| b == &parsed_command) {
-                    println!(
-                        "Warning: The command \"{}\" is banned and will not be executed.",
-                        parsed_command
-                    );
-                    return;
-                }
+/// How a command-translation request ended, which shell mode remembers so later prompts
+/// can refer back to it.
+#[derive(Debug, Default)]
+pub(crate) struct Translation {
+    /// The command the model suggested; empty if there was none.
+    pub(crate) command: String,
+    /// The command's exit status, as a shell reports it, or `None` if it wasn't run.
+    pub(crate) exit_status: Option<i32>,
+    /// Whether the user chose to run the command in the background, which is left to the
+    /// caller to start.
+    pub(crate) background: bool,
+    /// The command as the model suggested it, if the user changed it before it ran.
+    pub(crate) suggested: Option<String>,
+}
 
-                if no_execute {
-                    println!("{}", parsed_command);
-                } else {
-                    println!("\nGenerated Command:\n```bash\n{}\n```", parsed_command);
+impl Translation {
+    pub(crate) fn new(command: &str, exit_status: Option<i32>) -> Self {
+        Self {
+            command: command.to_string(),
+            exit_status,
+            background: false,
+            suggested: None,
+        }
+    }
+
+    /// A command the user chose to run in the background.
+    pub(crate) fn in_background(command: &str) -> Self {
+        Self {
+            background: true,
+            ..Self::new(command, None)
+        }
+    }
+}
+
+/// Processes a prompt like [`process_prompt`], telling the model what happened earlier in
+/// the session first.
+///
+/// # Arguments
+///
+/// * `prompt` - The user's input prompt.
+/// * `options` - The command-line options, e.g. `no_execute` to print instead of executing.
+/// * `piped_input` - Text piped to gptsh through stdin, attached as additional context.
+/// * `attachments` - Context given with `--file` and `--context`, one message each.
+/// * `context` - A description of the earlier prompts and commands, put before the prompt.
+///
+/// # Returns
+///
+/// * `Option<Translation>` - What was suggested and whether it ran, or `None` if the
+///   request could not be completed.
+pub(crate) fn process_prompt_with_context(
+    prompt: &str,
+    options: &CliOptions,
+    piped_input: Option<&str>,
+    attachments: &[String],
+    context: Option<&str>,
+) -> Option<Translation> {
+    let endpoint = match resolve_api_endpoint(options) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            eprintln!("{}", e);
+            return None;
+        }
+    };
 
-                    // Prompt user for confirmation with 'y', 'n', 'b' options
-                    print!("Do you want to execute this command? (Y/n/b for ban) ");
-                    io::stdout().flush().unwrap();
+    let request_body = build_request_with_context(prompt, options, piped_input, attachments, context, &endpoint);
+    if options.dry_run {
+        print_dry_run(&endpoint.url, &request_body);
+        return Some(Translation::default());
+    }
 
-                    let confirmation = read_user_confirmation();
+    match connect(options, endpoint) {
+        Ok(backend) => translate_prompt(
+            backend.as_ref(),
+            prompt,
+            options,
+            request_body,
+            &CommandLists::load(),
+            &mut read_terminal_line,
+        ),
+        Err(e) => {
+            eprintln!("{}", e);
+            None
+        }
+    }
+}
 
-                    match confirmation.as_str() {
-                        "y" | "yes" | "" => {
-                            // Execute the command
-                            execute_command(&parsed_command);
-                        }
-                        "n" | "no" => {
-                            println!("Command execution cancelled.");
-                        }
-                        "b" | "ban" => {
-                            // Add the command to the banned list
-                            if let Err(e) = add_banned_command(&parsed_command) {
-                                eprintln!("Error banning the command: {}", e);
-                            } else {
-                                println!("Command \"{}\" has been banned.", parsed_command);
-                            }
-                        }
-                        _ => {
-                            println!("Invalid input. Command execution cancelled.");
-                        }
-                    }
-                }
-            } else {
-                handle_non_success(resp);
-            }
+/// Builds the request that translates a prompt into a command for the user's shell, with
+/// `--seed` and a description of the session so far put before the prompt.
+fn build_request_with_context(
+    prompt: &str,
+    options: &CliOptions,
+    piped_input: Option<&str>,
+    attachments: &[String],
+    context: Option<&str>,
+    endpoint: &ApiEndpoint,
+) -> OpenAIRequest {
+    let mut request_body = build_prompt_request(prompt, piped_input, attachments, endpoint, &command_shell_name());
+    if options.seed.is_some() {
+        request_body.seed = options.seed;
+    }
+    if let (Some(context), Some(message)) = (context, request_body.messages.last_mut()) {
+        message.content = format!("{}\n\n{}", context, message.content);
+    }
+    request_body
+}
+
+/// Asks for a command like [`process_prompt_with_context`], but returns it instead of
+/// confirming and running it, so the caller can let the user edit it first.
+///
+/// # Arguments
+///
+/// * `prompt` - The user's input prompt.
+/// * `options` - The command-line options, e.g. `dry_run` to show the request instead.
+/// * `context` - A description of the earlier prompts and commands, put before the prompt.
+///
+/// # Returns
+///
+/// * `Option<CommandSuggestion>` - The suggested command, or `None` if there is none; the
+///   reason has been reported.
+pub(crate) fn suggest_command_with_context(
+    prompt: &str,
+    options: &CliOptions,
+    context: Option<&str>,
+) -> Option<CommandSuggestion> {
+    let endpoint = match resolve_api_endpoint(options) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            eprintln!("{}", e);
+            return None;
         }
+    };
+    let request_body = build_request_with_context(prompt, options, None, &[], context, &endpoint);
+    if options.dry_run {
+        print_dry_run(&endpoint.url, &request_body);
+        return None;
+    }
+    if let Err(e) = check_budget(options.override_budget) {
+        eprintln!("{}", e);
+        return None;
+    }
+
+    let backend = match connect(options, endpoint) {
+        Ok(backend) => backend,
         Err(e) => {
-            eprintln!("Error communicating with OpenAI API: {}", e);
-            std::process::exit(1);
+            eprintln!("{}", e);
+            return None;
+        }
+    };
+    match request_suggestion(backend.as_ref(), request_body, options.override_budget) {
+        Ok(suggestion) => Some(suggestion),
+        Err(e) => {
+            eprintln!("Error: No command was suggested: {}.", e);
+            None
         }
     }
 }
 
-/// Reads and interprets user confirmation input.
+/// How the result of a prompt given as arguments is printed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum OutputFormat {
+    /// The command, for people, with the question whether to run it.
+    #[default]
+    Text,
+    /// One JSON object describing the reply, for scripts; nothing is run.
+    Json,
+}
+
+impl OutputFormat {
+    /// Parses a format name, `text` or `json`, ignoring case.
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "text" => Some(Self::Text),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Describes a reply as the JSON object `--output json` prints: the model, whether the
+/// reply was cut off, and either the parsed command or, with `--raw`, the reply verbatim.
+///
+/// # Arguments
+///
+/// * `model` - The model the request was sent to.
+/// * `response` - The reply.
+/// * `raw` - Whether to include the reply as received instead of parsing it.
+/// * `banned_commands` - Commands in `.gptsh_banned`, so a banned command is marked.
 ///
 /// # Returns
 ///
-/// * `String` - The user's input in lowercase.
-fn read_user_confirmation() -> String {
-    let mut input = String::new();
-    if let Err(_) = io::stdin().read_line(&mut input) {
-        eprintln!("Failed to read input.");
-        return String::new();
+/// * `serde_json::Value` - The object, with `command`, `explanation`, `needs_confirmation`
+///   and `banned`, or with `raw`.
+fn describe_reply(
+    model: &str,
+    response: &OpenAIResponse,
+    raw: bool,
+    banned_commands: &[String],
+) -> serde_json::Value {
+    let content = response.choices.first().map(|choice| choice.message.content.as_str()).unwrap_or_default();
+    let mut reply = serde_json::json!({ "model": model, "truncated": response.is_truncated() });
+    if raw {
+        reply["raw"] = content.into();
+    } else {
+        let suggestion = parse_command_suggestion(content.trim());
+        reply["banned"] = banned_commands.contains(&suggestion.command).into();
+        reply["command"] = suggestion.command.into();
+        reply["explanation"] = suggestion.explanation.trim().into();
+        reply["needs_confirmation"] = suggestion.needs_confirmation.into();
     }
-    input.trim().to_lowercase()
+    reply
 }
 
+/// Sends a command-translation request and handles the suggested command: executing,
+/// refining, or banning it as the user decides.
+///
+/// # Arguments
+///
+/// * `backend` - Where the request is sent.
+/// * `prompt` - The user's input prompt.
+/// * `options` - The command-line options, e.g. `no_execute` to print instead of executing.
+/// * `request_body` - The request built from the prompt.
+/// * `lists` - The allowed and banned commands.
+/// * `read_line` - Reads the user's answers, e.g. [`read_terminal_line`].
+///
+/// # Returns
+///
+/// * `Option<Translation>` - What was suggested and whether it ran, or `None` if the
+///   request could not be completed.
+fn translate_prompt(
+    backend: &dyn CompletionBackend,
+    prompt: &str,
+    options: &CliOptions,
+    mut request_body: OpenAIRequest,
+    lists: &CommandLists,
+    read_line: &mut impl FnMut() -> io::Result<String>,
+) -> Option<Translation> {
+    let json = options.output == OutputFormat::Json;
+    let no_execute = options.no_execute || json;
+    let endpoint = backend.endpoint();
+    let max_refinements = load_max_refinements();
+    let mut refinements = 0;
+
+    // Stream the command as it is generated, unless the output is meant for another program
+    let stream = !no_execute
+        && !options.raw
+        && io::stdout().is_terminal()
+        && endpoint.capabilities.streaming
+        && load_config().ok().and_then(|config| config.stream).unwrap_or(true);
+
+    let config = load_config().unwrap_or_default();
+    let show_cost = options.show_cost || config.show_cost.unwrap_or(false);
+    let continue_truncated = config.continue_truncated.unwrap_or(false);
+
+    loop {
+        if let Err(e) = check_budget(options.override_budget) {
+            eprintln!("{}", e);
+            return None;
+        }
+
+        let (mut openai_response, mut streamed) =
+            match block_on(send_prompt_request(backend, &request_body, stream)) {
+                Ok(response) => response,
+                Err(PromptFailure::ResponseFormatRejected) => {
+                    // Retry with a free-text reply; the fence-stripping parser still applies
+                    eprintln!("Note: This endpoint does not support structured output; falling back to a plain reply.");
+                    request_body.response_format = None;
+                    continue;
+                }
+                Err(PromptFailure::ContextLengthExceeded(error_text)) => {
+                    match truncate_attached_input(&mut request_body.messages) {
+                        Some(dropped) => {
+                            eprintln!(
+                                "Note: Truncated {} characters of attached input to fit the context window.",
+                                dropped
+                            );
+                            continue;
+                        }
+                        None => {
+                            backend.report_api_error(StatusCode::BAD_REQUEST, &error_text);
+                            return None;
+                        }
+                    }
+                }
+                Err(PromptFailure::Failed) => return None,
+            };
+
+        if openai_response.is_truncated() && continue_truncated {
+            eprintln!("Note: The reply was cut off at the token limit; asking the model to continue.");
+            continue_reply(backend, &request_body, &mut openai_response, options.override_budget);
+            // The streamed command is incomplete, so the joined one is printed in full below
+            streamed = false;
+        }
+
+        if show_cost {
+            let usage = openai_response.usage.unwrap_or_default();
+            eprintln!("{}", describe_request_cost(&endpoint.model, &usage, &config));
+            if let Some(reproducibility) =
+                describe_reproducibility(request_body.seed, openai_response.system_fingerprint.as_deref())
+            {
+                eprintln!("{}", reproducibility);
+            }
+        }
+
+        // JSON output describes the reply for another program and never executes it
+        if json {
+            let reply = describe_reply(&endpoint.model, &openai_response, options.raw, &lists.banned);
+            println!("{}", reply);
+            if openai_response.is_truncated() {
+                eprintln!("Warning: The reply was cut off at the token limit and is incomplete.");
+                return None;
+            }
+            return Some(Translation::new(reply["command"].as_str().unwrap_or_default(), None));
+        }
+
+        // Raw mode prints the reply exactly as received and never executes it
+        if options.raw {
+            println!("{}", openai_response.choices[0].message.content);
+            if openai_response.is_truncated() {
+                eprintln!("Warning: The reply was cut off at the token limit and is incomplete.");
+                return None;
+            }
+            return Some(Translation::default());
+        }
+
+        if options.verbose {
+            eprintln!("Raw reply:\n{}", openai_response.choices[0].message.content);
+        }
+
+        // A command cut off mid-flag could do something quite different from what was asked
+        if openai_response.is_truncated() {
+            if !no_execute && !streamed {
+                println!("\nIncomplete reply:\n{}", openai_response.choices[0].message.content.trim());
+            }
+            eprintln!(
+                "Warning: The reply was cut off at the token limit, so the command is incomplete and will not be executed. \
+                 Raise `max_tokens` or set `continue_truncated` in .gptsh_config."
+            );
+            return None;
+        }
+
+        let command_with_block = openai_response.choices[0]
+            .message
+            .content
+            .trim()
+            .to_string();
+
+        // Read the command from the structured reply, or strip the code block around it
+        let suggestion = parse_command_suggestion(&command_with_block);
+        let parsed_command = suggestion.command.clone();
+
+        // Check if the command is in the allowed list
+        if lists.allowed.iter().any(|a| a == &parsed_command) {
+            if no_execute {
+                println!("{}", parsed_command);
+                return Some(Translation::new(&parsed_command, None));
+            }
+            if !streamed {
+                println!("\nGenerated Command:\n{}", fenced(&command_shell_name(), &parsed_command));
+            }
+            record_approved_command(options, prompt, &parsed_command);
+            let exit_status = execute_command(&parsed_command, options.continuous_mode);
+            return Some(Translation::new(&parsed_command, exit_status));
+        }
+
+        // Check if the command is banned
+        if lists.banned.iter().any(|b| b == &parsed_command) {
+            println!(
+                "Warning: The command \"{}\" is banned and will not be executed.",
+                parsed_command
+            );
+            return Some(Translation::new(&parsed_command, None));
+        }
+
+        if no_execute {
+            println!("{}", parsed_command);
+            return Some(Translation::new(&parsed_command, None));
+        }
+
+        if !streamed {
+            println!("\nGenerated Command:\n{}", fenced(&command_shell_name(), &parsed_command));
+        }
+        if !suggestion.explanation.trim().is_empty() {
+            println!("{}", suggestion.explanation.trim());
+        }
+        if suggestion.needs_confirmation {
+            println!("Warning: The model flagged this command as modifying or deleting data.");
+        }
+
+        // Pressing Enter runs the command here; chat mode's default is set by confirm_default
+        print!("{}", command_prompt(true, false, options.continuous_mode));
+        io::stdout().flush().unwrap();
+
+        let exit_status = match parse_command_answer(&read_user_confirmation(read_line), true) {
+            CommandAnswer::Run => {
+                record_approved_command(options, prompt, &parsed_command);
+                execute_command(&parsed_command, options.continuous_mode)
+            }
+            CommandAnswer::Cancel => {
+                println!("Command execution cancelled.");
+                if refinements >= max_refinements {
+                    if max_refinements > 0 {
+                        println!("Reached the limit of {} refinements.", max_refinements);
+                    }
+                    return Some(Translation::new(&parsed_command, None));
+                }
+
+                // Offer to refine the suggestion with the user's feedback
+                if let Some(feedback) = read_refinement_feedback(read_line) {
+                    refinements += 1;
+                    add_refinement_messages(
+                        &mut request_body.messages,
+                        &command_with_block,
+                        &parsed_command,
+                        &feedback,
+                    );
+                    continue;
+                }
+                None
+            }
+            CommandAnswer::Ban => {
+                if let Err(e) = add_banned_command(&parsed_command) {
+                    eprintln!("Error banning the command: {}", e);
+                } else {
+                    println!("Command \"{}\" has been banned.", parsed_command);
+                }
+                None
+            }
+            // Shell mode starts the job, so it can be kept track of
+            CommandAnswer::Background if options.continuous_mode => {
+                record_approved_command(options, prompt, &parsed_command);
+                return Some(Translation::in_background(&parsed_command));
+            }
+            CommandAnswer::Edit | CommandAnswer::Interactive | CommandAnswer::Background | CommandAnswer::Invalid => {
+                println!("Invalid input. Command execution cancelled.");
+                None
+            }
+        };
+        return Some(Translation::new(&parsed_command, exit_status));
+    }
+}
+
+/// Translates a prompt into a command without executing it or asking anything, for batch mode.
+/// Banned commands are refused like any other failure.
+///
+/// # Arguments
+///
+/// * `backend` - Where the request is sent.
+/// * `prompt` - The natural-language prompt.
+/// * `options` - The command-line options, for `--seed`.
+///
+/// # Returns
+///
+/// * `Result<CommandSuggestion, String>` - The suggested command, or why there is none.
+pub(crate) fn suggest_command(
+    backend: &dyn CompletionBackend,
+    prompt: &str,
+    options: &CliOptions,
+) -> Result<CommandSuggestion, String> {
+    let mut request_body = build_prompt_request(prompt, None, &[], backend.endpoint(), &command_shell_name());
+    if options.seed.is_some() {
+        request_body.seed = options.seed;
+    }
+    request_suggestion(backend, request_body, options.override_budget)
+}
+
+/// Sends a command-translation request and reads the suggested command from the reply,
+/// without streaming it, running it, or asking anything.
+fn request_suggestion(
+    backend: &dyn CompletionBackend,
+    request_body: OpenAIRequest,
+    override_budget: bool,
+) -> Result<CommandSuggestion, String> {
+    let continue_truncated = load_config().ok().and_then(|config| config.continue_truncated).unwrap_or(false);
+    let banned_commands = load_banned_commands().unwrap_or_default();
+    fetch_suggestion(backend, request_body, continue_truncated, override_budget, &banned_commands)
+}
+
+/// Does the work of `request_suggestion` with the settings it loads.
+///
+/// # Arguments
+///
+/// * `backend` - Where the request is sent.
+/// * `request_body` - The request to send.
+/// * `continue_truncated` - Whether a reply cut off at the token limit is continued.
+/// * `override_budget` - Whether `--override-budget` was given, for continuations.
+/// * `banned_commands` - Commands that are refused.
+///
+/// # Returns
+///
+/// * `Result<CommandSuggestion, String>` - The suggested command, or why there is none.
+fn fetch_suggestion(
+    backend: &dyn CompletionBackend,
+    mut request_body: OpenAIRequest,
+    continue_truncated: bool,
+    override_budget: bool,
+    banned_commands: &[String],
+) -> Result<CommandSuggestion, String> {
+    loop {
+        match block_on(send_prompt_request(backend, &request_body, false)) {
+            Ok((mut openai_response, _)) => {
+                if openai_response.is_truncated() && continue_truncated {
+                    continue_reply(backend, &request_body, &mut openai_response, override_budget);
+                }
+                if openai_response.is_truncated() {
+                    return Err("the reply was cut off at the token limit".to_string());
+                }
+                let suggestion = parse_command_suggestion(openai_response.choices[0].message.content.trim());
+                if suggestion.command.trim().is_empty() {
+                    return Err("the reply contained no command".to_string());
+                }
+                if banned_commands.iter().any(|b| b == &suggestion.command) {
+                    return Err(format!("the suggested command is banned: {}", suggestion.command));
+                }
+                return Ok(suggestion);
+            }
+            Err(PromptFailure::ResponseFormatRejected) => request_body.response_format = None,
+            Err(PromptFailure::ContextLengthExceeded(_)) => {
+                return Err("the prompt is longer than the model's context window".to_string())
+            }
+            Err(PromptFailure::Failed) => return Err("the request failed".to_string()),
+        }
+    }
+}
+
+/// Why a command-translation request produced no response.
+enum PromptFailure {
+    /// The endpoint rejected `response_format`; the request can be retried without it.
+    ResponseFormatRejected,
+    /// The request is longer than the context window; holds the unreported error body.
+    ContextLengthExceeded(String),
+    /// The failure has already been reported.
+    Failed,
+}
+
+/// Sends a command-translation request to the API while showing the loading animation.
+/// When streaming, the command is printed as it is generated.
+/// Rate-limited and transient failures are retried; other failures are reported.
+///
+/// # Arguments
+///
+/// * `backend` - Where the request is sent.
+/// * `request_body` - The request to send.
+/// * `stream` - Whether to stream the reply.
+///
+/// # Returns
+///
+/// * `Result<(OpenAIResponse, bool), PromptFailure>` - The parsed response, guaranteed to contain
+///   at least one choice, and whether the generated command was already printed while streaming;
+///   or why the request failed.
+async fn send_prompt_request(
+    backend: &dyn CompletionBackend,
+    request_body: &OpenAIRequest,
+    stream: bool,
+) -> Result<(OpenAIResponse, bool), PromptFailure> {
+    let endpoint = backend.endpoint();
+    let spinner = start_loading_animation();
+
+    // Send the request to the API
+    let mut body = serde_json::to_value(request_body).unwrap_or_default();
+    if stream {
+        endpoint.request_streaming(&mut body);
+    }
+    // Ctrl-C cancels the request and returns to the prompt in shell mode
+    let interrupt = InterruptGuard::new();
+    let Some(response) = interrupt.run(backend.complete(&body)).await else {
+        spinner.stop();
+        println!("Request cancelled.");
+        return Err(PromptFailure::Failed);
+    };
+
+    match response {
+        Ok(resp) if resp.status().is_success() && stream => {
+            spinner.stop();
+            println!("\nGenerated Command:\n```{}", command_shell_name());
+            let mut extractor = if request_body.response_format.is_some() {
+                CommandStream::Json(StreamingJsonCommandExtractor::default())
+            } else {
+                CommandStream::Fenced(StreamingCommandExtractor::default())
+            };
+            let mut content = String::new();
+            let mut usage = None;
+            let mut system_fingerprint = None;
+            let mut finish_reason = None;
+            let result = interrupt
+                .run(read_event_stream(resp, |chunk| {
+                    if let Some(fingerprint) = chunk["system_fingerprint"].as_str() {
+                        system_fingerprint = Some(fingerprint.to_string());
+                    }
+                    if let Some(reason) = chunk["choices"][0]["finish_reason"].as_str() {
+                        finish_reason = Some(reason.to_string());
+                    }
+                    if chunk["usage"].is_object() {
+                        usage = serde_json::from_value(chunk["usage"].clone()).ok();
+                    }
+                    if let Some(token) = chunk["choices"][0]["delta"]["content"].as_str() {
+                        content.push_str(token);
+                        print!("{}", extractor.push(token));
+                        io::stdout().flush().unwrap();
+                    }
+                    true
+                }))
+                .await;
+            println!("{}\n```", extractor.finish().trim_end());
+
+            match result {
+                Some(Ok(())) => {}
+                Some(Err(e)) => {
+                    eprintln!("Error reading streamed response: {}", e);
+                    return Err(PromptFailure::Failed);
+                }
+                // A partly generated command is never offered
+                None => {
+                    println!("Request cancelled.");
+                    return Err(PromptFailure::Failed);
+                }
+            }
+            if content.trim().is_empty() {
+                eprintln!("The response contains no choices.");
+                return Err(PromptFailure::Failed);
+            }
+
+            let mut openai_response = OpenAIResponse {
+                choices: vec![Choice {
+                    message: MessageContent { content },
+                    finish_reason,
+                }],
+                usage,
+                system_fingerprint,
+            };
+            record_request_usage(backend, request_body, &mut openai_response);
+            Ok((openai_response, true))
+        }
+        Ok(resp) if resp.status().is_success() => {
+            spinner.stop();
+            let mut openai_response: OpenAIResponse = match interrupt.run(resp.json()).await {
+                Some(Ok(json)) => json,
+                Some(Err(e)) => {
+                    eprintln!("Failed to parse the response: {}", e);
+                    return Err(PromptFailure::Failed);
+                }
+                None => {
+                    println!("Request cancelled.");
+                    return Err(PromptFailure::Failed);
+                }
+            };
+
+            if openai_response.choices.is_empty() {
+                eprintln!("The response contains no choices.");
+                return Err(PromptFailure::Failed);
+            }
+            record_request_usage(backend, request_body, &mut openai_response);
+            Ok((openai_response, false))
+        }
+        Ok(resp) if resp.status() == StatusCode::BAD_REQUEST => {
+            spinner.stop();
+            let status = resp.status();
+            let error_text = resp.text().await.unwrap_or_default();
+            if request_body.response_format.is_some() && error_text.contains("response_format") {
+                return Err(PromptFailure::ResponseFormatRejected);
+            }
+            if is_context_length_error(&error_text) {
+                return Err(PromptFailure::ContextLengthExceeded(error_text));
+            }
+            backend.report_api_error(status, &error_text);
+            Err(PromptFailure::Failed)
+        }
+        Ok(resp) => {
+            spinner.stop();
+            handle_non_success(backend, resp).await;
+            Err(PromptFailure::Failed)
+        }
+        Err(e) => {
+            spinner.stop();
+            backend.report_request_error(&e);
+            Err(PromptFailure::Failed)
+        }
+    }
+}
+
+/// Extends a reply that was cut off at the token limit by asking the model to continue it,
+/// up to `MAX_CONTINUATIONS` times. Each piece is appended to the reply and its tokens are
+/// added to the reply's usage. If a follow-up fails, the reply is left as it is.
+///
+/// # Arguments
+///
+/// * `backend` - Where the requests are sent.
+/// * `request_body` - The request that produced the reply.
+/// * `response` - The truncated reply, extended in place.
+/// * `override_budget` - Whether `--override-budget` was given.
+fn continue_reply(
+    backend: &dyn CompletionBackend,
+    request_body: &OpenAIRequest,
+    response: &mut OpenAIResponse,
+    override_budget: bool,
+) {
+    for _ in 0..MAX_CONTINUATIONS {
+        if !response.is_truncated() {
+            return;
+        }
+        if let Err(e) = check_budget(override_budget) {
+            eprintln!("{}", e);
+            return;
+        }
+        let mut follow_up = request_body.clone();
+        follow_up.messages.push(Message {
+            role: "assistant".to_string(),
+            content: response.choices[0].message.content.clone(),
+        });
+        follow_up.messages.push(Message {
+            role: "user".to_string(),
+            content: CONTINUE_PROMPT.to_string(),
+        });
+
+        let Ok((piece, _)) = block_on(send_prompt_request(backend, &follow_up, false)) else {
+            return;
+        };
+        let piece_usage = piece.usage.unwrap_or_default();
+        let Some(piece) = piece.choices.into_iter().next() else {
+            return;
+        };
+        response.choices[0].message.content.push_str(&piece.message.content);
+        response.choices[0].finish_reason = piece.finish_reason;
+        if let Some(usage) = response.usage.as_mut() {
+            usage.prompt_tokens += piece_usage.prompt_tokens;
+            usage.completion_tokens += piece_usage.completion_tokens;
+        }
+    }
+}
+
+/// Records the tokens used by a successful request. When the response didn't report any,
+/// an estimate is recorded and stored in the response.
+///
+/// # Arguments
+///
+/// * `backend` - Where the request was sent.
+/// * `request_body` - The request that was sent.
+/// * `response` - The response that was received.
+fn record_request_usage(backend: &dyn CompletionBackend, request_body: &OpenAIRequest, response: &mut OpenAIResponse) {
+    let usage = response.usage.unwrap_or_else(|| {
+        let prompt: Vec<&str> = request_body.messages.iter().map(|message| message.content.as_str()).collect();
+        estimate_usage(&prompt.join("\n"), &response.choices[0].message.content)
+    });
+    response.usage = Some(usage);
+    record_usage(backend, usage);
+}
+
+/// Asks the user what was wrong with a rejected command.
+///
+/// # Arguments
+///
+/// * `read_line` - Reads the user's answer.
+///
+/// # Returns
+///
+/// * `Option<String>` - The user's feedback, or `None` if they pressed Enter to cancel.
+fn read_refinement_feedback(read_line: &mut impl FnMut() -> io::Result<String>) -> Option<String> {
+    print!("Tell me what's wrong (or press Enter to cancel): ");
+    io::stdout().flush().unwrap();
+
+    match read_line() {
+        Ok(input) if !input.trim().is_empty() => Some(input.trim().to_string()),
+        Ok(_) => None,
+        Err(_) => {
+            eprintln!("Failed to read input.");
+            None
+        }
+    }
+}
+
+/// Extends a command-translation conversation with a rejected suggestion and the user's
+/// feedback, so the next request can produce a better command.
+/// The original prompt is already part of the conversation.
+///
+/// # Arguments
+///
+/// * `messages` - The conversation sent with the original request.
+/// * `reply` - The assistant's reply that contained the rejected command.
+/// * `rejected_command` - The command the user rejected.
+/// * `feedback` - The user's explanation of what was wrong.
+fn add_refinement_messages(
+    messages: &mut Vec<Message>,
+    reply: &str,
+    rejected_command: &str,
+    feedback: &str,
+) {
+    messages.push(Message {
+        role: "assistant".to_string(),
+        content: reply.to_string(),
+    });
+    messages.push(Message {
+        role: "user".to_string(),
+        content: format!(
+            "I rejected the command `{}`. Here is what's wrong with it:\n{}\n\
+             Translate my original prompt into a corrected command, replying in the same format as before.",
+            rejected_command, feedback
+        ),
+    });
+}
+
+/// Appends an approved command to the `--append-to` script, if one was requested.
+/// Failures are reported but never prevent the command from running.
+///
+/// # Arguments
+///
+/// * `options` - The command-line options.
+/// * `prompt` - The natural-language prompt that produced the command.
+/// * `command` - The approved command.
+pub(crate) fn record_approved_command(options: &CliOptions, prompt: &str, command: &str) {
+    if let Some(path) = &options.append_to {
+        if let Err(e) = append_to_script(path, prompt, command, command_shell()) {
+            eprintln!("Error appending command to {}: {}", path, e);
+        }
+    }
+}
+
+/// Appends a command to a script file, preceded by a comment with its prompt and a timestamp.
+/// A new file is created with a shebang for the command shell and made executable.
+///
+/// # Arguments
+///
+/// * `path` - The path to the script file.
+/// * `prompt` - The natural-language prompt that produced the command.
+/// * `command` - The command to append.
+/// * `shell` - The shell the command is written for, named in the shebang.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - An empty result or an I/O error.
+fn append_to_script(path: &str, prompt: &str, command: &str, shell: &Path) -> io::Result<()> {
+    let is_new = !PathBuf::from(path).exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    if is_new {
+        writeln!(file, "{}", shebang(shell))?;
+        make_executable(path)?;
+    }
+
+    let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
+    writeln!(file)?;
+    for line in prompt.lines() {
+        writeln!(file, "# {}", line)?;
+    }
+    writeln!(file, "# Generated by gptsh at {}", timestamp)?;
+    writeln!(file, "{}", command.trim())?;
+    Ok(())
+}
+
+/// Marks a file as executable by everyone who can read it.
+#[cfg(unix)]
+pub(crate) fn make_executable(path: &str) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(permissions.mode() | 0o111);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn make_executable(_path: &str) -> io::Result<()> {
+    Ok(())
+}
+
+/// An answer to the question whether to run a command.
+#[derive(Debug, PartialEq)]
+pub(crate) enum CommandAnswer {
+    Run,
+    Cancel,
+    Ban,
+    /// Edit the command before deciding; only offered in chat mode.
+    Edit,
+    /// Run the command with the terminal attached, for programs that need a TTY; only
+    /// offered in chat mode.
+    Interactive,
+    /// Run the command in the background; only offered in shell mode.
+    Background,
+    /// Anything else, which cancels too.
+    Invalid,
+}
+
+/// Builds the question asked before a command is run, e.g. `Do you want to execute this
+/// command? [y/N/b to ban] `. The capital letter marks what pressing Enter does.
+///
+/// # Arguments
+///
+/// * `allow_by_default` - Whether an empty answer runs the command.
+/// * `chat` - Whether the chat-only answers are offered: `e` to edit the command and `i` to
+///   run it interactively.
+/// * `background` - Whether `&` is offered, to run the command in the background.
+///
+/// # Returns
+///
+/// * `String` - The question, ending in a space for the answer.
+pub(crate) fn command_prompt(allow_by_default: bool, chat: bool, background: bool) -> String {
+    let options = yes_no_options(allow_by_default);
+    let chat_options = if chat { "/e to edit/i for interactive" } else { "" };
+    let background_option = if background { "/& for background" } else { "" };
+    format!(
+        "Do you want to execute this command? [{}{}{}/b to ban] ",
+        options, chat_options, background_option
+    )
+}
+
+/// Returns the yes/no hint for a question, capitalizing what pressing Enter chooses.
+///
+/// # Arguments
+///
+/// * `allow_by_default` - Whether an empty answer means yes.
+///
+/// # Returns
+///
+/// * `&'static str` - `Y/n` or `y/N`.
+pub(crate) fn yes_no_options(allow_by_default: bool) -> &'static str {
+    if allow_by_default { "Y/n" } else { "y/N" }
+}
+
+/// Interprets the answer to [`command_prompt`].
+///
+/// # Arguments
+///
+/// * `answer` - What the user typed; end of input should be passed as `n`.
+/// * `allow_by_default` - Whether an empty answer runs the command.
+///
+/// # Returns
+///
+/// * `CommandAnswer` - What the user chose.
+pub(crate) fn parse_command_answer(answer: &str, allow_by_default: bool) -> CommandAnswer {
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => CommandAnswer::Run,
+        "" if allow_by_default => CommandAnswer::Run,
+        "" | "n" | "no" => CommandAnswer::Cancel,
+        "b" | "ban" => CommandAnswer::Ban,
+        "e" | "edit" => CommandAnswer::Edit,
+        "i" | "interactive" => CommandAnswer::Interactive,
+        "&" | "bg" => CommandAnswer::Background,
+        _ => CommandAnswer::Invalid,
+    }
+}
+
+/// Reads whether chat mode's command confirmation runs a command when the user just
+/// presses Enter, from `confirm_default`.
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration.
+///
+/// # Returns
+///
+/// * `bool` - `true` for `allow`; `false` for `deny`, the default.
+pub(crate) fn confirm_allows_by_default(config: &Config) -> bool {
+    match config.confirm_default.as_deref() {
+        Some("allow") => true,
+        None | Some("deny") => false,
+        Some(other) => {
+            eprintln!(
+                "Warning: Unknown confirm_default '{}'; expected \"deny\" or \"allow\". Using \"deny\".",
+                other
+            );
+            false
+        }
+    }
+}
+
+/// Reads and interprets user confirmation input.
+///
+/// # Arguments
+///
+/// * `read_line` - Reads the user's answer.
+///
+/// # Returns
+///
+/// * `String` - The user's input in lowercase.
+fn read_user_confirmation(read_line: &mut impl FnMut() -> io::Result<String>) -> String {
+    match read_line() {
+        // An empty read means end of input, which must never count as approval.
+        Ok(input) if input.is_empty() => "n".to_string(),
+        Ok(input) => input.trim().to_lowercase(),
+        Err(_) => {
+            eprintln!("Failed to read input.");
+            "n".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{client::testing::CannedBackend, utils::testing::test_dir};
+
+    /// Asks a canned backend for a suggestion, refusing `rm -rf /`.
+    fn suggest(backend: &CannedBackend) -> Result<CommandSuggestion, String> {
+        let request_body = build_prompt_request("list all files", None, &[], backend.endpoint(), "bash");
+        fetch_suggestion(backend, request_body, false, false, &["rm -rf /".to_string()])
+    }
+
+    /// Translates "list all files" with a canned backend, answering questions with `answers`
+    /// in order; running out of answers fails the test.
+    fn translate(backend: &CannedBackend, lists: &CommandLists, answers: &[&str]) -> Option<Translation> {
+        let mut answers = answers.iter().map(|answer| format!("{}\n", answer));
+        let request_body = build_prompt_request("list all files", None, &[], backend.endpoint(), "bash");
+        translate_prompt(backend, "list all files", &CliOptions::default(), request_body, lists, &mut || {
+            Ok(answers.next().expect("gptsh asked more questions than expected"))
+        })
+    }
+
+    #[test]
+    fn allowed_commands_run_without_confirmation() {
+        let backend = CannedBackend::replying("true");
+        let lists = CommandLists {
+            allowed: vec!["true".to_string()],
+            ..CommandLists::default()
+        };
+        let translation = translate(&backend, &lists, &[]).unwrap();
+        assert_eq!(translation.command, "true");
+        assert_eq!(translation.exit_status, Some(0));
+    }
+
+    #[test]
+    fn banned_commands_are_refused_without_confirmation() {
+        let backend = CannedBackend::replying("touch banned");
+        let lists = CommandLists {
+            banned: vec!["touch banned".to_string()],
+            ..CommandLists::default()
+        };
+        let translation = translate(&backend, &lists, &[]).unwrap();
+        assert_eq!(translation.command, "touch banned");
+        assert_eq!(translation.exit_status, None);
+    }
+
+    #[test]
+    fn declined_commands_are_not_run() {
+        let backend = CannedBackend::replying("touch declined");
+        let translation = translate(&backend, &CommandLists::default(), &["n", ""]).unwrap();
+        assert_eq!(translation.command, "touch declined");
+        assert_eq!(translation.exit_status, None);
+        assert_eq!(backend.requests.borrow().len(), 1);
+    }
+
+    #[test]
+    fn declined_commands_are_refined_with_feedback() {
+        let body = |content: &str| {
+            serde_json::json!({"choices": [{"message": {"role": "assistant", "content": content}, "finish_reason": "stop"}]})
+                .to_string()
+        };
+        let backend = CannedBackend::new(&[(200, &body("ls")), (200, &body("ls -la"))]);
+        let translation = translate(&backend, &CommandLists::default(), &["n", "include hidden files", "n", ""]).unwrap();
+        assert_eq!(translation.command, "ls -la");
+        assert_eq!(translation.exit_status, None);
+
+        let requests = backend.requests.borrow();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1]["messages"].to_string().contains("include hidden files"));
+    }
+
+    #[test]
+    fn attachments_are_truncated_when_the_context_window_overflows() {
+        let overflow = r#"{"error": {"message": "Too long.", "code": "context_length_exceeded"}}"#;
+        let reply = serde_json::json!({"choices": [{"message": {"role": "assistant", "content": "ls"}, "finish_reason": "stop"}]});
+        let backend = CannedBackend::new(&[(400, overflow), (200, &reply.to_string())]);
+        let (before, after) = FILE_ATTACHMENT_INTRO;
+        let file = format!("{}build.log{}\n{}", before, after, fenced("", &"x".repeat(1000)));
+        let request_body = build_prompt_request("why did it fail", None, &[file], backend.endpoint(), "bash");
+        let options = CliOptions {
+            no_execute: true,
+            ..CliOptions::default()
+        };
+        let translation =
+            translate_prompt(&backend, "why did it fail", &options, request_body, &CommandLists::default(), &mut || {
+                panic!("no question should be asked")
+            });
+        assert_eq!(translation.unwrap().command, "ls");
+
+        let requests = backend.requests.borrow();
+        assert_eq!(requests.len(), 2);
+        let attached = |request: &serde_json::Value| {
+            let messages = request["messages"].as_array().unwrap();
+            let message = messages.iter().find(|message| message["content"].as_str().unwrap().starts_with(before));
+            message.unwrap()["content"].as_str().unwrap().to_string()
+        };
+        assert!(attached(&requests[0]).contains(&"x".repeat(1000)));
+        let truncated = attached(&requests[1]);
+        assert!(truncated.contains(&format!("\n{}{}\n```", "x".repeat(500), TRUNCATION_MARKER)), "{}", truncated);
+        assert!(!truncated.contains(&"x".repeat(501)));
+    }
+
+    #[test]
+    fn the_longest_attachment_is_truncated_first() {
+        let message = |content: String| Message {
+            role: "user".to_string(),
+            content,
+        };
+        let mut messages = vec![
+            message(format!("{}\n```\n{}\n```", PIPED_INPUT_INTRO, "p".repeat(300))),
+            message(format!("{}\n{}", CONTEXT_NOTE_INTRO, "c".repeat(600))),
+            message("list the files".to_string()),
+        ];
+        assert_eq!(truncate_attached_input(&mut messages), Some(300));
+        assert_eq!(messages[1].content, format!("{}\n{}{}", CONTEXT_NOTE_INTRO, "c".repeat(300), TRUNCATION_MARKER));
+        assert_eq!(truncate_attached_input(&mut messages), Some(150));
+        assert!(messages[1].content.starts_with(&format!("{}\n{}{}", CONTEXT_NOTE_INTRO, "c".repeat(150), TRUNCATION_MARKER)));
+        assert_eq!(truncate_attached_input(&mut messages), Some(150));
+        assert_eq!(
+            messages[0].content,
+            format!("{}\n```\n{}{}\n```", PIPED_INPUT_INTRO, "p".repeat(150), TRUNCATION_MARKER)
+        );
+        // Neither is long enough to shorten again
+        assert_eq!(truncate_attached_input(&mut messages), None);
+        assert_eq!(messages[2].content, "list the files");
+    }
+
+    #[test]
+    fn suggestion_from_a_plain_reply() {
+        let backend = CannedBackend::replying("ls -la");
+        assert_eq!(suggest(&backend).unwrap().command, "ls -la");
+
+        let requests = backend.requests.borrow();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0]["model"], backend.endpoint().model);
+        assert!(requests[0]["messages"].to_string().contains("list all files"));
+    }
+
+    #[test]
+    fn suggestion_from_a_fenced_reply() {
+        let backend = CannedBackend::replying("```bash\nfind . -name '*.rs'\n```");
+        assert_eq!(suggest(&backend).unwrap().command, "find . -name '*.rs'");
+    }
+
+    #[test]
+    fn banned_suggestions_are_refused() {
+        let backend = CannedBackend::replying("```bash\nrm -rf /\n```");
+        assert_eq!(suggest(&backend).unwrap_err(), "the suggested command is banned: rm -rf /");
+    }
+
+    #[test]
+    fn replies_without_choices_are_failures() {
+        let backend = CannedBackend::new(&[(200, r#"{"choices": []}"#)]);
+        assert_eq!(suggest(&backend).unwrap_err(), "the request failed");
+    }
+
+    #[test]
+    fn malformed_replies_are_failures() {
+        let backend = CannedBackend::new(&[(200, r#"{"choices": [{"message": "#)]);
+        assert_eq!(suggest(&backend).unwrap_err(), "the request failed");
+    }
+
+    #[test]
+    fn rejected_response_format_is_dropped_and_retried() {
+        let rejected = r#"{"error": {"message": "Invalid parameter: 'response_format' is not supported with this model.", "code": null}}"#;
+        let reply = serde_json::json!({"choices": [{"message": {"content": "```bash\npwd\n```"}}]}).to_string();
+        let backend = CannedBackend::new(&[(400, rejected), (200, &reply)]);
+        let mut request_body = build_prompt_request("where am I", None, &[], backend.endpoint(), "bash");
+        request_body.response_format = Some(serde_json::json!({"type": "json_object"}));
+
+        assert_eq!(fetch_suggestion(&backend, request_body, false, false, &[]).unwrap().command, "pwd");
+
+        let requests = backend.requests.borrow();
+        assert!(requests[0].get("response_format").is_some());
+        assert!(requests[1].get("response_format").is_none());
+    }
+
+    /// Streams tokens through a fenced-reply extractor and returns everything it displayed.
+    fn stream_fenced(tokens: &[&str]) -> String {
+        let mut extractor = StreamingCommandExtractor::default();
+        let mut shown: String = tokens.iter().map(|token| extractor.push(token)).collect();
+        shown.push_str(&extractor.finish());
+        shown
+    }
+
+    /// Streams tokens through a JSON-reply extractor and returns everything it displayed.
+    fn stream_json(tokens: &[&str]) -> String {
+        let mut extractor = StreamingJsonCommandExtractor::default();
+        tokens.iter().map(|token| extractor.push(token)).collect()
+    }
+
+    /// Writes a configuration file to a fresh temporary directory and returns its path.
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = test_dir(name).join(CONFIG_FILE);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn attachments_are_read_from_files_and_notes() {
+        let dir = test_dir("attachments");
+        let notes = dir.join("notes.md");
+        fs::write(&notes, "Deploys run from ```make deploy```.\n").unwrap();
+        let blank = dir.join("blank.txt");
+        fs::write(&blank, "\n").unwrap();
+        let notes = notes.to_str().unwrap().to_string();
+        let options = CliOptions {
+            files: vec![notes.clone(), blank.to_str().unwrap().to_string()],
+            context: vec!["Production is on port 8080.".to_string()],
+            ..CliOptions::default()
+        };
+
+        assert_eq!(
+            read_attachments(&options).unwrap(),
+            [
+                format!(
+                    "The file `{}` is attached as context:\n````\nDeploys run from ```make deploy```.\n\n````",
+                    notes
+                ),
+                "Additional context:\nProduction is on port 8080.".to_string(),
+            ]
+        );
+
+        let options = CliOptions { files: vec!["/nonexistent/notes.md".to_string()], ..CliOptions::default() };
+        assert!(read_attachments(&options).unwrap_err().starts_with("Error: Could not read /nonexistent/notes.md: "));
+    }
+
+    #[test]
+    fn dry_runs_show_the_request_with_its_context_in_order() {
+        let backend = CannedBackend::replying("");
+        let attachments = [
+            "The file `build.log` is attached as context:".to_string(),
+            "Additional context:\nUse cargo".to_string(),
+        ];
+        let request =
+            build_prompt_request("why did this fail", Some("error[E0425]"), &attachments, backend.endpoint(), "bash");
+        let url = "https://api.example.com/v1/chat/completions";
+        let preview = format_dry_run(url, &request);
+
+        assert!(preview.starts_with(&format!(
+            "Dry run: no request will be sent.\nEndpoint: {}\nModel: {}\nEstimated prompt tokens: ~",
+            url, request.model
+        )));
+        let position = |text: &str| preview.find(text).unwrap_or_else(|| panic!("{} not in {}", text, preview));
+        let last = request.messages.len();
+        assert!(position(PIPED_INPUT_INTRO) < position("The file `build.log`"));
+        assert!(position("The file `build.log`") < position("Additional context:\nUse cargo"));
+        let instruction = position(&format!("--- Message {} (user) ---\nTranslate", last));
+        assert!(position("Additional context:\nUse cargo") < instruction);
+        assert!(preview.ends_with("why did this fail"));
+    }
+
+    fn response(content: &str, finish_reason: &str) -> OpenAIResponse {
+        serde_json::from_value(serde_json::json!({
+            "choices": [{"message": {"role": "assistant", "content": content}, "finish_reason": finish_reason}]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn json_output_describes_the_command() {
+        let reply = r#"{"command": "du -sh /var/log", "explanation": " Sizes the logs. ", "needs_confirmation": false}"#;
+        assert_eq!(
+            describe_reply("gpt-4o", &response(reply, "stop"), false, &[]),
+            serde_json::json!({
+                "model": "gpt-4o",
+                "truncated": false,
+                "command": "du -sh /var/log",
+                "explanation": "Sizes the logs.",
+                "needs_confirmation": false,
+                "banned": false,
+            })
+        );
+
+        let banned = ["rm -rf /".to_string()];
+        let described = describe_reply("gpt-4", &response("```bash\nrm -rf /\n```", "stop"), false, &banned);
+        assert_eq!(described["command"], "rm -rf /");
+        assert_eq!(described["banned"], true);
+    }
+
+    #[test]
+    fn raw_json_output_keeps_the_reply_verbatim() {
+        let reply = "Here you go:\n```bash\nls -la\n```\n";
+        assert_eq!(
+            describe_reply("gpt-4", &response(reply, "length"), true, &[]),
+            serde_json::json!({ "model": "gpt-4", "truncated": true, "raw": reply })
+        );
+        assert_eq!(OutputFormat::parse(" JSON "), Some(OutputFormat::Json));
+        assert_eq!(OutputFormat::parse("yaml"), None);
+    }
+
+    #[test]
+    fn command_lists_skip_lines_that_are_not_text() {
+        let path = test_dir("command-list").join(BANNED_COMMANDS_FILE);
+        fs::write(&path, b"rm -rf /\n\xff\xfe\n  \n mkfs.ext4 /dev/sda \n").unwrap();
+
+        assert_eq!(load_commands_from_file(path.to_str().unwrap()).unwrap(), ["rm -rf /", "mkfs.ext4 /dev/sda"]);
+        assert!(load_commands_from_file("/nonexistent/.gptsh_banned").unwrap().is_empty());
+    }
+
+    #[test]
+    fn appended_scripts_use_the_command_shell() {
+        let path = test_dir("append-script").join("saved.fish");
+        let _ = fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+        append_to_script(path, "list files", "ls", Path::new("/usr/bin/fish")).unwrap();
+        append_to_script(path, "show disk usage", "df -h", Path::new("/usr/bin/fish")).unwrap();
+
+        let script = fs::read_to_string(path).unwrap();
+        assert!(script.starts_with("#!/usr/bin/env fish\n"));
+        assert_eq!(script.matches("#!").count(), 1);
+        assert!(script.ends_with("df -h\n"));
+    }
+
+    #[test]
+    fn missing_or_empty_config_is_the_default() {
+        assert!(read_config(Path::new("/nonexistent/.gptsh_config")).unwrap().max_retries.is_none());
+        let path = config_file("empty-config", "\n");
+        assert!(read_config(&path).unwrap().max_retries.is_none());
+    }
+
+    #[test]
+    fn config_is_read() {
+        let path = config_file("valid-config", r#"{"max_retries": 5}"#);
+        assert_eq!(read_config(&path).unwrap().max_retries, Some(5));
+    }
+
+    #[test]
+    fn invalid_config_is_an_error_naming_the_file() {
+        let path = config_file("invalid-config", "{\n  \"max_retries\": \"five\"\n}");
+        let error = read_config(&path).unwrap_err().to_string();
+        assert!(error.starts_with(&format!("{}: invalid type", path.display())), "{}", error);
+        assert!(error.ends_with("at line 2 column 23"), "{}", error);
+
+        let path = config_file("malformed-config", "{\"max_retries\": 5,");
+        let error = read_config(&path).unwrap_err().to_string();
+        assert!(error.starts_with(&format!("{}: ", path.display())), "{}", error);
+    }
+
+    #[test]
+    fn out_of_range_settings_are_an_error_naming_the_file() {
+        let path = config_file("range-config", r#"{"generation": {"chat": {"temperature": 3}}}"#);
+        assert_eq!(
+            read_config(&path).unwrap_err().to_string(),
+            format!("{}: generation.chat.temperature must be between 0 and 2, but is 3", path.display())
+        );
+    }
+
+    #[test]
+    fn streaming_strips_fences_split_across_tokens() {
+        assert_eq!(stream_fenced(&["``", "`ba", "sh\nls", " -", "la\n`", "``"]), "ls -la");
+    }
+
+    #[test]
+    fn streaming_passes_an_unfenced_reply_through() {
+        assert_eq!(stream_fenced(&["echo", " hi"]), "echo hi");
+    }
+
+    #[test]
+    fn streaming_skips_whitespace_before_the_opening_fence() {
+        assert_eq!(stream_fenced(&["  \n```sh\n", "pwd\n```"]), "pwd");
+    }
+
+    #[test]
+    fn streaming_keeps_backticks_inside_the_command() {
+        assert_eq!(stream_fenced(&["```bash\necho `date`\n```"]), "echo `date`");
+    }
+
+    #[test]
+    fn streaming_holds_back_a_possible_closing_fence() {
+        let mut extractor = StreamingCommandExtractor::default();
+        assert_eq!(extractor.push("```bash\nls\n"), "ls");
+        assert_eq!(extractor.push("``"), "");
+        assert_eq!(extractor.finish(), "");
+    }
+
+    #[test]
+    fn streaming_matches_the_whole_reply_parser() {
+        let reply = "```bash\nfor f in *.log; do\n  gzip \"$f\"\ndone\n```";
+        let tokens: Vec<String> = reply.chars().map(String::from).collect();
+        let tokens: Vec<&str> = tokens.iter().map(String::as_str).collect();
+        assert_eq!(stream_fenced(&tokens), extract_command(reply).unwrap());
+    }
+
+    #[test]
+    fn streaming_json_shows_only_the_command() {
+        assert_eq!(stream_json(&["{\"comm", "and\": \"ls", " -la\", \"expl", "anation\": \"x\"}"]), "ls -la");
+    }
+
+    #[test]
+    fn streaming_json_decodes_escapes_split_across_tokens() {
+        assert_eq!(stream_json(&["{\"command\":\"a\\", "nb \\\"c\\\"\"}"]), "a\nb \"c\"");
+        assert_eq!(stream_json(&["{\"command\":\"caf\\u00", "e9\"}"]), "café");
+    }
+}
@@ -0,0 +1,157 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{collections::BTreeMap, fs, io, path::PathBuf};
+
+use serde::Deserialize;
+
+use crate::utils::is_plain_file_name;
+
+const PERSONAS_DIR: &str = "personas";
+
+/// Personas that ship with gptsh, in the same format as persona files.
+/// A file with the same name in the personas directory takes precedence.
+const BUILT_IN_PERSONAS: &[(&str, &str)] = &[
+    (
+        "concise-ops",
+        r#"{
+            "description": "Terse operations engineer; answers with commands first",
+            "system_prompt": "You are a senior operations engineer working in ${USER}'s terminal. Answer as briefly as possible: lead with the exact command or config, then at most two sentences of explanation. Prefer standard tools available on a typical Linux system. Never pad answers with pleasantries.",
+            "temperature": 0.2
+        }"#,
+    ),
+    (
+        "teacher",
+        r#"{
+            "description": "Patient tutor who explains the why behind each step",
+            "system_prompt": "You are a patient teacher helping someone learn the command line and the tools they use. Explain concepts step by step, say why each command works, point out common mistakes, and end with a short exercise when it helps. Use proper Markdown formatting so your answers are easy to read in a terminal.",
+            "temperature": 0.7
+        }"#,
+    ),
+    (
+        "reviewer",
+        r#"{
+            "description": "Critical code and config reviewer",
+            "system_prompt": "You are a meticulous reviewer of code, scripts, and configuration. Look for bugs, security problems, portability issues, and unclear naming, in that order. Quote the exact lines you are commenting on, explain the problem, and suggest a fix. Say plainly when something is fine.",
+            "temperature": 0.3
+        }"#,
+    ),
+];
+
+/// An assistant personality for chat mode.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Persona {
+    /// A one-line summary, shown by `gptsh persona list`.
+    #[serde(default)]
+    pub(crate) description: String,
+    /// The system prompt. `${USER}` and `${PWD}` are expanded.
+    pub(crate) system_prompt: String,
+    /// The model to use, unless `--model` is given.
+    pub(crate) model: Option<String>,
+    /// The sampling temperature, between 0 and 2.
+    pub(crate) temperature: Option<f64>,
+}
+
+/// Returns the directory persona files are read from.
+///
+/// # Returns
+///
+/// * `Option<PathBuf>` - The directory, or `None` if there is no configuration directory.
+fn personas_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("gptsh").join(PERSONAS_DIR))
+}
+
+/// Parses a persona and checks that its temperature is in range.
+fn parse_persona(contents: &str) -> Result<Persona, String> {
+    let persona: Persona = serde_json::from_str(contents).map_err(|e| e.to_string())?;
+    if let Some(temperature) = persona.temperature.filter(|value| !(0.0..=2.0).contains(value)) {
+        return Err(format!("temperature must be between 0 and 2, but is {}", temperature));
+    }
+    Ok(persona)
+}
+
+/// Loads a persona by name, from the personas directory or the built-in set.
+///
+/// # Arguments
+///
+/// * `name` - The persona name; the file is `<name>.json`.
+///
+/// # Returns
+///
+/// * `Result<Persona, String>` - The persona, or an error message listing the valid names.
+pub(crate) fn load_persona(name: &str) -> Result<Persona, String> {
+    let file = personas_dir()
+        .filter(|_| is_plain_file_name(name))
+        .map(|dir| dir.join(format!("{}.json", name)));
+    if let Some(path) = file {
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                return parse_persona(&contents)
+                    .map_err(|e| format!("Error: Could not read persona '{}' ({}): {}", name, path.display(), e))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Error: Could not read persona '{}': {}", name, e)),
+        }
+    }
+
+    match BUILT_IN_PERSONAS.iter().find(|(built_in, _)| *built_in == name) {
+        Some((_, contents)) => parse_persona(contents).map_err(|e| format!("Error: Built-in persona '{}': {}", name, e)),
+        None => {
+            let names: Vec<String> = list_personas().into_keys().collect();
+            Err(format!(
+                "Error: There is no persona named '{}'. Available personas: {}.",
+                name,
+                names.join(", ")
+            ))
+        }
+    }
+}
+
+/// Lists the available personas with where each comes from.
+///
+/// # Returns
+///
+/// * `BTreeMap<String, String>` - Persona names, each with `built-in` or the path of its file.
+fn list_personas() -> BTreeMap<String, String> {
+    let mut personas: BTreeMap<String, String> = BUILT_IN_PERSONAS
+        .iter()
+        .map(|(name, _)| (name.to_string(), "built-in".to_string()))
+        .collect();
+    let entries = personas_dir().and_then(|dir| fs::read_dir(dir).ok());
+    for path in entries.into_iter().flatten().flatten().map(|entry| entry.path()) {
+        if path.extension().is_some_and(|extension| extension == "json") {
+            if let Some(name) = path.file_stem() {
+                personas.insert(name.to_string_lossy().into_owned(), path.display().to_string());
+            }
+        }
+    }
+    personas
+}
+
+/// Prints the available personas with their descriptions (`gptsh persona list`).
+pub(crate) fn print_personas() {
+    println!("Personas:");
+    for (name, source) in list_personas() {
+        match load_persona(&name) {
+            Ok(persona) if persona.description.is_empty() => println!("  {} ({})", name, source),
+            Ok(persona) => println!("  {} ({}): {}", name, source, persona.description),
+            Err(e) => println!("  {} ({}): {}", name, source, e),
+        }
+    }
+    if let Some(dir) = personas_dir() {
+        println!("Add your own as JSON files in {}.", dir.display());
+    }
+}
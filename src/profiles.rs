@@ -0,0 +1,127 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::BTreeMap;
+
+use crate::{
+    cli::CliOptions,
+    client::{describe_key_availability, resolve_profile_endpoint, PROVIDER_NAMES},
+    models::{Config, ProviderProfile},
+    openai::load_config,
+};
+
+/// Names the mode the options select, as used for `default_provider` keys.
+///
+/// # Arguments
+///
+/// * `options` - The command-line options.
+///
+/// # Returns
+///
+/// * `&'static str` - `chat`, `shell`, or `command`.
+fn mode_name(options: &CliOptions) -> &'static str {
+    if options.chat_mode {
+        "chat"
+    } else if options.continuous_mode {
+        "shell"
+    } else {
+        "command"
+    }
+}
+
+/// Picks the provider profile for a run: the one named by `--provider`, or else the
+/// mode's entry in `default_provider`.
+///
+/// # Arguments
+///
+/// * `options` - The command-line options.
+/// * `config` - The loaded configuration.
+///
+/// # Returns
+///
+/// * `Result<Option<ProviderProfile>, String>` - The profile, `None` if none is selected,
+///   or an error message for a name that isn't defined under `providers`.
+pub(crate) fn select_profile(options: &CliOptions, config: &Config) -> Result<Option<ProviderProfile>, String> {
+    let mode = mode_name(options);
+    let (name, selected_by) = match &options.provider {
+        Some(name) => (name, "--provider".to_string()),
+        None => match config.default_provider.as_ref().and_then(|defaults| defaults.get(mode)) {
+            Some(name) => (name, format!("default_provider.{}", mode)),
+            None => return Ok(None),
+        },
+    };
+
+    if let Some(profile) = config.providers.as_ref().and_then(|providers| providers.get(name)) {
+        return Ok(Some(profile.clone()));
+    }
+
+    let mut message = format!("Error: {} names the provider profile '{}', which is not defined", selected_by, name);
+    match config.providers.as_ref().filter(|providers| !providers.is_empty()) {
+        Some(providers) => {
+            let mut names: Vec<&str> = providers.keys().map(String::as_str).collect();
+            names.sort();
+            message.push_str(&format!(". Configured profiles: {}.", names.join(", ")));
+        }
+        None => message.push_str(" (.gptsh_config has no providers section)."),
+    }
+    if PROVIDER_NAMES.contains(&name.as_str()) {
+        message.push_str(&format!(" To use the built-in {} provider, set GPTSH_PROVIDER instead.", name));
+    }
+    Err(message)
+}
+
+/// Prints the configured provider profiles with their endpoints and models, and whether an
+/// API key is available for each (`gptsh providers list`). Keys themselves are never shown.
+pub(crate) fn print_provider_profiles() {
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: Could not load the configuration from {}", e);
+            return;
+        }
+    };
+    let profiles: BTreeMap<&String, &ProviderProfile> = config.providers.iter().flatten().collect();
+    if profiles.is_empty() {
+        println!("No provider profiles are configured. Add them under \"providers\" in .gptsh_config.");
+        return;
+    }
+
+    let options = CliOptions::default();
+    println!("Provider profiles:");
+    for (name, profile) in &profiles {
+        let provider = profile.provider.as_deref().unwrap_or("openai");
+        match resolve_profile_endpoint(&options, &config, Some(profile)) {
+            Ok(endpoint) => println!(
+                "  {} ({}): {}, model {}, {}",
+                name,
+                provider,
+                endpoint.url,
+                endpoint.model,
+                describe_key_availability(&endpoint)
+            ),
+            Err(e) => println!("  {} ({}): {}", name, provider, e),
+        }
+    }
+
+    let defaults: BTreeMap<&String, &String> = config.default_provider.iter().flatten().collect();
+    if !defaults.is_empty() {
+        let defaults: Vec<String> = defaults
+            .iter()
+            .map(|(mode, name)| format!("{} uses {}", mode, name))
+            .collect();
+        println!("Defaults: {}", defaults.join(", "));
+    }
+}
@@ -0,0 +1,378 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{env, path::PathBuf, time::Duration};
+
+use chrono::Local;
+use colored::{Color, Colorize};
+
+use crate::{
+    sysinfo::{git_has_changes, git_head, GitHead},
+    utils::{get_current_dir_with_tilde, get_username},
+};
+
+/// The shell-mode prompt unless `shell_prompt` is set, e.g. `✔ [gptsh (LLM)]:ann:~/src$ `.
+pub(crate) const DEFAULT_SHELL_PROMPT: &str = "{status_mark}[{mode_color}{mode_icon}{reset} ({mode})]:{green}{user}{reset}:{blue}{cwd}{reset}{magenta}{git}{reset}$ ";
+
+/// The values a prompt can show, by placeholder name.
+const PLACEHOLDERS: &[&str] =
+    &["mode", "mode_icon", "user", "cwd", "status", "status_mark", "time", "git", "git_branch", "git_dirty"];
+
+/// How long git may take to tell whether a repository has changes before the prompt is
+/// shown without knowing, so it never lags in a huge repository or on a network drive.
+const GIT_STATUS_TIMEOUT: Duration = Duration::from_millis(150);
+
+/// The color tags a prompt can use, besides `mode_color`, `bold`, `dim`, and `reset`.
+const COLOR_TAGS: &[(&str, Color)] = &[
+    ("black", Color::Black),
+    ("red", Color::Red),
+    ("green", Color::Green),
+    ("yellow", Color::Yellow),
+    ("blue", Color::Blue),
+    ("magenta", Color::Magenta),
+    ("cyan", Color::Cyan),
+    ("white", Color::White),
+];
+
+/// A piece of a prompt format.
+#[derive(Debug, PartialEq)]
+enum Part {
+    Text(String),
+    /// A value such as `cwd`, filled in each time the prompt is shown.
+    Value(&'static str),
+    /// Text after this is shown in a color.
+    Color(Color),
+    /// Text after this is shown in the current mode's color.
+    ModeColor,
+    Bold,
+    Dim,
+    /// Text after this is shown plainly again.
+    Reset,
+}
+
+/// What the prompt shows about the shell's state.
+pub(crate) struct PromptState {
+    /// Whether Direct Command Mode is on, rather than LLM Suggestion Mode.
+    pub(crate) direct_command: bool,
+    /// The exit status of the command the last input ran, or `None` if it ran nothing.
+    pub(crate) last_status: Option<i32>,
+}
+
+/// The git repository the working directory is in, as the prompt shows it.
+struct GitPrompt {
+    /// The branch, or the short hash of a commit checked out directly.
+    head: String,
+    /// Whether tracked files have uncommitted changes; `false` if git ran out of time.
+    dirty: bool,
+}
+
+/// A shell-mode prompt format, such as `{mode_icon} {cwd} {git_branch} ❯ `, parsed once so
+/// problems with it are reported once.
+pub(crate) struct PromptFormat {
+    parts: Vec<Part>,
+    /// The git repository last found and the directory it was looked up for, kept until
+    /// the directory changes or a command runs, since checking for changes runs git.
+    git: Option<(PathBuf, Option<GitPrompt>)>,
+}
+
+impl PromptFormat {
+    /// Parses a prompt format. Placeholders in braces are replaced with their values each
+    /// time the prompt is shown, and color tags such as `{red}` color the text after them
+    /// until `{reset}`. Unknown placeholders are shown as typed, with a warning.
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - The format, from `shell_prompt`.
+    ///
+    /// # Returns
+    ///
+    /// * `PromptFormat` - The parsed format.
+    pub(crate) fn parse(format: &str) -> Self {
+        let (parts, unknown) = parse_parts(format);
+        if let Some(warning) = unknown_placeholder_warning(&unknown) {
+            eprintln!("{}", warning);
+        }
+        Self { parts, git: None }
+    }
+
+    /// Forgets what was found about the git repository, so the next prompt looks again;
+    /// called after each command, which may have changed it.
+    pub(crate) fn refresh_git(&mut self) {
+        self.git = None;
+    }
+
+    /// Returns what the prompt shows about the working directory's git repository,
+    /// looking it up only when the directory has changed or [`Self::refresh_git`] was called.
+    fn git_prompt(&mut self) -> Option<&GitPrompt> {
+        let dir = env::current_dir().ok()?;
+        if self.git.as_ref().map(|(cached, _)| cached) != Some(&dir) {
+            let git = git_head(&dir).map(|(root, head)| GitPrompt {
+                head: match head {
+                    GitHead::Branch(branch) => branch,
+                    GitHead::Detached(commit) => commit,
+                    GitHead::Unknown => "unknown".to_string(),
+                },
+                dirty: git_has_changes(root, GIT_STATUS_TIMEOUT).unwrap_or(false),
+            });
+            self.git = Some((dir, git));
+        }
+        self.git.as_ref().and_then(|(_, git)| git.as_ref())
+    }
+
+    /// Renders the prompt for the shell's current state.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The mode and the last command's exit status.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The prompt, with color codes unless colors are turned off.
+    pub(crate) fn render(&mut self, state: &PromptState) -> String {
+        let uses_git = self.parts.iter().any(|part| matches!(part, Part::Value(name) if name.starts_with("git")));
+        let git = if uses_git { self.git_prompt().map(|git| (git.head.clone(), git.dirty)) } else { None };
+        let mode_color = if state.direct_command { Color::Yellow } else { Color::Red };
+        let mut color = None;
+        let mut bold = false;
+        let mut dim = false;
+        let mut prompt = String::new();
+        for part in &self.parts {
+            let text = match part {
+                Part::Text(text) => text.clone(),
+                Part::Value("status_mark") => {
+                    // The mark has its own colors, whatever the tags around it say
+                    let mark = match state.last_status {
+                        Some(0) => "✔ ".green(),
+                        Some(status) => format!("✘ {} ", status).red(),
+                        None => continue,
+                    };
+                    prompt.push_str(&mark.to_string());
+                    continue;
+                }
+                Part::Value(name) => placeholder_value(name, state, git.as_ref()),
+                Part::Color(new_color) => {
+                    color = Some(*new_color);
+                    continue;
+                }
+                Part::ModeColor => {
+                    color = Some(mode_color);
+                    continue;
+                }
+                Part::Bold => {
+                    bold = true;
+                    continue;
+                }
+                Part::Dim => {
+                    dim = true;
+                    continue;
+                }
+                Part::Reset => {
+                    (color, bold, dim) = (None, false, false);
+                    continue;
+                }
+            };
+            if text.is_empty() {
+                continue;
+            }
+            let mut styled = match color {
+                Some(color) => text.color(color),
+                None => text.normal(),
+            };
+            if bold {
+                styled = styled.bold();
+            }
+            if dim {
+                styled = styled.dimmed();
+            }
+            prompt.push_str(&styled.to_string());
+        }
+        prompt
+    }
+}
+
+/// Splits a prompt format into its parts, as [`PromptFormat::parse`] describes.
+///
+/// # Returns
+///
+/// * `(Vec<Part>, Vec<String>)` - The parts, and each unknown placeholder once, as typed.
+fn parse_parts(format: &str) -> (Vec<Part>, Vec<String>) {
+    let mut parts = Vec::new();
+    let mut unknown = Vec::new();
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        let Some(length) = rest[start..].find('}') else { break };
+        let name = &rest[start + 1..start + length];
+        if start > 0 {
+            parts.push(Part::Text(rest[..start].to_string()));
+        }
+        parts.push(match name {
+            "mode_color" => Part::ModeColor,
+            "bold" => Part::Bold,
+            "dim" => Part::Dim,
+            "reset" => Part::Reset,
+            _ => {
+                if let Some(placeholder) = PLACEHOLDERS.iter().find(|placeholder| **placeholder == name) {
+                    Part::Value(placeholder)
+                } else if let Some((_, color)) = COLOR_TAGS.iter().find(|(tag, _)| *tag == name) {
+                    Part::Color(*color)
+                } else {
+                    let typed = format!("{{{}}}", name);
+                    if !unknown.contains(&typed) {
+                        unknown.push(typed.clone());
+                    }
+                    Part::Text(typed)
+                }
+            }
+        });
+        rest = &rest[start + length + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest.to_string()));
+    }
+    (parts, unknown)
+}
+
+/// Returns the warning for placeholders a prompt format uses that don't exist, or `None`
+/// if there are none.
+fn unknown_placeholder_warning(unknown: &[String]) -> Option<String> {
+    if unknown.is_empty() {
+        return None;
+    }
+    let known: Vec<String> = PLACEHOLDERS.iter().map(|name| format!("{{{}}}", name)).collect();
+    Some(format!(
+        "Warning: Unknown placeholder {} in shell_prompt; shown as typed. Placeholders: {}.",
+        unknown.join(", "),
+        known.join(", ")
+    ))
+}
+
+/// Returns the current value of a placeholder; empty when there is nothing to show, such
+/// as the branch outside a git repository.
+fn placeholder_value(name: &str, state: &PromptState, git: Option<&(String, bool)>) -> String {
+    let dirty_mark = |dirty: bool| if dirty { "*" } else { "" };
+    match name {
+        "mode" => if state.direct_command { "CMD" } else { "LLM" }.to_string(),
+        "mode_icon" => if state.direct_command { "you" } else { "gptsh" }.to_string(),
+        "user" => get_username(),
+        "cwd" => get_current_dir_with_tilde(),
+        "status" => state.last_status.map(|status| status.to_string()).unwrap_or_default(),
+        "time" => Local::now().format("%H:%M:%S").to_string(),
+        // ` (main*)`, spaced from what comes before so it vanishes cleanly outside a repository
+        "git" => git.map(|(head, dirty)| format!(" ({}{})", head, dirty_mark(*dirty))).unwrap_or_default(),
+        "git_branch" => git.map(|(head, _)| head.clone()).unwrap_or_default(),
+        "git_dirty" => git.map(|(_, dirty)| dirty_mark(*dirty).to_string()).unwrap_or_default(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(format: &str) -> Vec<Part> {
+        let (parts, unknown) = parse_parts(format);
+        assert!(unknown.is_empty(), "{:?}", unknown);
+        parts
+    }
+
+    fn text(text: &str) -> Part {
+        Part::Text(text.to_string())
+    }
+
+    /// Renders a format without colors, in LLM Suggestion Mode after a failed command.
+    fn render(format: &str) -> String {
+        let state = PromptState {
+            direct_command: false,
+            last_status: Some(2),
+        };
+        PromptFormat::parse(format).render(&state)
+    }
+
+    #[test]
+    fn placeholders_are_values() {
+        for name in PLACEHOLDERS {
+            assert_eq!(parts(&format!("{{{}}}", name)), [Part::Value(name)]);
+        }
+    }
+
+    #[test]
+    fn placeholders_show_the_shell_state() {
+        let llm = PromptState {
+            direct_command: false,
+            last_status: None,
+        };
+        let direct = PromptState {
+            direct_command: true,
+            last_status: Some(1),
+        };
+        let git = ("main".to_string(), true);
+        assert_eq!(placeholder_value("mode", &llm, None), "LLM");
+        assert_eq!(placeholder_value("mode", &direct, None), "CMD");
+        assert_eq!(placeholder_value("mode_icon", &llm, None), "gptsh");
+        assert_eq!(placeholder_value("mode_icon", &direct, None), "you");
+        assert_eq!(placeholder_value("user", &llm, None), get_username());
+        assert_eq!(placeholder_value("cwd", &llm, None), get_current_dir_with_tilde());
+        assert_eq!(placeholder_value("status", &llm, None), "");
+        assert_eq!(placeholder_value("status", &direct, None), "1");
+        assert_eq!(placeholder_value("time", &llm, None).len(), "12:34:56".len());
+        assert_eq!(placeholder_value("git", &llm, Some(&git)), " (main*)");
+        assert_eq!(placeholder_value("git_branch", &llm, Some(&git)), "main");
+        assert_eq!(placeholder_value("git_dirty", &llm, Some(&git)), "*");
+        assert_eq!(placeholder_value("git_dirty", &llm, Some(&("main".to_string(), false))), "");
+        for name in ["git", "git_branch", "git_dirty"] {
+            assert_eq!(placeholder_value(name, &llm, None), "", "{}", name);
+        }
+    }
+
+    #[test]
+    fn color_tags_style_the_text_after_them() {
+        assert_eq!(
+            parts("{red}{bold}!{reset}{mode_color}{dim}{mode}"),
+            [Part::Color(Color::Red), Part::Bold, text("!"), Part::Reset, Part::ModeColor, Part::Dim, Part::Value("mode")]
+        );
+        for (tag, color) in COLOR_TAGS {
+            assert_eq!(parts(&format!("{{{}}}", tag)), [Part::Color(*color)]);
+        }
+    }
+
+    #[test]
+    fn unknown_placeholders_are_shown_as_typed_with_one_warning() {
+        let (parts, unknown) = parse_parts("{nope} {mode} {nope}{also}");
+        assert_eq!(parts, [text("{nope}"), text(" "), Part::Value("mode"), text(" "), text("{nope}"), text("{also}")]);
+        assert_eq!(unknown, ["{nope}", "{also}"]);
+
+        let warning = unknown_placeholder_warning(&unknown).unwrap();
+        assert!(warning.starts_with("Warning: Unknown placeholder {nope}, {also} in shell_prompt; shown as typed."));
+        assert!(warning.contains("{cwd}"));
+        assert_eq!(unknown_placeholder_warning(&[]), None);
+        assert_eq!(render("{nope} {mode}"), "{nope} LLM");
+    }
+
+    #[test]
+    fn unclosed_tags_are_text() {
+        assert_eq!(parts("$ {cwd"), [text("$ {cwd")]);
+        assert_eq!(parts("{mode} {"), [Part::Value("mode"), text(" {")]);
+        assert_eq!(render("{mode_icon} {red"), "gptsh {red");
+    }
+
+    #[test]
+    fn literal_text_is_kept() {
+        assert_eq!(parts("gptsh> "), [text("gptsh> ")]);
+        assert_eq!(parts("} $ "), [text("} $ ")]);
+        assert!(parts("").is_empty());
+        assert_eq!(render("[{status}] ❯ "), "[2] ❯ ");
+    }
+}
@@ -0,0 +1,393 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    env,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use chrono::Local;
+use serde_json::json;
+
+use crate::{
+    chat::{split_head_tail, CapturedOutput},
+    files::expand_home,
+    models::Config,
+};
+
+/// The most bytes of each of stdout and stderr written to the log for a command. Longer
+/// output keeps its start and end, around a line saying how much was left out.
+const MAX_LOGGED_OUTPUT_BYTES: usize = 2000;
+
+/// How a session log is written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum LogFormat {
+    /// Plain text for people to read: a line with the time, mode, and input, then the rest
+    /// indented beneath it.
+    Text,
+    /// One JSON object per line, for tools.
+    Jsonl,
+}
+
+impl LogFormat {
+    /// Parses a format name, `text` or `jsonl`, ignoring case.
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "text" => Some(Self::Text),
+            "jsonl" => Some(Self::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+/// What one line typed in shell mode led to, for the session log.
+#[derive(Default)]
+pub(crate) struct LogEntry {
+    /// The mode the line was handled in, e.g. `Direct Command`.
+    pub(crate) mode: &'static str,
+    /// The line as typed.
+    pub(crate) input: String,
+    /// The command the model suggested, if it was asked for one.
+    pub(crate) suggested: Option<String>,
+    /// Whether the user chose to run the suggested command, if there was one.
+    pub(crate) confirmed: Option<bool>,
+    /// The command that ran, which may be a suggestion the user edited or a direct command
+    /// with its alias expanded.
+    pub(crate) command: Option<String>,
+    /// Whether the command was started in the background, so has no exit status yet.
+    pub(crate) background: bool,
+    /// The command's exit status, as a shell reports it.
+    pub(crate) exit_status: Option<i32>,
+    /// What the command printed to stdout and stderr, or `None` if it wasn't captured.
+    pub(crate) output: Option<(String, String)>,
+    /// Whether the command ran with the terminal to itself, so its output wasn't captured.
+    pub(crate) terminal: bool,
+}
+
+/// A file that every line typed in shell mode is appended to, with what came of it. Failing
+/// to write never ends the session: the first failure is warned about, and later entries
+/// are still tried, in case the problem, such as a full disk, goes away.
+pub(crate) struct SessionLog {
+    path: PathBuf,
+    format: LogFormat,
+    /// The open file, or `None` until it could be opened.
+    file: Option<File>,
+    /// Whether a failure has been warned about already.
+    warned: bool,
+    /// Shows a warning to the user.
+    print_warning: fn(&str),
+}
+
+impl SessionLog {
+    /// Opens the session log chosen with `--log-session`, or with `session_log` in the
+    /// configuration, in the format from `--log-format` or `session_log_format`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file given on the command line, taking precedence over the config.
+    /// * `format` - The format given on the command line, taking precedence over the config.
+    /// * `config` - The loaded configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Self>` - The log, or `None` if the session isn't logged.
+    pub(crate) fn open(path: Option<&str>, format: Option<LogFormat>, config: &Config) -> Option<Self> {
+        let path = path.or(config.session_log.as_deref())?;
+        let format = format.unwrap_or_else(|| match config.session_log_format.as_deref() {
+            None => LogFormat::Text,
+            Some(name) => LogFormat::parse(name).unwrap_or_else(|| {
+                eprintln!("Warning: Unknown session_log_format '{}' in .gptsh_config; using text. Formats: text, jsonl.", name);
+                LogFormat::Text
+            }),
+        });
+        // Made absolute now, since `cd` in shell mode changes the working directory
+        let path = env::current_dir().unwrap_or_default().join(expand_home(path));
+        Some(Self::with_warnings(path, format, |warning| eprintln!("{}", warning)))
+    }
+
+    /// Creates a log, opening its file now so a problem with it is reported at the start.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file to append to.
+    /// * `format` - How entries are written.
+    /// * `print_warning` - Shows a warning to the user.
+    fn with_warnings(path: PathBuf, format: LogFormat, print_warning: fn(&str)) -> Self {
+        let mut log = Self {
+            path,
+            format,
+            file: None,
+            warned: false,
+            print_warning,
+        };
+        if let Err(e) = log.open_file() {
+            log.warn(&e);
+        }
+        log
+    }
+
+    /// Returns the file the log is appended to.
+    pub(crate) fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    /// Appends an entry, stamped with the current time.
+    pub(crate) fn write(&mut self, entry: &LogEntry) {
+        let text = match self.format {
+            LogFormat::Text => format_text(entry),
+            LogFormat::Jsonl => format_json(entry),
+        };
+        let result = self.open_file().and_then(|file| {
+            file.write_all(text.as_bytes())?;
+            file.flush()
+        });
+        if let Err(e) = result {
+            // The file may have been removed or its disk unmounted; open it again next time
+            self.file = None;
+            self.warn(&e);
+        }
+    }
+
+    /// Returns the open file, opening it first if needed.
+    fn open_file(&mut self) -> io::Result<&mut File> {
+        if self.file.is_none() {
+            self.file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        }
+        Ok(self.file.as_mut().expect("the log file was just opened"))
+    }
+
+    /// Warns about a failure to write the log, the first time only.
+    fn warn(&mut self, error: &io::Error) {
+        if !self.warned {
+            self.warned = true;
+            (self.print_warning)(&format!(
+                "Warning: Could not write the session log {}: {}. The session goes on; entries that can't be written are lost.",
+                self.path.display(),
+                error
+            ));
+        }
+    }
+}
+
+/// Formats an entry as plain text, e.g.
+/// `2024-06-01 14:02:07 [LLM Suggestion] list the big files` followed by the suggested
+/// command, whether it ran, its exit status, and its output, indented.
+fn format_text(entry: &LogEntry) -> String {
+    let mut text = format!(
+        "{} [{}] {}\n",
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        entry.mode,
+        entry.input.replace('\n', "\n    ")
+    );
+    // Output starts on the line after its name
+    let mut field = |name: &str, value: &str| {
+        let value = value.trim_end().replace('\n', "\n    ");
+        let separator = if value.starts_with('\n') { ":" } else { ": " };
+        text.push_str(&format!("  {}{}{}\n", name, separator, value));
+    };
+    if let Some(suggested) = &entry.suggested {
+        field("suggested", suggested);
+    }
+    if let Some(confirmed) = entry.confirmed {
+        field("confirmed", if confirmed { "yes" } else { "no" });
+    }
+    if let Some(command) = &entry.command {
+        field("ran", command);
+    }
+    if entry.background {
+        field("exit status", "none yet; started in the background");
+    } else if let Some(status) = entry.exit_status {
+        field("exit status", &status.to_string());
+    }
+    match &entry.output {
+        Some((stdout, stderr)) => {
+            for (name, output) in [("stdout", stdout), ("stderr", stderr)] {
+                if !output.trim().is_empty() {
+                    field(name, &format!("\n{}", output.trim_end()));
+                }
+            }
+        }
+        None if entry.terminal => field("output", "not captured; it ran with the terminal"),
+        None => {}
+    }
+    text.push('\n');
+    text
+}
+
+/// Formats an entry as a line of JSON with every field, `null` where it doesn't apply.
+fn format_json(entry: &LogEntry) -> String {
+    let (stdout, stderr) = entry.output.clone().unzip();
+    let line = json!({
+        "timestamp": Local::now().to_rfc3339(),
+        "mode": entry.mode,
+        "input": entry.input,
+        "suggested": entry.suggested,
+        "confirmed": entry.confirmed,
+        "command": entry.command,
+        "background": entry.background,
+        "exit_status": entry.exit_status,
+        "terminal": entry.terminal,
+        "stdout": stdout,
+        "stderr": stderr,
+    });
+    format!("{}\n", line)
+}
+
+/// Returns what a command printed for the log, cut down to its start and end if it is
+/// longer than [`MAX_LOGGED_OUTPUT_BYTES`].
+pub(crate) fn logged_output(captured: &CapturedOutput) -> String {
+    let (output, dropped) = captured.clone().into_text();
+    if output.len() <= MAX_LOGGED_OUTPUT_BYTES && dropped == 0 {
+        return output;
+    }
+    let (head, tail, omitted) = split_head_tail(&output, MAX_LOGGED_OUTPUT_BYTES);
+    format!("{}\n[... {} bytes left out ...]\n{}", head.trim_end_matches('\n'), omitted + dropped, tail)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fs};
+
+    use serde_json::Value;
+
+    use super::*;
+    use crate::utils::testing::test_dir;
+
+    thread_local! {
+        /// The warnings shown by logs in this test thread.
+        static WARNINGS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record_warning(warning: &str) {
+        WARNINGS.with(|warnings| warnings.borrow_mut().push(warning.to_string()));
+    }
+
+    /// A suggestion that was confirmed and failed.
+    fn confirmed_entry() -> LogEntry {
+        LogEntry {
+            mode: "LLM Suggestion",
+            input: "list the big files".to_string(),
+            suggested: Some("du -sh * | sort -h".to_string()),
+            confirmed: Some(true),
+            command: Some("du -sh * | sort -h".to_string()),
+            exit_status: Some(1),
+            output: Some(("4.0K\tsrc\n".to_string(), "du: cannot read\n".to_string())),
+            ..LogEntry::default()
+        }
+    }
+
+    fn captured(text: &str) -> CapturedOutput {
+        let mut captured = CapturedOutput::new(usize::MAX);
+        captured.push(text.as_bytes());
+        captured
+    }
+
+    #[test]
+    fn text_entries_start_with_the_time_mode_and_input() {
+        let text = format_text(&confirmed_entry());
+        let (time, rest) = text.split_at("2024-06-01 14:02:07".len());
+        assert!(chrono::NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S").is_ok(), "{}", time);
+        assert_eq!(
+            rest,
+            " [LLM Suggestion] list the big files\n\
+             \x20 suggested: du -sh * | sort -h\n\
+             \x20 confirmed: yes\n\
+             \x20 ran: du -sh * | sort -h\n\
+             \x20 exit status: 1\n\
+             \x20 stdout:\n    4.0K\tsrc\n\
+             \x20 stderr:\n    du: cannot read\n\n"
+        );
+
+        let background = LogEntry {
+            mode: "Direct Command",
+            input: "make &".to_string(),
+            command: Some("make".to_string()),
+            background: true,
+            terminal: true,
+            ..LogEntry::default()
+        };
+        assert!(format_text(&background).ends_with(
+            " [Direct Command] make &\n  ran: make\n  exit status: none yet; started in the background\n  output: not captured; it ran with the terminal\n\n"
+        ));
+    }
+
+    #[test]
+    fn json_entries_have_every_field() {
+        let line = format_json(&confirmed_entry());
+        assert!(line.ends_with('\n') && !line.trim_end().contains('\n'));
+        let entry: Value = serde_json::from_str(&line).unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(entry["timestamp"].as_str().unwrap()).is_ok());
+        assert_eq!(entry["mode"], "LLM Suggestion");
+        assert_eq!(entry["input"], "list the big files");
+        assert_eq!(entry["suggested"], "du -sh * | sort -h");
+        assert_eq!(entry["confirmed"], true);
+        assert_eq!(entry["command"], "du -sh * | sort -h");
+        assert_eq!(entry["background"], false);
+        assert_eq!(entry["exit_status"], 1);
+        assert_eq!(entry["terminal"], false);
+        assert_eq!(entry["stdout"], "4.0K\tsrc\n");
+        assert_eq!(entry["stderr"], "du: cannot read\n");
+
+        let direct: Value = serde_json::from_str(&format_json(&LogEntry::default())).unwrap();
+        for field in ["suggested", "confirmed", "command", "exit_status", "stdout", "stderr"] {
+            assert_eq!(direct[field], Value::Null, "{}", field);
+        }
+    }
+
+    #[test]
+    fn long_output_keeps_its_start_and_end() {
+        assert_eq!(logged_output(&captured("short\n")), "short\n");
+
+        let output = format!("{}{}", "a".repeat(3000), "z".repeat(3000));
+        let logged = logged_output(&captured(&output));
+        assert!(logged.starts_with(&"a".repeat(MAX_LOGGED_OUTPUT_BYTES / 2)));
+        assert!(logged.ends_with(&"z".repeat(MAX_LOGGED_OUTPUT_BYTES / 2)));
+        assert!(logged.contains("\n[... 4000 bytes left out ...]\n"), "{}", logged);
+
+        // Bytes already dropped while the command ran are counted as well
+        let mut partial = CapturedOutput::new(1500);
+        partial.push(output.as_bytes());
+        assert!(logged_output(&partial).contains("\n[... 4000 bytes left out ...]\n"));
+    }
+
+    #[test]
+    fn unwritable_logs_warn_once_and_go_on() {
+        // A directory can't be opened for appending
+        let path = test_dir("session-log-unwritable");
+        let mut log = SessionLog::with_warnings(path.clone(), LogFormat::Jsonl, record_warning);
+        for _ in 0..3 {
+            log.write(&confirmed_entry());
+        }
+
+        let warnings = WARNINGS.with(|warnings| warnings.take());
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].starts_with(&format!("Warning: Could not write the session log {}: ", path.display())));
+        assert_eq!(fs::read_dir(&path).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn entries_are_appended() {
+        let path = test_dir("session-log").join("session.log");
+        let mut log = SessionLog::with_warnings(path.clone(), LogFormat::Jsonl, record_warning);
+        log.write(&confirmed_entry());
+        log.write(&LogEntry::default());
+
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 2);
+        assert!(WARNINGS.with(|warnings| warnings.borrow().is_empty()));
+    }
+}
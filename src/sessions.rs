@@ -0,0 +1,230 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::utils::is_plain_file_name;
+
+const SESSIONS_DIR: &str = "sessions";
+
+/// A chat conversation as stored on disk.
+#[derive(Serialize, Deserialize)]
+struct SessionFile {
+    /// When the session was last saved, in RFC 3339 format.
+    saved_at: String,
+    /// The conversation, in the chat-completions message format.
+    messages: Vec<Value>,
+}
+
+/// Returns the directory chat sessions are saved in.
+///
+/// # Returns
+///
+/// * `Option<PathBuf>` - The directory, or `None` if there is no configuration directory.
+fn sessions_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("gptsh").join(SESSIONS_DIR))
+}
+
+/// Returns the path of the file a session is saved in.
+fn session_path(id: &str) -> io::Result<PathBuf> {
+    sessions_dir()
+        .map(|dir| dir.join(format!("{}.json", id)))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no configuration directory"))
+}
+
+/// Checks that a name given to `/save` or `/load` can be used as a file name.
+///
+/// # Arguments
+///
+/// * `name` - The session name.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - `Ok`, or a message saying what's wrong with the name.
+pub(crate) fn validate_session_name(name: &str) -> Result<(), String> {
+    if !is_plain_file_name(name) {
+        return Err(format!(
+            "Error: '{}' is not a valid session name. Use letters, digits, '-', '_', and '.'.",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Picks an id for a new session from the current time, e.g. `20240614-093012`.
+pub(crate) fn new_session_id() -> String {
+    let id = Local::now().format("%Y%m%d-%H%M%S").to_string();
+    let mut candidate = id.clone();
+    let mut suffix = 2;
+    while session_path(&candidate).is_ok_and(|path| path.exists()) {
+        candidate = format!("{}-{}", id, suffix);
+        suffix += 1;
+    }
+    candidate
+}
+
+/// Saves a conversation, replacing any earlier save under the same id.
+/// The file is written next to its destination and then renamed, so an interrupted
+/// save never leaves a half-written session behind.
+///
+/// # Arguments
+///
+/// * `id` - The session id, which is also the file name.
+/// * `messages` - The conversation to save.
+pub(crate) fn save_session(id: &str, messages: &[Value]) -> io::Result<()> {
+    write_session(&session_path(id)?, messages)
+}
+
+/// Does the work of `save_session` once the file is known.
+fn write_session(path: &Path, messages: &[Value]) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let session = SessionFile {
+        saved_at: Local::now().to_rfc3339(),
+        messages: messages.to_vec(),
+    };
+    let contents = serde_json::to_string_pretty(&session).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let partial = path.with_extension("json.tmp");
+    fs::write(&partial, contents)?;
+    fs::rename(partial, path)
+}
+
+/// Loads a saved conversation.
+///
+/// # Arguments
+///
+/// * `id` - The session id.
+///
+/// # Returns
+///
+/// * `io::Result<Vec<Value>>` - The saved messages, or an `InvalidData` error if the file
+///   isn't a session.
+pub(crate) fn load_session(id: &str) -> io::Result<Vec<Value>> {
+    read_session(&session_path(id)?)
+}
+
+/// Does the work of `load_session` once the file is known.
+fn read_session(path: &Path) -> io::Result<Vec<Value>> {
+    let contents = fs::read_to_string(path)?;
+    let session: SessionFile =
+        serde_json::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if let Some(index) = session.messages.iter().position(|message| !message["role"].is_string()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message {} has no role", index + 1),
+        ));
+    }
+    Ok(session.messages)
+}
+
+/// Loads the most recently saved conversation. Files that can't be read are skipped
+/// with a warning, so one damaged session doesn't hide the others.
+///
+/// # Returns
+///
+/// * `Option<(String, Vec<Value>)>` - The session id and its messages, or `None` if
+///   there is no readable session.
+pub(crate) fn load_latest_session() -> Option<(String, Vec<Value>)> {
+    list_sessions().into_iter().find_map(|id| match load_session(&id) {
+        Ok(messages) => Some((id, messages)),
+        Err(e) => {
+            eprintln!("Warning: Skipping unreadable chat session '{}': {}", id, e);
+            None
+        }
+    })
+}
+
+/// Lists the saved sessions, most recently saved first.
+///
+/// # Returns
+///
+/// * `Vec<String>` - The session ids.
+pub(crate) fn list_sessions() -> Vec<String> {
+    sessions_dir().map(|dir| list_sessions_in(&dir)).unwrap_or_default()
+}
+
+/// Does the work of `list_sessions` for a given directory.
+fn list_sessions_in(dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut sessions: Vec<(SystemTime, String)> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.extension()? != "json" {
+                return None;
+            }
+            let modified = entry.metadata().and_then(|metadata| metadata.modified()).ok()?;
+            Some((modified, path.file_stem()?.to_string_lossy().into_owned()))
+        })
+        .collect();
+    sessions.sort_by(|a, b| b.cmp(a));
+    sessions.into_iter().map(|(_, id)| id).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::utils::testing::test_dir;
+
+    #[test]
+    fn sessions_load_as_they_were_saved() {
+        let messages = vec![
+            json!({"role": "system", "content": "Be brief."}),
+            json!({"role": "user", "content": "what's in here? ✨", "timestamp": "2024-06-14T09:30:12+02:00"}),
+            json!({
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{"id": "call_1", "type": "function", "function": {"name": "list_directory", "arguments": "{\"path\":\".\"}"}}]
+            }),
+            json!({"role": "tool", "tool_call_id": "call_1", "content": "Cargo.toml\nsrc/"}),
+            json!({"role": "assistant", "function_call": {"name": "read_file", "arguments": "{\"path\":\"Cargo.toml\"}"}}),
+            json!({"role": "function", "name": "read_file", "content": "[package]\nname = \"gptsh\""}),
+            json!({"role": "assistant", "content": "A Rust crate, pal."}),
+        ];
+
+        let dir = test_dir("sessions");
+        let path = dir.join("round-trip.json");
+
+        write_session(&path, &messages).unwrap();
+
+        assert_eq!(read_session(&path).unwrap(), messages);
+        assert_eq!(list_sessions_in(&dir), ["round-trip"]);
+    }
+
+    #[test]
+    fn sessions_with_messages_missing_a_role_are_rejected() {
+        let path = test_dir("sessions-no-role").join("no-role.json");
+        fs::write(&path, r#"{"saved_at": "", "messages": [{"role": "user", "content": "hi"}, {"content": "?"}]}"#).unwrap();
+
+        let error = read_session(&path).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "message 2 has no role");
+    }
+}
@@ -14,57 +14,290 @@
  * limitations under the License.
  */
 
-use crate::cli::execute_command;
-use crate::openai::{initialize_files, process_prompt};
-use crate::utils::{get_current_dir_with_tilde, get_username};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use crate::aliases::{describe_aliases, expand_aliases, load_aliases, run_alias_command, run_unalias_command, Aliases};
+use crate::ask::answer_question;
+use crate::builtins::{parse_builtin, run_builtin};
+use crate::chat::{cap_command_output, DEFAULT_MAX_COMMAND_OUTPUT_BYTES};
+use crate::cli::{
+    capture_command_output, execute_command, notice_slow_commands, shell_status_code, take_last_command, CliOptions,
+    FinishedCommand,
+};
+use crate::command_shell::{command_shell_name, init_command_shell};
+use crate::completion::ShellHelper;
+use crate::history::{configure_history, history_entry, history_path, prepare_history_file};
+use crate::job_control::{strip_background, wait_for_job, JobState, Jobs};
+use crate::models::Config;
+use crate::openai::{
+    add_banned_command, initialize_files, load_banned_commands, load_config, process_prompt_with_context,
+    record_approved_command, suggest_command_with_context, Translation,
+};
+use crate::prompt::{PromptFormat, PromptState, DEFAULT_SHELL_PROMPT};
+use crate::session_log::{logged_output, LogEntry, SessionLog};
+use crate::transcript::fenced;
+use crate::utils::{estimate_tokens, install_interrupt_handler};
 use colored::Colorize;
 use rustyline::error::ReadlineError;
 use rustyline::history::FileHistory;
-use rustyline::Editor;
-use std::fs::OpenOptions;
+use rustyline::{CompletionType, DefaultEditor, Editor, EventHandler, KeyCode, KeyEvent, Modifiers};
 
 // Enum representing the different modes of the shell
+#[derive(Clone, Copy)]
 enum Mode {
     LlmSuggestion,
     DirectCommand,
 }
 
-// Struct to hold the shell state, including the current mode
+// The estimated tokens of earlier prompts sent along with each new one; the oldest are
+// left out beyond it
+const MAX_CONTEXT_TOKENS: usize = 1000;
+
+// The word that switches modes unless `mode_switch` is set
+const DEFAULT_MODE_SWITCH: &str = "youdu";
+
+// What starts a prompt for the model in Direct Command Mode unless `suggest_prefix` is set
+const DEFAULT_SUGGEST_PREFIX: &str = "#?";
+
+// The most characters of an answer kept in the session context; the start of an answer
+// is usually enough to follow up on
+const MAX_ANSWER_CONTEXT_CHARS: usize = 400;
+
+// The file in the home directory run when shell mode starts, unless `--no-rc` is given
+const STARTUP_FILE: &str = ".gptshrc";
+
+// How many seconds a command runs before the user is offered to kill it, unless
+// `slow_command_seconds` is set
+const DEFAULT_SLOW_COMMAND_SECONDS: u64 = 60;
+
+// A prompt given in LLM suggestion mode, and what came of it
+struct ShellTurn {
+    prompt: String,
+    outcome: TurnOutcome,
+}
+
+// What a prompt produced: a suggested command, or the answer to a `?` question
+enum TurnOutcome {
+    Command(Translation),
+    Answer(String),
+}
+
+// Struct to hold the shell state, including the current mode and the prompts given so far
 struct ShellState {
     mode: Mode,
+    turns: Vec<ShellTurn>,
+    // Whether the output of the last command is sent with the next prompt
+    share_output: bool,
+    // The most bytes of each of stdout and stderr sent; 0 is unlimited
+    output_limit: usize,
+    // Whether answers to `?` questions join the session context
+    remember_answers: bool,
+    // The exit status of the command the last input ran, shown in the prompt; `None` if
+    // it ran nothing
+    last_status: Option<i32>,
+    // How the prompt looks, from `shell_prompt`
+    prompt_format: PromptFormat,
+    // The aliases expanded in direct commands
+    aliases: Aliases,
+    // Whether the aliases are described to the model
+    aliases_in_context: bool,
+    // The word that switches modes, from `mode_switch`
+    mode_switch: String,
+    // What starts a prompt for the model in Direct Command Mode, from `suggest_prefix`
+    suggest_prefix: String,
+    // The environment when the session started, so `:env` can show what changed since
+    initial_environment: HashMap<OsString, OsString>,
+    // The last command run, exactly as it ran, for `:undo`
+    last_executed: Option<ExecutedCommand>,
+    // Whether suggestions are put on an editable line instead of asked about with Y/n
+    prefill_suggestions: bool,
+    // The commands started in the background with `&`
+    jobs: Jobs,
+    // Whether `exit` was just typed with jobs still running, so typing it again quits
+    exit_warned: bool,
+    // The last command run while output is shared, until it is sent with the next prompt
+    last_command: Option<FinishedCommand>,
+    // Where each line typed is logged, from `--log-session` or `session_log`
+    log: Option<SessionLog>,
+    // What the line being handled has led to so far, for the log
+    log_entry: LogEntry,
+}
+
+// A command that ran in this session
+struct ExecutedCommand {
+    command: String,
+    exit_status: i32,
+    // The working directory it ran in, which a `cd` since may have changed
+    directory: Option<PathBuf>,
 }
 
 impl ShellState {
     // Create a new ShellState, defaulting to LLM suggestion mode
-    fn new() -> Self {
+    fn new(config: &Config) -> Self {
         Self {
             mode: Mode::LlmSuggestion,
+            turns: Vec::new(),
+            share_output: config.share_command_output.unwrap_or(false),
+            output_limit: config.max_command_output_bytes.unwrap_or(DEFAULT_MAX_COMMAND_OUTPUT_BYTES),
+            remember_answers: config.shell_answers_in_context.unwrap_or(true),
+            last_status: None,
+            prompt_format: PromptFormat::parse(config.shell_prompt.as_deref().unwrap_or(DEFAULT_SHELL_PROMPT)),
+            aliases: load_aliases(config.aliases.as_ref()),
+            aliases_in_context: config.aliases_in_context.unwrap_or(false),
+            mode_switch: mode_switch_keyword(config),
+            suggest_prefix: match config.suggest_prefix.as_deref().map(str::trim) {
+                Some(prefix) if !prefix.is_empty() => prefix.to_string(),
+                _ => DEFAULT_SUGGEST_PREFIX.to_string(),
+            },
+            initial_environment: env::vars_os().collect(),
+            last_executed: None,
+            prefill_suggestions: config.prefill_suggestions.unwrap_or(true),
+            jobs: Jobs::default(),
+            exit_warned: false,
+            last_command: None,
+            log: None,
+            log_entry: LogEntry::default(),
+        }
+    }
+
+    // Captures the output of the commands run when it is shared with the model or logged,
+    // and forgets the last command's when sharing stops
+    fn update_capture(&mut self) {
+        capture_command_output(self.share_output || self.log.is_some(), self.output_limit);
+        if !self.share_output {
+            self.last_command = None;
         }
     }
+
+    // Describes the earlier prompts and the commands suggested for them, newest last, so
+    // a prompt like "now do the same for staging" makes sense to the model
+    fn describe_context(&self) -> Option<String> {
+        let mut lines = Vec::new();
+        let mut tokens = 0;
+        for turn in self.turns.iter().rev() {
+            let line = match &turn.outcome {
+                TurnOutcome::Command(translation) => {
+                    let outcome = if translation.background {
+                        "which the user ran in the background"
+                    } else if translation.exit_status.is_some() {
+                        "which the user ran"
+                    } else {
+                        "which the user did not run"
+                    };
+                    format!("- Asked: \"{}\"; suggested `{}`, {}.", turn.prompt, translation.command, outcome)
+                }
+                TurnOutcome::Answer(answer) => {
+                    let answer = answer.trim();
+                    let mut excerpt: String = answer.chars().take(MAX_ANSWER_CONTEXT_CHARS).collect();
+                    if excerpt.len() < answer.len() {
+                        excerpt.push('…');
+                    }
+                    format!("- Asked the question \"{}\"; answered: \"{}\"", turn.prompt, excerpt)
+                }
+            };
+            tokens += estimate_tokens(&line);
+            if tokens > MAX_CONTEXT_TOKENS {
+                break;
+            }
+            lines.push(line);
+        }
+        if lines.is_empty() {
+            return None;
+        }
+        lines.reverse();
+        Some(format!("Earlier in this shell session, oldest first:\n{}", lines.join("\n")))
+    }
+}
+
+// Describes the last command run and what it printed, for the model to see with the
+// next prompt
+fn describe_last_command(finished: FinishedCommand, output_limit: usize) -> String {
+    let ending = match finished.exit_code {
+        Some(code) => format!("exited with code {}", code),
+        None => "was ended by a signal".to_string(),
+    };
+    let mut description = format!("The last command the user ran was `{}`, which {}.", finished.command, ending);
+    match finished.output {
+        Some((stdout, stderr)) => {
+            for (name, captured) in [("stdout", stdout), ("stderr", stderr)] {
+                let text = cap_command_output(captured, output_limit);
+                if !text.trim().is_empty() {
+                    description.push_str(&format!("\nIts {}:\n{}", name, fenced("text", text.trim_end())));
+                }
+            }
+        }
+        None => description.push_str(" It ran with the terminal attached, so its output wasn't captured."),
+    }
+    description
 }
 
 // Main function to run the shell in continuous mode
-pub(crate) fn run_shell_mode(no_execute: bool) {
+pub(crate) fn run_shell_mode(options: &CliOptions) {
     initialize_files();
-    let mut state = ShellState::new();
+    // Ctrl-C at the prompt is rustyline's; while a command runs it goes to the command
+    install_interrupt_handler();
+    let config = load_config().unwrap_or_default();
+    // Warns now, rather than at the first command, if the shell to run commands is missing
+    init_command_shell(&config);
+    let mut state = ShellState::new(&config);
+    state.log = SessionLog::open(options.log_session.as_deref(), options.log_format, &config);
+    state.update_capture();
+    notice_slow_commands(config.slow_command_seconds.unwrap_or(DEFAULT_SLOW_COMMAND_SECONDS));
     println!("{}", "Entering continuous shell mode. Type 'exit' to quit.".cyan());
+    if let Some(log) = &state.log {
+        println!("{}", format!("Logging this session to {}.", log.path().display()).dimmed());
+    }
+    if !options.no_rc {
+        run_startup_file(&mut state, options);
+    }
+
+    // Initialize rustyline Editor for input handling with history, which keeps the newest
+    // entries up to the limit and by default skips repeats; Tab lists the completions like
+    // a shell does
+    let editor_config = configure_history(rustyline::Config::builder().completion_type(CompletionType::List), &config)
+        .expect("Failed to configure history")
+        .build();
+    let mut rl = Editor::<ShellHelper, FileHistory>::with_config(editor_config).expect("Failed to initialize editor");
+    let helper = ShellHelper::new(&state.mode_switch);
+    // Ctrl-R searches the history; Enter then leaves the match on the line to edit
+    rl.bind_sequence(
+        KeyEvent(KeyCode::Enter, Modifiers::NONE),
+        EventHandler::Conditional(Box::new(helper.accept_search_match())),
+    );
+    rl.set_helper(Some(helper));
 
-    // Initialize rustyline Editor for input handling with history
-    let mut rl = Editor::<(), FileHistory>::new().expect("Failed to initialize editor");
+    let ignore_space = config.history_ignore_space.unwrap_or(false);
 
-    // Load history (this returns a Result)
-    if rl.load_history(".gptsh_history").is_err() {
-        let _ = OpenOptions::new()
-            .write(true)
-            .create(true)  // Create the file if it does not exist
-            .append(true)  // Append to the file instead of overwriting
-            .open(".gptsh_history");
+    // Load history, which is shared by every directory unless configured otherwise
+    let history_path = history_path(&config);
+    if let Some(path) = &history_path {
+        prepare_history_file(path);
+        let _ = rl.load_history(path);
     }
+    let mut history_warned = false;
 
     loop {
-        let prompt_text = display_prompt(&state.mode);
+        report_job_changes(&mut state);
+        let prompt_text = display_prompt(&mut state);
+        if let Some(helper) = rl.helper_mut() {
+            helper.complete_commands = matches!(state.mode, Mode::DirectCommand);
+        }
         let prompt = match rl.readline(prompt_text.as_str()) {
-            Ok(line) => line,
+            Ok(line) => {
+                // Shell syntax is only checked in commands; a prompt like "don't delete
+                // anything" would otherwise look like an unclosed quote
+                let shell_syntax = matches!(state.mode, Mode::DirectCommand) || line.trim_start().starts_with('!');
+                match read_continuation(&mut rl, line, shell_syntax) {
+                    Some(input) => input,
+                    None => continue,
+                }
+            }
             Err(ReadlineError::Interrupted) => {
                 // Handle Ctrl-C
                 println!("{}", "^C".red());
@@ -72,7 +305,10 @@ pub(crate) fn run_shell_mode(no_execute: bool) {
             }
             Err(ReadlineError::Eof) => {
                 // Handle Ctrl-D
-                break;
+                if confirm_exit(&mut state) {
+                    break;
+                }
+                continue;
             }
             Err(err) => {
                 println!("Error: {:?}", err);
@@ -83,41 +319,356 @@ pub(crate) fn run_shell_mode(no_execute: bool) {
         let trimmed_prompt = prompt.trim();
 
         if trimmed_prompt.eq_ignore_ascii_case("exit") {
-            break;
+            if confirm_exit(&mut state) {
+                break;
+            }
+            continue;
         }
 
         if !trimmed_prompt.is_empty() {
-            let _ = rl.add_history_entry(trimmed_prompt);
-            if is_mode_switch_command(trimmed_prompt) {
-                // Mode switch now also runs the command
-                switch_mode(&mut state, trimmed_prompt, no_execute);
-            } else {
-                handle_input(trimmed_prompt, &state, no_execute);
+            state.exit_warned = false;
+            if let Some(entry) = history_entry(&prompt, ignore_space) {
+                let _ = rl.add_history_entry(entry);
+                // Saved before the line runs, so it is kept even if gptsh is ended while the
+                // line runs, e.g. by pressing Ctrl-C twice
+                if let Some(path) = &history_path {
+                    save_history(&mut rl, path, &mut history_warned);
+                }
+            }
+            // Only a command run for this input sets the status; an empty line keeps it
+            state.last_status = None;
+            state.log_entry = LogEntry {
+                mode: mode_name(state.mode),
+                input: trimmed_prompt.to_string(),
+                ..LogEntry::default()
+            };
+            if let Some(result) = handle_session_command(trimmed_prompt, &mut state, options) {
+                if let Err(message) = result {
+                    eprintln!("{}", message);
+                }
+                finish_input(&mut state);
+                continue;
+            }
+            match parse_mode_switch(trimmed_prompt, &state.mode_switch) {
+                Some(ModeSwitch::Switch(rest)) => switch_mode(&mut state, rest, options),
+                Some(ModeSwitch::Once("")) => {
+                    println!("Usage: {}! <input> runs one input in the other mode", state.mode_switch)
+                }
+                Some(ModeSwitch::Once(rest)) => run_in_other_mode(&mut state, rest, options),
+                None => handle_input(trimmed_prompt, &mut state, options),
             }
+            finish_input(&mut state);
+            // A command may have changed the repository the prompt shows
+            if state.last_status.is_some() {
+                state.prompt_format.refresh_git();
+            }
+        }
+    }
+}
+
+// Adds the entries typed since the last save to the history file, after what other sessions
+// saved in the meantime. Only the first failure is reported, rather than one per line
+fn save_history(rl: &mut Editor<ShellHelper, FileHistory>, path: &Path, warned: &mut bool) {
+    if let Err(e) = rl.append_history(path) {
+        if !mem::replace(warned, true) {
+            eprintln!("Warning: Could not save the history to {}: {}", path.display(), e);
+        }
+    }
+}
+
+// Runs ~/.gptshrc, if there is one, before the first prompt. Each line is a builtin such as
+// `export` or `cd`, an assignment like `FOO=bar`, a session command such as `:alias` or
+// `:context-output on`, or the mode-switch word on its own to start in Direct Command Mode.
+// Blank lines and lines starting with `#` are skipped. A bad line is reported with its
+// number and the rest of the file still runs
+fn run_startup_file(state: &mut ShellState, options: &CliOptions) {
+    let Some(path) = dirs::home_dir().map(|home| home.join(STARTUP_FILE)) else { return };
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            eprintln!("Warning: Could not read {}: {}", path.display(), e);
+            return;
+        }
+    };
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Err(message) = run_startup_line(line, state, options) {
+            let message = message.strip_prefix("Error: ").unwrap_or(&message);
+            eprintln!("Error: {}, line {}: {}", path.display(), index + 1, message);
         }
     }
+}
 
-    // Save the history on exit
-    rl.save_history(".gptsh_history").expect("Failed to save history");
+// Runs one line of ~/.gptshrc with the same handlers as the prompt. Commands that would
+// need a child process are refused, since what they change wouldn't outlast them
+fn run_startup_line(line: &str, state: &mut ShellState, options: &CliOptions) -> Result<(), String> {
+    if let Some(result) = handle_session_command(line, state, options) {
+        return result;
+    }
+    match parse_mode_switch(line, &state.mode_switch) {
+        Some(ModeSwitch::Switch("")) => {
+            switch_mode(state, "", options);
+            return Ok(());
+        }
+        Some(_) => return Err(format!("Only '{}' on its own can be used here, to switch modes.", state.mode_switch)),
+        None => {}
+    }
+    match parse_builtin(line) {
+        Some(builtin) => builtin.and_then(run_builtin),
+        None => Err(format!(
+            "'{}' can't be run at startup. Use export, unset, cd, assignments like FOO=bar, :alias, :context-output, or '{}'.",
+            line, state.mode_switch
+        )),
+    }
 }
 
-// Function to check if a command is meant to switch modes
-fn is_mode_switch_command(input: &str) -> bool {
-    input.eq_ignore_ascii_case("youdu")
+// Handles the commands that change the shell session rather than run anything, such as
+// `:reset`. Returns `None` if the input isn't one of them, or whether it succeeded
+fn handle_session_command(input: &str, state: &mut ShellState, options: &CliOptions) -> Option<Result<(), String>> {
+    let (name, arguments) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+    if name == ":fg" {
+        return Some(bring_job_to_foreground(arguments, state));
+    }
+    if name == ":alias" || name == ":unalias" {
+        let result = if name == ":alias" {
+            run_alias_command(arguments, &mut state.aliases)
+        } else {
+            run_unalias_command(arguments, &mut state.aliases)
+        };
+        return Some(result);
+    }
+    match input {
+        ":reset" => {
+            let count = state.turns.len();
+            state.turns.clear();
+            println!("{}", format!("Session context cleared ({} earlier prompts forgotten).", count).green());
+            Some(Ok(()))
+        }
+        ":undo" => {
+            undo_last_command(state, options);
+            Some(Ok(()))
+        }
+        ":env" => {
+            show_environment_changes(state);
+            Some(Ok(()))
+        }
+        ":jobs" => {
+            list_jobs(state);
+            Some(Ok(()))
+        }
+        ":context-output" => {
+            let state_name = if state.share_output { "on" } else { "off" };
+            println!("Sharing command output with the model is {}. Use :context-output on or :context-output off.", state_name);
+            Some(Ok(()))
+        }
+        ":context-output on" | ":context-output off" => {
+            state.share_output = input.ends_with("on");
+            state.update_capture();
+            if state.share_output {
+                println!(
+                    "{}",
+                    "The output and exit status of the last command you run will be sent to the model with your next prompt.".green()
+                );
+            } else {
+                println!("{}", "Command output will no longer be sent to the model.".green());
+            }
+            Some(Ok(()))
+        }
+        _ => None,
+    }
 }
 
-// Function to switch between the different modes of the shell and execute the command
-fn switch_mode(state: &mut ShellState, input: &str, no_execute: bool) {
-    state.mode = match state.mode {
-        Mode::LlmSuggestion => {
-            println!("{}", "Switching to Direct Command Mode".green());
-            Mode::DirectCommand
+// Starts a command in the background, saying its job number and process id as a shell
+// does
+fn start_background_job(command: &str, state: &mut ShellState) {
+    state.log_entry.command = Some(command.to_string());
+    match state.jobs.start(command) {
+        Ok(job) => {
+            println!("[{}] {}", job.number, job.id());
+            state.last_status = Some(0);
+            state.log_entry.background = true;
+        }
+        Err(e) => {
+            eprintln!("Failed to execute command: {}", e);
+            state.last_status = Some(127);
         }
-        Mode::DirectCommand => {
-            println!("{}", "Switching to LLM Suggestion Mode".green());
-            Mode::LlmSuggestion
+    }
+}
+
+// Says which background jobs finished or stopped since the last prompt
+fn report_job_changes(state: &mut ShellState) {
+    for (number, command, job_state) in state.jobs.poll() {
+        match job_state {
+            JobState::Finished(status) => match shell_status_code(status) {
+                0 => println!("[{}] Done: {}", number, command),
+                code => println!("[{}] Exit {}: {}", number, code, command),
+            },
+            JobState::Stopped => println!(
+                "[{}] Stopped: {}. It may be waiting for input; type :fg {} to bring it to the foreground.",
+                number, command, number
+            ),
+            JobState::Running => {}
         }
+    }
+}
+
+// Lists the background jobs that haven't finished
+fn list_jobs(state: &mut ShellState) {
+    report_job_changes(state);
+    if state.jobs.list().is_empty() {
+        println!("No background jobs are running.");
+        return;
+    }
+    for job in state.jobs.list() {
+        let job_state = if matches!(job.state, JobState::Stopped) { "Stopped" } else { "Running" };
+        println!("[{}] {:<7} {:<8} {}", job.number, job.id(), job_state, job.command);
+    }
+}
+
+// Waits for a background job in the foreground, the most recent one unless a number such
+// as `2` or `%2` is given
+fn bring_job_to_foreground(arguments: &str, state: &mut ShellState) -> Result<(), String> {
+    let number = match arguments.trim() {
+        "" => None,
+        argument => match argument.trim_start_matches('%').parse::<usize>() {
+            Ok(number) => Some(number),
+            Err(_) => return Err("Usage: :fg [job number]".to_string()),
+        },
     };
+    report_job_changes(state);
+    let Some(mut job) = state.jobs.take(number) else {
+        return Err(match number {
+            Some(number) => format!("Error: fg: {}: There is no such job. Type :jobs to list them.", number),
+            None => "Error: fg: There are no background jobs.".to_string(),
+        });
+    };
+    println!("{}", job.command);
+    let status = wait_for_job(&mut job).map_err(|e| format!("Error: fg: {}", e))?;
+    state.last_status = Some(shell_status_code(status));
+    // Go on after the ^C the terminal echoed on a line of its own
+    if status.code().is_none() && state.last_status == Some(130) {
+        println!();
+    }
+    // The job may have changed the repository the prompt shows
+    state.prompt_format.refresh_git();
+    Ok(())
+}
+
+// Checks whether the session can end. With background jobs still running, the first
+// `exit` only warns; a second one in a row quits and leaves them running
+fn confirm_exit(state: &mut ShellState) -> bool {
+    report_job_changes(state);
+    let count = state.jobs.list().len();
+    if count == 0 || state.exit_warned {
+        return true;
+    }
+    state.exit_warned = true;
+    let jobs = if count == 1 { "1 background job is".to_string() } else { format!("{} background jobs are", count) };
+    let them = if count == 1 { "it" } else { "them" };
+    println!("Warning: {} still running. Type exit again to quit and leave {} running, or :jobs to list {}.", jobs, them, them);
+    false
+}
+
+// Lists the environment variables set, changed, or removed in this session, by `export`,
+// `unset`, or assignments like `FOO=bar`, as commands that would make the same changes.
+// `PWD` and `OLDPWD`, which `cd` keeps up to date, are left out
+fn show_environment_changes(state: &ShellState) {
+    let quote = |value: &OsString| value.to_string_lossy().replace('\'', "'\\''");
+    let mut changes = Vec::new();
+    for (name, value) in env::vars_os() {
+        if state.initial_environment.get(&name) != Some(&value) {
+            changes.push((name.to_string_lossy().into_owned(), format!("export {}='{}'", name.to_string_lossy(), quote(&value))));
+        }
+    }
+    for name in state.initial_environment.keys() {
+        if env::var_os(name).is_none() {
+            changes.push((name.to_string_lossy().into_owned(), format!("unset {}", name.to_string_lossy())));
+        }
+    }
+    changes.retain(|(name, _)| name != "PWD" && name != "OLDPWD");
+    if changes.is_empty() {
+        println!("No environment variables have changed in this session.");
+        return;
+    }
+    changes.sort();
+    for (_, change) in changes {
+        println!("{}", change);
+    }
+}
+
+// A line starting with the mode-switch word, and the input after it
+#[derive(Debug, PartialEq)]
+enum ModeSwitch<'a> {
+    // Switch modes for good, then run the rest of the line, if any, in the new mode
+    Switch(&'a str),
+    // Run the rest of the line in the other mode, staying in this one
+    Once(&'a str),
+}
+
+// Reads the mode-switch word from `mode_switch`, falling back to `youdu` if it is empty or
+// holds spaces, which would make it match too much or never
+fn mode_switch_keyword(config: &Config) -> String {
+    match config.mode_switch.as_deref().map(str::trim) {
+        None => DEFAULT_MODE_SWITCH.to_string(),
+        Some(keyword) if keyword.is_empty() || keyword.contains(char::is_whitespace) => {
+            eprintln!("Warning: mode_switch in .gptsh_config must be a single word; using {}.", DEFAULT_MODE_SWITCH);
+            DEFAULT_MODE_SWITCH.to_string()
+        }
+        Some(keyword) => keyword.to_string(),
+    }
+}
+
+// Parses a line that starts with the mode-switch word, in any case: `youdu` switches
+// modes, `youdu ls` switches and runs `ls` in the new mode, and `youdu! ls` runs `ls` in
+// the other mode without switching. Returns `None` for any other line, including ones
+// that merely start with the same letters, like `youdufoo`
+fn parse_mode_switch<'a>(input: &'a str, keyword: &str) -> Option<ModeSwitch<'a>> {
+    if !input.get(..keyword.len())?.eq_ignore_ascii_case(keyword) {
+        return None;
+    }
+    let rest = &input[keyword.len()..];
+    if let Some(once) = rest.strip_prefix('!') {
+        return Some(ModeSwitch::Once(once.trim()));
+    }
+    (rest.is_empty() || rest.starts_with(char::is_whitespace)).then(|| ModeSwitch::Switch(rest.trim()))
+}
+
+// Names a mode for the session log
+fn mode_name(mode: Mode) -> &'static str {
+    match mode {
+        Mode::LlmSuggestion => "LLM Suggestion",
+        Mode::DirectCommand => "Direct Command",
+    }
+}
+
+// Returns the mode a mode switch goes to
+fn other_mode(mode: Mode) -> Mode {
+    match mode {
+        Mode::LlmSuggestion => Mode::DirectCommand,
+        Mode::DirectCommand => Mode::LlmSuggestion,
+    }
+}
+
+// Runs one input in the other mode, then goes back to the current one
+fn run_in_other_mode(state: &mut ShellState, input: &str, options: &CliOptions) {
+    let mode = state.mode;
+    state.mode = other_mode(mode);
+    handle_input(input, state, options);
+    state.mode = mode;
+}
+
+// Function to switch between the different modes of the shell and execute the rest of the
+// line, if any, in the new mode
+fn switch_mode(state: &mut ShellState, input: &str, options: &CliOptions) {
+    state.mode = other_mode(state.mode);
+    match state.mode {
+        Mode::DirectCommand => println!("{}", "Switching to Direct Command Mode".green()),
+        Mode::LlmSuggestion => println!("{}", "Switching to LLM Suggestion Mode".green()),
+    }
 
     // Display confirmation message
     println!(
@@ -129,65 +680,628 @@ fn switch_mode(state: &mut ShellState, input: &str, no_execute: bool) {
                 Mode::DirectCommand => "Direct Command Mode",
             }
         )
-            .blue()
+        .blue()
     );
 
     // After switching modes, execute the command if there's any additional input
-    let trimmed_input = trim_mode_prefix(input);
-    if !trimmed_input.is_empty() && trimmed_input != "youdu" {
-        handle_input(trimmed_input, state, no_execute);
+    if !input.is_empty() {
+        handle_input(input, state, options);
     }
 }
 
-// Updated handle_input function to delegate command handling
-fn handle_input(input: &str, state: &ShellState, no_execute: bool) {
+// Updated handle_input function to delegate command handling. In LLM suggestion mode,
+// input starting with `!` runs as a direct command without a trip to the API, and input
+// starting with `?` is answered rather than turned into a command. In direct command mode,
+// input starting with `#?` is turned into a command by the model
+fn handle_input(input: &str, state: &mut ShellState, options: &CliOptions) {
+    state.log_entry.mode = mode_name(state.mode);
     match state.mode {
-        Mode::LlmSuggestion => process_llm_suggestion(input, no_execute),
-        Mode::DirectCommand => execute_direct_command(input),
+        Mode::LlmSuggestion => match split_bang_prefix(input) {
+            (true, "") => println!("Usage: !<command> runs a command directly, e.g. !git status"),
+            (true, command) => execute_direct_command(command, state),
+            (false, prompt) => match prompt.strip_prefix('?') {
+                Some(question) if !question.trim().is_empty() => answer_llm_question(question.trim(), state, options),
+                Some(_) => println!("Usage: ?<question> answers a question instead of suggesting a command"),
+                None => process_llm_suggestion(prompt, state, options),
+            },
+        },
+        Mode::DirectCommand => match input.strip_prefix(state.suggest_prefix.as_str()) {
+            Some(prompt) if !prompt.trim().is_empty() => process_llm_suggestion(prompt.trim(), state, options),
+            Some(_) => println!(
+                "Usage: {}<prompt> asks the model for a command, e.g. {} find files over 1 GB",
+                state.suggest_prefix, state.suggest_prefix
+            ),
+            None => execute_direct_command(input, state),
+        },
     }
 }
 
-// Helper function to remove the mode switch prefix "u-" from the input
-fn trim_mode_prefix(input: &str) -> &str {
-    if input.starts_with("u-") {
-        &input[2..] // Remove the "u-" prefix
+// Splits off the `!` that marks a direct command, returning whether there was one and the
+// rest of the input. `\!` at the start stands for a literal `!`, sent on to the model
+fn split_bang_prefix(input: &str) -> (bool, &str) {
+    if let Some(command) = input.strip_prefix('!') {
+        (true, command.trim_start())
+    } else if input.starts_with("\\!") {
+        (false, &input[1..])
     } else {
-        input
+        (false, input)
     }
 }
 
-// Function to process a command in LLM suggestion mode
-fn process_llm_suggestion(input: &str, no_execute: bool) {
-    process_prompt(input, no_execute);
+// Reads more lines, with a `... ` prompt, for as long as the input so far is unfinished,
+// such as a loop without its `done` or a line ending in `\`. Returns `None` if the user
+// gives up with Ctrl-C or Ctrl-D
+fn read_continuation(rl: &mut Editor<ShellHelper, FileHistory>, first_line: String, shell_syntax: bool) -> Option<String> {
+    let mut input = first_line;
+    while needs_continuation(&input, shell_syntax) {
+        match rl.readline("... ") {
+            Ok(line) => {
+                input.push('\n');
+                input.push_str(&line);
+            }
+            Err(ReadlineError::Interrupted) => {
+                println!("{}", "^C".red());
+                return None;
+            }
+            Err(_) => return None,
+        }
+    }
+    Some(input)
 }
 
-// Function to execute a command in direct mode
-fn execute_direct_command(input: &str) {
-    execute_command(input);
+// Checks whether input goes on past what was typed so far: it ends in an unescaped `\`,
+// or, for shell syntax, a quote is still open or a `do`, `if`, `case`, or `{` hasn't
+// been closed yet
+fn needs_continuation(input: &str, shell_syntax: bool) -> bool {
+    if !shell_syntax {
+        let backslashes = input.chars().rev().take_while(|c| *c == '\\').count();
+        return backslashes % 2 == 1;
+    }
+
+    let mut quote = None;
+    let mut escaped = false;
+    let mut comment = false;
+    let mut word = String::new();
+    let mut command_start = true;
+    let mut depth = 0;
+    for c in input.chars() {
+        if comment {
+            if c == '\n' {
+                comment = false;
+                command_start = true;
+            }
+            continue;
+        }
+        if escaped {
+            escaped = false;
+            word.push(c);
+            continue;
+        }
+        match quote {
+            Some('\'') => {
+                if c == '\'' {
+                    quote = None;
+                }
+                continue;
+            }
+            Some(_) => {
+                match c {
+                    '\\' => escaped = true,
+                    '"' => quote = None,
+                    _ => {}
+                }
+                continue;
+            }
+            None => {}
+        }
+        match c {
+            // Escaped and quoted text is kept in the word so it is never taken for a keyword
+            '\\' => {
+                escaped = true;
+                word.push(c);
+            }
+            '\'' | '"' => {
+                quote = Some(c);
+                word.push(c);
+            }
+            '#' if word.is_empty() => comment = true,
+            c if c.is_whitespace() || ";&|()".contains(c) => {
+                command_start = count_block_word(&word, command_start, &mut depth);
+                word.clear();
+                if c == '\n' || ";&|()".contains(c) {
+                    command_start = true;
+                }
+            }
+            _ => word.push(c),
+        }
+    }
+    count_block_word(&word, command_start, &mut depth);
+    escaped || quote.is_some() || depth > 0
 }
 
-// Displays the shell prompt based on the current mode
-fn display_prompt(mode: &Mode) -> String {
-    let working_directory = get_current_dir_with_tilde();
-    let username = get_username();
+// Counts a word that opens or closes a block, if it is where a command would be. Returns
+// whether the word after it is where a command would be
+fn count_block_word(word: &str, command_start: bool, depth: &mut i32) -> bool {
+    if word.is_empty() {
+        return command_start;
+    }
+    if !command_start {
+        return false;
+    }
+    match word {
+        "if" | "do" | "{" => {
+            *depth += 1;
+            true
+        }
+        "case" => {
+            *depth += 1;
+            false
+        }
+        "fi" | "done" | "}" | "esac" => {
+            *depth -= 1;
+            false
+        }
+        "then" | "else" | "elif" | "while" | "until" | "!" => true,
+        _ => false,
+    }
+}
 
-    let prompt_prefix = match mode {
-        Mode::LlmSuggestion => "gptsh".red(),
-        Mode::DirectCommand => "you".yellow(),
-    };
+// Gathers what the model should know about the session before the next prompt: the
+// aliases if they are shared, the earlier prompts, and the last command's output if it is
+// shared
+fn session_context(state: &mut ShellState) -> Option<String> {
+    let aliases = if state.aliases_in_context { describe_aliases(&state.aliases) } else { None };
+    let last_command = state.last_command.take().map(|finished| describe_last_command(finished, state.output_limit));
+    let context: Vec<String> = [aliases, state.describe_context(), last_command].into_iter().flatten().collect();
+    (!context.is_empty()).then(|| context.join("\n\n"))
+}
 
-    let mode_indicator = match mode {
-        Mode::LlmSuggestion => "(LLM)",
-        Mode::DirectCommand => "(CMD)",
+// Function to process a command in LLM suggestion mode, with the earlier prompts of the
+// session as context
+fn process_llm_suggestion(input: &str, state: &mut ShellState, options: &CliOptions) {
+    let context = session_context(state);
+    let directory = env::current_dir().ok();
+    // The editable line needs a terminal, and is no use when commands aren't run
+    let prefill = state.prefill_suggestions && !options.no_execute && !options.raw && io::stdin().is_terminal();
+    let translation = if prefill {
+        prefill_suggestion(input, options, context.as_deref())
+    } else {
+        process_prompt_with_context(input, options, None, &[], context.as_deref())
     };
+    if let Some(translation) = translation {
+        state.last_status = translation.exit_status;
+        if !translation.command.is_empty() {
+            let confirmed = translation.exit_status.is_some() || translation.background;
+            state.log_entry.suggested = Some(translation.suggested.clone().unwrap_or_else(|| translation.command.clone()));
+            state.log_entry.confirmed = Some(confirmed);
+            if confirmed {
+                state.log_entry.command = Some(translation.command.clone());
+            }
+        }
+        if translation.background {
+            start_background_job(&translation.command, state);
+        } else {
+            record_executed(state, &translation.command, directory);
+        }
+        if !translation.command.is_empty() {
+            state.turns.push(ShellTurn {
+                prompt: input.to_string(),
+                outcome: TurnOutcome::Command(translation),
+            });
+        }
+    }
+}
 
-    let prompt = format!(
-        "[{} {}]:{}:{}$ ",
-        prompt_prefix,
-        mode_indicator,
-        username.green(),
-        working_directory.blue()
+// Asks the model for a command and puts it on an editable line, as if typed, so Enter
+// runs it, perhaps after changes, Ctrl-C discards it, and `:ban` bans it
+fn prefill_suggestion(input: &str, options: &CliOptions, context: Option<&str>) -> Option<Translation> {
+    let suggestion = suggest_command_with_context(input, options, context)?;
+    if !suggestion.explanation.trim().is_empty() {
+        println!("{}", suggestion.explanation.trim());
+    }
+    if suggestion.needs_confirmation {
+        println!("Warning: The model flagged this command as modifying or deleting data.");
+    }
+    println!(
+        "{}",
+        "Press Enter to run the command, after editing it if you like; end it with & to run it in the background, press Ctrl-C to discard it, or replace it with :ban to ban it."
+            .dimmed()
     );
 
+    let line = match DefaultEditor::new().and_then(|mut editor| editor.readline_with_initial("$ ", (&suggestion.command, ""))) {
+        Ok(line) => line,
+        Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => String::new(),
+        Err(err) => {
+            println!("Error: {:?}", err);
+            String::new()
+        }
+    };
+    // Keeps the model's suggestion for the session log if it was changed
+    let suggested = |translation: Translation| Translation {
+        suggested: (translation.command != suggestion.command).then(|| suggestion.command.clone()),
+        ..translation
+    };
+    let banned_commands = load_banned_commands().unwrap_or_default();
+    match parse_prefilled_line(&line, &banned_commands) {
+        PrefilledLine::Discard => {
+            println!("Command discarded.");
+            Some(Translation::new(&suggestion.command, None))
+        }
+        PrefilledLine::Ban => {
+            match add_banned_command(&suggestion.command) {
+                Ok(()) => println!("Command \"{}\" has been banned.", suggestion.command),
+                Err(e) => eprintln!("Error banning the command: {}", e),
+            }
+            Some(Translation::new(&suggestion.command, None))
+        }
+        PrefilledLine::Banned(command) => {
+            println!("Warning: The command \"{}\" is banned and will not be executed.", command);
+            Some(suggested(Translation::new(command, None)))
+        }
+        PrefilledLine::Background(command) => {
+            record_approved_command(options, input, command);
+            Some(suggested(Translation::in_background(command)))
+        }
+        PrefilledLine::Run(command) => {
+            record_approved_command(options, input, command);
+            let exit_status = execute_command(command, true);
+            Some(suggested(Translation::new(command, exit_status)))
+        }
+    }
+}
+
+// What the user left on the editable line a suggestion was put on
+#[derive(Debug, PartialEq)]
+enum PrefilledLine<'a> {
+    // The line was emptied, or Ctrl-C or Ctrl-D pressed
+    Discard,
+    // `:ban`, to ban the suggestion
+    Ban,
+    // A command in `.gptsh_banned`, perhaps edited into one
+    Banned(&'a str),
+    // A command ending in `&`, to run in the background
+    Background(&'a str),
+    // Any other command, to run in the foreground
+    Run(&'a str),
+}
+
+// Works out what to do with the line a suggestion was edited into. An edited command is
+// checked against the banned commands just like a suggested one
+fn parse_prefilled_line<'a>(line: &'a str, banned_commands: &[String]) -> PrefilledLine<'a> {
+    let line = line.trim();
+    if line.is_empty() {
+        return PrefilledLine::Discard;
+    }
+    if line == ":ban" {
+        return PrefilledLine::Ban;
+    }
+    let background = strip_background(line);
+    let command = background.unwrap_or(line);
+    if banned_commands.iter().any(|banned| banned == command) {
+        PrefilledLine::Banned(command)
+    } else if background.is_some() {
+        PrefilledLine::Background(command)
+    } else {
+        PrefilledLine::Run(command)
+    }
+}
+
+// Remembers the command that just ran, if one did, for `:undo`
+fn record_executed(state: &mut ShellState, command: &str, directory: Option<PathBuf>) {
+    if let Some(exit_status) = state.last_status {
+        state.last_executed = Some(ExecutedCommand {
+            command: command.to_string(),
+            exit_status,
+            directory,
+        });
+    }
+}
+
+// Asks the model for the command that undoes the last one run, which is confirmed like
+// any other suggestion. Commands that destroy data are warned about first, since the
+// model can rarely bring it back
+fn undo_last_command(state: &mut ShellState, options: &CliOptions) {
+    let Some(executed) = &state.last_executed else {
+        println!("Nothing to undo: no command has run in this session yet.");
+        return;
+    };
+    if let Some(reason) = irreversible_reason(&executed.command) {
+        println!("{}", format!("Warning: `{}` {}, so it may not be possible to undo it fully.", executed.command, reason).yellow());
+    }
+    let prompt = undo_prompt(executed);
+    process_llm_suggestion(&prompt, state, options);
+}
+
+// Builds the prompt that asks for the inverse of a command, with the exact command, where
+// it ran, and how it ended
+fn undo_prompt(executed: &ExecutedCommand) -> String {
+    let mut prompt = format!(
+        "Give the command that undoes this command, which the user just ran and which exited with status {}:\n{}\n",
+        executed.exit_status,
+        fenced(&command_shell_name(), &executed.command)
+    );
+    let current = env::current_dir().ok();
+    match &executed.directory {
+        Some(directory) if Some(directory) != current.as_ref() => prompt.push_str(&format!(
+            "It ran in {}, but the working directory is now {}, so use absolute paths.\n",
+            directory.display(),
+            current.map_or_else(|| "unknown".to_string(), |dir| dir.display().to_string())
+        )),
+        Some(directory) => prompt.push_str(&format!("It ran in {}, which is still the working directory.\n", directory.display())),
+        None => {}
+    }
+    prompt.push_str(
+        "If it failed partway, undo only what it did. If it can't be undone, for instance because it deleted or \
+         overwrote files without a backup, reply with a command that only prints why, such as \
+         echo 'Cannot undo: the files were deleted'.",
+    );
     prompt
 }
+
+// Says why a command may destroy data that can't be brought back: deleting files,
+// overwriting them with `>`, or discarding git changes
+fn irreversible_reason(command: &str) -> Option<&'static str> {
+    let words: Vec<&str> = command.split(|c: char| c.is_whitespace() || ";&|()".contains(c)).filter(|word| !word.is_empty()).collect();
+    for (index, word) in words.iter().enumerate() {
+        let program = word.rsplit('/').next().unwrap_or(word);
+        match program {
+            "rm" | "unlink" | "shred" => return Some("deletes files without a backup"),
+            "truncate" | "dd" => return Some("overwrites data in place"),
+            _ if program.starts_with("mkfs") => return Some("erases a file system"),
+            "git" if words[index + 1..].iter().take(2).any(|next| matches!(*next, "clean" | "--hard")) => {
+                return Some("discards uncommitted changes")
+            }
+            _ => {}
+        }
+    }
+    // `>` replaces a file's contents, unlike `>>`; redirecting to a device such as
+    // /dev/null loses nothing
+    let chars: Vec<char> = command.chars().collect();
+    for (index, c) in chars.iter().enumerate() {
+        let before = index.checked_sub(1).map(|before| chars[before]);
+        let after = chars.get(index + 1).copied();
+        if *c == '>' && !matches!(before, Some('>') | Some('&')) && !matches!(after, Some('>') | Some('&')) {
+            let target: String = chars[index + 1..].iter().collect();
+            if !target.trim_start().starts_with("/dev/") {
+                return Some("overwrites a file with >");
+            }
+        }
+    }
+    None
+}
+
+// Answers a question asked with `?` instead of suggesting a command for it
+fn answer_llm_question(question: &str, state: &mut ShellState, options: &CliOptions) {
+    let context = session_context(state);
+    if let Some(answer) = answer_question(question, options, context.as_deref()) {
+        if state.remember_answers && !answer.trim().is_empty() {
+            state.turns.push(ShellTurn {
+                prompt: question.to_string(),
+                outcome: TurnOutcome::Answer(answer),
+            });
+        }
+    }
+}
+
+// Function to execute a command in direct mode, after expanding any alias it starts with,
+// remembering its exit status for the prompt
+fn execute_direct_command(input: &str, state: &mut ShellState) {
+    let command = expand_aliases(input, &state.aliases);
+    if let Some(command) = strip_background(&command) {
+        start_background_job(command, state);
+        return;
+    }
+    state.log_entry.command = Some(command.clone());
+    let directory = env::current_dir().ok();
+    state.last_status = execute_command(&command, true);
+    record_executed(state, &command, directory);
+}
+
+// Ends the handling of a line: logs what it led to, if the session is logged, and keeps
+// the output of the command it ran for the next prompt, if output is shared
+fn finish_input(state: &mut ShellState) {
+    let finished = take_last_command();
+    let mut entry = mem::take(&mut state.log_entry);
+    if let Some(log) = &mut state.log {
+        entry.exit_status = if entry.background { None } else { state.last_status };
+        if let Some(finished) = &finished {
+            match &finished.output {
+                Some((stdout, stderr)) => entry.output = Some((logged_output(stdout), logged_output(stderr))),
+                None => entry.terminal = true,
+            }
+        }
+        log.write(&entry);
+    }
+    if state.share_output && finished.is_some() {
+        state.last_command = finished;
+    }
+}
+
+// Displays the shell prompt in the format from `shell_prompt`, by default
+// `✘ 127 [gptsh (LLM)]:user:~/dir$ `
+fn display_prompt(state: &mut ShellState) -> String {
+    state.prompt_format.render(&PromptState {
+        direct_command: matches!(state.mode, Mode::DirectCommand),
+        last_status: state.last_status,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_bang_runs_the_rest_directly() {
+        assert_eq!(split_bang_prefix("!git status"), (true, "git status"));
+        assert_eq!(split_bang_prefix("!  ls -la"), (true, "ls -la"));
+        assert_eq!(split_bang_prefix("!"), (true, ""));
+        // The command keeps its own bangs
+        assert_eq!(split_bang_prefix("!echo hi!"), (true, "echo hi!"));
+    }
+
+    #[test]
+    fn an_escaped_bang_goes_to_the_model() {
+        assert_eq!(split_bang_prefix("\\!important: list files"), (false, "!important: list files"));
+        assert_eq!(split_bang_prefix("\\\\!x"), (false, "\\\\!x"));
+    }
+
+    #[test]
+    fn other_input_goes_to_the_model_unchanged() {
+        assert_eq!(split_bang_prefix("list files, not dirs!"), (false, "list files, not dirs!"));
+        assert_eq!(split_bang_prefix(" !ls"), (false, " !ls"));
+        assert_eq!(split_bang_prefix(""), (false, ""));
+    }
+
+    #[test]
+    fn trailing_backslashes_continue_the_line() {
+        for shell_syntax in [true, false] {
+            assert!(needs_continuation("make \\", shell_syntax));
+            assert!(!needs_continuation("echo a\\\\", shell_syntax));
+            assert!(needs_continuation("echo a\\\\\\", shell_syntax));
+            assert!(!needs_continuation("ls -la", shell_syntax));
+        }
+        assert!(!needs_continuation("make \\\nall", true));
+    }
+
+    #[test]
+    fn open_quotes_continue_commands() {
+        assert!(needs_continuation("echo 'hello", true));
+        assert!(needs_continuation("echo \"it's", true));
+        assert!(needs_continuation("echo \"a \\\" b", true));
+        assert!(!needs_continuation("echo 'a\nb'", true));
+        assert!(!needs_continuation("echo \\'", true));
+        assert!(!needs_continuation("echo \"it's\"", true));
+        // Prompts are prose, where apostrophes are no quotes
+        assert!(!needs_continuation("don't delete anything", false));
+    }
+
+    #[test]
+    fn open_blocks_continue_commands() {
+        assert!(needs_continuation("for f in *.txt; do", true));
+        assert!(needs_continuation("for f in *.txt; do\n  echo $f", true));
+        assert!(!needs_continuation("for f in *.txt; do\n  echo $f\ndone", true));
+        assert!(needs_continuation("if true; then\n  while true; do sleep 1; done", true));
+        assert!(!needs_continuation("if true; then echo y; else echo n; fi", true));
+        assert!(needs_continuation("f() {", true));
+        assert!(!needs_continuation("f() { echo hi; }", true));
+        assert!(needs_continuation("case $x in", true));
+        assert!(!needs_continuation("case $x in\n  a) echo a;;\nesac", true));
+        assert!(!needs_continuation("for f in *.txt; do", false));
+    }
+
+    #[test]
+    fn keywords_only_count_where_a_command_would_be() {
+        assert!(!needs_continuation("echo do if {", true));
+        assert!(!needs_continuation("grep -r done .", true));
+        assert!(!needs_continuation("echo 'do' \"if\" \\{", true));
+        assert!(!needs_continuation("ls # for f in *; do", true));
+        assert!(needs_continuation("ls; if true", true));
+        assert!(needs_continuation("true && {", true));
+    }
+
+    #[test]
+    fn the_mode_switch_word_switches_modes() {
+        assert_eq!(parse_mode_switch("youdu", "youdu"), Some(ModeSwitch::Switch("")));
+        assert_eq!(parse_mode_switch("YouDu", "youdu"), Some(ModeSwitch::Switch("")));
+        assert_eq!(parse_mode_switch(":mode", ":mode"), Some(ModeSwitch::Switch("")));
+        assert_eq!(parse_mode_switch("youdu", ":mode"), None);
+    }
+
+    #[test]
+    fn the_rest_of_the_line_runs_after_switching() {
+        assert_eq!(parse_mode_switch("youdu ls -la", "youdu"), Some(ModeSwitch::Switch("ls -la")));
+        assert_eq!(parse_mode_switch(":mode   list big files ", ":mode"), Some(ModeSwitch::Switch("list big files")));
+    }
+
+    #[test]
+    fn a_bang_runs_one_input_in_the_other_mode() {
+        assert_eq!(parse_mode_switch("youdu! git status", "youdu"), Some(ModeSwitch::Once("git status")));
+        assert_eq!(parse_mode_switch(":mode!ls", ":mode"), Some(ModeSwitch::Once("ls")));
+        assert_eq!(parse_mode_switch("youdu!", "youdu"), Some(ModeSwitch::Once("")));
+    }
+
+    #[test]
+    fn words_that_merely_start_like_the_switch_are_left_alone() {
+        assert_eq!(parse_mode_switch("youdufoo", "youdu"), None);
+        assert_eq!(parse_mode_switch("you", "youdu"), None);
+        assert_eq!(parse_mode_switch("ls youdu", "youdu"), None);
+        assert_eq!(parse_mode_switch("é", "youdu"), None);
+    }
+
+    #[test]
+    fn the_mode_switch_word_must_be_a_single_word() {
+        let keyword = |mode_switch: Option<&str>| {
+            mode_switch_keyword(&Config { mode_switch: mode_switch.map(str::to_string), ..Config::default() })
+        };
+        assert_eq!(keyword(None), "youdu");
+        assert_eq!(keyword(Some(" :mode ")), ":mode");
+        assert_eq!(keyword(Some("")), "youdu");
+        assert_eq!(keyword(Some("switch mode")), "youdu");
+    }
+
+    fn executed(command: &str, exit_status: i32, directory: Option<PathBuf>) -> ExecutedCommand {
+        ExecutedCommand { command: command.to_string(), exit_status, directory }
+    }
+
+    #[test]
+    fn undo_prompts_quote_the_exact_command() {
+        let command = "mv reports/ /tmp/archive/ && echo `done`";
+        let prompt = undo_prompt(&executed(command, 0, None));
+        assert!(prompt.starts_with(
+            "Give the command that undoes this command, which the user just ran and which exited with status 0:\n"
+        ));
+        assert!(prompt.contains(&format!("\n{}\n", fenced(&command_shell_name(), command))));
+        assert!(prompt.contains("If it can't be undone"));
+        assert!(!prompt.contains("It ran in"));
+    }
+
+    #[test]
+    fn undo_prompts_say_where_the_command_ran() {
+        let current = env::current_dir().unwrap();
+        let prompt = undo_prompt(&executed("mkdir build", 0, Some(current.clone())));
+        assert!(prompt.contains(&format!("It ran in {}, which is still the working directory.\n", current.display())));
+
+        let elsewhere = PathBuf::from("/tmp/gptsh-elsewhere");
+        let prompt = undo_prompt(&executed("mkdir build", 1, Some(elsewhere)));
+        assert!(prompt.contains("exited with status 1:"));
+        assert!(prompt.contains(&format!(
+            "It ran in /tmp/gptsh-elsewhere, but the working directory is now {}, so use absolute paths.\n",
+            current.display()
+        )));
+    }
+
+    #[test]
+    fn destructive_commands_are_recognized() {
+        assert_eq!(irreversible_reason("rm -rf build"), Some("deletes files without a backup"));
+        assert_eq!(irreversible_reason("cd /tmp && /bin/rm x"), Some("deletes files without a backup"));
+        assert_eq!(irreversible_reason("dd if=/dev/zero of=disk.img"), Some("overwrites data in place"));
+        assert_eq!(irreversible_reason("sudo mkfs.ext4 /dev/sdb1"), Some("erases a file system"));
+        assert_eq!(irreversible_reason("git reset --hard HEAD~1"), Some("discards uncommitted changes"));
+        assert_eq!(irreversible_reason("git clean -fd"), Some("discards uncommitted changes"));
+        assert_eq!(irreversible_reason("sort names > names.txt"), Some("overwrites a file with >"));
+    }
+
+    #[test]
+    fn reversible_commands_get_no_warning() {
+        for command in ["mv a b", "echo hi >> log", "make 2>&1", "ls > /dev/null", "git log --hard-wrap", "chmod +x run.sh"] {
+            assert_eq!(irreversible_reason(command), None, "{}", command);
+        }
+    }
+
+    #[test]
+    fn prefilled_lines_run_as_edited() {
+        assert_eq!(parse_prefilled_line("ls -la ", &[]), PrefilledLine::Run("ls -la"));
+        assert_eq!(parse_prefilled_line("make &", &[]), PrefilledLine::Background("make"));
+        assert_eq!(parse_prefilled_line("make && make install", &[]), PrefilledLine::Run("make && make install"));
+        assert_eq!(parse_prefilled_line("  ", &[]), PrefilledLine::Discard);
+        assert_eq!(parse_prefilled_line(":ban", &[]), PrefilledLine::Ban);
+    }
+
+    #[test]
+    fn banned_commands_are_refused_even_when_typed_in() {
+        let banned = ["rm -rf /".to_string()];
+        assert_eq!(parse_prefilled_line("rm -rf /", &banned), PrefilledLine::Banned("rm -rf /"));
+        assert_eq!(parse_prefilled_line(" rm -rf / &", &banned), PrefilledLine::Banned("rm -rf /"));
+        assert_eq!(parse_prefilled_line("rm -rf ./build", &banned), PrefilledLine::Run("rm -rf ./build"));
+    }
+}
@@ -0,0 +1,281 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    env, fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::command_shell::command_shell_name;
+
+/// The facts `get_system_info` can report, in the order they are listed.
+pub(crate) const SYSTEM_INFO_FIELDS: &[&str] = &["os", "kernel", "shell", "cwd", "git", "tools"];
+
+/// Tools whose versions are reported when found on `PATH`, with the arguments that make
+/// each print its version.
+const VERSIONED_TOOLS: &[(&str, &[&str])] = &[
+    ("git", &["--version"]),
+    ("docker", &["--version"]),
+    ("podman", &["--version"]),
+    ("kubectl", &["version", "--client"]),
+    ("python3", &["--version"]),
+    ("node", &["--version"]),
+    ("go", &["version"]),
+    ("cargo", &["--version"]),
+];
+
+/// How long a tool may take to print its version before it is skipped.
+const VERSION_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Checks configured field names, warning about any gptsh doesn't know.
+///
+/// # Arguments
+///
+/// * `fields` - The `system_info_fields` config value.
+///
+/// # Returns
+///
+/// * `Vec<String>` - The known fields, in the order given.
+pub(crate) fn validate_fields(fields: &[String]) -> Vec<String> {
+    fields
+        .iter()
+        .filter(|field| {
+            let known = SYSTEM_INFO_FIELDS.contains(&field.as_str());
+            if !known {
+                eprintln!(
+                    "Warning: Ignoring unknown system_info_fields entry '{}'. Known fields: {}.",
+                    field,
+                    SYSTEM_INFO_FIELDS.join(", ")
+                );
+            }
+            known
+        })
+        .cloned()
+        .collect()
+}
+
+/// Gathers facts about the user's environment for the assistant. Everything is read
+/// directly by gptsh; the only programs run are the listed tools, with fixed arguments,
+/// to ask for their versions.
+///
+/// # Arguments
+///
+/// * `fields` - The facts to include, from [`SYSTEM_INFO_FIELDS`].
+///
+/// # Returns
+///
+/// * `Value` - A JSON object with one entry per field that could be determined.
+pub(crate) fn collect_system_info(fields: &[String]) -> Value {
+    let mut info = Map::new();
+    for field in fields {
+        let value = match field.as_str() {
+            "os" => Some(json!(operating_system())),
+            "kernel" => kernel_release().map(Value::from),
+            "shell" => Some(json!(command_shell_name())),
+            "cwd" => env::current_dir().ok().map(|dir| json!(dir.display().to_string())),
+            "git" => env::current_dir().ok().and_then(|dir| git_summary(&dir)),
+            "tools" => Some(tool_versions()),
+            _ => None,
+        };
+        if let Some(value) = value {
+            info.insert(field.clone(), value);
+        }
+    }
+    Value::Object(info)
+}
+
+/// Describes the operating system, using the distribution name on Linux.
+fn operating_system() -> String {
+    let distribution = fs::read_to_string("/etc/os-release").ok().and_then(|release| {
+        release.lines().find_map(|line| {
+            line.strip_prefix("PRETTY_NAME=")
+                .map(|name| name.trim_matches('"').to_string())
+        })
+    });
+    match distribution {
+        Some(name) => format!("{} ({})", name, env::consts::ARCH),
+        None => format!("{} ({})", env::consts::OS, env::consts::ARCH),
+    }
+}
+
+/// Returns the kernel release, e.g. `6.8.0-45-generic`.
+fn kernel_release() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/osrelease")
+        .ok()
+        .map(|release| release.trim().to_string())
+        .or_else(|| command_output("uname", &["-r"]))
+}
+
+/// What a git repository has checked out.
+pub(crate) enum GitHead {
+    Branch(String),
+    /// A commit checked out directly, by its short hash.
+    Detached(String),
+    Unknown,
+}
+
+/// Finds the git repository containing a directory and what is checked out in it,
+/// reading `HEAD` rather than running git, so it is quick enough for every prompt.
+///
+/// # Arguments
+///
+/// * `dir` - The directory, usually the working directory.
+///
+/// # Returns
+///
+/// * `Option<(&Path, GitHead)>` - The repository's root and its checked-out branch or
+///   commit; `None` outside a repository.
+pub(crate) fn git_head(dir: &Path) -> Option<(&Path, GitHead)> {
+    let root = dir.ancestors().find(|ancestor| ancestor.join(".git").exists())?;
+    let head = fs::read_to_string(git_dir(root).join("HEAD")).unwrap_or_default();
+    let head = head.trim();
+    let head = match head.strip_prefix("ref: refs/heads/") {
+        Some(branch) => GitHead::Branch(branch.to_string()),
+        None if head.len() >= 7 && head.is_ascii() => GitHead::Detached(head[..7].to_string()),
+        None => GitHead::Unknown,
+    };
+    Some((root, head))
+}
+
+/// Returns a repository's git directory: `.git`, or where a `.git` file points for
+/// worktrees and submodules.
+fn git_dir(root: &Path) -> PathBuf {
+    let dot_git = root.join(".git");
+    match fs::read_to_string(&dot_git) {
+        Ok(contents) => match contents.trim().strip_prefix("gitdir:") {
+            Some(dir) => root.join(dir.trim()),
+            None => dot_git,
+        },
+        Err(_) => dot_git,
+    }
+}
+
+/// Checks whether a repository has uncommitted changes to tracked files, giving up if
+/// git takes longer than allowed.
+///
+/// # Arguments
+///
+/// * `root` - The repository's root.
+/// * `timeout` - How long git may take.
+///
+/// # Returns
+///
+/// * `Option<bool>` - Whether there are changes, or `None` if git isn't installed, failed,
+///   or ran out of time.
+pub(crate) fn git_has_changes(root: &Path, timeout: Duration) -> Option<bool> {
+    let git = find_on_path("git")?;
+    // Optional locks are skipped so a prompt never holds up the user's own git commands
+    let args = ["--no-optional-locks", "status", "--porcelain", "--untracked-files=no"];
+    command_output_at(&git, &args, root, timeout).map(|status| !status.trim().is_empty())
+}
+
+/// Summarizes the git repository containing a directory: the branch and how many files
+/// are changed or untracked.
+fn git_summary(dir: &Path) -> Option<Value> {
+    let (root, head) = git_head(dir)?;
+    let branch = match head {
+        GitHead::Branch(branch) => branch,
+        GitHead::Detached(commit) => format!("detached at {}", commit),
+        GitHead::Unknown => "unknown".to_string(),
+    };
+    let mut summary = json!({ "root": root.display().to_string(), "branch": branch });
+    if let Some(status) = find_on_path("git").and_then(|git| command_output_at(&git, &["status", "--porcelain"], root, VERSION_TIMEOUT)) {
+        let untracked = status.lines().filter(|line| line.starts_with("??")).count();
+        let changed = status.lines().count() - untracked;
+        summary["changed_files"] = json!(changed);
+        summary["untracked_files"] = json!(untracked);
+    }
+    Some(summary)
+}
+
+/// Reports the version of each known tool found on `PATH`.
+fn tool_versions() -> Value {
+    let mut versions = Map::new();
+    for (tool, args) in VERSIONED_TOOLS {
+        if let Some(version) = find_on_path(tool).and_then(|path| command_output(&path.to_string_lossy(), args)) {
+            versions.insert(tool.to_string(), json!(version));
+        }
+    }
+    Value::Object(versions)
+}
+
+/// Finds an executable on `PATH`.
+fn find_on_path(name: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Runs a program with fixed arguments and returns the first line it prints.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = command_output_at(Path::new(program), args, Path::new("."), VERSION_TIMEOUT)?;
+    output.lines().map(str::trim).find(|line| !line.is_empty()).map(str::to_string)
+}
+
+/// Runs a program with fixed arguments in a directory and returns what it prints, giving
+/// up after a timeout so a hung tool can't stall the chat.
+fn command_output_at(program: &Path, args: &[&str], dir: &Path, timeout: Duration) -> Option<String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .ok()?;
+
+    // The output is read on other threads so a chatty program can't fill the pipe and stall
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().ok()? {
+            break status;
+        }
+        if started.elapsed() > timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return None;
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+    if !status.success() {
+        return None;
+    }
+
+    let (stdout, stderr) = (stdout.join().ok()?, stderr.join().ok()?);
+    // Some tools print their version to stderr
+    let text = if stdout.is_empty() { stderr } else { stdout };
+    Some(String::from_utf8_lossy(&text).into_owned())
+}
+
+/// Reads a child's output stream to the end on a separate thread.
+fn read_in_background<R: Read + Send + 'static>(stream: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut stream) = stream {
+            let _ = stream.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
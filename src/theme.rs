@@ -0,0 +1,199 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{str::FromStr, sync::OnceLock};
+
+use colored::{Color, ColoredString, Colorize};
+
+use crate::models::Config;
+
+/// The colors of chat output and rendered Markdown. Colors are never used when `NO_COLOR`
+/// is set or stdout isn't a terminal, whatever the theme.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Theme {
+    /// The `gptsh:` before each reply.
+    assistant_prefix: Option<Color>,
+    /// The `You:` prompt.
+    user_prefix: Option<Color>,
+    /// The lines around code blocks and the gutter beside them; dimmed when `None`.
+    code_border: Option<Color>,
+    /// The background of code lines.
+    code_background: Option<Color>,
+    /// Inline code in replies.
+    inline_code: Option<Color>,
+    /// Errors, and what commands print to stderr.
+    error: Option<Color>,
+}
+
+/// The built-in themes, by name. The first is used unless `theme` is set.
+const THEMES: &[(&str, Theme)] = &[
+    (
+        "default",
+        Theme {
+            assistant_prefix: Some(Color::Green),
+            user_prefix: Some(Color::Blue),
+            code_border: None,
+            code_background: None,
+            inline_code: Some(Color::Cyan),
+            error: Some(Color::Red),
+        },
+    ),
+    (
+        "solarized",
+        Theme {
+            assistant_prefix: Some(Color::TrueColor { r: 0x26, g: 0x8b, b: 0xd2 }),
+            user_prefix: Some(Color::TrueColor { r: 0x85, g: 0x99, b: 0x00 }),
+            code_border: Some(Color::TrueColor { r: 0x58, g: 0x6e, b: 0x75 }),
+            code_background: Some(Color::TrueColor { r: 0x07, g: 0x36, b: 0x42 }),
+            inline_code: Some(Color::TrueColor { r: 0x2a, g: 0xa1, b: 0x98 }),
+            error: Some(Color::TrueColor { r: 0xdc, g: 0x32, b: 0x2f }),
+        },
+    ),
+    (
+        "mono",
+        Theme {
+            assistant_prefix: None,
+            user_prefix: None,
+            code_border: None,
+            code_background: None,
+            inline_code: None,
+            error: None,
+        },
+    ),
+];
+
+/// The theme chosen when the session started.
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+/// Chooses the theme from the configuration, warning about unknown names and colors.
+/// Only the first call has an effect.
+///
+/// # Arguments
+///
+/// * `config` - The loaded configuration, with `theme` and `theme_colors`.
+pub(crate) fn init_theme(config: &Config) {
+    let name = config.theme.as_deref().unwrap_or(THEMES[0].0);
+    let mut theme = match THEMES.iter().find(|(theme_name, _)| theme_name.eq_ignore_ascii_case(name)) {
+        Some((_, theme)) => *theme,
+        None => {
+            let names: Vec<_> = THEMES.iter().map(|(name, _)| *name).collect();
+            eprintln!(
+                "Warning: Unknown theme '{}' in .gptsh_config; using the default. Themes: {}.",
+                name,
+                names.join(", ")
+            );
+            THEMES[0].1
+        }
+    };
+
+    if let Some(colors) = &config.theme_colors {
+        let overrides = [
+            ("assistant_prefix", &colors.assistant_prefix, &mut theme.assistant_prefix),
+            ("user_prefix", &colors.user_prefix, &mut theme.user_prefix),
+            ("code_border", &colors.code_border, &mut theme.code_border),
+            ("code_background", &colors.code_background, &mut theme.code_background),
+            ("inline_code", &colors.inline_code, &mut theme.inline_code),
+            ("error", &colors.error, &mut theme.error),
+        ];
+        for (key, value, color) in overrides {
+            let Some(value) = value else { continue };
+            match parse_color(value) {
+                Some(parsed) => *color = parsed,
+                None => eprintln!(
+                    "Warning: theme_colors.{} in .gptsh_config is not a color: '{}'. Use a name such as green or bright blue, a hex color such as #268bd2, or none.",
+                    key, value
+                ),
+            }
+        }
+    }
+    let _ = THEME.set(theme);
+}
+
+/// Returns the theme in use; the default one if [`init_theme`] hasn't been called.
+pub(crate) fn theme() -> Theme {
+    THEME.get().copied().unwrap_or(THEMES[0].1)
+}
+
+/// Parses a color from the configuration.
+///
+/// # Arguments
+///
+/// * `value` - A name such as `bright blue`, a hex color such as `#268bd2`, or `none`.
+///
+/// # Returns
+///
+/// * `Option<Option<Color>>` - The color, `Some(None)` for `none`, or `None` if the value
+///   isn't a color.
+fn parse_color(value: &str) -> Option<Option<Color>> {
+    let value = value.trim();
+    if value.eq_ignore_ascii_case("none") {
+        return Some(None);
+    }
+    if let Some(hex) = value.strip_prefix('#') {
+        if hex.len() != 6 || !hex.is_ascii() {
+            return None;
+        }
+        let channel = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16).ok();
+        return Some(Some(Color::TrueColor { r: channel(0)?, g: channel(2)?, b: channel(4)? }));
+    }
+    Color::from_str(value).ok().map(Some)
+}
+
+/// Colors text if a color is given.
+fn paint(text: &str, color: Option<Color>) -> ColoredString {
+    match color {
+        Some(color) => text.color(color),
+        None => text.normal(),
+    }
+}
+
+impl Theme {
+    /// Styles the `gptsh:` before a reply.
+    pub(crate) fn assistant_prefix(&self, text: &str) -> String {
+        paint(text, self.assistant_prefix).bold().to_string()
+    }
+
+    /// Styles the `You:` prompt.
+    pub(crate) fn user_prefix(&self, text: &str) -> String {
+        paint(text, self.user_prefix).bold().to_string()
+    }
+
+    /// Styles the lines around a code block and the gutter beside it.
+    pub(crate) fn code_border(&self, text: &str) -> String {
+        match self.code_border {
+            Some(color) => text.color(color).to_string(),
+            None => text.dimmed().to_string(),
+        }
+    }
+
+    /// Gives a line of code the code background, keeping any styling it already has.
+    pub(crate) fn code_line(&self, line: ColoredString) -> String {
+        match self.code_background {
+            Some(color) if !line.is_empty() => line.on_color(color).to_string(),
+            _ => line.to_string(),
+        }
+    }
+
+    /// Styles inline code in a reply.
+    pub(crate) fn inline_code(&self, text: &str) -> String {
+        paint(text, self.inline_code).to_string()
+    }
+
+    /// Styles an error message, or a line a command printed to stderr.
+    pub(crate) fn error(&self, text: &str) -> String {
+        paint(text, self.error).to_string()
+    }
+}
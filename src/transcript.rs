@@ -0,0 +1,239 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+
+use chrono::DateTime;
+use serde_json::Value;
+
+use crate::images::{count_images, message_text};
+
+/// The field holding when a chat message was sent or a command was run, in RFC 3339
+/// format. It is kept in saved sessions but never sent to the API.
+pub(crate) const TIMESTAMP_FIELD: &str = "timestamp";
+
+/// Renders a chat conversation as Markdown: each message under a heading with its time,
+/// and each command the assistant ran with its output and exit code.
+///
+/// # Arguments
+///
+/// * `messages` - The conversation, in the chat-completions message format.
+/// * `include_system` - Whether system prompts are included.
+///
+/// # Returns
+///
+/// * `String` - The transcript.
+pub(crate) fn render_transcript(messages: &[Value], include_system: bool) -> String {
+    let mut transcript = String::from("# gptsh chat transcript\n");
+    // Calls the assistant made, by id, waiting for their results
+    let mut calls: HashMap<&str, (&str, &str)> = HashMap::new();
+    // The last call made in the legacy `function_call` format, which has no id
+    let mut legacy_call: Option<(&str, &str)> = None;
+
+    for message in messages {
+        let content = message["content"].as_str().unwrap_or_default().trim();
+        match message["role"].as_str().unwrap_or_default() {
+            "system" if include_system => {
+                transcript.push_str(&format!("\n## System prompt\n\n{}\n", quote(content)));
+            }
+            "user" => {
+                let mut text = message_text(message).trim().to_string();
+                for _ in 0..count_images(message) {
+                    text.push_str("\n\n_(An image was attached.)_");
+                }
+                transcript.push_str(&format!("\n## You{}\n\n{}\n", format_time(message), text.trim_start()));
+            }
+            "assistant" => {
+                if !content.is_empty() {
+                    transcript.push_str(&format!("\n## Assistant\n\n{}\n", content));
+                }
+                for call in message["tool_calls"].as_array().into_iter().flatten() {
+                    let function = &call["function"];
+                    calls.insert(
+                        call["id"].as_str().unwrap_or_default(),
+                        (
+                            function["name"].as_str().unwrap_or_default(),
+                            function["arguments"].as_str().unwrap_or_default(),
+                        ),
+                    );
+                }
+                let function = &message["function_call"];
+                if !function.is_null() {
+                    legacy_call = Some((
+                        function["name"].as_str().unwrap_or_default(),
+                        function["arguments"].as_str().unwrap_or_default(),
+                    ));
+                }
+            }
+            "tool" | "function" => {
+                let call = match message["tool_call_id"].as_str() {
+                    Some(id) => calls.remove(id),
+                    None => legacy_call.take(),
+                };
+                let (name, arguments) = call.unwrap_or((message["name"].as_str().unwrap_or("function"), ""));
+                transcript.push_str(&render_function_call(name, arguments, content, &format_time(message)));
+            }
+            _ => {}
+        }
+    }
+    transcript
+}
+
+/// Renders a function the assistant called and its result. Commands are shown as a
+/// console session with their exit code; other functions with their arguments.
+fn render_function_call(name: &str, arguments: &str, result: &str, time: &str) -> String {
+    let arguments: Value = serde_json::from_str(arguments).unwrap_or(Value::Null);
+    if name != "execute_command" {
+        let arguments = if arguments.is_null() { String::new() } else { arguments.to_string() };
+        return format!("\n**{}** `{}`{}\n\n{}\n", name, arguments, time, fenced("text", result));
+    }
+
+    let command = arguments["command"].as_str().unwrap_or_default();
+    let mut rendered = format!("\n**Ran a command**{}\n\n", time);
+    // Results are JSON with stdout, stderr, and exit_code, or a note saying why the
+    // command didn't run
+    let Ok(output) = serde_json::from_str::<Value>(result) else {
+        rendered.push_str(&format!("{}\n\n_{}_\n", fenced("console", &format!("$ {}", command)), result));
+        return rendered;
+    };
+    // The user may have edited the command before running it
+    let command = output["command_edited_by_user"].as_str().unwrap_or(command);
+    let stdout = output["stdout"].as_str().unwrap_or_default().trim_end();
+    let stderr = output["stderr"].as_str().unwrap_or_default().trim_end();
+    let mut session = format!("$ {}", command);
+    if !stdout.is_empty() {
+        session.push_str(&format!("\n{}", stdout));
+    }
+    rendered.push_str(&fenced("console", &session));
+    rendered.push('\n');
+    if !stderr.is_empty() {
+        rendered.push_str(&format!("\nstderr:\n\n{}\n", fenced("text", stderr)));
+    }
+    if output["interactive"].as_bool() == Some(true) {
+        rendered.push_str("\n_Ran with the terminal attached; its output wasn't captured._\n");
+    }
+    match output["exit_code"].as_i64() {
+        Some(code) => rendered.push_str(&format!("\nExit code {}.\n", code)),
+        None => rendered.push_str("\nStopped before it finished.\n"),
+    }
+    rendered
+}
+
+/// Wraps text in a code fence longer than any run of backticks inside it.
+pub(crate) fn fenced(language: &str, text: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    let fence = "`".repeat((longest + 1).max(3));
+    format!("{}{}\n{}\n{}", fence, language, text, fence)
+}
+
+/// Prefixes every line with `> `.
+fn quote(text: &str) -> String {
+    text.lines().map(|line| format!("> {}", line).trim_end().to_string()).collect::<Vec<_>>().join("\n")
+}
+
+/// Formats a message's timestamp for a heading, e.g. ` · 2024-05-01 14:02`, or returns an
+/// empty string if it has none.
+fn format_time(message: &Value) -> String {
+    message[TIMESTAMP_FIELD]
+        .as_str()
+        .and_then(|time| DateTime::parse_from_rfc3339(time).ok())
+        .map(|time| format!(" · {}", time.format("%Y-%m-%d %H:%M")))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// A conversation in which the assistant runs a command and reads a file.
+    fn conversation() -> Vec<Value> {
+        vec![
+            json!({ "role": "system", "content": "You are a shell assistant." }),
+            json!({ "role": "user", "content": "Why is the disk full?", "timestamp": "2024-05-01T14:02:30+02:00" }),
+            json!({ "role": "assistant", "content": "Let me check.", "tool_calls": [
+                { "id": "call_1", "type": "function",
+                  "function": { "name": "execute_command", "arguments": "{\"command\":\"du -sh /var\"}" } },
+                { "id": "call_2", "type": "function",
+                  "function": { "name": "read_file", "arguments": "{\"path\":\"notes.txt\"}" } },
+            ] }),
+            // Results may come back in any order; each is matched to its call by id
+            json!({ "role": "tool", "tool_call_id": "call_2", "content": "keep /var small" }),
+            json!({ "role": "tool", "tool_call_id": "call_1", "timestamp": "2024-05-01T14:03:00+02:00",
+                    "content": "{\"stdout\":\"4.0G\\t/var\\n\",\"stderr\":\"du: cannot read /var/lib\\n\",\"exit_code\":1}" }),
+            json!({ "role": "assistant", "content": "/var takes up 4GB.\n" }),
+        ]
+    }
+
+    #[test]
+    fn transcripts_show_messages_and_tool_calls() {
+        assert_eq!(
+            render_transcript(&conversation(), false),
+            "# gptsh chat transcript\n\
+             \n## You · 2024-05-01 14:02\n\nWhy is the disk full?\n\
+             \n## Assistant\n\nLet me check.\n\
+             \n**read_file** `{\"path\":\"notes.txt\"}`\n\n```text\nkeep /var small\n```\n\
+             \n**Ran a command** · 2024-05-01 14:03\n\n```console\n$ du -sh /var\n4.0G\t/var\n```\n\
+             \nstderr:\n\n```text\ndu: cannot read /var/lib\n```\n\
+             \nExit code 1.\n\
+             \n## Assistant\n\n/var takes up 4GB.\n"
+        );
+    }
+
+    #[test]
+    fn system_prompts_are_quoted_when_included() {
+        let transcript = render_transcript(&conversation(), true);
+        assert!(transcript.starts_with("# gptsh chat transcript\n\n## System prompt\n\n> You are a shell assistant.\n\n## You"));
+    }
+
+    #[test]
+    fn commands_that_did_not_run_show_why() {
+        let messages = [
+            json!({ "role": "assistant", "content": null,
+                    "function_call": { "name": "execute_command", "arguments": "{\"command\":\"rm -rf /\"}" } }),
+            json!({ "role": "function", "name": "execute_command", "content": "The user declined to run this command." }),
+        ];
+        assert_eq!(
+            render_transcript(&messages, false),
+            "# gptsh chat transcript\n\n**Ran a command**\n\n```console\n$ rm -rf /\n```\n\n_The user declined to run this command._\n"
+        );
+    }
+
+    #[test]
+    fn edited_and_interrupted_commands_are_shown_as_run() {
+        let messages = [
+            json!({ "role": "assistant", "tool_calls": [{ "id": "a", "type": "function",
+                    "function": { "name": "execute_command", "arguments": "{\"command\":\"sleep 100\"}" } }] }),
+            json!({ "role": "tool", "tool_call_id": "a",
+                    "content": "{\"command_edited_by_user\":\"sleep 10\",\"stdout\":\"\",\"stderr\":\"\",\"exit_code\":null}" }),
+        ];
+        assert_eq!(
+            render_transcript(&messages, false),
+            "# gptsh chat transcript\n\n**Ran a command**\n\n```console\n$ sleep 10\n```\n\nStopped before it finished.\n"
+        );
+    }
+
+    #[test]
+    fn fences_outgrow_the_backticks_inside() {
+        assert_eq!(fenced("text", "a ``` b"), "````text\na ``` b\n````");
+        assert_eq!(fenced("", "plain"), "```\nplain\n```");
+    }
+}
@@ -0,0 +1,623 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    client::CompletionBackend,
+    models::{Config, Usage},
+    openai::load_config,
+    utils::estimate_tokens,
+};
+
+const USAGE_FILE: &str = "usage.json";
+
+/// How long to wait for another gptsh to finish updating the usage file.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A lock file older than this was left behind by a gptsh that exited mid-update.
+const STALE_LOCK_AGE: Duration = Duration::from_secs(30);
+
+/// Built-in prices in US dollars per million prompt and completion tokens,
+/// keyed by model name prefix, most specific first.
+const PRICE_TABLE: &[(&str, f64, f64)] = &[
+    ("gpt-4o-mini", 0.15, 0.60),
+    ("gpt-4o", 2.50, 10.00),
+    ("gpt-4.1-nano", 0.10, 0.40),
+    ("gpt-4.1-mini", 0.40, 1.60),
+    ("gpt-4.1", 2.00, 8.00),
+    ("gpt-4-turbo", 10.00, 30.00),
+    ("gpt-4", 30.00, 60.00),
+    ("gpt-3.5-turbo", 0.50, 1.50),
+    ("gpt-5-nano", 0.05, 0.40),
+    ("gpt-5-mini", 0.25, 2.00),
+    ("gpt-5", 1.25, 10.00),
+    ("o1-mini", 1.10, 4.40),
+    ("o1", 15.00, 60.00),
+    ("o3-mini", 1.10, 4.40),
+    ("o3", 2.00, 8.00),
+    ("o4-mini", 1.10, 4.40),
+];
+
+/// Token totals per day (`YYYY-MM-DD`) and model, as stored on disk.
+/// When several API keys are configured, totals are also kept per day and key label.
+#[derive(Serialize, Deserialize, Default)]
+struct UsageLog {
+    days: BTreeMap<String, BTreeMap<String, Usage>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    keys: BTreeMap<String, BTreeMap<String, Usage>>,
+}
+
+/// Returns the path of the file that usage is recorded in.
+///
+/// # Returns
+///
+/// * `Option<PathBuf>` - The path, or `None` if there is no configuration directory.
+pub(crate) fn usage_file_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("gptsh").join(USAGE_FILE))
+}
+
+/// Loads the usage log, starting a new one if the file doesn't exist yet.
+///
+/// # Arguments
+///
+/// * `path` - The usage file, or `None` if there is nowhere to keep one.
+fn load_usage_log(path: Option<&Path>) -> io::Result<UsageLog> {
+    let Some(path) = path else {
+        return Ok(UsageLog::default());
+    };
+    match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(UsageLog::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes the usage log, creating its directory if needed. The log is written to a temporary
+/// file that then replaces the old one, so an interrupted write never leaves a corrupt log.
+fn save_usage_log(path: &Path, log: &UsageLog) -> io::Result<()> {
+    let contents = serde_json::to_string_pretty(log).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let temp_path = path.with_extension("json.tmp");
+    fs::write(&temp_path, contents)?;
+    fs::rename(temp_path, path)
+}
+
+/// Holds the usage file's lock file while the log is updated, and removes it when dropped.
+struct UsageLock {
+    path: PathBuf,
+}
+
+impl UsageLock {
+    /// Takes the lock, waiting up to `LOCK_TIMEOUT` for another gptsh to release it.
+    /// A lock left behind by a gptsh that exited mid-update is taken over.
+    ///
+    /// # Arguments
+    ///
+    /// * `usage_path` - The path of the usage file.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<UsageLock>` - The lock, or why it couldn't be taken.
+    fn acquire(usage_path: &Path) -> io::Result<UsageLock> {
+        if let Some(dir) = usage_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let path = usage_path.with_extension("json.lock");
+        let started = SystemTime::now();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(UsageLock { path }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+            let lock_age = fs::metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok());
+            if lock_age.is_some_and(|age| age > STALE_LOCK_AGE) {
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            if started.elapsed().unwrap_or_default() > LOCK_TIMEOUT {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} is held by another gptsh", path.display()),
+                ));
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+impl Drop for UsageLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Adds the tokens used by a request to today's totals. The usage file is locked while it is
+/// updated, so requests finishing in several gptsh processes at once are all counted.
+///
+/// # Arguments
+///
+/// * `backend` - Where the request was sent; names the model, key, and usage file.
+/// * `usage` - The tokens the request used.
+pub(crate) fn record_usage(backend: &dyn CompletionBackend, usage: Usage) {
+    let Some(path) = backend.usage_file() else {
+        return;
+    };
+    let model = &backend.endpoint().model;
+    let result = UsageLock::acquire(&path).and_then(|_lock| {
+        let mut log = load_usage_log(Some(&path))?;
+        let today = Local::now().format("%Y-%m-%d").to_string();
+        add_usage(log.days.entry(today.clone()).or_default(), model, usage);
+        if let Some(label) = backend.key_label() {
+            add_usage(log.keys.entry(today).or_default(), &label, usage);
+        }
+        save_usage_log(&path, &log)
+    });
+    if let Err(e) = result {
+        eprintln!("Warning: Could not record token usage: {}", e);
+    }
+}
+
+/// Adds token counts to the total kept under `name`.
+fn add_usage(totals: &mut BTreeMap<String, Usage>, name: &str, usage: Usage) {
+    let total = totals.entry(name.to_string()).or_default();
+    total.prompt_tokens += usage.prompt_tokens;
+    total.completion_tokens += usage.completion_tokens;
+}
+
+/// Estimates the tokens used by a request whose response didn't report them.
+///
+/// # Arguments
+///
+/// * `prompt` - The text sent to the model.
+/// * `completion` - The text the model replied with.
+///
+/// # Returns
+///
+/// * `Usage` - The estimated token counts.
+pub(crate) fn estimate_usage(prompt: &str, completion: &str) -> Usage {
+    Usage {
+        prompt_tokens: estimate_tokens(prompt) as u64,
+        completion_tokens: estimate_tokens(completion) as u64,
+    }
+}
+
+/// Looks up the price of a model in dollars per million prompt and completion tokens.
+/// Prices in the `prices` config key take precedence over the built-in table.
+///
+/// # Arguments
+///
+/// * `model` - The model name.
+/// * `config` - The loaded configuration.
+///
+/// # Returns
+///
+/// * `Option<(f64, f64)>` - The prompt and completion prices, or `None` for unknown models.
+fn price_for(model: &str, config: &Config) -> Option<(f64, f64)> {
+    if let Some(price) = config.prices.as_ref().and_then(|prices| prices.get(model)) {
+        return Some((price.prompt, price.completion));
+    }
+    PRICE_TABLE
+        .iter()
+        .find(|(prefix, _, _)| model.starts_with(prefix))
+        .map(|(_, prompt, completion)| (*prompt, *completion))
+}
+
+/// Converts token usage into an estimated cost in dollars.
+///
+/// # Arguments
+///
+/// * `model` - The model the tokens were used with.
+/// * `usage` - The token counts.
+/// * `config` - The loaded configuration.
+///
+/// # Returns
+///
+/// * `Option<f64>` - The cost, or `None` if the model's price is unknown.
+pub(crate) fn estimate_cost(model: &str, usage: &Usage, config: &Config) -> Option<f64> {
+    price_for(model, config).map(|(prompt, completion)| {
+        (usage.prompt_tokens as f64 * prompt + usage.completion_tokens as f64 * completion) / 1_000_000.0
+    })
+}
+
+/// Formats a dollar amount, keeping more precision for amounts under a cent.
+pub(crate) fn format_cost(cost: f64) -> String {
+    if cost < 0.01 {
+        format!("${:.4}", cost)
+    } else {
+        format!("${:.2}", cost)
+    }
+}
+
+/// Describes the tokens and estimated cost of a single request, e.g.
+/// `≈ $0.0031 (142 prompt + 28 completion tokens, gpt-4o)`.
+/// Models without a known price show token counts only.
+///
+/// # Arguments
+///
+/// * `model` - The model the request was sent to.
+/// * `usage` - The tokens the request used.
+/// * `config` - The loaded configuration.
+///
+/// # Returns
+///
+/// * `String` - The description.
+pub(crate) fn describe_request_cost(model: &str, usage: &Usage, config: &Config) -> String {
+    let tokens = format!(
+        "{} prompt + {} completion tokens, {}",
+        usage.prompt_tokens, usage.completion_tokens, model
+    );
+    match estimate_cost(model, usage, config) {
+        Some(cost) => format!("≈ {} ({})", format_cost(cost), tokens),
+        None => tokens,
+    }
+}
+
+/// Describes the running total of a chat session, e.g. `session ≈ $0.0100`.
+///
+/// # Arguments
+///
+/// * `model` - The model the session uses.
+/// * `usage` - The tokens used so far.
+/// * `config` - The loaded configuration.
+///
+/// # Returns
+///
+/// * `String` - The description.
+pub(crate) fn describe_session_cost(model: &str, usage: &Usage, config: &Config) -> String {
+    match estimate_cost(model, usage, config) {
+        Some(cost) => format!("session ≈ {}", format_cost(cost)),
+        None => format!(
+            "session {} tokens",
+            usage.prompt_tokens + usage.completion_tokens
+        ),
+    }
+}
+
+/// Describes the seed a request was sent with and the backend fingerprint it was served by,
+/// e.g. `seed 42, fingerprint fp_44709d6fcb`, so runs can be compared.
+///
+/// # Arguments
+///
+/// * `seed` - The seed sent with the request, if any.
+/// * `fingerprint` - The `system_fingerprint` of the response, if the model reported one.
+///
+/// # Returns
+///
+/// * `Option<String>` - The description, or `None` if there is neither.
+pub(crate) fn describe_reproducibility(seed: Option<u64>, fingerprint: Option<&str>) -> Option<String> {
+    let parts: Vec<String> = seed
+        .map(|seed| format!("seed {}", seed))
+        .into_iter()
+        .chain(fingerprint.map(|fingerprint| format!("fingerprint {}", fingerprint)))
+        .collect();
+    (!parts.is_empty()).then(|| parts.join(", "))
+}
+
+/// Sums the estimated cost of every day whose key starts with `period`.
+/// Models without a known price are not counted.
+fn spend_for_period(log: &UsageLog, period: &str, config: &Config) -> f64 {
+    log.days
+        .iter()
+        .filter(|(day, _)| day.starts_with(period))
+        .flat_map(|(_, models)| models.iter())
+        .filter_map(|(model, usage)| estimate_cost(model, usage, config))
+        .sum()
+}
+
+/// Checks today's and this month's estimated spend against the configured budget.
+/// Over budget, or when the usage file can't be read, requests are refused unless
+/// `override_budget` is set, in which case a warning is printed instead.
+///
+/// # Arguments
+///
+/// * `override_budget` - Whether `--override-budget` was given.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - `Ok` if the request may be sent, or an error message.
+pub(crate) fn check_budget(override_budget: bool) -> Result<(), String> {
+    let config = load_config().unwrap_or_default();
+    check_spend(usage_file_path().as_deref(), &config, override_budget)
+}
+
+/// Does the work of `check_budget` with the usage file and configuration it resolves.
+///
+/// # Arguments
+///
+/// * `usage_file` - The usage file, or `None` if there is nowhere to keep one.
+/// * `config` - The loaded configuration, for the budget and prices.
+/// * `override_budget` - Whether `--override-budget` was given.
+///
+/// # Returns
+///
+/// * `Result<(), String>` - `Ok` if the request may be sent, or an error message.
+fn check_spend(usage_file: Option<&Path>, config: &Config, override_budget: bool) -> Result<(), String> {
+    let Some(budget) = config.budget.as_ref() else {
+        return Ok(());
+    };
+    let log = match load_usage_log(usage_file) {
+        Ok(log) => log,
+        Err(e) if override_budget => {
+            eprintln!(
+                "Warning: Could not read the usage file to check the budget: {}. \
+                 Sending anyway because of --override-budget.",
+                e
+            );
+            return Ok(());
+        }
+        Err(e) => {
+            return Err(format!(
+                "Error: Could not read the usage file to check the budget: {}. \
+                 Pass --override-budget to send the request anyway.",
+                e
+            ))
+        }
+    };
+    let now = Local::now();
+
+    let limits = [
+        ("daily", budget.daily, now.format("%Y-%m-%d").to_string()),
+        ("monthly", budget.monthly, now.format("%Y-%m").to_string()),
+    ];
+    for (name, limit, period) in limits {
+        let Some(limit) = limit else { continue };
+        let spent = spend_for_period(&log, &period, config);
+        if spent < limit {
+            continue;
+        }
+        let message = format!(
+            "estimated spend of {} has reached the {} budget of {}",
+            format_cost(spent),
+            name,
+            format_cost(limit)
+        );
+        if override_budget {
+            eprintln!("Warning: The {}. Sending anyway because of --override-budget.", message);
+        } else {
+            return Err(format!(
+                "Error: The {}. Pass --override-budget to send the request anyway.",
+                message
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Prints today's and this month's token totals and estimated spend (`gptsh usage`).
+pub(crate) fn print_usage_report() {
+    let log = match load_usage_log(usage_file_path().as_deref()) {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("Error: Could not read the usage file: {}", e);
+            return;
+        }
+    };
+    let config = load_config().unwrap_or_default();
+    let budget = config.budget.as_ref();
+    let now = Local::now();
+
+    print_period(
+        &format!("Today ({})", now.format("%Y-%m-%d")),
+        &log,
+        &now.format("%Y-%m-%d").to_string(),
+        budget.and_then(|budget| budget.daily),
+        &config,
+    );
+    println!();
+    print_period(
+        &format!("This month ({})", now.format("%Y-%m")),
+        &log,
+        &now.format("%Y-%m").to_string(),
+        budget.and_then(|budget| budget.monthly),
+        &config,
+    );
+}
+
+/// Prints the per-model totals for one period of the usage report.
+fn print_period(title: &str, log: &UsageLog, period: &str, limit: Option<f64>, config: &Config) {
+    println!("{}:", title);
+
+    let mut totals: BTreeMap<&str, Usage> = BTreeMap::new();
+    for models in log.days.iter().filter(|(day, _)| day.starts_with(period)).map(|(_, models)| models) {
+        for (model, usage) in models {
+            let total = totals.entry(model.as_str()).or_default();
+            total.prompt_tokens += usage.prompt_tokens;
+            total.completion_tokens += usage.completion_tokens;
+        }
+    }
+
+    if totals.is_empty() {
+        println!("  No requests recorded.");
+    }
+    for (model, usage) in &totals {
+        let cost = estimate_cost(model, usage, config).map_or_else(|| "unknown price".to_string(), format_cost);
+        println!(
+            "  {:<16} {:>10} prompt + {:>9} completion tokens  {}",
+            model, usage.prompt_tokens, usage.completion_tokens, cost
+        );
+    }
+
+    let mut key_totals: BTreeMap<&str, Usage> = BTreeMap::new();
+    for keys in log.keys.iter().filter(|(day, _)| day.starts_with(period)).map(|(_, keys)| keys) {
+        for (label, usage) in keys {
+            let total = key_totals.entry(label.as_str()).or_default();
+            total.prompt_tokens += usage.prompt_tokens;
+            total.completion_tokens += usage.completion_tokens;
+        }
+    }
+    if !key_totals.is_empty() {
+        println!("  By API key:");
+    }
+    for (label, usage) in &key_totals {
+        println!(
+            "    {:<14} {:>10} prompt + {:>9} completion tokens",
+            label, usage.prompt_tokens, usage.completion_tokens
+        );
+    }
+
+    let spent = format_cost(spend_for_period(log, period, config));
+    match limit {
+        Some(limit) => println!("  Estimated spend: {} of {} budget", spent, format_cost(limit)),
+        None => println!("  Estimated spend: {}", spent),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        client::testing::CannedBackend,
+        models::{BudgetConfig, PriceConfig},
+        utils::testing::test_dir,
+    };
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64) -> Usage {
+        Usage { prompt_tokens, completion_tokens }
+    }
+
+    #[test]
+    fn request_cost_shows_dollars_and_tokens() {
+        let config = Config::default();
+        assert_eq!(
+            describe_request_cost("gpt-4o", &usage(1000, 200), &config),
+            "≈ $0.0045 (1000 prompt + 200 completion tokens, gpt-4o)"
+        );
+        assert_eq!(
+            describe_request_cost("gpt-4-0613", &usage(10_000, 5_000), &config),
+            "≈ $0.60 (10000 prompt + 5000 completion tokens, gpt-4-0613)"
+        );
+    }
+
+    #[test]
+    fn request_cost_prefers_the_most_specific_price() {
+        let config = Config::default();
+        assert_eq!(
+            describe_request_cost("gpt-4o-mini", &usage(1_000_000, 0), &config),
+            "≈ $0.15 (1000000 prompt + 0 completion tokens, gpt-4o-mini)"
+        );
+    }
+
+    #[test]
+    fn unknown_models_show_tokens_only() {
+        let config = Config::default();
+        assert_eq!(
+            describe_request_cost("llama3", &usage(142, 28), &config),
+            "142 prompt + 28 completion tokens, llama3"
+        );
+        assert_eq!(describe_session_cost("llama3", &usage(142, 28), &config), "session 170 tokens");
+    }
+
+    #[test]
+    fn configured_prices_override_the_table() {
+        let config = Config {
+            prices: Some(HashMap::from([(
+                "llama3".to_string(),
+                PriceConfig { prompt: 1.0, completion: 2.0 },
+            )])),
+            ..Config::default()
+        };
+        assert_eq!(
+            describe_request_cost("llama3", &usage(10_000, 10_000), &config),
+            "≈ $0.03 (10000 prompt + 10000 completion tokens, llama3)"
+        );
+        assert_eq!(describe_session_cost("llama3", &usage(10_000, 10_000), &config), "session ≈ $0.03");
+    }
+
+    #[test]
+    fn usage_is_added_to_the_file_the_backend_names() {
+        let dir = test_dir("usage-record");
+        let path = dir.join(USAGE_FILE);
+        let mut backend = CannedBackend::new(&[]);
+        backend.usage_file = Some(path.clone());
+
+        record_usage(&backend, usage(100, 20));
+        record_usage(&backend, usage(50, 10));
+
+        let log = load_usage_log(Some(&path)).unwrap();
+        let today = Local::now().format("%Y-%m-%d").to_string();
+        let total = log.days[&today]["gpt-4"];
+        assert_eq!((total.prompt_tokens, total.completion_tokens), (150, 30));
+        // Neither the temporary file nor the lock is left behind
+        let mut files: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        files.sort();
+        assert_eq!(files, [USAGE_FILE]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_budget_refuses_requests_once_spent_or_when_the_file_is_unreadable() {
+        let dir = test_dir("usage-budget");
+        let path = dir.join(USAGE_FILE);
+        let config = Config {
+            budget: Some(BudgetConfig { daily: Some(1.0), monthly: None }),
+            ..Config::default()
+        };
+
+        // No usage recorded yet
+        assert_eq!(check_spend(Some(&path), &config, false), Ok(()));
+
+        let mut backend = CannedBackend::new(&[]);
+        backend.usage_file = Some(path.clone());
+        record_usage(&backend, usage(40_000, 0));
+        assert!(check_spend(Some(&path), &config, false).unwrap_err().contains("daily budget of $1.00"));
+        assert_eq!(check_spend(Some(&path), &config, true), Ok(()));
+
+        fs::write(&path, "{ not json").unwrap();
+        assert!(check_spend(Some(&path), &config, false).unwrap_err().contains("Could not read the usage file"));
+        assert_eq!(check_spend(Some(&path), &config, true), Ok(()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_usage_lock_is_released_when_dropped_and_taken_over_when_stale() {
+        let dir = test_dir("usage-lock");
+        let usage_path = dir.join(USAGE_FILE);
+        let lock_path = dir.join("usage.json.lock");
+
+        let lock = UsageLock::acquire(&usage_path).unwrap();
+        assert!(lock_path.exists());
+        drop(lock);
+        assert!(!lock_path.exists());
+
+        // A lock left behind by a gptsh that exited mid-update
+        let stale = fs::File::create(&lock_path).unwrap();
+        stale.set_modified(SystemTime::now() - STALE_LOCK_AGE * 2).unwrap();
+        drop(stale);
+        let lock = UsageLock::acquire(&usage_path).unwrap();
+        assert!(lock_path.exists());
+        drop(lock);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
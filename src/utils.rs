@@ -14,27 +14,32 @@
  * limitations under the License.
  */
 
-use std::{env, io, thread};
-use std::io::Write;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::{env, fs, io};
+use std::io::{BufRead, IsTerminal, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::path::Path;
+use std::future::Future;
+use std::time::{Duration, Instant};
 
-// Gets the current directory, replacing the home directory path with '~'
+use crate::job_control::interrupt_foreground_command;
+
+// Gets the current directory, replacing the home directory path with '~'. A working
+// directory that was deleted is shown as '?'
 pub fn get_current_dir_with_tilde() -> String {
-    let current_dir = env::current_dir().expect("Failed to get current directory");
-    let home_dir = dirs::home_dir().expect("Failed to get home directory");
-
-    let current_dir_str = current_dir
-        .to_str()
-        .expect("Current directory path is not valid UTF-8");
-    let home_dir_str = home_dir
-        .to_str()
-        .expect("Home directory path is not valid UTF-8");
-
-    if current_dir_str.starts_with(home_dir_str) {
-        current_dir_str.replacen(home_dir_str, "~", 1)
-    } else {
-        current_dir_str.to_string()
+    match env::current_dir() {
+        Ok(current_dir) => path_with_tilde(&current_dir, dirs::home_dir().as_deref()),
+        Err(_) => "?".to_string(),
+    }
+}
+
+// Replaces the home directory at the start of a path with '~'. Parts of the path that
+// aren't valid UTF-8 are shown with replacement characters
+fn path_with_tilde(path: &Path, home_dir: Option<&Path>) -> String {
+    match home_dir.and_then(|home_dir| path.strip_prefix(home_dir).ok()) {
+        Some(rest) if rest.as_os_str().is_empty() => "~".to_string(),
+        Some(rest) => format!("~/{}", rest.to_string_lossy()),
+        None => path.to_string_lossy().into_owned(),
     }
 }
 
@@ -43,16 +48,513 @@ pub fn get_username() -> String {
     env::var("USER").unwrap_or_else(|_| "Unknown User".to_string())
 }
 
-/// Starts the loading animation in a separate thread.
-pub(crate) fn start_loading_animation(stop_signal: Arc<Mutex<bool>>) {
-    let spinner_chars = ['/', '-', '\\', '|'];
-    let mut i = 0;
-    while !*stop_signal.lock().unwrap() {
-        print!("\r{}", spinner_chars[i]);
+/// A spinner shown while waiting for a reply. It runs as a task on the runtime driving the
+/// request, and stops when `stop` is called or it is dropped.
+pub(crate) struct LoadingAnimation(tokio::task::JoinHandle<()>);
+
+/// Text shown beside the loading animation, such as a countdown; empty for none.
+static LOADING_STATUS: Mutex<String> = Mutex::new(String::new());
+
+/// Starts the loading animation. Must be called from within the request's runtime.
+pub(crate) fn start_loading_animation() -> LoadingAnimation {
+    LoadingAnimation(tokio::spawn(async {
+        let spinner_chars = ['/', '-', '\\', '|'];
+        let mut i = 0;
+        // Pads each frame over the previous one, so a cleared status doesn't linger
+        let mut width = 0;
+        loop {
+            let status = LOADING_STATUS.lock().map(|status| status.clone()).unwrap_or_default();
+            let frame = if status.is_empty() {
+                spinner_chars[i].to_string()
+            } else {
+                format!("{} {}", spinner_chars[i], status)
+            };
+            print!("\r{:<width$}", frame, width = width);
+            io::stdout().flush().unwrap();
+            width = frame.chars().count();
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            i = (i + 1) % spinner_chars.len();
+        }
+    }))
+}
+
+/// Sets the text shown beside the loading animation.
+fn set_loading_status(status: String) {
+    if let Ok(mut current) = LOADING_STATUS.lock() {
+        *current = status;
+    }
+}
+
+/// Waits out a delay while the loading animation counts down the seconds left,
+/// e.g. `Rate limited, waiting 12s…`.
+///
+/// # Arguments
+///
+/// * `delay` - How long to wait.
+/// * `reason` - Why, shown before the countdown.
+pub(crate) async fn count_down(delay: Duration, reason: &str) {
+    let deadline = tokio::time::Instant::now() + delay;
+    loop {
+        let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        set_loading_status(format!("{}, waiting {}s…", reason, remaining.as_secs_f32().ceil()));
+        tokio::time::sleep(remaining.min(Duration::from_millis(250))).await;
+    }
+    set_loading_status(String::new());
+    print!("\r\x1b[2K");
+    io::stdout().flush().unwrap();
+}
+
+impl LoadingAnimation {
+    /// Stops the animation, clears the spinner, and moves to a new line.
+    pub(crate) fn stop(self) {
+        drop(self);
+        println!("\r ");
+    }
+}
+
+impl Drop for LoadingAnimation {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Maximum number of bytes accepted from piped stdin, or from a file given with `--file`,
+/// before truncating.
+const MAX_PIPED_INPUT_BYTES: usize = 100 * 1024;
+
+/// Why input meant as context could not be used.
+#[derive(Debug)]
+enum TextInputError {
+    /// It could not be read.
+    Unreadable(io::Error),
+    /// It doesn't look like text.
+    Binary,
+}
+
+/// Reads input piped to gptsh through stdin, if any.
+/// Returns `Ok(None)` when stdin is an interactive terminal.
+/// Input larger than 100KB is truncated with a notice, and binary input is rejected.
+pub(crate) fn read_piped_stdin() -> Result<Option<String>, String> {
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        return Ok(None);
+    }
+    read_text_input(stdin.lock()).map_err(|e| match e {
+        TextInputError::Unreadable(e) => format!("Error: Failed to read piped input: {}", e),
+        TextInputError::Binary => binary_input_error(),
+    })
+}
+
+/// Reads a file given with `--file` to attach to a request as context, with the same limit
+/// and checks as piped input.
+///
+/// # Arguments
+///
+/// * `path` - The file's path.
+///
+/// # Returns
+///
+/// * `Result<Option<String>, String>` - The file's text, `None` if it is blank, or an error
+///   if it could not be read or looks binary.
+pub(crate) fn read_context_file(path: &str) -> Result<Option<String>, String> {
+    fs::File::open(path)
+        .map_err(TextInputError::Unreadable)
+        .and_then(read_text_input)
+        .map_err(|e| match e {
+            TextInputError::Unreadable(e) => format!("Error: Could not read {}: {}", path, e),
+            TextInputError::Binary => {
+                format!("Error: {} appears to be binary data. Only text can be used as context.", path)
+            }
+        })
+}
+
+/// Reads text from a reader, truncating it with a notice past the size limit.
+///
+/// # Arguments
+///
+/// * `reader` - Where the input comes from.
+///
+/// # Returns
+///
+/// * `Result<Option<String>, TextInputError>` - The text, `None` if it is blank, or why it
+///   can't be used.
+fn read_text_input(reader: impl Read) -> Result<Option<String>, TextInputError> {
+    let mut buffer = Vec::new();
+    reader
+        .take(MAX_PIPED_INPUT_BYTES as u64 + 1)
+        .read_to_end(&mut buffer)
+        .map_err(TextInputError::Unreadable)?;
+
+    let truncated = buffer.len() > MAX_PIPED_INPUT_BYTES;
+    buffer.truncate(MAX_PIPED_INPUT_BYTES);
+
+    let mut text = decode_text(buffer, truncated).ok_or(TextInputError::Binary)?;
+
+    if text.trim().is_empty() {
+        return Ok(None);
+    }
+
+    if truncated {
+        text.push_str(&format!(
+            "\n[... input truncated to the first {} bytes ...]",
+            MAX_PIPED_INPUT_BYTES
+        ));
+    }
+
+    Ok(Some(text))
+}
+
+/// Decodes bytes as UTF-8 text, rejecting data that looks binary.
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes to decode.
+/// * `truncated` - Whether the bytes were cut off at a size limit, in which case a
+///   multi-byte character split at the end is dropped rather than treated as binary.
+///
+/// # Returns
+///
+/// * `Option<String>` - The text, or `None` if the bytes contain NUL or invalid UTF-8.
+pub(crate) fn decode_text(bytes: Vec<u8>, truncated: bool) -> Option<String> {
+    if bytes.contains(&0) {
+        return None;
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(text) => Some(text),
+        Err(e) if truncated && e.utf8_error().error_len().is_none() => {
+            let valid_up_to = e.utf8_error().valid_up_to();
+            let mut bytes = e.into_bytes();
+            bytes.truncate(valid_up_to);
+            String::from_utf8(bytes).ok()
+        }
+        Err(_) => None,
+    }
+}
+
+/// Builds the error shown when piped input does not look like text.
+fn binary_input_error() -> String {
+    "Error: Piped input appears to be binary data. Only text can be used as context; \
+     try piping through a tool like `strings` or `xxd` first."
+        .to_string()
+}
+
+/// Reads a single line of user input from the terminal.
+/// When stdin has been redirected (e.g. input was piped to gptsh), the line is read
+/// from the controlling terminal instead so confirmations still reach the user.
+pub(crate) fn read_terminal_line() -> io::Result<String> {
+    let mut input = String::new();
+    if io::stdin().is_terminal() {
+        io::stdin().read_line(&mut input)?;
+    } else {
+        let tty = fs::File::open("/dev/tty")?;
+        io::BufReader::new(tty).read_line(&mut input)?;
+    }
+    Ok(input)
+}
+
+/// Checks that a user-supplied name can be used as a file name inside one of gptsh's
+/// directories: letters, digits, `-`, `_`, and `.`, not starting with a dot.
+pub(crate) fn is_plain_file_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Formats a count with thousands separators, e.g. `53,112`.
+pub(crate) fn format_count(count: usize) -> String {
+    let digits = count.to_string();
+    let mut formatted = String::new();
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+    formatted
+}
+
+/// Counts the single-character insertions, deletions, substitutions, and swaps of
+/// neighbouring characters needed to turn one string into another, so `/hepl` is one
+/// edit away from `/help`.
+pub(crate) fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // distances[i][j] is the distance between the first i characters of a and the first j of b
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    distances[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let substitution = distances[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = substitution.min(distances[i - 1][j] + 1).min(distances[i][j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+/// Returns how many columns wide the terminal showing stdout is, falling back to the
+/// `COLUMNS` environment variable. It is read again on every call, so a resized window
+/// is noticed.
+///
+/// # Returns
+///
+/// * `Option<usize>` - The width, or `None` if it is unknown.
+pub(crate) fn terminal_width() -> Option<usize> {
+    #[cfg(unix)]
+    if let Ok(size) = rustix::termios::tcgetwinsize(io::stdout()) {
+        if size.ws_col > 0 {
+            return Some(usize::from(size.ws_col));
+        }
+    }
+    env::var("COLUMNS").ok().and_then(|columns| columns.parse().ok()).filter(|columns| *columns > 0)
+}
+
+/// Roughly estimates the number of tokens in a piece of text.
+/// Uses the common heuristic of about four characters per token.
+pub(crate) fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
+}
+
+/// Set while an interruptible operation (such as a streamed reply) is in progress.
+static INTERRUPTIBLE: AtomicBool = AtomicBool::new(false);
+/// Set when Ctrl-C is pressed during an interruptible operation.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Set while a program run with the terminal attached is in the foreground.
+static FOREGROUND_PROGRAM: AtomicBool = AtomicBool::new(false);
+/// The prompt shown while input is read at an `InputPrompt`, if any.
+static INPUT_PROMPT: Mutex<Option<String>> = Mutex::new(None);
+/// When Ctrl-C was last pressed.
+static LAST_INTERRUPT: Mutex<Option<Instant>> = Mutex::new(None);
+/// Pressing Ctrl-C twice within this time exits, whatever is going on.
+const DOUBLE_INTERRUPT_WINDOW: Duration = Duration::from_secs(1);
+
+/// Installs a Ctrl-C handler that interrupts the current operation while an
+/// `InterruptGuard` is active, clears the line being typed at an `InputPrompt`, and
+/// otherwise exits like the default handler would. Pressing Ctrl-C twice within a
+/// second always exits, except while a `ForegroundProgram` or a command started with
+/// [`spawn_in_foreground`](crate::job_control::spawn_in_foreground) runs: then Ctrl-C is
+/// left to that program.
+pub(crate) fn install_interrupt_handler() {
+    // Installing twice fails harmlessly; the first handler stays in place.
+    let _ = ctrlc::set_handler(|| {
+        if FOREGROUND_PROGRAM.load(Ordering::SeqCst) || interrupt_foreground_command() {
+            return;
+        }
+        let now = Instant::now();
+        let previous = LAST_INTERRUPT.lock().ok().and_then(|mut last| last.replace(now));
+        if previous.is_some_and(|previous| now.duration_since(previous) < DOUBLE_INTERRUPT_WINDOW) {
+            println!();
+            std::process::exit(130);
+        }
+
+        if INTERRUPTIBLE.load(Ordering::SeqCst) {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+        } else if let Some(prompt) = INPUT_PROMPT.lock().ok().and_then(|prompt| prompt.clone()) {
+            // The terminal discards the partly typed line, so start it over
+            print!("\n{}", prompt);
+            io::stdout().flush().ok();
+        } else {
+            std::process::exit(130);
+        }
+    });
+}
+
+/// Marks that input is being read after a prompt, so Ctrl-C clears the line instead of
+/// ending the program. The mark is removed when the value is dropped.
+pub(crate) struct InputPrompt;
+
+impl InputPrompt {
+    /// Prints a prompt and marks that input is being read after it.
+    pub(crate) fn new(prompt: &str) -> Self {
+        let input_prompt = Self;
+        input_prompt.show(prompt);
+        input_prompt
+    }
+
+    /// Prints a new prompt, such as a continuation prompt, in place of the last one.
+    pub(crate) fn show(&self, prompt: &str) {
+        print!("{}", prompt);
         io::stdout().flush().unwrap();
-        thread::sleep(Duration::from_millis(100));
-        i = (i + 1) % spinner_chars.len();
+        if let Ok(mut input_prompt) = INPUT_PROMPT.lock() {
+            *input_prompt = Some(prompt.to_string());
+        }
+    }
+}
+
+impl Drop for InputPrompt {
+    fn drop(&mut self) {
+        if let Ok(mut input_prompt) = INPUT_PROMPT.lock() {
+            *input_prompt = None;
+        }
+    }
+}
+
+/// Marks a section of work that Ctrl-C interrupts instead of ending the program.
+pub(crate) struct InterruptGuard;
+
+impl InterruptGuard {
+    pub(crate) fn new() -> Self {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+        INTERRUPTIBLE.store(true, Ordering::SeqCst);
+        Self
+    }
+
+    /// Returns `true` if Ctrl-C was pressed since the guard was created.
+    pub(crate) fn interrupted(&self) -> bool {
+        INTERRUPTED.load(Ordering::SeqCst)
+    }
+
+    /// Runs a future until it completes or Ctrl-C is pressed, whichever comes first.
+    /// An interrupted future is dropped, cancelling whatever request it was making.
+    ///
+    /// # Arguments
+    ///
+    /// * `future` - The work to run.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<F::Output>` - What the future produced, or `None` if it was interrupted.
+    pub(crate) async fn run<F: Future>(&self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            output = future => Some(output),
+            _ = async {
+                while !self.interrupted() {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            } => None,
+        }
     }
-    // Clear the spinner and move to a new line
-    println!("\r ");
-}
\ No newline at end of file
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        INTERRUPTIBLE.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Marks that a program run with the terminal attached, such as an editor, is in the
+/// foreground, so Ctrl-C is left to it instead of ending gptsh. The mark is removed when
+/// the value is dropped.
+pub(crate) struct ForegroundProgram;
+
+impl ForegroundProgram {
+    pub(crate) fn new() -> Self {
+        FOREGROUND_PROGRAM.store(true, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for ForegroundProgram {
+    fn drop(&mut self) {
+        FOREGROUND_PROGRAM.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Helpers shared by the unit tests of several modules.
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::{fs, path::PathBuf};
+
+    /// Creates an empty directory for a test's files in the temporary directory. The name and
+    /// the process id keep tests that run at the same time out of each other's files.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - A name for the directory, unique among the tests.
+    ///
+    /// # Returns
+    ///
+    /// * `PathBuf` - The directory.
+    pub(crate) fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gptsh-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads input as piped text, with what [`read_text_input`] returns as a plain result.
+    fn read(input: &[u8]) -> Result<Option<String>, String> {
+        read_text_input(input).map_err(|e| format!("{:?}", e))
+    }
+
+    #[test]
+    fn piped_input_is_read_as_text() {
+        let input = "make: *** [all] Error 1\n";
+        assert_eq!(read(input.as_bytes()), Ok(Some(input.to_string())));
+    }
+
+    #[test]
+    fn blank_piped_input_is_no_input() {
+        assert_eq!(read(b""), Ok(None));
+        assert_eq!(read(b" \n\t\n"), Ok(None));
+    }
+
+    #[test]
+    fn large_piped_input_is_truncated_with_a_notice() {
+        let input = "x".repeat(MAX_PIPED_INPUT_BYTES + 10);
+        let text = read(input.as_bytes()).unwrap().unwrap();
+        assert!(text.starts_with(&"x".repeat(MAX_PIPED_INPUT_BYTES)));
+        assert!(text.ends_with("\n[... input truncated to the first 102400 bytes ...]"));
+        assert_eq!(text.matches('x').count(), MAX_PIPED_INPUT_BYTES);
+
+        // Exactly at the limit nothing is cut off
+        let input = "x".repeat(MAX_PIPED_INPUT_BYTES);
+        assert_eq!(read(input.as_bytes()), Ok(Some(input)));
+    }
+
+    #[test]
+    fn binary_piped_input_is_rejected() {
+        assert_eq!(read(b"\x7fELF\x02\x01\x01\0\0"), Err("Binary".to_string()));
+        assert_eq!(read(b"caf\xe9 latin-1"), Err("Binary".to_string()));
+    }
+
+    #[test]
+    fn a_character_split_by_truncation_is_dropped() {
+        // "é" is two bytes; only its first is left at the end
+        assert_eq!(decode_text(b"caf\xc3".to_vec(), true), Some("caf".to_string()));
+        assert_eq!(decode_text(b"caf\xc3".to_vec(), false), None);
+        assert_eq!(decode_text("café".as_bytes().to_vec(), false), Some("café".to_string()));
+        assert_eq!(decode_text(b"a\0b".to_vec(), true), None);
+    }
+
+    #[test]
+    fn context_files_name_the_file_when_they_cant_be_used() {
+        let error = read_context_file("/nonexistent/notes.txt").unwrap_err();
+        assert!(error.starts_with("Error: Could not read /nonexistent/notes.txt: "), "{}", error);
+    }
+
+    #[test]
+    fn the_home_directory_is_shown_as_a_tilde() {
+        let home = Path::new("/home/pal");
+        assert_eq!(path_with_tilde(Path::new("/home/pal"), Some(home)), "~");
+        assert_eq!(path_with_tilde(Path::new("/home/pal/src/gptsh"), Some(home)), "~/src/gptsh");
+        // Only whole directory names match
+        assert_eq!(path_with_tilde(Path::new("/home/pal2/src"), Some(home)), "/home/pal2/src");
+        assert_eq!(path_with_tilde(Path::new("/tmp"), None), "/tmp");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paths_that_are_not_utf8_are_shown_lossily() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let path = Path::new(OsStr::from_bytes(b"/home/pal/caf\xe9"));
+        assert_eq!(path_with_tilde(path, Some(Path::new("/home/pal"))), "~/caf\u{fffd}");
+    }
+}
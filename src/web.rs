@@ -0,0 +1,282 @@
+/*
+ * Copyright 2024 Blake Rhodes
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::{error::Error, time::Duration};
+
+use regex::Regex;
+use reqwest::{header::CONTENT_TYPE, redirect, Url};
+
+use crate::client::client_builder;
+use crate::utils::decode_text;
+
+/// The most redirects followed when fetching a page.
+const MAX_REDIRECTS: usize = 5;
+/// The most bytes downloaded from a page; anything after it is ignored.
+const MAX_DOWNLOAD_BYTES: usize = 2 * 1024 * 1024;
+/// The most bytes of text returned to the assistant.
+const MAX_FETCHED_TEXT_BYTES: usize = 32 * 1024;
+/// How long a fetch may take in total.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Parses a URL the assistant asked to fetch, accepting only `http` and `https`.
+///
+/// # Arguments
+///
+/// * `url` - The URL as given by the assistant.
+///
+/// # Returns
+///
+/// * `Result<Url, String>` - The parsed URL, or an error message.
+pub(crate) fn parse_fetch_url(url: &str) -> Result<Url, String> {
+    let parsed = Url::parse(url.trim()).map_err(|e| format!("Error: '{}' is not a valid URL: {}", url, e))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!(
+            "Error: Only http and https URLs can be fetched, not '{}'.",
+            parsed.scheme()
+        ));
+    }
+    Ok(parsed)
+}
+
+/// Downloads a page with a GET request and returns it as text. HTML is reduced to its
+/// readable text, and long pages are truncated with a notice. At most five redirects are
+/// followed, and only to other http(s) URLs.
+///
+/// # Arguments
+///
+/// * `url` - The URL to fetch, as returned by [`parse_fetch_url`].
+///
+/// # Returns
+///
+/// * `Result<String, String>` - The page's text, or an error message.
+pub(crate) async fn fetch_text(url: Url) -> Result<String, String> {
+    let policy = redirect::Policy::custom(|attempt| {
+        if attempt.previous().len() > MAX_REDIRECTS {
+            attempt.error(format!("more than {} redirects", MAX_REDIRECTS))
+        } else if !matches!(attempt.url().scheme(), "http" | "https") {
+            let scheme = attempt.url().scheme().to_string();
+            attempt.error(format!("redirected to an unsupported '{}' URL", scheme))
+        } else {
+            attempt.follow()
+        }
+    });
+    let client = client_builder()?
+        .redirect(policy)
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .map_err(|e| format!("Error: Could not create the HTTP client: {}", e))?;
+
+    let fetch_error = |e: reqwest::Error| format!("Error: Could not fetch '{}': {}", url, describe_error(&e));
+    let mut response = client.get(url.clone()).send().await.map_err(fetch_error)?;
+    if !response.status().is_success() {
+        return Err(format!("Error: '{}' returned HTTP {}.", response.url(), response.status()));
+    }
+
+    let final_url = response.url().clone();
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(fetch_error)? {
+        body.extend_from_slice(&chunk);
+        if body.len() > MAX_DOWNLOAD_BYTES {
+            break;
+        }
+    }
+    let cut_off = body.len() > MAX_DOWNLOAD_BYTES;
+    body.truncate(MAX_DOWNLOAD_BYTES);
+
+    let text = decode_text(body, cut_off)
+        .ok_or_else(|| format!("Error: '{}' is not a text page ({}).", final_url, content_type))?;
+    let mut text = if content_type.contains("html") || (content_type.is_empty() && looks_like_html(&text)) {
+        html_to_text(&text)
+    } else {
+        text
+    };
+
+    if text.len() > MAX_FETCHED_TEXT_BYTES || cut_off {
+        let mut end = MAX_FETCHED_TEXT_BYTES.min(text.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        text.push_str(&format!(
+            "\n[... page truncated to the first {} bytes of text ...]",
+            MAX_FETCHED_TEXT_BYTES
+        ));
+    }
+    if final_url != url {
+        text.insert_str(0, &format!("[Redirected to {}]\n", final_url));
+    }
+    Ok(text)
+}
+
+/// Describes an HTTP error with its causes, which say e.g. why a redirect wasn't followed.
+fn describe_error(error: &reqwest::Error) -> String {
+    let mut description = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        description.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    description
+}
+
+/// Guesses whether a page served without a content type is HTML.
+fn looks_like_html(text: &str) -> bool {
+    let start: String = text.trim_start().chars().take(15).collect::<String>().to_ascii_lowercase();
+    start.starts_with("<!doctype html") || start.starts_with("<html")
+}
+
+/// Reduces an HTML page to its readable text: scripts, styles, and markup are removed,
+/// block elements become line breaks, and common entities are decoded.
+///
+/// # Arguments
+///
+/// * `html` - The page source.
+///
+/// # Returns
+///
+/// * `String` - The page's text.
+fn html_to_text(html: &str) -> String {
+    let hidden = Regex::new(
+        r"(?is)<!--.*?-->|<script\b.*?</script\s*>|<style\b.*?</style\s*>|<head\b.*?</head\s*>|<noscript\b.*?</noscript\s*>|<svg\b.*?</svg\s*>",
+    )
+    .expect("valid regex");
+    let list_item = Regex::new(r"(?i)<li\b[^>]*>").expect("valid regex");
+    let block = Regex::new(
+        r"(?i)<(br|hr|/p|/div|/h[1-6]|/tr|/pre|/blockquote|/section|/article|/table|/ul|/ol|/dt|/dd)\b[^>]*>",
+    )
+    .expect("valid regex");
+    let tag = Regex::new(r"(?s)<[^>]*>").expect("valid regex");
+
+    let text = hidden.replace_all(html, "");
+    let text = list_item.replace_all(&text, "\n- ");
+    let text = block.replace_all(&text, "\n");
+    let text = tag.replace_all(&text, "");
+    let text = decode_entities(&text);
+
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        // Keep single blank lines between paragraphs, but no runs of them
+        if !line.is_empty() || lines.last().is_some_and(|last| !last.is_empty()) {
+            lines.push(line);
+        }
+    }
+    lines.join("\n").trim().to_string()
+}
+
+/// Decodes the named HTML entities that matter for reading, and numeric ones.
+fn decode_entities(text: &str) -> String {
+    let entity = Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").expect("valid regex");
+    entity
+        .replace_all(text, |captures: &regex::Captures| {
+            let name = &captures[1];
+            let decoded = match name {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ if name.starts_with("#x") || name.starts_with("#X") => {
+                    u32::from_str_radix(&name[2..], 16).ok().and_then(char::from_u32)
+                }
+                _ if name.starts_with('#') => name[1..].parse().ok().and_then(char::from_u32),
+                _ => None,
+            };
+            decoded.map_or_else(|| captures[0].to_string(), String::from)
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{
+        block_on,
+        testing::{http_response, serve},
+    };
+
+    /// Fetches a page from `url`, which is served locally.
+    fn fetch(url: &str) -> Result<String, String> {
+        block_on(fetch_text(parse_fetch_url(url).unwrap()))
+    }
+
+    #[test]
+    fn only_web_urls_are_fetched() {
+        assert_eq!(parse_fetch_url(" https://example.com/a ").unwrap().as_str(), "https://example.com/a");
+        assert!(parse_fetch_url("http://example.com").is_ok());
+        assert_eq!(
+            parse_fetch_url("file:///etc/passwd").unwrap_err(),
+            "Error: Only http and https URLs can be fetched, not 'file'."
+        );
+        assert!(parse_fetch_url("ftp://example.com/file").is_err());
+        assert!(parse_fetch_url("not a url").unwrap_err().contains("is not a valid URL"));
+    }
+
+    #[test]
+    fn markup_is_stripped() {
+        let html = "<html><head><title>Hidden</title></head><body>\
+                    <script>alert(1)</script><style>p {}</style><!-- note -->\
+                    <h1>Install</h1><p>Run <code>make</code> &amp; wait.</p>\
+                    <ul><li>one</li><li>two&#33;</li></ul></body></html>";
+        assert_eq!(html_to_text(html), "Install\nRun make & wait.\n\n- one\n- two!");
+    }
+
+    #[test]
+    fn short_documents_are_recognized_as_html() {
+        assert!(looks_like_html("<html></html>"));
+        assert!(looks_like_html("  <HTML>"));
+        assert!(looks_like_html("<!DOCTYPE html><p>hi</p>"));
+        assert!(!looks_like_html("<h"));
+        assert!(!looks_like_html("plain text"));
+    }
+
+    #[test]
+    fn long_pages_are_truncated() {
+        let body = "a".repeat(MAX_FETCHED_TEXT_BYTES + 100);
+        let (url, _server) = serve(vec![http_response("200 OK", &["Content-Type: text/plain"], &body)]);
+        let text = fetch(&url).unwrap();
+        assert!(text.starts_with(&"a".repeat(MAX_FETCHED_TEXT_BYTES)));
+        assert!(text.ends_with("\n[... page truncated to the first 32768 bytes of text ...]"));
+    }
+
+    #[test]
+    fn redirects_are_followed_up_to_the_limit() {
+        let redirect = http_response("302 Found", &["Location: /next"], "");
+        let mut responses = vec![redirect.clone(); MAX_REDIRECTS];
+        responses.push(http_response("200 OK", &["Content-Type: text/plain"], "arrived"));
+        let (url, _server) = serve(responses);
+        assert_eq!(fetch(&url).unwrap(), format!("[Redirected to {}/next]\narrived", url));
+
+        let (url, _server) = serve(vec![redirect; MAX_REDIRECTS + 1]);
+        assert!(fetch(&url).unwrap_err().contains("more than 5 redirects"), "{}", url);
+    }
+
+    #[test]
+    fn redirects_to_other_schemes_are_refused() {
+        let (url, _server) = serve(vec![http_response("302 Found", &["Location: file:///etc/passwd"], "")]);
+        // reqwest hands back the redirect itself rather than follow it to a file
+        let error = fetch(&url).unwrap_err();
+        assert!(error.ends_with("returned HTTP 302 Found."), "{}", error);
+    }
+}